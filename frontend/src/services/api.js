@@ -79,6 +79,11 @@ export const api = {
       body: JSON.stringify(credentials)
     }),
     
+    loginTwoFactor: (data) => apiRequest(`${config.api.auth}/login/2fa`, {
+      method: 'POST',
+      body: JSON.stringify(data)
+    }),
+    
     register: (userData) => apiRequest(`${config.api.auth}/register`, {
       method: 'POST',
       body: JSON.stringify(userData)
@@ -43,6 +43,9 @@ const createAuthStore = () => {
       if (typeof window !== 'undefined') {
         localStorage.setItem('jwt', userData.token);
         sessionStorage.setItem('jwt', userData.token);
+        if (userData.refresh_token) {
+          localStorage.setItem('refresh_token', userData.refresh_token);
+        }
         localStorage.setItem('user', JSON.stringify({
           id: userData.user_id || userData.id,
           username: userData.username,
@@ -72,8 +75,18 @@ const createAuthStore = () => {
         return;
       }
       
+      // Sign the session out on the server too
+      const token = localStorage.getItem('jwt');
+      if (token) {
+        fetch(config.api.auth + '/logout', {
+          method: 'POST',
+          headers: { 'Authorization': `Bearer ${token}` }
+        }).catch(error => console.error('Logout request failed:', error));
+      }
+      
       localStorage.removeItem('jwt');
       sessionStorage.removeItem('jwt');
+      localStorage.removeItem('refresh_token');
       // Only remove auth_token_backup if not in checkout flow
       if (!window.location.pathname.includes('/checkout/')) {
         localStorage.removeItem('auth_token_backup');
@@ -86,9 +99,34 @@ const createAuthStore = () => {
       set(initialState);
     },
     
+    // Trade the refresh token for a new access token; returns the new token or null
+    refresh: async () => {
+      const refreshToken = localStorage.getItem('refresh_token');
+      if (!refreshToken) {
+        return null;
+      }
+      
+      const response = await fetch(config.api.auth + '/refresh', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ refresh_token: refreshToken })
+      });
+      if (!response.ok) {
+        localStorage.removeItem('refresh_token');
+        return null;
+      }
+      
+      const data = await response.json();
+      localStorage.setItem('jwt', data.token);
+      sessionStorage.setItem('jwt', data.token);
+      localStorage.setItem('refresh_token', data.refresh_token);
+      update(state => ({ ...state, token: data.token }));
+      return data.token;
+    },
+    
     // Check if user is authenticated and fetch profile
     checkAuth: async () => {
-      const token = localStorage.getItem('jwt') || sessionStorage.getItem('jwt');
+      let token = localStorage.getItem('jwt') || sessionStorage.getItem('jwt');
       const savedUser = localStorage.getItem('user');
       
       if (!token) {
@@ -111,14 +149,22 @@ const createAuthStore = () => {
           }));
         }
         
-        // Verify with backend
-        const response = await fetch(config.api.auth + '/profile', {
+        // Verify with backend, renewing an expired access token once
+        const fetchProfile = (token) => fetch(config.api.auth + '/profile', {
           headers: {
             'Authorization': `Bearer ${token}`,
             'Accept': 'application/json',
             'Content-Type': 'application/json'
           }
         });
+        let response = await fetchProfile(token);
+        if (response.status === 401) {
+          const renewed = await authStore.refresh();
+          if (renewed) {
+            token = renewed;
+            response = await fetchProfile(token);
+          }
+        }
         
         if (!response.ok) {
           const errorText = await response.text();
@@ -148,6 +194,7 @@ const createAuthStore = () => {
         // Clear auth state on failure
         localStorage.removeItem('jwt');
         sessionStorage.removeItem('jwt');
+        localStorage.removeItem('refresh_token');
         localStorage.removeItem('user');
         set(initialState);
         return false;
@@ -0,0 +1,504 @@
+//! Self-service account settings: changing the password or username, and
+//! deleting the account.
+//!
+//! Password changes and deletion need the current password; wrong guesses
+//! count towards the same backoff and lockout as failed logins. Deleting an
+//! account keeps its orders for the store's records but detaches them from
+//! the account and removes the customer's personal details from them.
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder, post, put, delete};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Row, SqliteConnection, SqlitePool};
+use crate::AppState;
+use crate::auth;
+use crate::login_guard;
+use crate::passwords::{self, PasswordError};
+use crate::pgp;
+use crate::rbac::{self, RoleError};
+use crate::session;
+use crate::two_factor;
+
+pub const USERNAME_MIN_LENGTH: usize = 3;
+pub const USERNAME_MAX_LENGTH: usize = 32;
+
+/// Orders that may still need the customer's shipping details
+const OPEN_ORDER_STATUSES: [&str; 4] = ["Pending", "AwaitingPayment", "Paid", "Shipped"];
+
+/// Accounts, roles and the permissions they grant. Kept across restarts,
+/// and created by `admin bootstrap` too so it works before the first start.
+pub const ACCOUNT_TABLES: [&str; 3] = [
+    r#"
+    CREATE TABLE IF NOT EXISTS users (
+        id TEXT PRIMARY KEY NOT NULL,
+        username TEXT UNIQUE NOT NULL,
+        password_hash TEXT NOT NULL,
+        role TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS roles (
+        name TEXT PRIMARY KEY NOT NULL,
+        description TEXT NOT NULL DEFAULT '',
+        built_in INTEGER NOT NULL DEFAULT 0,
+        created_at INTEGER NOT NULL
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS role_permissions (
+        role TEXT NOT NULL REFERENCES roles(name),
+        permission TEXT NOT NULL,
+        PRIMARY KEY (role, permission)
+    )
+    "#,
+];
+
+#[derive(Debug)]
+pub enum AccountError {
+    /// The current password was wrong (401)
+    InvalidPassword,
+    /// The new username isn't allowed (400)
+    InvalidUsername(String),
+    /// The new username belongs to someone else (409)
+    UsernameTaken,
+    /// Orders still being processed need the account (409)
+    OpenOrders(i64),
+    /// Too many wrong passwords (429)
+    Blocked(login_guard::Blocked),
+    Password(PasswordError),
+    Role(RoleError),
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for AccountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountError::InvalidPassword => write!(f, "Current password is incorrect"),
+            AccountError::InvalidUsername(e) => write!(f, "{}", e),
+            AccountError::UsernameTaken => write!(f, "Username already exists"),
+            AccountError::OpenOrders(n) => write!(
+                f, "This account has {} order(s) still being processed; delete it once they are completed or cancelled", n
+            ),
+            AccountError::Blocked(_) => write!(f, "Too many failed attempts"),
+            AccountError::Password(e) => write!(f, "{}", e),
+            AccountError::Role(e) => write!(f, "{}", e),
+            AccountError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AccountError {
+    fn from(e: sqlx::Error) -> Self {
+        AccountError::Database(e)
+    }
+}
+
+impl From<PasswordError> for AccountError {
+    fn from(e: PasswordError) -> Self {
+        AccountError::Password(e)
+    }
+}
+
+impl From<RoleError> for AccountError {
+    fn from(e: RoleError) -> Self {
+        match e {
+            RoleError::Database(e) => AccountError::Database(e),
+            e => AccountError::Role(e),
+        }
+    }
+}
+
+impl AccountError {
+    pub fn to_response(&self) -> HttpResponse {
+        let mut response = match self {
+            AccountError::InvalidPassword => HttpResponse::Unauthorized(),
+            AccountError::InvalidUsername(_) => HttpResponse::BadRequest(),
+            AccountError::UsernameTaken | AccountError::OpenOrders(_) => HttpResponse::Conflict(),
+            AccountError::Blocked(blocked) => return blocked.to_response(),
+            AccountError::Password(e) => return e.to_response(),
+            AccountError::Role(e) => return e.to_response(),
+            AccountError::Database(e) => {
+                error!("Account update failed: {}", e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to update account"
+                }));
+            },
+        };
+        response.json(json!({
+            "success": false,
+            "error": self.to_string()
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeUsernameRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: String,
+}
+
+/// Letters, digits, `.`, `_` and `-`, starting with a letter or digit
+fn check_username(username: &str) -> Result<(), AccountError> {
+    let length = username.chars().count();
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        return Err(AccountError::InvalidUsername(format!(
+            "Username must be {} to {} characters", USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH
+        )));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        || !username.starts_with(|c: char| c.is_ascii_alphanumeric())
+    {
+        return Err(AccountError::InvalidUsername(
+            "Username may only contain letters, digits, '.', '_' and '-', and must start with a letter or digit".to_string()
+        ));
+    }
+    Ok(())
+}
+
+/// Validate a username someone wants to take and make sure no other account
+/// has it, compared case-insensitively so look-alikes can't be registered.
+/// `user_id` is the account taking it, if it already exists.
+pub(crate) async fn check_new_username(pool: &SqlitePool, username: &str, user_id: Option<&str>) -> Result<(), AccountError> {
+    check_username(username)?;
+    let taken = sqlx::query("SELECT id FROM users WHERE username = ? COLLATE NOCASE AND id IS NOT ?")
+        .bind(username)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    if taken.is_some() {
+        return Err(AccountError::UsernameTaken);
+    }
+    Ok(())
+}
+
+/// Check the signed-in user's current password, throttled like login.
+async fn confirm_password(
+    pool: &SqlitePool,
+    req: &HttpRequest,
+    claims: &auth::Claims,
+    password: &str,
+) -> Result<(), AccountError> {
+    let row = sqlx::query("SELECT username, password_hash FROM users WHERE id = ?")
+        .bind(&claims.sub)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AccountError::InvalidPassword)?;
+    let username: String = row.get("username");
+    let (device, ip) = auth::client_info(req);
+    let attempt = login_guard::Attempt { username: &username, ip: &ip, device: &device };
+
+    if let Some(blocked) = login_guard::check(pool, &attempt).await? {
+        return Err(AccountError::Blocked(blocked));
+    }
+    if !passwords::verify_password(password, &row.get::<String, _>("password_hash")) {
+        warn!("Wrong current password for {}", username);
+        login_guard::record_failure(pool, &attempt, Some(&claims.sub)).await?;
+        return Err(AccountError::InvalidPassword);
+    }
+    Ok(())
+}
+
+/// Remove a user and everything tied to them. Orders stay, detached from the
+/// account; finished orders also lose the customer's name and address.
+/// Orders still in progress keep their shipping details so they can be
+/// delivered. Login history is kept without the account or its name.
+pub async fn delete_user_data(conn: &mut SqliteConnection, user_id: &str) -> Result<u64, sqlx::Error> {
+    let username: Option<String> = sqlx::query("SELECT username FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .map(|row| row.get("username"));
+    let Some(username) = username else {
+        return Ok(0);
+    };
+
+    sqlx::query(
+        "UPDATE orders SET shipping_name = 'Deleted user', shipping_address = '', shipping_city = '',
+            shipping_state = '', shipping_zip = '', shipping_country = '', shipping_email = ''
+         WHERE user_id = ? AND status NOT IN (?, ?, ?, ?)"
+    )
+    .bind(user_id)
+    .bind(OPEN_ORDER_STATUSES[0])
+    .bind(OPEN_ORDER_STATUSES[1])
+    .bind(OPEN_ORDER_STATUSES[2])
+    .bind(OPEN_ORDER_STATUSES[3])
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query("UPDATE orders SET user_id = NULL WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("UPDATE order_status_history SET actor = 'customer' WHERE actor = ?")
+        .bind(format!("customer:{}", user_id))
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("UPDATE coupon_redemptions SET user_id = NULL WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM addresses WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+
+    session::delete_user_sessions(&mut *conn, user_id).await?;
+    two_factor::delete_user_factors(&mut *conn, user_id).await?;
+    pgp::delete_user_keys(&mut *conn, user_id).await?;
+    login_guard::delete_user_records(&mut *conn, user_id, &username).await?;
+    let result = sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+// Change the password; every other session is signed out
+#[post("/password")]
+pub async fn change_password(
+    req: HttpRequest,
+    body: web::Json<ChangePasswordRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+
+    let result = async {
+        confirm_password(&app_state.db, &req, &claims, &body.current_password).await?;
+        passwords::check_policy(&body.new_password, &claims.username)?;
+        let password_hash = passwords::hash_password(&body.new_password)?;
+        sqlx::query("UPDATE users SET password_hash = ? WHERE id = ?")
+            .bind(&password_hash)
+            .bind(&claims.sub)
+            .execute(&app_state.db)
+            .await?;
+        let revoked = session::revoke_all_sessions(
+            &app_state.db, &claims.sub, claims.sid.as_deref(), "password changed",
+        ).await?;
+        Ok::<_, AccountError>(revoked)
+    }.await;
+
+    match result {
+        Ok(revoked) => {
+            info!("User {} changed their password; {} other session(s) signed out", claims.sub, revoked);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "sessions_revoked": revoked
+            }))
+        },
+        Err(e) => e.to_response(),
+    }
+}
+
+// Change the username; returns a new access token carrying it
+#[put("/username")]
+pub async fn change_username(
+    req: HttpRequest,
+    body: web::Json<ChangeUsernameRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    let username = body.username.trim();
+
+    let result = async {
+        check_new_username(&app_state.db, username, Some(&claims.sub)).await?;
+        sqlx::query("UPDATE users SET username = ? WHERE id = ?")
+            .bind(username)
+            .bind(&claims.sub)
+            .execute(&app_state.db)
+            .await
+            .map_err(|e| match &e {
+                sqlx::Error::Database(db) if db.is_unique_violation() => AccountError::UsernameTaken,
+                _ => AccountError::Database(e),
+            })?;
+        Ok::<_, AccountError>(())
+    }.await;
+
+    if let Err(e) = result {
+        return e.to_response();
+    }
+    info!("User {} changed username from {} to {}", claims.sub, claims.username, username);
+    match session::create_jwt(&claims.sub, username, &claims.role, claims.sid.as_deref()) {
+        Ok(token) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "username": username,
+            "token": token
+        })),
+        Err(e) => {
+            error!("Failed to issue token after username change: {}", e);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "username": username
+            }))
+        }
+    }
+}
+
+// Delete the current user's account
+#[delete("/account")]
+pub async fn delete_account(
+    req: HttpRequest,
+    body: web::Json<DeleteAccountRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+
+    let result = async {
+        confirm_password(&app_state.db, &req, &claims, &body.password).await?;
+
+        let mut tx = app_state.db.begin().await?;
+        rbac::ensure_not_last_admin(&mut tx, &claims.sub).await?;
+        let open_orders: i64 = sqlx::query(
+            "SELECT COUNT(*) AS n FROM orders WHERE user_id = ? AND status IN (?, ?, ?, ?)"
+        )
+        .bind(&claims.sub)
+        .bind(OPEN_ORDER_STATUSES[0])
+        .bind(OPEN_ORDER_STATUSES[1])
+        .bind(OPEN_ORDER_STATUSES[2])
+        .bind(OPEN_ORDER_STATUSES[3])
+        .fetch_one(&mut *tx)
+        .await?
+        .get("n");
+        if open_orders > 0 {
+            return Err(AccountError::OpenOrders(open_orders));
+        }
+        delete_user_data(&mut tx, &claims.sub).await?;
+        tx.commit().await?;
+        Ok::<_, AccountError>(())
+    }.await;
+
+    match result {
+        Ok(()) => {
+            info!("User {} ({}) deleted their account", claims.sub, claims.username);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "Account deleted"
+            }))
+        },
+        Err(e) => e.to_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in ACCOUNT_TABLES.into_iter().chain([
+            "CREATE TABLE orders (id TEXT PRIMARY KEY, user_id TEXT, status TEXT NOT NULL, shipping_name TEXT NOT NULL, shipping_address TEXT NOT NULL, shipping_city TEXT NOT NULL, shipping_state TEXT NOT NULL, shipping_zip TEXT NOT NULL, shipping_country TEXT NOT NULL, shipping_email TEXT NOT NULL)",
+            "CREATE TABLE order_status_history (id INTEGER PRIMARY KEY AUTOINCREMENT, order_id TEXT NOT NULL, actor TEXT NOT NULL)",
+            "CREATE TABLE coupon_redemptions (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id TEXT)",
+            "CREATE TABLE addresses (id TEXT PRIMARY KEY, user_id TEXT NOT NULL)",
+            "CREATE TABLE sessions (id TEXT PRIMARY KEY, user_id TEXT NOT NULL)",
+            "CREATE TABLE refresh_tokens (token_hash TEXT PRIMARY KEY, session_id TEXT NOT NULL)",
+            "CREATE TABLE user_totp (user_id TEXT PRIMARY KEY)",
+            "CREATE TABLE totp_recovery_codes (user_id TEXT NOT NULL, code_hash TEXT NOT NULL)",
+            "CREATE TABLE user_pgp_keys (user_id TEXT PRIMARY KEY)",
+            "CREATE TABLE pgp_challenges (id TEXT PRIMARY KEY, user_id TEXT)",
+            "CREATE TABLE pgp_key_changes (user_id TEXT PRIMARY KEY)",
+            "CREATE TABLE login_failures (scope TEXT NOT NULL, key TEXT NOT NULL, failures INTEGER NOT NULL, last_failure_at INTEGER NOT NULL, blocked_until INTEGER NOT NULL, locked BOOLEAN NOT NULL DEFAULT FALSE, PRIMARY KEY (scope, key))",
+            "CREATE TABLE login_events (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id TEXT, username TEXT NOT NULL, ip TEXT NOT NULL, device TEXT NOT NULL, event TEXT NOT NULL, detail TEXT, created_at INTEGER NOT NULL)",
+            "INSERT INTO users (id, username, password_hash, role, created_at) VALUES ('u1', 'alice', 'x', 'user', 0), ('u2', 'bob', 'x', 'user', 0)",
+            "INSERT INTO orders VALUES
+                ('ORD-done', 'u1', 'Completed', 'Alice', '1 Road', 'Town', 'State', '12345', 'NL', 'a@example.com'),
+                ('ORD-open', 'u1', 'Paid', 'Alice', '1 Road', 'Town', 'State', '12345', 'NL', 'a@example.com'),
+                ('ORD-bob', 'u2', 'Completed', 'Bob', '2 Road', 'City', 'Region', '54321', 'DE', 'b@example.com')",
+            "INSERT INTO order_status_history (order_id, actor) VALUES ('ORD-done', 'customer:u1'), ('ORD-bob', 'customer:u2')",
+            "INSERT INTO coupon_redemptions (user_id) VALUES ('u1')",
+            "INSERT INTO addresses VALUES ('addr-1', 'u1')",
+            "INSERT INTO sessions VALUES ('s1', 'u1'), ('s2', 'u2')",
+            "INSERT INTO refresh_tokens VALUES ('t1', 's1'), ('t2', 's2')",
+            "INSERT INTO user_totp VALUES ('u1')",
+            "INSERT INTO user_pgp_keys VALUES ('u1')",
+            "INSERT INTO login_events (user_id, username, ip, device, event, created_at) VALUES ('u1', 'alice', '10.0.0.1', 'test', 'login', 0)",
+        ]) {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    async fn count(db: &SqlitePool, sql: &str) -> i64 {
+        sqlx::query(sql).fetch_one(db).await.unwrap().get(0)
+    }
+
+    #[test]
+    fn usernames_are_checked() {
+        assert!(check_username("alice.b-1").is_ok());
+        assert!(check_username("al").is_err());
+        assert!(check_username(&"a".repeat(USERNAME_MAX_LENGTH + 1)).is_err());
+        assert!(check_username("_alice").is_err());
+        assert!(check_username("a b").is_err());
+    }
+
+    #[actix_web::test]
+    async fn taken_usernames_ignore_case_but_not_the_owner() {
+        let db = database().await;
+        assert!(matches!(check_new_username(&db, "ALICE", None).await, Err(AccountError::UsernameTaken)));
+        assert!(check_new_username(&db, "Alice", Some("u1")).await.is_ok());
+        assert!(check_new_username(&db, "carol", None).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn deleting_a_user_removes_their_data() {
+        let db = database().await;
+        let mut conn = db.acquire().await.unwrap();
+        assert_eq!(delete_user_data(&mut conn, "u1").await.unwrap(), 1);
+        assert_eq!(delete_user_data(&mut conn, "u1").await.unwrap(), 0);
+        drop(conn);
+
+        // Finished orders keep nothing that identifies the customer
+        let row = sqlx::query("SELECT * FROM orders WHERE id = 'ORD-done'").fetch_one(&db).await.unwrap();
+        assert_eq!(row.get::<Option<String>, _>("user_id"), None);
+        assert_eq!(row.get::<String, _>("shipping_name"), "Deleted user");
+        for column in ["shipping_address", "shipping_city", "shipping_state", "shipping_zip", "shipping_country", "shipping_email"] {
+            assert_eq!(row.get::<String, _>(column), "", "{}", column);
+        }
+
+        // Open orders still need somewhere to go
+        let row = sqlx::query("SELECT * FROM orders WHERE id = 'ORD-open'").fetch_one(&db).await.unwrap();
+        assert_eq!(row.get::<Option<String>, _>("user_id"), None);
+        assert_eq!(row.get::<String, _>("shipping_country"), "NL");
+
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM order_status_history WHERE actor LIKE 'customer:%'").await, 1);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM coupon_redemptions WHERE user_id IS NOT NULL").await, 0);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM users WHERE id = 'u1'").await, 0);
+        for table in ["addresses", "sessions", "user_totp", "user_pgp_keys"] {
+            let sql = format!("SELECT COUNT(*) FROM {} WHERE user_id = 'u1'", table);
+            assert_eq!(count(&db, &sql).await, 0, "{}", table);
+        }
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM refresh_tokens").await, 1);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM login_events WHERE user_id IS NOT NULL OR username = 'alice'").await, 0);
+
+        // Someone else's account is untouched
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM users WHERE id = 'u2'").await, 1);
+        let row = sqlx::query("SELECT * FROM orders WHERE id = 'ORD-bob'").fetch_one(&db).await.unwrap();
+        assert_eq!(row.get::<String, _>("shipping_country"), "DE");
+    }
+}
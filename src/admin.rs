@@ -1,9 +1,27 @@
 // src/admin.rs
-use actix_web::{web, HttpResponse, Responder, HttpRequest, get, post, put, delete};
+use actix_web::{web, HttpResponse, Responder, get, post, put, delete};
 use crate::AppState;
+use crate::middleware::StaffMiddleware;
+use crate::rbac::{self, perm, Authorized};
+use crate::inventory;
+use crate::variants;
+use crate::catalog_io;
+use crate::categories;
+use crate::coupons;
+use crate::digital;
+use crate::images;
+use crate::search;
+use crate::shipping;
+use crate::tax;
+use crate::shipments;
+use crate::orders::{self, OrderStatus, StatusChange};
+use crate::products;
+use crate::passwords;
+use crate::login_guard;
+use crate::account;
 use sqlx;
 use serde_json;
-use log::{info, error};
+use log::{info, warn, error};
 use serde::{Deserialize, Serialize};
 use sqlx::Row;
 use chrono::Utc;
@@ -75,7 +93,7 @@ pub async fn admin_dashboard() -> HttpResponse {
                     
                     const response = await fetch(`/admin/${endpoint}`, {
                         headers: {
-                            'Authorization': 'Bearer admintoken'
+                            'Authorization': 'Bearer ' + (localStorage.getItem('jwt') || '')
                         }
                     });
                     
@@ -151,7 +169,7 @@ pub async fn admin_dashboard() -> HttpResponse {
         .body(html)
 }
 
-pub async fn get_all_users(_req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+pub async fn get_all_users(_auth: Authorized<perm::UsersManage>, data: web::Data<AppState>) -> impl Responder {
     info!("Fetching all users");
     
     match sqlx::query!("SELECT id, username, role, created_at FROM users")
@@ -191,14 +209,7 @@ pub async fn get_all_users(_req: HttpRequest, data: web::Data<AppState>) -> impl
 }
 
 // Fix product display in orders table
-pub async fn get_all_orders(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
-    // Check authorization header
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        info!("Auth header found: {:?}", auth_header);
-    } else {
-        info!("No auth header, but allowing access for testing");
-    }
-    
+pub async fn get_all_orders(_auth: Authorized<perm::OrdersRead>, data: web::Data<AppState>) -> impl Responder {
     // Update query to match our actual database schema
     match sqlx::query!(
         "SELECT o.id, o.user_id, o.payment_id, o.status, o.created_at, 
@@ -243,38 +254,20 @@ pub async fn get_all_orders(req: HttpRequest, data: web::Data<AppState>) -> impl
     }
 }
 
-// Fix the unused variable in get_all_products
-pub async fn get_all_products(_req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
-    // Always allow access for testing
+// Legacy product listing for the admin panel; includes archived products
+pub async fn get_all_products(_auth: Authorized<perm::ProductsWrite>, data: web::Data<AppState>) -> impl Responder {
     info!("Attempting to fetch all products from database");
     
-    match sqlx::query!("SELECT * FROM products ORDER BY created_at DESC")
-        .fetch_all(&data.db)
-        .await
-    {
+    match products::fetch_products(&data.db, true).await {
         Ok(products) => {
-            let products_json: Vec<serde_json::Value> = products
-                .iter()
-                .map(|p| {
-                    serde_json::json!({
-                        "id": p.id,
-                        "name": p.name,
-                        "description": p.description,
-                        "price": p.price,
-                        "available": p.available,
-                        "created_at": p.created_at
-                    })
-                })
-                .collect();
-            
-            info!("Found {} products in database", products_json.len());
-            return HttpResponse::Ok().json(products_json);
+            info!("Found {} products in database", products.len());
+            HttpResponse::Ok().json(products)
         }
         Err(e) => {
             error!("Failed to fetch products: {}", e);
-            return HttpResponse::InternalServerError().json(
+            HttpResponse::InternalServerError().json(
                 serde_json::json!({"error": format!("Failed to fetch products: {}", e)})
-            );
+            )
         }
     }
 }
@@ -304,38 +297,36 @@ pub struct UpdateUserRequest {
 // Create a new user
 #[post("/users")]
 pub async fn create_user(
-    _req: HttpRequest,
+    auth: Authorized<perm::UsersManage>,
     app_state: web::Data<AppState>,
     user_data: web::Json<CreateUserRequest>
 ) -> impl Responder {
-    // In production, verify admin rights here
     info!("Creating new user: {}", user_data.username);
     
-    // Check if username already exists
-    match sqlx::query("SELECT COUNT(*) as count FROM users WHERE username = ?")
-        .bind(&user_data.username)
-        .fetch_one(&app_state.db)
-        .await {
-            Ok(row) => {
-                let count: i64 = row.get("count");
-                if count > 0 {
-                    return HttpResponse::BadRequest().json(json!({
-                        "success": false,
-                        "error": "Username already exists"
-                    }));
-                }
-            },
-            Err(e) => {
-                error!("Database error checking username: {}", e);
-                return HttpResponse::InternalServerError().json(json!({
-                    "success": false,
-                    "error": "Database error"
-                }));
-            }
-        }
+    let role = user_data.role.trim().to_lowercase();
+    match rbac::role_exists(&app_state.db, &role).await {
+        Ok(true) => {},
+        Ok(false) => return rbac::RoleError::RoleNotFound(role).to_response(),
+        Err(e) => return rbac::RoleError::Database(e).to_response(),
+    }
+    let allowed = async {
+        let mut conn = app_state.db.acquire().await?;
+        rbac::ensure_admin_caller(&mut conn, &auth.claims.sub, "", Some(&role)).await
+    }.await;
+    if let Err(e) = allowed {
+        return e.to_response();
+    }
     
-    // In production, hash the password (use bcrypt, argon2, etc.)
-    let password_hash = user_data.password.clone(); // Replace with real hashing
+    // Same username rules as self-service registration
+    if let Err(e) = account::check_new_username(&app_state.db, &user_data.username, None).await {
+        return e.to_response();
+    }
+    
+    let password_hash = match passwords::check_policy(&user_data.password, &user_data.username)
+        .and_then(|_| passwords::hash_password(&user_data.password)) {
+        Ok(hash) => hash,
+        Err(e) => return e.to_response(),
+    };
     
     let user_id = format!("usr-{}", Uuid::new_v4().simple());
     let now = Utc::now().timestamp();
@@ -346,18 +337,19 @@ pub async fn create_user(
         .bind(&user_id)
         .bind(&user_data.username)
         .bind(&password_hash)
-        .bind(&user_data.role)
+        .bind(&role)
         .bind(now)
         .execute(&app_state.db)
         .await {
             Ok(_) => {
+                info!("User {} created with role {} by {}", user_id, role, auth.claims.username);
                 HttpResponse::Created().json(json!({
                     "success": true,
                     "message": "User created successfully",
                     "user": {
                         "id": user_id,
                         "username": user_data.username,
-                        "role": user_data.role,
+                        "role": role,
                         "created_at": now
                     }
                 }))
@@ -375,7 +367,7 @@ pub async fn create_user(
 // Update a user
 #[put("/users/{id}")]
 pub async fn update_user(
-    _req: HttpRequest,
+    auth: Authorized<perm::UsersManage>,
     app_state: web::Data<AppState>,
     path: web::Path<String>,
     user_data: web::Json<UpdateUserRequest>
@@ -384,18 +376,16 @@ pub async fn update_user(
     info!("Updating user: {}", user_id);
     
     // Check if user exists
-    match sqlx::query("SELECT COUNT(*) as count FROM users WHERE id = ?")
+    let current_username: String = match sqlx::query("SELECT username FROM users WHERE id = ?")
         .bind(&user_id)
-        .fetch_one(&app_state.db)
+        .fetch_optional(&app_state.db)
         .await {
-            Ok(row) => {
-                let count: i64 = row.get("count");
-                if count == 0 {
-                    return HttpResponse::NotFound().json(json!({
-                        "success": false,
-                        "error": "User not found"
-                    }));
-                }
+            Ok(Some(row)) => row.get("username"),
+            Ok(None) => {
+                return HttpResponse::NotFound().json(json!({
+                    "success": false,
+                    "error": "User not found"
+                }));
             },
             Err(e) => {
                 error!("Database error checking user: {}", e);
@@ -404,29 +394,57 @@ pub async fn update_user(
                     "error": "Database error"
                 }));
             }
+        };
+    
+    // Only admins may take over an admin's login
+    if user_data.username.is_some() || user_data.password.is_some() {
+        let allowed = async {
+            let mut conn = app_state.db.acquire().await?;
+            rbac::ensure_admin_caller(&mut conn, &auth.claims.sub, &user_id, None).await
+        }.await;
+        if let Err(e) = allowed {
+            return e.to_response();
         }
+    }
     
     // Update user based on what was provided
     let mut query_parts = Vec::new();
     let mut bindings = Vec::new();
     
     if let Some(username) = &user_data.username {
+        if let Err(e) = account::check_new_username(&app_state.db, username, Some(&user_id)).await {
+            return e.to_response();
+        }
         query_parts.push("username = ?");
         bindings.push(username.clone());
     }
     
     if let Some(password) = &user_data.password {
         if !password.is_empty() {
-            // In production, hash the password
-            let password_hash = password.clone(); // Replace with real hashing
+            let username = user_data.username.as_deref().unwrap_or(&current_username);
+            let password_hash = match passwords::check_policy(password, username)
+                .and_then(|_| passwords::hash_password(password)) {
+                Ok(hash) => hash,
+                Err(e) => return e.to_response(),
+            };
             query_parts.push("password_hash = ?");
             bindings.push(password_hash);
         }
     }
     
+    // Role changes go through the same checks as the role assignment endpoint
     if let Some(role) = &user_data.role {
-        query_parts.push("role = ?");
-        bindings.push(role.clone());
+        if let Err(e) = rbac::assign_role(&app_state.db, &auth.claims.sub, &user_id, role).await {
+            warn!("Role change for user {} refused: {}", user_id, e);
+            return e.to_response();
+        }
+        info!("User {} given role {} by {}", user_id, role, auth.claims.username);
+        if query_parts.is_empty() {
+            return HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "User updated successfully"
+            }));
+        }
     }
     
     if query_parts.is_empty() {
@@ -472,45 +490,52 @@ pub async fn update_user(
 // Delete a user
 #[delete("/users/{id}")]
 pub async fn delete_user(
-    _req: HttpRequest,
+    auth: Authorized<perm::UsersManage>,
     app_state: web::Data<AppState>,
     path: web::Path<String>
 ) -> impl Responder {
     let user_id = path.into_inner();
     info!("Deleting user: {}", user_id);
     
-    match sqlx::query("DELETE FROM users WHERE id = ?")
-        .bind(&user_id)
-        .execute(&app_state.db)
-        .await {
-            Ok(result) => {
-                let rows_affected = result.rows_affected();
-                if rows_affected > 0 {
-                    HttpResponse::Ok().json(json!({
-                        "success": true,
-                        "message": "User deleted successfully"
-                    }))
-                } else {
-                    HttpResponse::NotFound().json(json!({
-                        "success": false,
-                        "error": "User not found"
-                    }))
-                }
-            },
-            Err(e) => {
-                error!("Failed to delete user: {}", e);
-                HttpResponse::InternalServerError().json(json!({
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        rbac::ensure_admin_caller(&mut tx, &auth.claims.sub, &user_id, None).await?;
+        rbac::ensure_not_last_admin(&mut tx, &user_id).await?;
+        let rows_affected = account::delete_user_data(&mut tx, &user_id).await?;
+        tx.commit().await?;
+        Ok::<_, rbac::RoleError>(rows_affected)
+    }.await;
+    
+    match result {
+        Err(e @ (rbac::RoleError::LastAdmin | rbac::RoleError::AdminOnly)) => e.to_response(),
+        Ok(rows_affected) => {
+            if rows_affected > 0 {
+                info!("User {} deleted by {}", user_id, auth.claims.username);
+                HttpResponse::Ok().json(json!({
+                    "success": true,
+                    "message": "User deleted successfully"
+                }))
+            } else {
+                HttpResponse::NotFound().json(json!({
                     "success": false,
-                    "error": "Failed to delete user"
+                    "error": "User not found"
                 }))
             }
+        },
+        Err(e) => {
+            error!("Failed to delete user {}: {}", user_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to delete user"
+            }))
         }
+    }
 }
 
 // Get a single user
 #[get("/users/{id}")]
 pub async fn get_user(
-    _req: HttpRequest,
+    _auth: Authorized<perm::UsersManage>,
     app_state: web::Data<AppState>,
     path: web::Path<String>
 ) -> impl Responder {
@@ -558,11 +583,12 @@ pub async fn get_user(
 #[derive(Deserialize)]
 pub struct UpdateOrderStatusRequest {
     pub status: String,
+    pub reason: Option<String>,
 }
 
 // Add this function to get all orders with details
 #[get("/orders")]
-pub async fn admin_get_all_orders(_req: HttpRequest, app_state: web::Data<AppState>) -> impl Responder {
+pub async fn admin_get_all_orders(_auth: Authorized<perm::OrdersRead>, app_state: web::Data<AppState>) -> impl Responder {
     info!("Admin: Fetching all orders with details");
     
     // This query joins orders with multiple tables to get comprehensive information
@@ -571,7 +597,7 @@ pub async fn admin_get_all_orders(_req: HttpRequest, app_state: web::Data<AppSta
             o.id, o.user_id, o.payment_id, o.status, o.shipping_name, 
             o.shipping_address, o.shipping_city, o.shipping_state,
             o.shipping_zip, o.shipping_country, o.shipping_email,
-            o.total_amount, o.created_at, o.updated_at,
+            o.total_amount, o.coupon_code, o.discount_amount, o.shipping_method_name, o.shipping_cost, o.tax_amount, o.prices_include_tax, o.created_at, o.updated_at,
             mp.address as monero_address, mp.status as payment_status
         FROM orders o
         LEFT JOIN monero_payments mp ON o.payment_id = mp.payment_id
@@ -599,6 +625,12 @@ pub async fn admin_get_all_orders(_req: HttpRequest, app_state: web::Data<AppSta
                         "shipping_country": row.get::<String, _>("shipping_country"),
                         "shipping_email": row.get::<String, _>("shipping_email"),
                         "total_amount": row.get::<f64, _>("total_amount"),
+                        "coupon_code": row.get::<Option<String>, _>("coupon_code"),
+                        "discount_amount": row.get::<f64, _>("discount_amount"),
+                        "shipping_method_name": row.get::<Option<String>, _>("shipping_method_name"),
+                        "shipping_cost": row.get::<f64, _>("shipping_cost"),
+                        "tax_amount": row.get::<f64, _>("tax_amount"),
+                        "prices_include_tax": row.get::<bool, _>("prices_include_tax"),
                         "created_at": row.get::<i64, _>("created_at"),
                         "updated_at": row.get::<i64, _>("updated_at"),
                         "monero_address": row.get::<Option<String>, _>("monero_address"),
@@ -623,7 +655,7 @@ pub async fn admin_get_all_orders(_req: HttpRequest, app_state: web::Data<AppSta
 
 // Add a function to get a single order with details
 #[get("/orders/{id}")]
-pub async fn admin_get_order(_req: HttpRequest, app_state: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+pub async fn admin_get_order(_auth: Authorized<perm::OrdersRead>, app_state: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
     let order_id = path.into_inner();
     info!("Admin: Fetching order details for ID: {}", order_id);
     
@@ -633,7 +665,7 @@ pub async fn admin_get_order(_req: HttpRequest, app_state: web::Data<AppState>,
             o.id, o.user_id, o.payment_id, o.status, o.shipping_name, 
             o.shipping_address, o.shipping_city, o.shipping_state,
             o.shipping_zip, o.shipping_country, o.shipping_email,
-            o.total_amount, o.created_at, o.updated_at,
+            o.total_amount, o.coupon_code, o.discount_amount, o.shipping_method_name, o.shipping_cost, o.tax_amount, o.prices_include_tax, o.created_at, o.updated_at,
             mp.address as monero_address, mp.status as payment_status
         FROM orders o
         LEFT JOIN monero_payments mp ON o.payment_id = mp.payment_id
@@ -648,36 +680,46 @@ pub async fn admin_get_order(_req: HttpRequest, app_state: web::Data<AppState>,
     match order_result {
         Ok(Some(row)) => {
             // Now get the order items
-            let items_query = r#"
-                SELECT oi.id, oi.product_id, oi.quantity, oi.price, p.name
-                FROM order_items oi
-                LEFT JOIN products p ON oi.product_id = p.id
-                WHERE oi.order_number = ?
-            "#;
-            
-            let items_result = sqlx::query(items_query)
-                .bind(&order_id)
-                .fetch_all(&app_state.db)
-                .await;
-                
-            let items = match items_result {
-                Ok(items) => {
-                    items.iter().map(|item_row| {
-                        json!({
-                            "id": item_row.get::<i64, _>("id"),
-                            "product_id": item_row.get::<String, _>("product_id"),
-                            "name": item_row.get::<Option<String>, _>("name"),
-                            "quantity": item_row.get::<i64, _>("quantity"),
-                            "price": item_row.get::<f64, _>("price")
-                        })
-                    }).collect::<Vec<_>>()
-                },
+            let items = match orders::fetch_order_items(&app_state.db, &order_id).await {
+                Ok(items) => items,
                 Err(e) => {
                     error!("Failed to fetch order items: {}", e);
                     Vec::new()
                 }
             };
             
+            let digital = match digital::deliveries_for_order(&app_state.db, &order_id).await {
+                Ok(digital) => digital,
+                Err(e) => {
+                    error!("Failed to fetch digital deliveries: {}", e);
+                    Vec::new()
+                }
+            };
+            
+            let tax_lines = match tax::tax_lines_for_order(&app_state.db, &order_id).await {
+                Ok(lines) => lines,
+                Err(e) => {
+                    error!("Failed to fetch tax lines: {}", e);
+                    Vec::new()
+                }
+            };
+            
+            let status_history = match orders::status_history(&app_state.db, &order_id).await {
+                Ok(history) => history,
+                Err(e) => {
+                    error!("Failed to fetch status history: {}", e);
+                    Vec::new()
+                }
+            };
+            
+            let shipments = match shipments::shipments_for_order(&app_state.db, &order_id).await {
+                Ok(shipments) => shipments,
+                Err(e) => {
+                    error!("Failed to fetch shipments: {}", e);
+                    Vec::new()
+                }
+            };
+            
             let order = json!({
                 "id": row.get::<String, _>("id"),
                 "user_id": row.get::<Option<String>, _>("user_id"),
@@ -691,11 +733,21 @@ pub async fn admin_get_order(_req: HttpRequest, app_state: web::Data<AppState>,
                 "shipping_country": row.get::<String, _>("shipping_country"),
                 "shipping_email": row.get::<String, _>("shipping_email"),
                 "total_amount": row.get::<f64, _>("total_amount"),
+                "coupon_code": row.get::<Option<String>, _>("coupon_code"),
+                "discount_amount": row.get::<f64, _>("discount_amount"),
+                "shipping_method_name": row.get::<Option<String>, _>("shipping_method_name"),
+                "shipping_cost": row.get::<f64, _>("shipping_cost"),
+                "tax_amount": row.get::<f64, _>("tax_amount"),
+                "prices_include_tax": row.get::<bool, _>("prices_include_tax"),
                 "created_at": row.get::<i64, _>("created_at"),
                 "updated_at": row.get::<i64, _>("updated_at"),
                 "monero_address": row.get::<Option<String>, _>("monero_address"),
                 "payment_status": row.get::<Option<String>, _>("payment_status"),
-                "items": items
+                "items": items,
+                "tax_lines": tax_lines,
+                "digital": digital,
+                "shipments": shipments,
+                "status_history": status_history
             });
             
             HttpResponse::Ok().json(json!({
@@ -722,56 +774,54 @@ pub async fn admin_get_order(_req: HttpRequest, app_state: web::Data<AppState>,
 // Add endpoint to update order status
 #[put("/orders/{id}/status")]
 pub async fn update_order_status(
-    _req: HttpRequest,
+    auth: Authorized<perm::OrdersFulfil>,
     app_state: web::Data<AppState>,
     path: web::Path<String>,
     status_update: web::Json<UpdateOrderStatusRequest>
 ) -> impl Responder {
     let order_id = path.into_inner();
-    let new_status = &status_update.status;
     
-    info!("Updating order {} status to {}", order_id, new_status);
+    info!("Updating order {} status to {}", order_id, status_update.status);
     
-    let now = Utc::now().timestamp();
+    let new_status: OrderStatus = match status_update.status.parse() {
+        Ok(status) => status,
+        Err(e) => return e.to_response(),
+    };
     
-    match sqlx::query(
-        "UPDATE orders SET status = ?, updated_at = ? WHERE id = ?"
-    )
-    .bind(new_status)
-    .bind(now)
-    .bind(&order_id)
-    .execute(&app_state.db)
-    .await {
-        Ok(result) => {
-            if result.rows_affected() > 0 {
-                HttpResponse::Ok().json(json!({
-                    "success": true,
-                    "message": format!("Order status updated to {}", new_status)
-                }))
-            } else {
-                HttpResponse::NotFound().json(json!({
-                    "success": false,
-                    "error": "Order not found"
-                }))
-            }
-        },
+    let reason = status_update.reason.as_deref()
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(str::to_string);
+    let change = StatusChange::admin(&auth.claims.username, reason);
+    
+    match orders::change_status(&app_state.db, &order_id, new_status, &change).await {
+        Ok(previous) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "message": format!("Order status updated to {}", new_status),
+            "previous_status": previous
+        })),
         Err(e) => {
-            error!("Failed to update order status: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "success": false,
-                "error": format!("Failed to update order status: {}", e)
-            }))
+            warn!("Rejected status change for order {}: {}", order_id, e);
+            e.to_response()
         }
     }
 }
 
 // Update the init_routes function to correctly use the handlers
 pub fn init_routes() -> actix_web::Scope {
+    // The dashboard page is a static shell that loads its data from the
+    // guarded routes below with the admin's token
     web::scope("/admin")
         .route("", web::get().to(admin_dashboard))
         .route("/", web::get().to(admin_dashboard))
         .route("/dashboard", web::get().to(admin_dashboard))
         .route("/panel", web::get().to(admin_dashboard))
+        // Everything else under /admin is for staff, each route needing its own permission
+        .service(admin_api_scope().wrap(StaffMiddleware))
+}
+
+fn admin_api_scope() -> actix_web::Scope {
+    web::scope("")
         .route("/users", web::get().to(get_all_users))
         .service(admin_get_all_orders)  // Use service instead of route
         .service(
@@ -786,4 +836,67 @@ pub fn init_routes() -> actix_web::Scope {
         .service(delete_user)
         .service(admin_get_order)
         .service(update_order_status)
+        .service(rbac::list_permissions)
+        .service(rbac::get_roles)
+        .service(rbac::create_role)
+        .service(rbac::update_role)
+        .service(rbac::delete_role)
+        .service(rbac::set_user_role)
+        .service(login_guard::unlock_user)
+        .service(login_guard::list_login_events)
+        .service(inventory::get_product_stock)
+        .service(inventory::adjust_product_stock)
+        .service(variants::create_variant)
+        .service(variants::update_variant)
+        .service(variants::delete_variant)
+        .service(categories::create_category)
+        .service(categories::update_category)
+        .service(categories::delete_category)
+        .service(categories::set_product_category)
+        .service(categories::update_product_tags)
+        .service(search::rebuild_search_index)
+        .service(images::upload_product_images)
+        .service(images::reorder_product_images)
+        .service(images::delete_product_image)
+        .service(digital::set_digital_delivery)
+        .service(digital::list_digital_files)
+        .service(digital::upload_digital_files)
+        .service(digital::delete_digital_file)
+        .service(digital::list_license_keys)
+        .service(digital::add_license_keys)
+        .service(digital::delete_license_key)
+        .service(digital::retry_fulfilment)
+        .service(coupons::list_coupons)
+        .service(coupons::get_coupon)
+        .service(coupons::create_coupon)
+        .service(coupons::update_coupon)
+        .service(coupons::delete_coupon)
+        .service(shipping::list_zones)
+        .service(shipping::create_zone)
+        .service(shipping::update_zone)
+        .service(shipping::delete_zone)
+        .service(shipping::create_method)
+        .service(shipping::update_method)
+        .service(shipping::delete_method)
+        .service(tax::list_rules)
+        .service(tax::create_rule)
+        .service(tax::update_rule)
+        .service(tax::delete_rule)
+        .service(tax::tax_report)
+        .service(shipments::list_carriers)
+        .service(shipments::create_carrier)
+        .service(shipments::update_carrier)
+        .service(shipments::delete_carrier)
+        .service(shipments::list_order_shipments)
+        .service(shipments::ship_order)
+        // Registered before the /products/{id} routes so "import" and
+        // "export" are not taken as product ids
+        .service(catalog_io::import_products)
+        .service(catalog_io::export_products)
+        .service(products::admin_list_products)
+        .service(products::admin_get_product)
+        .service(products::update_product)
+        .service(products::archive_product)
+        .service(products::restore_product)
+        .service(products::duplicate_product)
 }
@@ -1,15 +1,20 @@
 // src/auth.rs
 use serde_json::json;
-use actix_web::{web, HttpResponse, Responder, HttpRequest, http::header, get};
+use actix_web::{web, HttpResponse, Responder, HttpRequest, ResponseError, http::header, http::StatusCode, get};
 use serde::{Deserialize, Serialize};
-use chrono::{Utc, Duration};
+use chrono::Utc;
 use uuid::Uuid;
 use crate::AppState;
 use crate::session;
+use crate::rbac::{self, perm, Authorized};
 use sqlx;
 use log::{info, error, warn};
-use bcrypt::{hash, DEFAULT_COST};
-use jsonwebtoken::{encode, Header, EncodingKey};
+use crate::passwords;
+use crate::login_guard;
+use crate::account;
+use crate::pgp;
+use crate::two_factor;
+use sqlx::Row;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
@@ -34,67 +39,56 @@ pub struct UserLogin {
 
 #[derive(Serialize)]
 pub struct UserResponse {
-    pub id: String,
+    pub user_id: String,
     pub username: String,
     pub role: String,
     pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
 }
 
-// Secret key for JWT tokens - in production, use environment variables
-pub const JWT_SECRET: &[u8] = b"secure_jwt_secret_key";
-const TOKEN_EXPIRY_HOURS: i64 = 24;
+pub const ROLE_ADMIN: &str = "admin";
+pub const ROLE_USER: &str = "user";
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String,        // Subject (user ID)
     pub username: String,   // Username
     pub role: String,       // User role
     pub exp: usize,        // Expiration time (UTC timestamp)
     pub iat: usize,        // Issued at (UTC timestamp)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>, // Session the token was issued for
 }
 
 pub async fn register(
+    req: HttpRequest,
     user_data: web::Json<UserRegistration>,
     state: web::Data<AppState>,
 ) -> impl Responder {
-    let user = user_data.into_inner();
-    
-    // Check if username already exists
-    let existing_user = sqlx::query!(
-        "SELECT id FROM users WHERE username = ?",
-        user.username
-    )
-    .fetch_optional(&state.db)
-    .await;
+    let mut user = user_data.into_inner();
+    user.username = user.username.trim().to_string();
     
-    match existing_user {
-        Ok(Some(_)) => {
-            warn!("Registration failed: Username {} already exists", user.username);
-            return HttpResponse::Conflict().json(
-                serde_json::json!({"error": "Username already exists"})
-            );
-        }
-        Ok(None) => {
-            // Username is available, create user
-            info!("Username {} is available, creating new user", user.username);
-        }
-        Err(e) => {
-            error!("Database error checking username: {}", e);
-            return HttpResponse::InternalServerError().json(
-                serde_json::json!({"error": "Internal server error"})
-            );
-        }
+    // Same rules as renaming an account, so names can't differ only by case
+    if let Err(e) = account::check_new_username(&state.db, &user.username, None).await {
+        warn!("Registration for {} refused: {}", user.username, e);
+        return e.to_response();
     }
+    info!("Username {} is available, creating new user", user.username);
     
-    // Hash password
-    let password_hash = match hash(&user.password, DEFAULT_COST) {
+    // Check the password against the policy, then hash it
+    if let Err(e) = passwords::check_policy(&user.password, &user.username) {
+        warn!("Registration for {} refused: {}", user.username, e);
+        return e.to_response();
+    }
+    let password_hash = match passwords::hash_password(&user.password) {
         Ok(hash) => hash,
-        Err(e) => {
-            error!("Failed to hash password: {}", e);
-            return HttpResponse::InternalServerError().json(
-                serde_json::json!({"error": "Failed to process password"})
-            );
-        }
+        Err(e) => return e.to_response(),
     };
     
     // Create new user
@@ -106,7 +100,7 @@ pub async fn register(
         user_id,
         user.username,
         password_hash,
-        "user", // Default role
+        ROLE_USER, // Default role
         now
     )
     .execute(&state.db)
@@ -116,31 +110,21 @@ pub async fn register(
         Ok(_) => {
             info!("User {} successfully registered with ID: {}", user.username, user_id);
             
-            // Generate JWT token
-            let claims = Claims {
-                sub: user_id.clone(),
-                username: user.username.clone(),
-                role: "user".to_string(),
-                exp: (Utc::now() + Duration::hours(TOKEN_EXPIRY_HOURS)).timestamp() as usize,
-                iat: Utc::now().timestamp() as usize,
-            };
-            
-            match encode(
-                &Header::default(),
-                &claims,
-                &EncodingKey::from_secret(JWT_SECRET)
-            ) {
-                Ok(token) => {
-                    info!("JWT token generated for user: {}", user.username);
+            let (device, ip) = client_info(&req);
+            match session::start_session(&state.db, &user_id, &user.username, ROLE_USER, &device, &ip).await {
+                Ok(tokens) => {
+                    info!("Session started for new user: {}", user.username);
                     HttpResponse::Created().json(json!({
                         "user_id": user_id,
                         "username": user.username,
-                        "token": token,
-                        "role": "user"
+                        "token": tokens.access_token,
+                        "refresh_token": tokens.refresh_token,
+                        "expires_in": tokens.expires_in,
+                        "role": ROLE_USER
                     }))
                 },
                 Err(e) => {
-                    error!("Failed to generate token: {}", e);
+                    error!("Failed to start session: {}", e);
                     HttpResponse::InternalServerError().json(json!({
                         "error": "Failed to generate authentication token"
                     }))
@@ -156,161 +140,374 @@ pub async fn register(
     }
 }
 
-pub async fn login(user: web::Json<UserLogin>, data: web::Data<AppState>) -> impl Responder {
+/// Replace a hash that verified but is bcrypt or uses old Argon2 parameters.
+/// Failing only means the next login tries again.
+async fn rehash_password(db: &sqlx::SqlitePool, user_id: &str, password: &str) {
+    let hash = match passwords::hash_password(password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            warn!("Couldn't rehash password of {}: {}", user_id, e);
+            return;
+        }
+    };
+    match sqlx::query("UPDATE users SET password_hash = ? WHERE id = ?")
+        .bind(&hash)
+        .bind(user_id)
+        .execute(db)
+        .await {
+        Ok(_) => info!("Upgraded password hash of {} to Argon2id", user_id),
+        Err(e) => warn!("Couldn't store rehashed password of {}: {}", user_id, e),
+    }
+}
+
+pub async fn login(req: HttpRequest, user: web::Json<UserLogin>, data: web::Data<AppState>) -> impl Responder {
     let user_data = user.into_inner();
+    let (device, ip) = client_info(&req);
+    let attempt = login_guard::Attempt { username: &user_data.username, ip: &ip, device: &device };
     
-    // Special case for hardcoded admin credentials - check this first
-    if user_data.username == "admin" && user_data.password == "admin123" {
-        info!("Admin login detected, creating admin token");
-        
-        // Create admin token with current timestamp
-        let now = Utc::now();
-        let token = format!("admin-token-{}", now.timestamp_millis());
-        
-        return HttpResponse::Ok().json(serde_json::json!({
-            "token": token,
-            "user_id": "admin-user",
-            "username": "admin",
-            "role": "admin"
-        }));
+    // Refuse usernames and addresses that are backing off or locked
+    match login_guard::check(&data.db, &attempt).await {
+        Ok(None) => {},
+        Ok(Some(blocked)) => {
+            warn!("Login for {} from {} blocked for {}s", user_data.username, ip, blocked.retry_after);
+            login_guard::record_event(&data.db, &attempt, None, login_guard::LoginEvent::Blocked, None).await;
+            return blocked.to_response();
+        },
+        Err(e) => {
+            error!("Failed to check login throttling: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Authentication error"}));
+        }
     }
     
-    // Regular user authentication
-    match sqlx::query!(
+    // Admins and customers are both `users` rows; the role goes into the token
+    let db_user = match sqlx::query!(
         "SELECT id, username, password_hash, role, created_at FROM users WHERE username = ?",
         user_data.username
     )
     .fetch_optional(&data.db)
     .await {
-        Ok(Some(db_user)) => {
-            // Verify password
-            match bcrypt::verify(&user_data.password, &db_user.password_hash) {
-                Ok(true) => {
-                    // Create JWT token
-                    let user_id = db_user.id.clone();
-                    let role = db_user.role.clone();
-                    let token = match session::create_jwt(&user_id, &role) {
-                        Ok(t) => t,
-                        Err(e) => {
-                            error!("Failed to create JWT token: {}", e);
-                            return HttpResponse::InternalServerError().json(
-                                json!({"error": "Authentication error"})
-                            );
-                        }
-                    };
-                    
-                    HttpResponse::Ok().json(UserResponse {
-                        id: user_id,
-                        username: db_user.username.clone(),
-                        role: role,
-                        token
-                    })
-                },
-                _ => {
-                    // Password is incorrect
+        Ok(db_user) => db_user,
+        Err(e) => {
+            error!("Database error during login: {}", e);
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Authentication error"
+            }));
+        }
+    };
+    
+    // Verify password; bcrypt hashes from before Argon2id still verify.
+    // Unknown usernames cost the same time and get the same answer.
+    let db_user = match db_user {
+        Some(db_user) if passwords::verify_password(&user_data.password, &db_user.password_hash) => db_user,
+        db_user => {
+            let user_id = match &db_user {
+                Some(db_user) => {
                     warn!("Invalid password for user: {}", user_data.username);
-                    HttpResponse::Unauthorized().json(serde_json::json!({
-                        "error": "Invalid credentials"
-                    }))
+                    Some(db_user.id.as_str())
+                },
+                None => {
+                    passwords::verify_dummy(&user_data.password);
+                    warn!("Login attempt for non-existent user: {}", user_data.username);
+                    None
                 }
+            };
+            if let Err(e) = login_guard::record_failure(&data.db, &attempt, user_id).await {
+                error!("Failed to record failed login for {}: {}", user_data.username, e);
             }
-        },
-        Ok(None) => {
-            // User doesn't exist
-            warn!("Login attempt for non-existent user: {}", user_data.username);
-            HttpResponse::Unauthorized().json(serde_json::json!({
+            return HttpResponse::Unauthorized().json(serde_json::json!({
                 "error": "Invalid credentials"
-            }))
-        },
-        Err(e) => {
-            error!("Database error during login: {}", e);
-            HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Authentication error"
-            }))
+            }));
         }
+    };
+    
+    if passwords::needs_rehash(&db_user.password_hash) {
+        rehash_password(&data.db, &db_user.id, &user_data.password).await;
     }
+    
+    complete_first_factor(&data.db, &attempt, &db_user.id, &db_user.username, &db_user.role).await
 }
 
-pub async fn refresh_token(req: HttpRequest) -> impl Responder {
-    if let Some(auth_header) = req.headers().get(header::AUTHORIZATION) {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if let Some(token) = auth_str.strip_prefix("Bearer ") {
-                match session::verify_jwt(token) {
-                    Ok(claims) => {
-                        // Generate new token with the same claims but extended expiration
-                        match session::create_jwt(&claims.sub, &claims.role) {
-                            Ok(new_token) => {
-                                return HttpResponse::Ok().json(serde_json::json!({
-                                    "token": new_token
-                                }));
-                            }
-                            Err(e) => {
-                                log::error!("Failed to create new token: {}", e);
-                                return HttpResponse::InternalServerError().json(
-                                    serde_json::json!({"error": "Failed to refresh token"})
-                                );
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        return HttpResponse::Unauthorized().json(
-                            serde_json::json!({"error": format!("Invalid token: {}", e)})
-                        );
-                    }
+/// Finish a login whose first factor (password or PGP challenge) checked out:
+/// hand out a pre-auth token if 2FA is on, otherwise start the session.
+pub(crate) async fn complete_first_factor(
+    db: &sqlx::SqlitePool,
+    attempt: &login_guard::Attempt<'_>,
+    user_id: &str,
+    username: &str,
+    role: &str,
+) -> HttpResponse {
+    // With 2FA on, the first factor only earns a token for the second step
+    match two_factor::is_enabled(db, user_id).await {
+        Ok(false) => {},
+        Ok(true) => {
+            login_guard::record_event(db, attempt, Some(user_id), login_guard::LoginEvent::SecondFactorPending, None).await;
+            return match two_factor::create_pre_auth_token(user_id) {
+                Ok(pre_auth_token) => HttpResponse::Ok().json(json!({
+                    "two_factor_required": true,
+                    "pre_auth_token": pre_auth_token,
+                    "expires_in": two_factor::PRE_AUTH_MINUTES * 60
+                })),
+                Err(e) => {
+                    error!("Failed to create pre-auth token: {}", e);
+                    HttpResponse::InternalServerError().json(json!({"error": "Authentication error"}))
                 }
-            }
+            };
+        },
+        Err(e) => {
+            error!("Failed to check 2FA for {}: {}", user_id, e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Authentication error"}));
         }
     }
     
-    HttpResponse::Unauthorized().json(serde_json::json!({"error": "Authorization header missing"}))
+    finish_login(db, attempt, user_id, username, role).await
 }
 
-pub async fn get_all_users(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
-    // Check authorization
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if let Some(token) = auth_str.strip_prefix("Bearer ") {
-                if let Ok(claims) = session::verify_jwt(token) {
-                    // Only allow admin users to get all users
-                    if claims.role != "admin" {
-                        return HttpResponse::Forbidden().json(
-                            serde_json::json!({"error": "Admin access required"})
-                        );
-                    }
-                    
-                    // Fetch all users from database
-                    match sqlx::query!(
-                        "SELECT id, username, role, created_at FROM users"
-                    )
-                    .fetch_all(&data.db)
-                    .await {
-                        Ok(users) => {
-                            let users_json: Vec<serde_json::Value> = users
-                                .iter()
-                                .map(|u| {
-                                    serde_json::json!({
-                                        "id": u.id,
-                                        "username": u.username,
-                                        "role": u.role,
-                                        "created_at": u.created_at
-                                    })
-                                })
-                                .collect();
-                            return HttpResponse::Ok().json(users_json);
-                        }
-                        Err(e) => {
-                            log::error!("Failed to fetch users: {}", e);
-                            return HttpResponse::InternalServerError().json(
-                                serde_json::json!({"error": "Failed to fetch users"})
-                            );
-                        }
-                    }
-                }
-            }
+/// Record a successful login and start the session it signs in to.
+async fn finish_login(
+    db: &sqlx::SqlitePool,
+    attempt: &login_guard::Attempt<'_>,
+    user_id: &str,
+    username: &str,
+    role: &str,
+) -> HttpResponse {
+    if let Err(e) = login_guard::record_success(db, attempt, user_id).await {
+        error!("Failed to record login for {}: {}", username, e);
+    }
+    
+    // Start a session; its tokens carry the account's role
+    let tokens = match session::start_session(db, user_id, username, role, attempt.device, attempt.ip).await {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            error!("Failed to start session: {}", e);
+            return HttpResponse::InternalServerError().json(
+                json!({"error": "Authentication error"})
+            );
+        }
+    };
+    
+    info!("User {} logged in with role {}", username, role);
+    HttpResponse::Ok().json(UserResponse {
+        user_id: user_id.to_string(),
+        username: username.to_string(),
+        role: role.to_string(),
+        token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+        expires_in: tokens.expires_in,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorLogin {
+    pub pre_auth_token: String,
+    pub code: String,
+}
+
+// Second login step: the pre-auth token from the password step and a TOTP
+// or recovery code
+pub async fn login_two_factor(
+    req: HttpRequest,
+    body: web::Json<TwoFactorLogin>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = match two_factor::verify_pre_auth_token(&body.pre_auth_token) {
+        Ok(user_id) => user_id,
+        Err(e) => return AuthError::Unauthenticated(e).to_response(),
+    };
+    let user = match sqlx::query("SELECT username, role FROM users WHERE id = ?")
+        .bind(&user_id)
+        .fetch_optional(&data.db)
+        .await {
+        Ok(Some(row)) => (row.get::<String, _>("username"), row.get::<String, _>("role")),
+        Ok(None) => return AuthError::Unauthenticated("Account no longer exists".to_string()).to_response(),
+        Err(e) => {
+            error!("Database error during login: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Authentication error"}));
+        }
+    };
+    let (username, role) = user;
+    let (device, ip) = client_info(&req);
+    let attempt = login_guard::Attempt { username: &username, ip: &ip, device: &device };
+    
+    // Codes count towards the same backoff and lockout as passwords
+    match login_guard::check(&data.db, &attempt).await {
+        Ok(None) => {},
+        Ok(Some(blocked)) => {
+            login_guard::record_event(&data.db, &attempt, Some(&user_id), login_guard::LoginEvent::Blocked, None).await;
+            return blocked.to_response();
+        },
+        Err(e) => {
+            error!("Failed to check login throttling: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Authentication error"}));
         }
     }
     
-    HttpResponse::Unauthorized().json(serde_json::json!({"error": "Unauthorized"}))
+    match two_factor::verify_second_factor(&data.db, &user_id, &body.code).await {
+        Ok(true) => finish_login(&data.db, &attempt, &user_id, &username, &role).await,
+        Ok(false) => {
+            warn!("Invalid 2FA code for user: {}", username);
+            if let Err(e) = login_guard::record_failure(&data.db, &attempt, Some(&user_id)).await {
+                error!("Failed to record failed login for {}: {}", username, e);
+            }
+            two_factor::TwoFactorError::InvalidCode.to_response()
+        },
+        Err(e) => e.to_response(),
+    }
+}
+
+/// The user agent and address a session is started or refreshed from
+pub(crate) fn client_info(req: &HttpRequest) -> (String, String) {
+    let device = req.headers().get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(|agent| agent.chars().take(200).collect())
+        .unwrap_or_else(|| "Unknown device".to_string());
+    let ip = req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    (device, ip)
+}
+
+// Trade a refresh token for new tokens; the old refresh token stops working
+pub async fn refresh_token(
+    req: HttpRequest,
+    body: web::Json<RefreshRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (_, ip) = client_info(&req);
+    match session::refresh(&data.db, &body.refresh_token, &ip).await {
+        Ok(tokens) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "token": tokens.access_token,
+            "refresh_token": tokens.refresh_token,
+            "expires_in": tokens.expires_in
+        })),
+        Err(e) => e.to_response(),
+    }
+}
+
+/// Verify the request's access token and that its session hasn't been
+/// signed out. Tokens that don't name a session are refused.
+pub async fn validate_session(req: &HttpRequest, db: &sqlx::SqlitePool) -> Result<Claims, AuthError> {
+    let claims = validate_token(req.clone()).map_err(AuthError::Unauthenticated)?;
+    // Every token we issue names its session; one without can't be revoked
+    let sid = claims.sid.as_deref()
+        .ok_or_else(|| AuthError::Unauthenticated("Token is not tied to a session".to_string()))?;
+    let active = session::is_active(db, sid).await.map_err(|e| {
+        error!("Failed to check session {}: {}", sid, e);
+        AuthError::Internal("Failed to check session".to_string())
+    })?;
+    if !active {
+        return Err(AuthError::Unauthenticated("Session has been signed out".to_string()));
+    }
+    Ok(claims)
+}
+
+// Sign out the session the access token belongs to
+pub async fn logout(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let claims = match validate_session(&req, &data.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    let Some(sid) = claims.sid else {
+        return HttpResponse::Ok().json(json!({"success": true, "revoked": 0}));
+    };
+    match session::revoke_session(&data.db, &sid, "logout").await {
+        Ok(revoked) => HttpResponse::Ok().json(json!({"success": true, "revoked": u64::from(revoked)})),
+        Err(e) => {
+            error!("Failed to revoke session {}: {}", sid, e);
+            HttpResponse::InternalServerError().json(json!({"success": false, "error": "Failed to sign out"}))
+        }
+    }
+}
+
+// Sign out every session of the current user, this one included
+pub async fn logout_all(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let claims = match validate_session(&req, &data.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    match session::revoke_all_sessions(&data.db, &claims.sub, None, "logout everywhere").await {
+        Ok(revoked) => HttpResponse::Ok().json(json!({"success": true, "revoked": revoked})),
+        Err(e) => {
+            error!("Failed to revoke sessions of {}: {}", claims.sub, e);
+            HttpResponse::InternalServerError().json(json!({"success": false, "error": "Failed to sign out"}))
+        }
+    }
+}
+
+// The current user's signed-in devices
+pub async fn list_sessions(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let claims = match validate_session(&req, &data.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    match session::active_sessions(&data.db, &claims.sub).await {
+        Ok(sessions) => {
+            let sessions: Vec<serde_json::Value> = sessions.iter().map(|s| json!({
+                "id": s.id,
+                "device": s.device,
+                "ip": s.ip,
+                "created_at": s.created_at,
+                "last_used_at": s.last_used_at,
+                "expires_at": s.expires_at,
+                "current": claims.sid.as_deref() == Some(s.id.as_str())
+            })).collect();
+            HttpResponse::Ok().json(json!({"success": true, "sessions": sessions}))
+        },
+        Err(e) => {
+            error!("Failed to list sessions of {}: {}", claims.sub, e);
+            HttpResponse::InternalServerError().json(json!({"success": false, "error": "Failed to list sessions"}))
+        }
+    }
+}
+
+// Sign out one of the current user's sessions
+pub async fn revoke_session(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let claims = match validate_session(&req, &data.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    let session_id = path.into_inner();
+    match session::revoke_user_session(&data.db, &claims.sub, &session_id, "revoked by user").await {
+        Ok(true) => HttpResponse::Ok().json(json!({"success": true})),
+        Ok(false) => HttpResponse::NotFound().json(json!({"success": false, "error": "Session not found"})),
+        Err(e) => {
+            error!("Failed to revoke session {}: {}", session_id, e);
+            HttpResponse::InternalServerError().json(json!({"success": false, "error": "Failed to revoke session"}))
+        }
+    }
+}
+
+pub async fn get_all_users(_auth: Authorized<perm::UsersManage>, data: web::Data<AppState>) -> impl Responder {
+    match sqlx::query!(
+        "SELECT id, username, role, created_at FROM users"
+    )
+    .fetch_all(&data.db)
+    .await {
+        Ok(users) => {
+            let users_json: Vec<serde_json::Value> = users
+                .iter()
+                .map(|u| {
+                    serde_json::json!({
+                        "id": u.id,
+                        "username": u.username,
+                        "role": u.role,
+                        "created_at": u.created_at
+                    })
+                })
+                .collect();
+            HttpResponse::Ok().json(users_json)
+        }
+        Err(e) => {
+            log::error!("Failed to fetch users: {}", e);
+            HttpResponse::InternalServerError().json(
+                serde_json::json!({"error": "Failed to fetch users"})
+            )
+        }
+    }
 }
 
 pub fn validate_token(req: HttpRequest) -> Result<Claims, String> {
@@ -325,94 +522,201 @@ pub fn validate_token(req: HttpRequest) -> Result<Claims, String> {
     let token = auth_str.strip_prefix("Bearer ")
         .ok_or_else(|| "Invalid token format. Expected 'Bearer <token>'".to_string())?;
     
-    // Special handling for admin tokens generated on client side
-    if token.starts_with("admin-token-") {
-        info!("Using special admin token: {}", token);
-        // Create a claims object for admin with all required fields
-        return Ok(Claims {
-            sub: "admin-user".to_string(),
-            role: "admin".to_string(),
-            username: "admin".to_string(),
-            exp: (Utc::now() + Duration::hours(24)).timestamp() as usize,
-            iat: Utc::now().timestamp() as usize,
-        });
-    }
-    
-    // For regular tokens, delegate to session::verify_jwt 
     session::verify_jwt(token)
 }
 
+/// Why a request was refused access to a staff route
+#[derive(Debug)]
+pub enum AuthError {
+    /// No token, or one that doesn't verify (401)
+    Unauthenticated(String),
+    /// A valid token without the permission the route needs (403)
+    Forbidden(String),
+    /// The permissions couldn't be looked up (500)
+    Internal(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Unauthenticated(e) | AuthError::Forbidden(e) | AuthError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl AuthError {
+    /// The 401/403 response every staff route sends when refusing a request.
+    pub fn to_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({
+            "success": false,
+            "error": self.to_string()
+        }))
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AuthError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.to_response()
+    }
+}
+
 #[get("/profile")]
 async fn get_profile(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
-    let auth_header = match req.headers().get("Authorization") {
-        Some(h) => h,
-        None => return HttpResponse::Unauthorized().json(json!({"error": "Missing authorization header"}))
+    let claims = match validate_session(&req, &data.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
     };
     
-    let auth_str = match auth_header.to_str() {
-        Ok(s) => s,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({"error": "Invalid authorization header"}))
-    };
-    
-    if !auth_str.starts_with("Bearer ") {
-        return HttpResponse::Unauthorized().json(json!({"error": "Invalid token format"}));
-    }
-    
-    let token = &auth_str["Bearer ".len()..];
-    
-    // Special case for admin tokens
-    if token.starts_with("admin-token-") {
-        info!("Admin token detected in profile endpoint");
-        return HttpResponse::Ok().json(json!({
-            "id": "admin-user",
-            "username": "admin",
-            "role": "admin"
-        }));
-    }
-    
-    // Regular JWT validation for standard tokens
-    match session::verify_jwt(token) {
-        Ok(claims) => {
-            // Fetch user data from database
-            match sqlx::query!(
-                "SELECT id, username, role FROM users WHERE id = ?",
-                claims.sub
-            )
-            .fetch_optional(&data.db)
-            .await {
-                Ok(Some(user)) => {
-                    HttpResponse::Ok().json(json!({
-                        "id": user.id,
-                        "username": user.username,
-                        "role": user.role
-                    }))
-                },
-                Ok(None) => {
-                    HttpResponse::NotFound().json(json!({
-                        "error": "User not found"
-                    }))
-                },
+    // Fetch user data from database
+    match sqlx::query!(
+        "SELECT id, username, role FROM users WHERE id = ?",
+        claims.sub
+    )
+    .fetch_optional(&data.db)
+    .await {
+        Ok(Some(user)) => {
+            let permissions = match rbac::permissions_for_user(&data.db, &user.id).await {
+                Ok(permissions) => permissions,
                 Err(e) => {
-                    error!("Database error: {}", e);
-                    HttpResponse::InternalServerError().json(json!({
-                        "error": "Internal server error"
-                    }))
+                    error!("Failed to load permissions for {}: {}", user.id, e);
+                    Vec::new()
                 }
-            }
+            };
+            let pgp_fingerprint = match pgp::fingerprint_for_user(&data.db, &user.id).await {
+                Ok(fingerprint) => fingerprint,
+                Err(e) => {
+                    error!("Failed to load PGP key for {}: {}", user.id, e);
+                    None
+                }
+            };
+            HttpResponse::Ok().json(json!({
+                "id": user.id,
+                "username": user.username,
+                "role": user.role,
+                "permissions": permissions,
+                "pgp_fingerprint": pgp_fingerprint
+            }))
+        },
+        Ok(None) => {
+            HttpResponse::NotFound().json(json!({
+                "error": "User not found"
+            }))
         },
         Err(e) => {
-            HttpResponse::Unauthorized().json(json!({
-                "error": format!("Invalid token: {}", e)
+            error!("Database error: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "error": "Internal server error"
             }))
         }
     }
 }
 
+// Public keys for verifying our tokens elsewhere; empty when tokens are
+// signed with a shared HS256 secret
+#[get("/keys")]
+async fn get_public_keys() -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "keys": session::public_keys()
+    }))
+}
+
 pub fn init_routes() -> actix_web::Scope {
     web::scope("/auth")
         .route("/register", web::post().to(register))
         .route("/login", web::post().to(login))
+        .route("/login/2fa", web::post().to(login_two_factor))
         .route("/refresh", web::post().to(refresh_token))
+        .route("/logout", web::post().to(logout))
+        .route("/logout-all", web::post().to(logout_all))
+        .route("/sessions", web::get().to(list_sessions))
+        .route("/sessions/{id}", web::delete().to(revoke_session))
         .service(get_profile)  // Use service instead of route for #[get] handlers
+        .service(get_public_keys)
+        .service(two_factor::status)
+        .service(two_factor::setup)
+        .service(two_factor::enable)
+        .service(two_factor::regenerate_recovery_codes)
+        .service(two_factor::disable)
+        .service(pgp::get_key)
+        .service(pgp::attach_key)
+        .service(pgp::remove_key)
+        .service(pgp::confirm_key_change)
+        .service(pgp::create_challenge)
+        .service(pgp::login)
+        .service(pgp::recover)
+        .service(account::change_password)
+        .service(account::change_username)
+        .service(account::delete_account)
         .route("/users", web::get().to(get_all_users))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn database() -> sqlx::SqlitePool {
+        // Every test shares the process-wide keys; only the first install counts
+        let _ = session::install_keys(session::JwtKeys::hmac("test", b"auth-test-secret-0123456789abcdef").unwrap());
+
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE sessions (id TEXT PRIMARY KEY, user_id TEXT NOT NULL, device TEXT NOT NULL, ip TEXT NOT NULL, created_at INTEGER NOT NULL, last_used_at INTEGER NOT NULL, expires_at INTEGER NOT NULL, revoked_at INTEGER, revoked_reason TEXT)",
+            "CREATE TABLE refresh_tokens (token_hash TEXT PRIMARY KEY, session_id TEXT NOT NULL, created_at INTEGER NOT NULL, used_at INTEGER)",
+            "CREATE TABLE login_failures (scope TEXT NOT NULL, key TEXT NOT NULL, failures INTEGER NOT NULL, last_failure_at INTEGER NOT NULL, blocked_until INTEGER NOT NULL, locked BOOLEAN NOT NULL DEFAULT FALSE, PRIMARY KEY (scope, key))",
+            "CREATE TABLE login_events (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id TEXT, username TEXT NOT NULL, ip TEXT NOT NULL, device TEXT NOT NULL, event TEXT NOT NULL, detail TEXT, created_at INTEGER NOT NULL)",
+            "CREATE TABLE user_totp (user_id TEXT PRIMARY KEY, secret TEXT NOT NULL, created_at INTEGER NOT NULL, enabled_at INTEGER, last_used_step INTEGER)",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    fn bearer(token: &str) -> HttpRequest {
+        actix_web::test::TestRequest::default()
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_http_request()
+    }
+
+    #[actix_web::test]
+    async fn admins_sign_in_with_a_signed_token() {
+        let db = database().await;
+        let attempt = login_guard::Attempt { username: "root", ip: "127.0.0.1", device: "test" };
+
+        let resp = complete_first_factor(&db, &attempt, "adm-1", "root", ROLE_ADMIN).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["role"], ROLE_ADMIN);
+
+        let claims = validate_token(bearer(body["token"].as_str().unwrap())).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.role.as_str()), ("adm-1", ROLE_ADMIN));
+        assert!(claims.sid.is_some());
+    }
+
+    #[actix_web::test]
+    async fn made_up_admin_tokens_are_refused() {
+        let db = database().await;
+
+        for token in ["admin-token-1700000000000", "admin-token-", "not.a.jwt"] {
+            assert!(validate_token(bearer(token)).is_err(), "{}", token);
+            assert!(matches!(
+                validate_session(&bearer(token), &db).await,
+                Err(AuthError::Unauthenticated(_))
+            ), "{}", token);
+        }
+    }
+}
@@ -3,8 +3,17 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Mutex;
 use uuid::Uuid;
-use log::{info, error};
+use log::{info, warn, error};
 use crate::AppState;
+use crate::inventory::StockError;
+use crate::orders::{self, OrderItem, OrderStatus, StatusChange};
+use crate::coupons::{self, CouponLine};
+use crate::shipping::{self, ShippingLine};
+use crate::tax::{self, TaxableLine};
+use crate::variants;
+use crate::images;
+use crate::products;
+use std::collections::BTreeMap;
 use crate::monero::MoneroPaymentRequest;
 use chrono::{Utc};
 use crate::auth;
@@ -29,6 +38,12 @@ pub struct Cart {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CartItem {
     pub id: String,
+    #[serde(default)]
+    pub variant_id: Option<String>,
+    #[serde(default)]
+    pub sku: Option<String>,
+    #[serde(default)]
+    pub options: Option<BTreeMap<String, String>>,
     pub quantity: i32,
     pub price: f64,
     pub name: String,
@@ -40,6 +55,8 @@ pub struct CartItem {
 pub struct AddToCartRequest {
     pub user_id: String,
     pub product_id: String,
+    #[serde(default)]
+    pub variant_id: Option<String>,
     pub quantity: i32,
 }
 
@@ -49,26 +66,21 @@ pub struct CartItemId {
     pub item_index: usize,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct CheckoutRequest {
-    pub items: Vec<CartItem>,
-    pub total: f64,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct CheckoutResponse {
-    pub success: bool,
-    pub order_id: String,
-    pub payment: Option<MoneroPaymentRequest>,
-    pub message: Option<String>,
-}
-
 // Define the CheckoutData struct
 #[derive(Debug, Deserialize)]
 pub struct CheckoutData {
     pub items: Vec<CartItem>,
+    /// Order total before any coupon discount, shipping or tax
     pub total: f64,
+    /// `country` should be an ISO 3166-1 alpha-2 code and `state` a region
+    /// code for shipping and tax to be worked out
     pub shipping_info: Option<ShippingInfo>,
+    #[serde(default)]
+    pub coupon_code: Option<String>,
+    /// One of the methods from `/shipping/quote`; required when the order
+    /// has physical items and shipping is configured
+    #[serde(default)]
+    pub shipping_method_id: Option<String>,
 }
 
 // Helper function to get or create a cart
@@ -111,14 +123,9 @@ pub async fn add_to_cart(
     
     info!("Adding product {} to cart for user {}", add_request.product_id, user_id);
     
-    // Check if product exists
-    let product = match sqlx::query!(
-        "SELECT id, name, price, available FROM products WHERE id = ?",
-        add_request.product_id
-    )
-    .fetch_optional(&state.db)
-    .await {
-        Ok(Some(product)) => {
+    // Check if product exists and is still for sale
+    let product = match products::fetch_product(&state.db, &add_request.product_id).await {
+        Ok(Some(product)) if product.archived_at.is_none() => {
             if !product.available {
                 return HttpResponse::BadRequest().json(
                     serde_json::json!({"error": "Product is not available"})
@@ -126,7 +133,7 @@ pub async fn add_to_cart(
             }
             product
         },
-        Ok(None) => {
+        Ok(_) => {
             return HttpResponse::NotFound().json(
                 serde_json::json!({"error": "Product not found"})
             );
@@ -139,13 +146,60 @@ pub async fn add_to_cart(
         }
     };
     
+    // Products sold in variants must have one selected
+    let variant = match &add_request.variant_id {
+        Some(variant_id) => match variants::fetch_variant(&state.db, &add_request.product_id, variant_id).await {
+            Ok(Some(variant)) if variant.available => Some(variant),
+            Ok(Some(_)) => {
+                return HttpResponse::BadRequest().json(
+                    serde_json::json!({"error": "Variant is not available"})
+                );
+            },
+            Ok(None) => {
+                return HttpResponse::NotFound().json(
+                    serde_json::json!({"error": "Variant not found"})
+                );
+            },
+            Err(e) => {
+                error!("Database error: {}", e);
+                return HttpResponse::InternalServerError().json(
+                    serde_json::json!({"error": "Failed to retrieve variant"})
+                );
+            }
+        },
+        None => {
+            match variants::variants_for_product(&state.db, &add_request.product_id).await {
+                Ok(existing) if !existing.is_empty() => {
+                    return HttpResponse::BadRequest().json(
+                        serde_json::json!({"error": "Please select a variant for this product"})
+                    );
+                },
+                Ok(_) => None,
+                Err(e) => {
+                    error!("Database error: {}", e);
+                    return HttpResponse::InternalServerError().json(
+                        serde_json::json!({"error": "Failed to retrieve product variants"})
+                    );
+                }
+            }
+        }
+    };
+    
+    let image = match images::primary_image_url(&state.db, &add_request.product_id).await {
+        Ok(image) => image,
+        Err(e) => {
+            error!("Failed to fetch product image: {}", e);
+            None
+        }
+    };
+    
     // Get or create cart
     let mut cart = get_or_create_cart(&state.carts, user_id);
     
-    // Check if product already exists in cart
+    // Check if product (and variant) already exists in cart
     let mut found = false;
     for item in &mut cart.items {
-        if item.id == add_request.product_id {
+        if item.id == add_request.product_id && item.variant_id == add_request.variant_id {
             item.quantity += add_request.quantity;
             found = true;
             break;
@@ -156,10 +210,16 @@ pub async fn add_to_cart(
     if !found {
         cart.items.push(CartItem {
             id: add_request.product_id.clone(),
+            variant_id: variant.as_ref().map(|v| v.id.clone()),
+            sku: variant.as_ref().map(|v| v.sku.clone()),
+            options: variant.as_ref().map(|v| v.options.clone()),
             quantity: add_request.quantity,
-            price: product.price,
-            name: product.name.clone(),
-            image: None,
+            price: variant.as_ref().map(|v| v.price).unwrap_or(product.price),
+            name: match &variant {
+                Some(v) => format!("{} - {}", product.name, v.name),
+                None => product.name.clone(),
+            },
+            image,
         });
     }
     
@@ -240,7 +300,7 @@ pub async fn checkout(
     app_state: web::Data<AppState>
 ) -> impl Responder {
     // Get user ID from authentication
-    let user_id = match auth::validate_token(req.clone()) {
+    let user_id = match auth::validate_session(&req, &app_state.db).await {
         Ok(claims) => claims.sub,
         Err(_) => "guest".to_string()
     };
@@ -293,8 +353,8 @@ pub async fn checkout(
         "#,
         order_id,
         user_id,
-        "Pending",
-        data.total,
+        "AwaitingPayment",
+        0.0, // set from catalogue prices once the lines are in
         now,
         now,
         shipping_info.name,
@@ -320,25 +380,65 @@ pub async fn checkout(
         }
     }
     
-    // Create order items
+    let customer = (user_id != "guest").then_some(user_id.as_str());
+    match orders::record_status_change(
+        &mut tx,
+        &order_id,
+        None,
+        OrderStatus::AwaitingPayment,
+        &StatusChange::customer(customer, "Order placed"),
+    ).await {
+        Ok(()) => {},
+        Err(e) => {
+            error!("Failed to create order: {}", e);
+            let _ = tx.rollback().await;
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to create order"
+            }));
+        }
+    }
+    
+    // Create order items, holding their stock for the payment window;
+    // this fails the whole checkout on oversell. Prices come from the
+    // catalogue, never from the posted cart
+    let mut subtotal = 0.0;
+    let mut priced = Vec::with_capacity(data.items.len());
     for item in &data.items {
-        match sqlx::query!(
-            r#"
-            INSERT INTO order_items (
-                order_number, product_id, quantity, price
-            ) VALUES (?, ?, ?, ?)
-            "#,
-            order_id,
-            item.id,
-            item.quantity,
-            item.price
-        )
-        .execute(&mut *tx)
-        .await {
-            Ok(_) => {
+        let mut line = OrderItem {
+            product_id: item.id.clone(),
+            variant_id: item.variant_id.clone(),
+            quantity: item.quantity.max(0) as u32,
+            price: item.price,
+        };
+        
+        match orders::insert_order_line(&mut tx, &order_id, &line).await {
+            Ok(unit_price) => {
+                subtotal += unit_price * line.quantity as f64;
+                line.price = unit_price;
+                priced.push(line);
                 info!("Added item {} to order {}", item.id, order_id);
             },
-            Err(e) => {
+            Err(e @ StockError::UnknownProduct { .. }) => {
+                let _ = tx.rollback().await;
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": e.to_string()
+                }));
+            },
+            Err(StockError::Insufficient { product_id, requested, available }) => {
+                warn!("Checkout rejected: product {} requested {} but only {} available",
+                      product_id, requested, available);
+                let _ = tx.rollback().await;
+                return HttpResponse::Conflict().json(json!({
+                    "success": false,
+                    "error": "Insufficient stock",
+                    "product_id": product_id,
+                    "requested": requested,
+                    "available": available
+                }));
+            },
+            Err(StockError::Database(e)) => {
                 error!("Failed to add item to order: {}", e);
                 let _ = tx.rollback().await;
                 return HttpResponse::InternalServerError().json(json!({
@@ -349,6 +449,90 @@ pub async fn checkout(
         }
     }
     
+    let subtotal = (subtotal * 100.0).round() / 100.0;
+    if let Err(e) = sqlx::query("UPDATE orders SET total_amount = ? WHERE id = ?")
+        .bind(subtotal)
+        .bind(&order_id)
+        .execute(&mut *tx)
+        .await
+    {
+        error!("Failed to set order total: {}", e);
+        let _ = tx.rollback().await;
+        return HttpResponse::InternalServerError().json(json!({
+            "success": false,
+            "error": "Failed to create order"
+        }));
+    }
+    
+    // Apply the coupon last: the order row written above holds the write
+    // lock, so usage limits cannot be raced by a concurrent checkout
+    let mut amount_due = subtotal;
+    let mut coupon = None;
+    if let Some(code) = data.coupon_code.as_deref().filter(|c| !c.trim().is_empty()) {
+        let lines: Vec<CouponLine> = priced.iter().map(|line| CouponLine {
+            product_id: line.product_id.clone(),
+            quantity: line.quantity as i64,
+            price: line.price,
+        }).collect();
+        let customer = if user_id == "guest" { None } else { Some(user_id.as_str()) };
+        
+        match coupons::apply_to_order(&mut tx, code, &order_id, customer, &lines).await {
+            Ok(applied) => {
+                info!("Coupon {} took {} off order {}", applied.code, applied.discount, order_id);
+                amount_due = (subtotal - applied.discount).max(0.0);
+                coupon = Some(applied);
+            },
+            Err(e) => {
+                let _ = tx.rollback().await;
+                return e.to_response();
+            }
+        }
+    }
+    
+    // Shipping is charged on the physical items whatever discount applied
+    let lines: Vec<ShippingLine> = priced.iter().map(|line| ShippingLine {
+        product_id: line.product_id.clone(),
+        quantity: line.quantity as i64,
+        price: line.price,
+    }).collect();
+    let method_id = data.shipping_method_id.as_deref().filter(|m| !m.trim().is_empty());
+    
+    let shipping = match shipping::apply_to_order(&mut tx, &order_id, &shipping_info.country, method_id, &lines).await {
+        Ok(quote) => quote,
+        Err(e) => {
+            let _ = tx.rollback().await;
+            return e.to_response();
+        }
+    };
+    if let Some(quote) = &shipping {
+        info!("Shipping {} ({}) added to order {}", quote.name, quote.cost, order_id);
+        amount_due += quote.cost;
+    }
+    
+    // Tax goes on last, over the discounted items and shipping
+    let lines: Vec<TaxableLine> = priced.iter().map(|line| TaxableLine {
+        product_id: line.product_id.clone(),
+        quantity: line.quantity as i64,
+        price: line.price,
+    }).collect();
+    let discount = coupon.as_ref().map_or(0.0, |c| c.discount);
+    let shipping_cost = shipping.as_ref().map_or(0.0, |s| s.cost);
+    
+    let tax = match tax::apply_to_order(&mut tx, &order_id, &shipping_info.country, Some(&shipping_info.state), &lines, discount, shipping_cost).await {
+        Ok(tax) => tax,
+        Err(e) => {
+            error!("Failed to calculate tax for order {}: {}", order_id, e);
+            let _ = tx.rollback().await;
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to calculate tax"
+            }));
+        }
+    };
+    if !tax.prices_include_tax {
+        amount_due += tax.total_tax;
+    }
+    
     // Commit transaction
     if let Err(e) = tx.commit().await {
         error!("Failed to commit transaction: {}", e);
@@ -362,17 +546,25 @@ pub async fn checkout(
     let payment = match monero_api::create_payment_for_order(
         &app_state,
         &order_id,
-        data.total
+        amount_due
     ).await {
         Ok(payment_details) => {
             HttpResponse::Ok().json(json!({
                 "success": true,
                 "order_id": order_id,
+                "coupon": coupon,
+                "shipping": shipping,
+                "tax": tax,
                 "payment": payment_details
             }))
         },
         Err(e) => {
             error!("Failed to create payment: {}", e);
+            // Nothing could ever pay or expire this order, so give its stock back now
+            let change = StatusChange::system("Payment could not be created");
+            if let Err(e) = orders::change_status(&app_state.db, &order_id, OrderStatus::Cancelled, &change).await {
+                error!("Failed to cancel order {} after payment creation failed: {}", order_id, e);
+            }
             HttpResponse::InternalServerError().json(json!({
                 "success": false,
                 "error": "Failed to create payment"
@@ -383,89 +575,6 @@ pub async fn checkout(
     payment
 }
 
-// Direct checkout endpoint
-#[post("/api/direct-checkout")]
-pub async fn direct_checkout(
-    app_state: web::Data<AppState>,
-    checkout_data: web::Json<CheckoutRequest>,
-    req: HttpRequest,
-) -> impl Responder {
-    println!("Received direct checkout request: {:?}", checkout_data);
-    
-    // Extract user ID from the authentication token
-    let user_id = match auth::validate_token(req.clone()) {
-        Ok(claims) => {
-            println!("User authenticated, ID: {}", claims.sub);
-            claims.sub
-        },
-        Err(e) => {
-            println!("Auth error: {}. Using guest ID", e);
-            "guest".to_string()
-        }
-    };
-    
-    // Create a unique order ID
-    let order_id = Uuid::new_v4().to_string();
-    
-    // Create Monero payment request
-    let total_amount = checkout_data.total;
-    println!("Creating payment for total amount: {} for user {}", total_amount, user_id);
-    
-    let payment = app_state.monero_payments.create_payment_sync(order_id.clone(), total_amount);
-    println!("Created Monero payment: {:?}", payment);
-    
-    // Store the order in the database with the user_id
-    let now = Utc::now().timestamp();
-    
-    // Create the order record with proper debugging
-    println!("Inserting order into database: id={}, user_id={}", order_id, user_id);
-    match sqlx::query(
-        "INSERT INTO orders (id, user_id, payment_id, status, shipping_name, shipping_address, 
-         shipping_city, shipping_state, shipping_zip, shipping_country, shipping_email, 
-         total_amount, created_at, updated_at)
-         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
-    )
-    .bind(&order_id)
-    .bind(&user_id)  // Make sure user_id is passed correctly
-    .bind(&payment.payment_id)
-    .bind("Pending")
-    .bind("Customer")  // Default shipping info
-    .bind("Address")
-    .bind("City")
-    .bind("State")
-    .bind("Zip")
-    .bind("Country")
-    .bind("customer@example.com")
-    .bind(total_amount)
-    .bind(now)
-    .bind(now)
-    .execute(&app_state.db)
-    .await {
-        Ok(_) => {
-            println!("✅ Successfully created order {} for user {}", order_id, user_id);
-        },
-        Err(e) => {
-            println!("❌ Error creating order: {}", e);
-            return HttpResponse::InternalServerError().json(CheckoutResponse {
-                success: false,
-                order_id: "".to_string(),
-                payment: None,
-                message: Some(format!("Failed to create order: {}", e)),
-            });
-        }
-    }
-    
-    // Return the checkout response with payment details
-    HttpResponse::Ok()
-        .content_type("application/json")
-        .json(CheckoutResponse {
-            success: true,
-            order_id,
-            payment: Some(payment),
-            message: Some("Please send Monero to the provided address".to_string()),
-        })
-}
-
 // Initialize routes
 pub fn init_routes() -> actix_web::Scope {
     web::scope("/cart")
@@ -473,5 +582,4 @@ pub fn init_routes() -> actix_web::Scope {
         .route("/{user_id}", web::get().to(get_cart))
         .route("/remove/{cart_id}/{item_index}", web::delete().to(remove_from_cart))
         .service(checkout)
-        .service(direct_checkout)
 }
\ No newline at end of file
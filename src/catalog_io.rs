@@ -0,0 +1,735 @@
+// src/catalog_io.rs
+use actix_web::{web, HttpResponse, Responder, HttpRequest, get, post};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::Utc;
+use uuid::Uuid;
+use sqlx::{Row, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use log::{info, error};
+use crate::AppState;
+use crate::rbac::{perm, Authorized};
+use crate::categories;
+use crate::products::{self, NEXT_VERSION};
+use crate::tax;
+
+/// Largest accepted import body.
+pub const MAX_IMPORT_BYTES: usize = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CatalogFormat {
+    Csv,
+    Json,
+}
+
+impl CatalogFormat {
+    pub fn parse(value: &str) -> Option<CatalogFormat> {
+        match value.to_lowercase().as_str() {
+            "csv" => Some(CatalogFormat::Csv),
+            "json" => Some(CatalogFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            CatalogFormat::Csv => "text/csv; charset=utf-8",
+            CatalogFormat::Json => "application/json",
+        }
+    }
+}
+
+/// One product in an import or export. `sku` identifies the product: rows
+/// with a known SKU update it, unknown SKUs create a new product.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CatalogRow {
+    pub sku: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub price: f64,
+    #[serde(default = "default_available")]
+    pub available: bool,
+    /// On-hand stock (including units reserved by unpaid orders)
+    #[serde(default)]
+    pub stock: i64,
+    /// Shipping weight in grams; left unchanged on update when omitted
+    #[serde(default)]
+    pub weight_grams: Option<i64>,
+    /// Tax class; left unchanged on update and `standard` on create when omitted
+    #[serde(default)]
+    pub tax_class: Option<String>,
+    /// Category slug
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+fn default_available() -> bool {
+    true
+}
+
+/// CSV shape of a row: tags are a single `|`-separated column.
+#[derive(Serialize, Deserialize)]
+struct CsvRow {
+    sku: String,
+    name: String,
+    #[serde(default)]
+    description: String,
+    price: f64,
+    #[serde(default = "default_available")]
+    available: bool,
+    #[serde(default)]
+    stock: i64,
+    #[serde(default)]
+    weight_grams: Option<i64>,
+    #[serde(default)]
+    tax_class: Option<String>,
+    #[serde(default)]
+    category: Option<String>,
+    #[serde(default)]
+    tags: String,
+}
+
+impl From<CsvRow> for CatalogRow {
+    fn from(row: CsvRow) -> Self {
+        CatalogRow {
+            sku: row.sku,
+            name: row.name,
+            description: row.description,
+            price: row.price,
+            available: row.available,
+            stock: row.stock,
+            weight_grams: row.weight_grams,
+            tax_class: row.tax_class.filter(|c| !c.trim().is_empty()),
+            category: row.category.filter(|c| !c.trim().is_empty()),
+            tags: row.tags.split('|').map(str::to_string).collect(),
+        }
+    }
+}
+
+impl From<&CatalogRow> for CsvRow {
+    fn from(row: &CatalogRow) -> Self {
+        CsvRow {
+            sku: row.sku.clone(),
+            name: row.name.clone(),
+            description: row.description.clone(),
+            price: row.price,
+            available: row.available,
+            stock: row.stock,
+            weight_grams: row.weight_grams,
+            tax_class: row.tax_class.clone(),
+            category: row.category.clone(),
+            tags: row.tags.join("|"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct RowError {
+    /// 1-based data row number (CSV header not counted)
+    pub row: usize,
+    pub sku: Option<String>,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub total_rows: usize,
+    pub created: usize,
+    pub updated: usize,
+    pub errors: Vec<RowError>,
+}
+
+/// Rows read from an import file with their row numbers, and the rows that couldn't be read
+pub type ParsedRows = (Vec<(usize, CatalogRow)>, Vec<RowError>);
+
+/// Parse an import file. Rows that cannot be read at all are reported by
+/// number; the rest are returned for validation.
+pub fn parse_rows(data: &[u8], format: CatalogFormat) -> Result<ParsedRows, String> {
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+
+    match format {
+        CatalogFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data);
+            for (index, record) in reader.deserialize::<CsvRow>().enumerate() {
+                match record {
+                    Ok(row) => rows.push((index + 1, row.into())),
+                    Err(e) => errors.push(RowError { row: index + 1, sku: None, error: e.to_string() }),
+                }
+            }
+        },
+        CatalogFormat::Json => {
+            let values: Vec<serde_json::Value> = serde_json::from_slice(data)
+                .map_err(|e| format!("Expected a JSON array of products: {}", e))?;
+            for (index, value) in values.into_iter().enumerate() {
+                let sku = value.get("sku").and_then(|s| s.as_str()).map(str::to_string);
+                match serde_json::from_value::<CatalogRow>(value) {
+                    Ok(row) => rows.push((index + 1, row)),
+                    Err(e) => errors.push(RowError { row: index + 1, sku, error: e.to_string() }),
+                }
+            }
+        },
+    }
+
+    Ok((rows, errors))
+}
+
+/// A row that passed validation, with everything resolved against the database.
+struct PreparedRow {
+    row: CatalogRow,
+    category_id: Option<String>,
+    tags: Vec<String>,
+    /// Existing product with this SKU, and its current stock
+    existing: Option<(String, i64)>,
+}
+
+/// Validate parsed rows and apply them. Nothing is written if any row has an
+/// error or when `dry_run` is set; otherwise all rows go in one transaction.
+pub async fn import_rows(
+    pool: &SqlitePool,
+    rows: Vec<(usize, CatalogRow)>,
+    mut errors: Vec<RowError>,
+    dry_run: bool,
+    actor: &str,
+) -> Result<ImportReport, sqlx::Error> {
+    let mut report = ImportReport {
+        dry_run,
+        total_rows: rows.len() + errors.len(),
+        ..Default::default()
+    };
+
+    let category_ids: HashMap<String, String> = categories::fetch_categories(pool)
+        .await?
+        .into_iter()
+        .flat_map(|c| [(c.slug.clone(), c.id.clone()), (c.id.clone(), c.id)])
+        .collect();
+
+    let existing: HashMap<String, (String, i64, i64)> = sqlx::query("SELECT id, sku, stock, reserved FROM products WHERE sku IS NOT NULL")
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| (row.get("sku"), (row.get("id"), row.get("stock"), row.get("reserved"))))
+        .collect();
+
+    let variant_skus: HashSet<String> = sqlx::query("SELECT sku FROM product_variants")
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| row.get("sku"))
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut prepared = Vec::new();
+
+    for (number, mut row) in rows {
+        let fail = |sku: &str, error: String| RowError { row: number, sku: Some(sku.to_string()), error };
+
+        row.sku = match products::normalize_sku(Some(&row.sku)) {
+            Ok(Some(sku)) => sku,
+            Ok(None) => {
+                errors.push(fail(&row.sku, "SKU is required".to_string()));
+                continue;
+            },
+            Err(msg) => {
+                errors.push(fail(&row.sku, msg));
+                continue;
+            }
+        };
+        row.name = row.name.trim().to_string();
+
+        if !seen.insert(row.sku.clone()) {
+            errors.push(fail(&row.sku, "SKU appears more than once in this file".to_string()));
+            continue;
+        }
+        if variant_skus.contains(&row.sku) {
+            errors.push(fail(&row.sku, "SKU belongs to a product variant".to_string()));
+            continue;
+        }
+        if let Err(msg) = products::validate_product_fields(&row.name, &row.description, row.price) {
+            errors.push(fail(&row.sku, msg));
+            continue;
+        }
+        if row.stock < 0 {
+            errors.push(fail(&row.sku, "Stock cannot be negative".to_string()));
+            continue;
+        }
+        if row.weight_grams.is_some_and(|w| w < 0) {
+            errors.push(fail(&row.sku, "Weight cannot be negative".to_string()));
+            continue;
+        }
+        row.tax_class = match row.tax_class.as_deref().map(tax::normalize_class).transpose() {
+            Ok(class) => class,
+            Err(msg) => {
+                errors.push(fail(&row.sku, msg));
+                continue;
+            }
+        };
+
+        let category_id = match &row.category {
+            Some(category) => match category_ids.get(category.trim()) {
+                Some(id) => Some(id.clone()),
+                None => {
+                    errors.push(fail(&row.sku, format!("Unknown category: {}", category)));
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        let tags = match categories::normalize_tags(&row.tags) {
+            Ok(tags) => tags,
+            Err(msg) => {
+                errors.push(fail(&row.sku, msg));
+                continue;
+            }
+        };
+
+        let existing = match existing.get(&row.sku) {
+            Some((id, stock, reserved)) => {
+                if row.stock < *reserved {
+                    errors.push(fail(&row.sku, format!(
+                        "Stock cannot be set below the {} units reserved by unpaid orders",
+                        reserved
+                    )));
+                    continue;
+                }
+                Some((id.clone(), *stock))
+            },
+            None => None,
+        };
+
+        prepared.push(PreparedRow { row, category_id, tags, existing });
+    }
+
+    errors.sort_by_key(|e| e.row);
+    report.created = prepared.iter().filter(|p| p.existing.is_none()).count();
+    report.updated = prepared.len() - report.created;
+    report.errors = errors;
+
+    if dry_run || !report.errors.is_empty() {
+        return Ok(report);
+    }
+
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+
+    for item in prepared {
+        let row = &item.row;
+        let product_id = match &item.existing {
+            Some((product_id, current_stock)) => {
+                sqlx::query(&format!(
+                    "UPDATE products
+                     SET name = ?, description = ?, price = ?, available = ?, stock = ?,
+                         weight_grams = COALESCE(?, weight_grams), tax_class = COALESCE(?, tax_class),
+                         category_id = ?, updated_at = {}
+                     WHERE id = ?",
+                    NEXT_VERSION
+                ))
+                .bind(&row.name)
+                .bind(&row.description)
+                .bind(row.price)
+                .bind(row.available)
+                .bind(row.stock)
+                .bind(row.weight_grams)
+                .bind(&row.tax_class)
+                .bind(&item.category_id)
+                .bind(product_id)
+                .execute(&mut *tx)
+                .await?;
+
+                let delta = row.stock - current_stock;
+                if delta != 0 {
+                    sqlx::query(
+                        "INSERT INTO stock_adjustments (product_id, variant_id, delta, reason, actor, order_id, created_at)
+                         VALUES (?, NULL, ?, 'Bulk import', ?, NULL, ?)"
+                    )
+                    .bind(product_id)
+                    .bind(delta)
+                    .bind(actor)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+
+                product_id.clone()
+            },
+            None => {
+                let product_id = Uuid::new_v4().to_string();
+                sqlx::query(
+                    "INSERT INTO products (id, sku, name, description, price, available, stock, weight_grams, tax_class, category_id, created_at, updated_at)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+                )
+                .bind(&product_id)
+                .bind(&row.sku)
+                .bind(&row.name)
+                .bind(&row.description)
+                .bind(row.price)
+                .bind(row.available)
+                .bind(row.stock)
+                .bind(row.weight_grams.unwrap_or(0))
+                .bind(row.tax_class.clone().unwrap_or_else(tax::default_class))
+                .bind(&item.category_id)
+                .bind(now)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+
+                if row.stock > 0 {
+                    sqlx::query(
+                        "INSERT INTO stock_adjustments (product_id, variant_id, delta, reason, actor, order_id, created_at)
+                         VALUES (?, NULL, ?, 'Bulk import', ?, NULL, ?)"
+                    )
+                    .bind(&product_id)
+                    .bind(row.stock)
+                    .bind(actor)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+
+                product_id
+            }
+        };
+
+        categories::set_product_tags(&mut tx, &product_id, &item.tags).await?;
+    }
+
+    tx.commit().await?;
+    info!("Bulk import by {}: {} created, {} updated", actor, report.created, report.updated);
+    Ok(report)
+}
+
+/// The catalog as import-compatible rows, ordered by SKU. Products without a
+/// SKU are exported with an empty one and need one assigned before re-import.
+pub async fn export_rows(pool: &SqlitePool, include_archived: bool) -> Result<Vec<CatalogRow>, sqlx::Error> {
+    let filter = if include_archived { "" } else { "WHERE p.archived_at IS NULL" };
+    let rows = sqlx::query(&format!(
+        "SELECT COALESCE(p.sku, '') AS sku, p.name, p.description, p.price, p.available, p.stock, p.weight_grams, p.tax_class,
+                c.slug AS category,
+                (SELECT GROUP_CONCAT(t.tag) FROM product_tags t WHERE t.product_id = p.id) AS tags
+         FROM products p
+         LEFT JOIN categories c ON c.id = p.category_id
+         {}
+         ORDER BY p.sku, p.id",
+        filter
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(|row| {
+        let tags: Option<String> = row.get("tags");
+        let mut tags: Vec<String> = tags
+            .map(|t| t.split(',').map(str::to_string).collect())
+            .unwrap_or_default();
+        tags.sort();
+
+        CatalogRow {
+            sku: row.get("sku"),
+            name: row.get("name"),
+            description: row.get("description"),
+            price: row.get("price"),
+            available: row.get("available"),
+            stock: row.get("stock"),
+            weight_grams: Some(row.get("weight_grams")),
+            tax_class: Some(row.get("tax_class")),
+            category: row.get("category"),
+            tags,
+        }
+    }).collect())
+}
+
+/// Serialize rows in the given format.
+pub fn write_rows(rows: &[CatalogRow], format: CatalogFormat) -> Result<Vec<u8>, String> {
+    match format {
+        CatalogFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for row in rows {
+                writer.serialize(CsvRow::from(row)).map_err(|e| e.to_string())?;
+            }
+            writer.into_inner().map_err(|e| e.to_string())
+        },
+        CatalogFormat::Json => serde_json::to_vec_pretty(rows).map_err(|e| e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    pub format: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub format: Option<String>,
+    #[serde(default)]
+    pub include_archived: bool,
+}
+
+// Admin: import products from a CSV or JSON request body. The format comes
+// from ?format= or the Content-Type header.
+#[post("/products/import")]
+pub async fn import_products(
+    auth: Authorized<perm::ProductsWrite>,
+    req: HttpRequest,
+    query: web::Query<ImportQuery>,
+    mut payload: web::Payload,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let content_type = req.headers()
+        .get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let format = match query.format.as_deref() {
+        Some(format) => CatalogFormat::parse(format),
+        None if content_type.starts_with("text/csv") => Some(CatalogFormat::Csv),
+        None if content_type.starts_with("application/json") => Some(CatalogFormat::Json),
+        None => None,
+    };
+    let format = match format {
+        Some(format) => format,
+        None => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": "Specify format=csv or format=json"
+            }));
+        }
+    };
+
+    let mut body = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        match chunk {
+            Ok(chunk) if body.len() + chunk.len() <= MAX_IMPORT_BYTES => body.extend_from_slice(&chunk),
+            Ok(_) => {
+                return HttpResponse::PayloadTooLarge().json(json!({
+                    "success": false,
+                    "error": format!("Import files may not exceed {} bytes", MAX_IMPORT_BYTES)
+                }));
+            },
+            Err(e) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": format!("Failed to read upload: {}", e)
+                }));
+            }
+        }
+    }
+
+    let (rows, errors) = match parse_rows(&body, format) {
+        Ok(parsed) => parsed,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": msg
+            }));
+        }
+    };
+
+    match import_rows(&app_state.db, rows, errors, query.dry_run, &auth.claims.sub).await {
+        Ok(report) if report.errors.is_empty() => HttpResponse::Ok().json(json!({
+            "success": true,
+            "report": report
+        })),
+        Ok(report) => HttpResponse::UnprocessableEntity().json(json!({
+            "success": false,
+            "error": "Some rows are invalid; nothing was imported",
+            "report": report
+        })),
+        Err(e) => {
+            error!("Bulk import failed: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Import failed"
+            }))
+        }
+    }
+}
+
+// Admin: download the catalog as CSV (default) or JSON
+#[get("/products/export")]
+pub async fn export_products(
+    _auth: Authorized<perm::ProductsWrite>,
+    query: web::Query<ExportQuery>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let format = match query.format.as_deref().map(CatalogFormat::parse).unwrap_or(Some(CatalogFormat::Csv)) {
+        Some(format) => format,
+        None => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": "Specify format=csv or format=json"
+            }));
+        }
+    };
+
+    let body = match export_rows(&app_state.db, query.include_archived).await {
+        Ok(rows) => write_rows(&rows, format),
+        Err(e) => Err(e.to_string()),
+    };
+
+    match body {
+        Ok(body) => {
+            let filename = match format {
+                CatalogFormat::Csv => "products.csv",
+                CatalogFormat::Json => "products.json",
+            };
+            HttpResponse::Ok()
+                .content_type(format.content_type())
+                .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+                .body(body)
+        },
+        Err(e) => {
+            error!("Catalog export failed: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Export failed"
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    const CSV: &str = "\
+sku,name,description,price,available,stock,weight_grams,tax_class,category,tags
+MUG-1,Mug,Holds coffee,8.5,true,10,350,Reduced,kitchen,gift|Sale
+TEE-1, Tee ,,15,false,0,,,,
+";
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE categories (id TEXT PRIMARY KEY, name TEXT NOT NULL, slug TEXT UNIQUE NOT NULL, parent_id TEXT, created_at INTEGER NOT NULL)",
+            "CREATE TABLE products (id TEXT PRIMARY KEY, sku TEXT UNIQUE, name TEXT NOT NULL, description TEXT NOT NULL, price REAL NOT NULL, available BOOLEAN NOT NULL DEFAULT TRUE, weight_grams INTEGER NOT NULL DEFAULT 0, tax_class TEXT NOT NULL DEFAULT 'standard', stock INTEGER NOT NULL DEFAULT 0, reserved INTEGER NOT NULL DEFAULT 0, category_id TEXT, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL, archived_at INTEGER)",
+            "CREATE TABLE product_variants (id TEXT PRIMARY KEY, product_id TEXT NOT NULL, sku TEXT UNIQUE NOT NULL)",
+            "CREATE TABLE product_tags (product_id TEXT NOT NULL, tag TEXT NOT NULL, PRIMARY KEY (product_id, tag))",
+            "CREATE TABLE stock_adjustments (id INTEGER PRIMARY KEY AUTOINCREMENT, product_id TEXT NOT NULL, variant_id TEXT, delta INTEGER NOT NULL, reason TEXT NOT NULL, actor TEXT NOT NULL, order_id TEXT, created_at INTEGER NOT NULL)",
+            "INSERT INTO categories VALUES ('c-kitchen', 'Kitchen', 'kitchen', NULL, 0)",
+            "INSERT INTO products (id, sku, name, description, price, stock, reserved, created_at, updated_at) VALUES ('p-mug', 'MUG-1', 'Old mug', '', 5.0, 4, 3, 0, 0)",
+            "INSERT INTO products (id, sku, name, description, price, created_at, updated_at, archived_at) VALUES ('p-old', 'OLD-1', 'Old', '', 1.0, 0, 0, 1)",
+            "INSERT INTO product_variants VALUES ('v-1', 'p-mug', 'MUG-1-BLUE')",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    async fn import(db: &SqlitePool, data: &str, format: CatalogFormat, dry_run: bool) -> ImportReport {
+        let (rows, errors) = parse_rows(data.as_bytes(), format).unwrap();
+        import_rows(db, rows, errors, dry_run, "test").await.unwrap()
+    }
+
+    async fn count(db: &SqlitePool, sql: &str) -> i64 {
+        sqlx::query(sql).fetch_one(db).await.unwrap().get(0)
+    }
+
+    #[test]
+    fn csv_rows_are_trimmed_and_tags_split() {
+        let (rows, errors) = parse_rows(CSV.as_bytes(), CatalogFormat::Csv).unwrap();
+        assert!(errors.is_empty());
+        let (number, mug) = &rows[0];
+        assert_eq!((*number, mug.sku.as_str(), mug.weight_grams), (1, "MUG-1", Some(350)));
+        assert_eq!(mug.tags, ["gift", "Sale"]);
+        let tee = &rows[1].1;
+        assert_eq!((tee.name.as_str(), tee.available, tee.tax_class.clone(), tee.category.clone()), ("Tee", false, None, None));
+
+        let (rows, errors) = parse_rows(b"sku,name,price\nA,One,cheap\nB,Two,2\n", CatalogFormat::Csv).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(errors[0].row, 1);
+    }
+
+    #[test]
+    fn json_must_be_an_array_of_products() {
+        assert!(parse_rows(b"{\"sku\": \"A\"}", CatalogFormat::Json).is_err());
+        let (rows, errors) = parse_rows(br#"[{"sku": "A", "name": "One", "price": 1}, {"sku": "B", "name": "Two"}]"#, CatalogFormat::Json).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].1.available);
+        assert_eq!((errors[0].row, errors[0].sku.as_deref()), (2, Some("B")));
+        assert_eq!(CatalogFormat::parse("JSON"), Some(CatalogFormat::Json));
+        assert_eq!(CatalogFormat::parse("xml"), None);
+    }
+
+    #[actix_web::test]
+    async fn import_creates_and_updates_by_sku() {
+        let db = database().await;
+
+        let dry = import(&db, CSV, CatalogFormat::Csv, true).await;
+        assert_eq!((dry.created, dry.updated, dry.errors.len()), (1, 1, 0));
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM products").await, 2, "a dry run writes nothing");
+
+        let report = import(&db, CSV, CatalogFormat::Csv, false).await;
+        assert_eq!((report.created, report.updated), (1, 1));
+
+        let mug = sqlx::query("SELECT name, price, stock, reserved, weight_grams, tax_class, category_id FROM products WHERE id = 'p-mug'")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(mug.get::<String, _>("name"), "Mug");
+        assert_eq!((mug.get::<i64, _>("stock"), mug.get::<i64, _>("reserved")), (10, 3));
+        assert_eq!(mug.get::<String, _>("tax_class"), "reduced");
+        assert_eq!(mug.get::<Option<String>, _>("category_id").as_deref(), Some("c-kitchen"));
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM product_tags WHERE product_id = 'p-mug' AND tag IN ('gift', 'sale')").await, 2);
+        // Stock changes are logged like any other adjustment
+        assert_eq!(count(&db, "SELECT delta FROM stock_adjustments WHERE product_id = 'p-mug'").await, 6);
+
+        let tee = sqlx::query("SELECT available, stock, tax_class FROM products WHERE sku = 'TEE-1'").fetch_one(&db).await.unwrap();
+        assert!(!tee.get::<bool, _>("available"));
+        assert_eq!(tee.get::<String, _>("tax_class"), tax::default_class());
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM stock_adjustments").await, 1, "no adjustment for zero stock");
+    }
+
+    #[actix_web::test]
+    async fn one_bad_row_rejects_the_whole_file() {
+        let db = database().await;
+        let data = "\
+sku,name,price,stock,category
+NEW-1,Fine,1,1,
+NEW-1,Again,1,1,
+MUG-1-BLUE,Variant,1,1,
+MUG-1,Mug,1,2,
+NEW-2,Lost,1,1,garden
+NEW-3,,1,1,
+NEW-4,Minus,-1,1,
+";
+        let report = import(&db, data, CatalogFormat::Csv, false).await;
+        let rows: Vec<_> = report.errors.iter().map(|e| e.row).collect();
+        assert_eq!(rows, [2, 3, 4, 5, 6, 7]);
+        assert!(report.errors[2].error.contains("reserved"), "{}", report.errors[2].error);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM products").await, 2);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM stock_adjustments").await, 0);
+    }
+
+    #[actix_web::test]
+    async fn exports_import_back_unchanged() {
+        let db = database().await;
+        import(&db, CSV, CatalogFormat::Csv, false).await;
+
+        let exported = export_rows(&db, false).await.unwrap();
+        let skus: Vec<_> = exported.iter().map(|r| r.sku.as_str()).collect();
+        assert_eq!(skus, ["MUG-1", "TEE-1"]);
+        assert_eq!(exported[0].tags, ["gift", "sale"]);
+        assert_eq!(exported[0].category.as_deref(), Some("kitchen"));
+        assert_eq!(export_rows(&db, true).await.unwrap().len(), 3);
+
+        for format in [CatalogFormat::Csv, CatalogFormat::Json] {
+            let body = String::from_utf8(write_rows(&exported, format).unwrap()).unwrap();
+            let report = import(&db, &body, format, false).await;
+            assert_eq!((report.created, report.updated, report.errors.len()), (0, 2, 0), "{:?}", format);
+            assert_eq!(count(&db, "SELECT COUNT(*) FROM stock_adjustments").await, 1, "{:?}", format);
+            assert_eq!(count(&db, "SELECT COUNT(*) FROM product_tags").await, 2, "{:?}", format);
+        }
+    }
+}
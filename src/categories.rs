@@ -0,0 +1,605 @@
+// src/categories.rs
+use actix_web::{web, HttpResponse, Responder, post, put, delete};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::Utc;
+use uuid::Uuid;
+use sqlx::{Row, SqlitePool};
+use log::{info, error};
+use crate::AppState;
+use crate::rbac::{perm, Authorized};
+use crate::products;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Category {
+    pub id: String,
+    pub name: String,
+    pub slug: String,
+    pub parent_id: Option<String>,
+    pub created_at: i64,
+}
+
+/// A category with its subcategories, as returned by the public tree endpoint.
+#[derive(Serialize, Debug)]
+pub struct CategoryNode {
+    #[serde(flatten)]
+    pub category: Category,
+    pub children: Vec<CategoryNode>,
+}
+
+#[derive(Deserialize)]
+pub struct CategoryInput {
+    pub name: String,
+    pub slug: Option<String>,
+    pub parent_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ProductCategoryRequest {
+    pub category_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ProductTagsRequest {
+    pub tags: Vec<String>,
+}
+
+/// Lower-case, hyphenated form of a name for use in URLs and filters.
+pub fn slugify(name: &str) -> String {
+    name.trim()
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Tags are stored lower-case and trimmed; commas are reserved as a separator.
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() {
+            continue;
+        }
+        if tag.contains(',') || tag.len() > 50 {
+            return Err(format!("Invalid tag: {}", tag));
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    Ok(normalized)
+}
+
+/// Replace a product's tags inside an existing transaction.
+pub async fn set_product_tags(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    product_id: &str,
+    tags: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM product_tags WHERE product_id = ?")
+        .bind(product_id)
+        .execute(&mut **tx)
+        .await?;
+
+    for tag in tags {
+        sqlx::query("INSERT INTO product_tags (product_id, tag) VALUES (?, ?)")
+            .bind(product_id)
+            .bind(tag)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+fn category_from_row(row: &sqlx::sqlite::SqliteRow) -> Category {
+    Category {
+        id: row.get("id"),
+        name: row.get("name"),
+        slug: row.get("slug"),
+        parent_id: row.get("parent_id"),
+        created_at: row.get("created_at"),
+    }
+}
+
+pub async fn fetch_categories(pool: &SqlitePool) -> Result<Vec<Category>, sqlx::Error> {
+    let rows = sqlx::query("SELECT id, name, slug, parent_id, created_at FROM categories ORDER BY name")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(category_from_row).collect())
+}
+
+fn build_tree(categories: &[Category], parent_id: Option<&str>) -> Vec<CategoryNode> {
+    categories
+        .iter()
+        .filter(|c| c.parent_id.as_deref() == parent_id)
+        .map(|c| CategoryNode {
+            category: c.clone(),
+            children: build_tree(categories, Some(&c.id)),
+        })
+        .collect()
+}
+
+/// True if `candidate` is `category_id` itself or one of its descendants.
+async fn is_self_or_descendant(
+    pool: &SqlitePool,
+    category_id: &str,
+    candidate: &str,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        "WITH RECURSIVE sub(id) AS (
+             SELECT id FROM categories WHERE id = ?
+             UNION ALL
+             SELECT c.id FROM categories c JOIN sub ON c.parent_id = sub.id
+         )
+         SELECT COUNT(*) AS count FROM sub WHERE id = ?"
+    )
+    .bind(category_id)
+    .bind(candidate)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(row.get::<i64, _>("count") > 0)
+}
+
+pub async fn category_exists(pool: &SqlitePool, category_id: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query("SELECT COUNT(*) AS count FROM categories WHERE id = ?")
+        .bind(category_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(row.get::<i64, _>("count") > 0)
+}
+
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(db) if db.message().contains("UNIQUE"))
+}
+
+// Public: the full category tree
+pub async fn list_categories(app_state: web::Data<AppState>) -> impl Responder {
+    match fetch_categories(&app_state.db).await {
+        Ok(categories) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "categories": build_tree(&categories, None)
+        })),
+        Err(e) => {
+            error!("Failed to fetch categories: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to fetch categories"
+            }))
+        }
+    }
+}
+
+// Admin: create a category, optionally under a parent
+#[post("/categories")]
+pub async fn create_category(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    category_data: web::Json<CategoryInput>,
+) -> impl Responder {
+    let input = category_data.into_inner();
+    let name = input.name.trim().to_string();
+    let slug = slugify(input.slug.as_deref().unwrap_or(&name));
+
+    if name.is_empty() || slug.is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "Category name is required"
+        }));
+    }
+
+    if let Some(parent_id) = &input.parent_id {
+        match category_exists(&app_state.db, parent_id).await {
+            Ok(true) => {},
+            Ok(false) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": "Parent category not found"
+                }));
+            },
+            Err(e) => {
+                error!("Database error checking parent category: {}", e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Database error"
+                }));
+            }
+        }
+    }
+
+    let category = Category {
+        id: format!("cat-{}", Uuid::new_v4().simple()),
+        name,
+        slug,
+        parent_id: input.parent_id,
+        created_at: Utc::now().timestamp(),
+    };
+
+    match sqlx::query("INSERT INTO categories (id, name, slug, parent_id, created_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(&category.id)
+        .bind(&category.name)
+        .bind(&category.slug)
+        .bind(&category.parent_id)
+        .bind(category.created_at)
+        .execute(&app_state.db)
+        .await {
+            Ok(_) => {
+                info!("Created category {} ({})", category.id, category.slug);
+                HttpResponse::Created().json(json!({
+                    "success": true,
+                    "category": category
+                }))
+            },
+            Err(e) if is_unique_violation(&e) => {
+                HttpResponse::Conflict().json(json!({
+                    "success": false,
+                    "error": format!("Category slug {} already exists", category.slug)
+                }))
+            },
+            Err(e) => {
+                error!("Failed to create category: {}", e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to create category"
+                }))
+            }
+        }
+}
+
+// Admin: rename or move a category
+#[put("/categories/{id}")]
+pub async fn update_category(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    category_data: web::Json<CategoryInput>,
+) -> impl Responder {
+    let category_id = path.into_inner();
+    let input = category_data.into_inner();
+    let name = input.name.trim().to_string();
+    let slug = slugify(input.slug.as_deref().unwrap_or(&name));
+
+    if name.is_empty() || slug.is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "Category name is required"
+        }));
+    }
+
+    // A category cannot be moved underneath itself
+    if let Some(parent_id) = &input.parent_id {
+        let valid_parent = match category_exists(&app_state.db, parent_id).await {
+            Ok(true) => is_self_or_descendant(&app_state.db, &category_id, parent_id).await.map(|cycle| !cycle),
+            Ok(false) => Ok(false),
+            Err(e) => Err(e),
+        };
+
+        match valid_parent {
+            Ok(true) => {},
+            Ok(false) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": "Invalid parent category"
+                }));
+            },
+            Err(e) => {
+                error!("Database error checking parent category: {}", e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Database error"
+                }));
+            }
+        }
+    }
+
+    match sqlx::query("UPDATE categories SET name = ?, slug = ?, parent_id = ? WHERE id = ?")
+        .bind(&name)
+        .bind(&slug)
+        .bind(&input.parent_id)
+        .bind(&category_id)
+        .execute(&app_state.db)
+        .await {
+            Ok(result) if result.rows_affected() == 0 => {
+                HttpResponse::NotFound().json(json!({
+                    "success": false,
+                    "error": "Category not found"
+                }))
+            },
+            Ok(_) => {
+                HttpResponse::Ok().json(json!({
+                    "success": true,
+                    "message": "Category updated"
+                }))
+            },
+            Err(e) if is_unique_violation(&e) => {
+                HttpResponse::Conflict().json(json!({
+                    "success": false,
+                    "error": format!("Category slug {} already exists", slug)
+                }))
+            },
+            Err(e) => {
+                error!("Failed to update category {}: {}", category_id, e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to update category"
+                }))
+            }
+        }
+}
+
+// Admin: delete a category. Subcategories move up to the deleted category's
+// parent and its products become uncategorised.
+#[delete("/categories/{id}")]
+pub async fn delete_category(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let category_id = path.into_inner();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+
+        let parent_id: Option<Option<String>> = sqlx::query("SELECT parent_id FROM categories WHERE id = ?")
+            .bind(&category_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .map(|row| row.get("parent_id"));
+
+        let parent_id = match parent_id {
+            Some(parent_id) => parent_id,
+            None => return Ok(false),
+        };
+
+        sqlx::query("UPDATE categories SET parent_id = ? WHERE parent_id = ?")
+            .bind(&parent_id)
+            .bind(&category_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("UPDATE products SET category_id = NULL WHERE category_id = ?")
+            .bind(&category_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("DELETE FROM categories WHERE id = ?")
+            .bind(&category_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok::<bool, sqlx::Error>(true)
+    }.await;
+
+    match result {
+        Ok(true) => {
+            info!("Deleted category {}", category_id);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "Category deleted"
+            }))
+        },
+        Ok(false) => {
+            HttpResponse::NotFound().json(json!({
+                "success": false,
+                "error": "Category not found"
+            }))
+        },
+        Err(e) => {
+            error!("Failed to delete category {}: {}", category_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to delete category"
+            }))
+        }
+    }
+}
+
+// Admin: assign (or clear) a product's category
+#[put("/products/{id}/category")]
+pub async fn set_product_category(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<ProductCategoryRequest>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    if let Some(category_id) = &body.category_id {
+        match category_exists(&app_state.db, category_id).await {
+            Ok(true) => {},
+            Ok(false) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": "Category not found"
+                }));
+            },
+            Err(e) => {
+                error!("Database error checking category: {}", e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Database error"
+                }));
+            }
+        }
+    }
+
+    match sqlx::query(
+        "UPDATE products SET category_id = ?, updated_at = MAX(updated_at + 1, CAST(strftime('%s', 'now') AS INTEGER))
+         WHERE id = ?"
+    )
+        .bind(&body.category_id)
+        .bind(&product_id)
+        .execute(&app_state.db)
+        .await {
+            Ok(result) if result.rows_affected() == 0 => {
+                HttpResponse::NotFound().json(json!({
+                    "success": false,
+                    "error": "Product not found"
+                }))
+            },
+            Ok(_) => {
+                HttpResponse::Ok().json(json!({
+                    "success": true,
+                    "product_id": product_id,
+                    "category_id": body.category_id
+                }))
+            },
+            Err(e) => {
+                error!("Failed to set category for product {}: {}", product_id, e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to set product category"
+                }))
+            }
+        }
+}
+
+// Admin: replace a product's tags
+#[put("/products/{id}/tags")]
+pub async fn update_product_tags(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<ProductTagsRequest>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    let tags = match normalize_tags(&body.tags) {
+        Ok(tags) => tags,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": msg
+            }));
+        }
+    };
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+
+        let exists = sqlx::query("SELECT id FROM products WHERE id = ?")
+            .bind(&product_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+        if !exists {
+            return Ok(false);
+        }
+
+        set_product_tags(&mut tx, &product_id, &tags).await?;
+        products::touch_product(&mut *tx, &product_id).await?;
+        tx.commit().await?;
+        Ok::<bool, sqlx::Error>(true)
+    }.await;
+
+    match result {
+        Ok(true) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "product_id": product_id,
+            "tags": tags
+        })),
+        Ok(false) => HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": "Product not found"
+        })),
+        Err(e) => {
+            error!("Failed to update tags for product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to update product tags"
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE categories (id TEXT PRIMARY KEY, name TEXT NOT NULL, slug TEXT UNIQUE NOT NULL, parent_id TEXT, created_at INTEGER NOT NULL)",
+            "CREATE TABLE product_tags (product_id TEXT NOT NULL, tag TEXT NOT NULL, PRIMARY KEY (product_id, tag))",
+            "INSERT INTO categories VALUES
+                ('c-clothes', 'Clothes', 'clothes', NULL, 0),
+                ('c-shirts', 'Shirts', 'shirts', 'c-clothes', 0),
+                ('c-tees', 'T-shirts', 't-shirts', 'c-shirts', 0),
+                ('c-books', 'Books', 'books', NULL, 0)",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn slugs_are_lower_case_and_hyphenated() {
+        assert_eq!(slugify("  Men's T-Shirts & Tops "), "men-s-t-shirts-tops");
+        assert_eq!(slugify("Books"), "books");
+        assert_eq!(slugify("Ünïcode"), "n-code");
+        assert_eq!(slugify("!!!"), "");
+    }
+
+    #[test]
+    fn tags_are_trimmed_lower_cased_and_deduplicated() {
+        let tags = ["Sale", " sale ", "", "New Arrival"].map(String::from);
+        assert_eq!(normalize_tags(&tags).unwrap(), ["sale", "new arrival"]);
+        assert!(normalize_tags(&["a,b".to_string()]).is_err());
+        assert!(normalize_tags(&["x".repeat(51)]).is_err());
+    }
+
+    #[actix_web::test]
+    async fn categories_form_a_tree() {
+        let db = database().await;
+        let tree = build_tree(&fetch_categories(&db).await.unwrap(), None);
+
+        let roots: Vec<_> = tree.iter().map(|n| n.category.slug.as_str()).collect();
+        assert_eq!(roots, ["books", "clothes"]);
+        let shirts = &tree[1].children[0];
+        assert_eq!(shirts.category.id, "c-shirts");
+        assert_eq!(shirts.children[0].category.id, "c-tees");
+        assert!(shirts.children[0].children.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn a_category_cannot_move_under_itself() {
+        let db = database().await;
+        assert!(is_self_or_descendant(&db, "c-clothes", "c-clothes").await.unwrap());
+        assert!(is_self_or_descendant(&db, "c-clothes", "c-tees").await.unwrap());
+        assert!(!is_self_or_descendant(&db, "c-tees", "c-clothes").await.unwrap());
+        assert!(!is_self_or_descendant(&db, "c-clothes", "c-books").await.unwrap());
+
+        assert!(category_exists(&db, "c-books").await.unwrap());
+        assert!(!category_exists(&db, "books").await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn setting_tags_replaces_the_old_ones() {
+        let db = database().await;
+        for tags in [vec!["sale", "new"], vec!["gift"]] {
+            let tags: Vec<String> = tags.into_iter().map(String::from).collect();
+            let mut tx = db.begin().await.unwrap();
+            set_product_tags(&mut tx, "p1", &tags).await.unwrap();
+            tx.commit().await.unwrap();
+        }
+
+        let tags: Vec<String> = sqlx::query("SELECT tag FROM product_tags WHERE product_id = 'p1'")
+            .fetch_all(&db)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("tag"))
+            .collect();
+        assert_eq!(tags, ["gift"]);
+    }
+}
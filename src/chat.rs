@@ -212,9 +212,9 @@ pub async fn post_message(
     payload: web::Json<ChatInput>
 ) -> impl Responder {
     // Verify the user is authenticated
-    let claims = match auth::validate_token(req) {
+    let claims = match auth::validate_session(&req, &data.db).await {
         Ok(claims) => claims,
-        Err(e) => return HttpResponse::Unauthorized().json(serde_json::json!({"error": format!("Invalid token: {}", e)})),
+        Err(e) => return e.to_response(),
     };
     
     // Get username from database
@@ -0,0 +1,246 @@
+// src/cli.rs
+//
+// Maintenance commands, run as `secure_store <command> ...` instead of
+// starting the server.
+use sqlx::{Row, SqlitePool};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use rand::{distributions::Alphanumeric, Rng};
+use uuid::Uuid;
+use secure_store::get_db_path;
+use crate::account;
+use crate::auth;
+use crate::passwords;
+use crate::rbac;
+use crate::two_factor;
+use crate::catalog_io::{self, CatalogFormat};
+
+const USAGE: &str = "\
+Usage:
+  secure_store                                   Start the server
+  secure_store products import <file> [--format csv|json] [--dry-run]
+  secure_store products export [--format csv|json] [--output <file>] [--include-archived]
+  secure_store admin bootstrap [--username <name>]   Create the first admin with a generated password";
+
+/// Length of generated admin passwords
+const GENERATED_PASSWORD_LEN: usize = 24;
+
+fn usage_error(message: &str) -> Error {
+    eprintln!("{}\n\n{}", message, USAGE);
+    Error::new(ErrorKind::InvalidInput, message.to_string())
+}
+
+/// Value following `--name`, if the flag is present.
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+fn has_flag(args: &[String], name: &str) -> bool {
+    args.iter().any(|arg| arg == name)
+}
+
+/// Format from `--format`, falling back to the file extension.
+fn resolve_format(args: &[String], path: Option<&str>) -> Result<CatalogFormat, Error> {
+    let requested = option_value(args, "--format").or_else(|| {
+        path.and_then(|p| Path::new(p).extension()).and_then(|ext| ext.to_str())
+    });
+
+    match requested {
+        Some(format) => CatalogFormat::parse(format)
+            .ok_or_else(|| usage_error(&format!("Unknown format: {}", format))),
+        None => Ok(CatalogFormat::Csv),
+    }
+}
+
+async fn connect() -> Result<SqlitePool, Error> {
+    SqlitePool::connect(&format!("sqlite:{}", get_db_path()))
+        .await
+        .map_err(|e| Error::other(format!("Failed to open database: {}", e)))
+}
+
+pub async fn run(args: &[String]) -> std::io::Result<()> {
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["products", "import", file, ..] => import_products(file, &args[3..]).await,
+        ["products", "export", ..] => export_products(&args[2..]).await,
+        ["admin", "bootstrap", ..] => bootstrap_admin(&args[2..]).await,
+        ["help"] | ["--help"] | ["-h"] => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        _ => Err(usage_error(&format!("Unknown command: {}", args.join(" ")))),
+    }
+}
+
+async fn import_products(file: &str, args: &[String]) -> std::io::Result<()> {
+    let format = resolve_format(args, Some(file))?;
+    let dry_run = has_flag(args, "--dry-run");
+    let data = std::fs::read(file)?;
+
+    let (rows, errors) = catalog_io::parse_rows(&data, format)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    let pool = connect().await?;
+    let report = catalog_io::import_rows(&pool, rows, errors, dry_run, "cli")
+        .await
+        .map_err(|e| Error::other(format!("Import failed: {}", e)))?;
+
+    for error in &report.errors {
+        eprintln!("row {} ({}): {}", error.row, error.sku.as_deref().unwrap_or("-"), error.error);
+    }
+
+    if !report.errors.is_empty() {
+        println!("{} of {} rows invalid; nothing imported", report.errors.len(), report.total_rows);
+        return Err(Error::new(ErrorKind::InvalidData, "Import rejected"));
+    }
+
+    println!(
+        "{}{} products created, {} updated",
+        if dry_run { "Dry run: " } else { "" },
+        report.created,
+        report.updated
+    );
+    Ok(())
+}
+
+async fn export_products(args: &[String]) -> std::io::Result<()> {
+    let output = option_value(args, "--output");
+    let format = resolve_format(args, output)?;
+
+    let pool = connect().await?;
+    let rows = catalog_io::export_rows(&pool, has_flag(args, "--include-archived"))
+        .await
+        .map_err(|e| Error::other(format!("Export failed: {}", e)))?;
+    let body = catalog_io::write_rows(&rows, format)
+        .map_err(Error::other)?;
+
+    match output {
+        Some(path) => {
+            std::fs::write(path, body)?;
+            println!("Exported {} products to {}", rows.len(), path);
+        },
+        None => {
+            use std::io::Write;
+            std::io::stdout().write_all(&body)?;
+        }
+    }
+    Ok(())
+}
+
+/// Create the first admin account. Refuses once any admin exists, so it
+/// can't be used to mint extra admins on a running shop.
+async fn bootstrap_admin(args: &[String]) -> std::io::Result<()> {
+    let username = option_value(args, "--username").unwrap_or("admin").trim();
+
+    let pool = connect().await?;
+    let (user_id, password) = create_first_admin(&pool, username).await?;
+
+    println!("Created admin account {}", user_id);
+    println!("  username: {}", username);
+    println!("  password: {}", password);
+    println!("Store the password now; it is not shown again.");
+    if two_factor::admin_requirement() {
+        println!("Admin routes stay closed until two-factor authentication is enabled at /auth/2fa/setup.");
+    }
+    Ok(())
+}
+
+/// Insert the first admin with a generated password, returning its id and
+/// the password.
+async fn create_first_admin(pool: &SqlitePool, username: &str) -> std::io::Result<(String, String)> {
+    let db_error = |e: sqlx::Error| Error::other(format!("Bootstrap failed: {}", e));
+
+    // The server hasn't necessarily run yet to create these
+    for sql in account::ACCOUNT_TABLES {
+        sqlx::query(sql).execute(pool).await.map_err(db_error)?;
+    }
+    rbac::seed_built_in_roles(pool).await.map_err(db_error)?;
+
+    if let Err(e) = account::check_new_username(pool, username, None).await {
+        return Err(usage_error(&e.to_string()));
+    }
+
+    let admins: i64 = sqlx::query("SELECT COUNT(*) AS n FROM users WHERE role = ?")
+        .bind(auth::ROLE_ADMIN)
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)?
+        .get("n");
+    if admins > 0 {
+        return Err(Error::new(ErrorKind::AlreadyExists, "An admin account already exists"));
+    }
+
+    let password: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_PASSWORD_LEN)
+        .map(char::from)
+        .collect();
+    let password_hash = passwords::hash_password(&password).map_err(|e| Error::other(e.to_string()))?;
+
+    let user_id = format!("usr-{}", Uuid::new_v4().simple());
+    let result = sqlx::query("INSERT INTO users (id, username, password_hash, role, created_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(&user_id)
+        .bind(username)
+        .bind(&password_hash)
+        .bind(auth::ROLE_ADMIN)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(pool)
+        .await;
+    match result {
+        Ok(_) => {},
+        Err(sqlx::Error::Database(e)) if e.message().contains("UNIQUE") => {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("Username {} is taken", username)));
+        },
+        Err(e) => return Err(db_error(e)),
+    }
+    Ok((user_id, password))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn bootstrap_creates_one_admin_on_an_empty_database() {
+        let db = database().await;
+
+        let (user_id, password) = create_first_admin(&db, "root").await.unwrap();
+        assert_eq!(password.len(), GENERATED_PASSWORD_LEN);
+        let row = sqlx::query("SELECT password_hash, role FROM users WHERE id = ?")
+            .bind(&user_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert!(passwords::verify_password(&password, row.get("password_hash")));
+        assert_eq!(row.get::<String, _>("role"), auth::ROLE_ADMIN);
+        assert!(rbac::has_permission(&db, &user_id, rbac::Permission::UsersManage).await.unwrap());
+
+        // Not a way to mint more admins later
+        let err = create_first_admin(&db, "second").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[actix_web::test]
+    async fn bootstrap_checks_the_username() {
+        let db = database().await;
+
+        for username in ["", "a b"] {
+            let err = create_first_admin(&db, username).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", username);
+        }
+        let admins: i64 = sqlx::query("SELECT COUNT(*) AS n FROM users").fetch_one(&db).await.unwrap().get("n");
+        assert_eq!(admins, 0);
+    }
+}
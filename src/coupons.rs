@@ -0,0 +1,931 @@
+// src/coupons.rs
+use actix_web::{web, HttpResponse, Responder, HttpRequest, get, post, put, delete};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::Utc;
+use uuid::Uuid;
+use sqlx::{Row, Sqlite, SqlitePool, Transaction, Executor};
+use sqlx::sqlite::SqliteRow;
+use std::collections::HashSet;
+use log::{info, error};
+use crate::AppState;
+use crate::auth;
+use crate::rbac::{perm, Authorized};
+use crate::categories;
+
+pub const KIND_PERCENTAGE: &str = "percentage";
+pub const KIND_FIXED: &str = "fixed";
+
+const MIN_CODE_LENGTH: usize = 3;
+const MAX_CODE_LENGTH: usize = 32;
+
+// Redemptions on cancelled orders give their use back
+const ACTIVE_REDEMPTION: &str = "NOT EXISTS (SELECT 1 FROM orders o WHERE o.id = r.order_id AND o.status = 'Cancelled')";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Coupon {
+    pub id: String,
+    pub code: String,
+    /// `percentage` (value is 0-100) or `fixed` (value is an amount)
+    pub kind: String,
+    pub value: f64,
+    /// Order subtotal required before the code applies
+    pub min_order_value: Option<f64>,
+    /// Total redemptions allowed across all customers
+    pub max_uses: Option<i64>,
+    pub max_uses_per_user: Option<i64>,
+    pub starts_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub active: bool,
+    /// When either list is non-empty, only matching items are discounted
+    pub product_ids: Vec<String>,
+    /// Categories include their subcategories
+    pub category_ids: Vec<String>,
+    pub times_used: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CouponInput {
+    pub code: String,
+    pub kind: String,
+    pub value: f64,
+    pub min_order_value: Option<f64>,
+    pub max_uses: Option<i64>,
+    pub max_uses_per_user: Option<i64>,
+    pub starts_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+    #[serde(default)]
+    pub product_ids: Vec<String>,
+    #[serde(default)]
+    pub category_ids: Vec<String>,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+/// One order line as far as discounts are concerned.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CouponLine {
+    #[serde(alias = "id")]
+    pub product_id: String,
+    pub quantity: i64,
+    pub price: f64,
+}
+
+#[derive(Deserialize)]
+pub struct QuoteRequest {
+    pub code: String,
+    pub items: Vec<CouponLine>,
+}
+
+/// A coupon worked out against a set of order lines.
+#[derive(Serialize, Debug, Clone)]
+pub struct AppliedCoupon {
+    pub coupon_id: String,
+    pub code: String,
+    pub subtotal: f64,
+    /// Part of the subtotal the coupon's product/category scope covers
+    pub eligible_subtotal: f64,
+    pub discount: f64,
+    pub total: f64,
+}
+
+#[derive(Debug)]
+pub enum CouponError {
+    NotFound,
+    Inactive,
+    NotStarted,
+    Expired,
+    BelowMinimum(f64),
+    NotApplicable,
+    UsageLimitReached,
+    UserLimitReached,
+    SignInRequired,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for CouponError {
+    fn from(e: sqlx::Error) -> Self {
+        CouponError::Database(e)
+    }
+}
+
+impl std::fmt::Display for CouponError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CouponError::NotFound => write!(f, "Unknown coupon code"),
+            CouponError::Inactive => write!(f, "This coupon is no longer active"),
+            CouponError::NotStarted => write!(f, "This coupon is not valid yet"),
+            CouponError::Expired => write!(f, "This coupon has expired"),
+            CouponError::BelowMinimum(min) => write!(f, "This coupon requires an order of at least {:.2}", min),
+            CouponError::NotApplicable => write!(f, "This coupon does not apply to any item in the order"),
+            CouponError::UsageLimitReached => write!(f, "This coupon has been fully redeemed"),
+            CouponError::UserLimitReached => write!(f, "You have already used this coupon the maximum number of times"),
+            CouponError::SignInRequired => write!(f, "Sign in to use this coupon"),
+            CouponError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl CouponError {
+    /// Response for a coupon that cannot be used; database errors are hidden.
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            CouponError::Database(e) => {
+                error!("Coupon lookup failed: {}", e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to apply coupon"
+                }))
+            },
+            other => HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": other.to_string()
+            })),
+        }
+    }
+}
+
+pub fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn round_money(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(db) if db.message().contains("UNIQUE"))
+}
+
+const COUPON_COLUMNS: &str = r#"SELECT
+            c.id, c.code, c.kind, c.value, c.min_order_value, c.max_uses, c.max_uses_per_user,
+            c.starts_at, c.expires_at, c.active, c.created_at, c.updated_at,
+            (SELECT GROUP_CONCAT(product_id) FROM coupon_products WHERE coupon_id = c.id) AS product_ids,
+            (SELECT GROUP_CONCAT(category_id) FROM coupon_categories WHERE coupon_id = c.id) AS category_ids,
+            (SELECT COUNT(*) FROM coupon_redemptions r WHERE r.coupon_id = c.id AND "#;
+
+fn coupon_query(filter: &str) -> String {
+    format!("{}{}) AS times_used FROM coupons c {}", COUPON_COLUMNS, ACTIVE_REDEMPTION, filter)
+}
+
+fn split_ids(value: Option<String>) -> Vec<String> {
+    let mut ids: Vec<String> = value
+        .map(|v| v.split(',').map(str::to_string).collect())
+        .unwrap_or_default();
+    ids.sort();
+    ids
+}
+
+fn coupon_from_row(row: &SqliteRow) -> Coupon {
+    Coupon {
+        id: row.get("id"),
+        code: row.get("code"),
+        kind: row.get("kind"),
+        value: row.get("value"),
+        min_order_value: row.get("min_order_value"),
+        max_uses: row.get("max_uses"),
+        max_uses_per_user: row.get("max_uses_per_user"),
+        starts_at: row.get("starts_at"),
+        expires_at: row.get("expires_at"),
+        active: row.get("active"),
+        product_ids: split_ids(row.get("product_ids")),
+        category_ids: split_ids(row.get("category_ids")),
+        times_used: row.get("times_used"),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+    }
+}
+
+async fn fetch_coupon<'e, E: Executor<'e, Database = Sqlite>>(executor: E, id: &str) -> Result<Option<Coupon>, sqlx::Error> {
+    let row = sqlx::query(&coupon_query("WHERE c.id = ?"))
+        .bind(id)
+        .fetch_optional(executor)
+        .await?;
+    Ok(row.as_ref().map(coupon_from_row))
+}
+
+async fn fetch_coupon_by_code<'e, E: Executor<'e, Database = Sqlite>>(executor: E, code: &str) -> Result<Option<Coupon>, sqlx::Error> {
+    let row = sqlx::query(&coupon_query("WHERE c.code = ?"))
+        .bind(normalize_code(code))
+        .fetch_optional(executor)
+        .await?;
+    Ok(row.as_ref().map(coupon_from_row))
+}
+
+/// Ids of the products a scoped coupon covers: listed products plus every
+/// product in a listed category or one of its subcategories.
+async fn scoped_product_ids(
+    tx: &mut Transaction<'_, Sqlite>,
+    coupon_id: &str,
+) -> Result<HashSet<String>, sqlx::Error> {
+    let rows = sqlx::query(
+        "WITH RECURSIVE scope(id) AS (
+             SELECT category_id FROM coupon_categories WHERE coupon_id = ?
+             UNION
+             SELECT c.id FROM categories c JOIN scope ON c.parent_id = scope.id
+         )
+         SELECT product_id AS id FROM coupon_products WHERE coupon_id = ?
+         UNION
+         SELECT p.id FROM products p WHERE p.category_id IN (SELECT id FROM scope)"
+    )
+    .bind(coupon_id)
+    .bind(coupon_id)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
+
+/// Check a code against order lines and work out the discount. Runs inside
+/// the caller's transaction so the usage counts it reads cannot change before
+/// the redemption is recorded.
+pub async fn evaluate(
+    tx: &mut Transaction<'_, Sqlite>,
+    code: &str,
+    user_id: Option<&str>,
+    lines: &[CouponLine],
+) -> Result<AppliedCoupon, CouponError> {
+    let coupon = fetch_coupon_by_code(&mut **tx, code).await?.ok_or(CouponError::NotFound)?;
+    let now = Utc::now().timestamp();
+
+    if !coupon.active {
+        return Err(CouponError::Inactive);
+    }
+    if coupon.starts_at.is_some_and(|starts| now < starts) {
+        return Err(CouponError::NotStarted);
+    }
+    if coupon.expires_at.is_some_and(|expires| now >= expires) {
+        return Err(CouponError::Expired);
+    }
+
+    let subtotal: f64 = lines.iter().map(|l| l.price * l.quantity as f64).sum();
+    if let Some(min) = coupon.min_order_value {
+        if subtotal < min {
+            return Err(CouponError::BelowMinimum(min));
+        }
+    }
+
+    let eligible_subtotal: f64 = if coupon.product_ids.is_empty() && coupon.category_ids.is_empty() {
+        subtotal
+    } else {
+        let scope = scoped_product_ids(tx, &coupon.id).await?;
+        lines.iter()
+            .filter(|l| scope.contains(&l.product_id))
+            .map(|l| l.price * l.quantity as f64)
+            .sum()
+    };
+    if eligible_subtotal <= 0.0 {
+        return Err(CouponError::NotApplicable);
+    }
+
+    if coupon.max_uses.is_some_and(|max| coupon.times_used >= max) {
+        return Err(CouponError::UsageLimitReached);
+    }
+
+    if let Some(max_per_user) = coupon.max_uses_per_user {
+        let user_id = user_id.ok_or(CouponError::SignInRequired)?;
+        let used: i64 = sqlx::query(&format!(
+            "SELECT COUNT(*) AS used FROM coupon_redemptions r WHERE r.coupon_id = ? AND r.user_id = ? AND {}",
+            ACTIVE_REDEMPTION
+        ))
+        .bind(&coupon.id)
+        .bind(user_id)
+        .fetch_one(&mut **tx)
+        .await?
+        .get("used");
+
+        if used >= max_per_user {
+            return Err(CouponError::UserLimitReached);
+        }
+    }
+
+    let discount = if coupon.kind == KIND_PERCENTAGE {
+        eligible_subtotal * coupon.value / 100.0
+    } else {
+        coupon.value.min(eligible_subtotal)
+    };
+    let discount = round_money(discount);
+
+    Ok(AppliedCoupon {
+        coupon_id: coupon.id,
+        code: coupon.code,
+        subtotal: round_money(subtotal),
+        eligible_subtotal: round_money(eligible_subtotal),
+        discount,
+        total: round_money((subtotal - discount).max(0.0)),
+    })
+}
+
+/// Apply a code to an order being created in `tx`: records the redemption and
+/// stores the code and discount on the order, reducing its total.
+pub async fn apply_to_order(
+    tx: &mut Transaction<'_, Sqlite>,
+    code: &str,
+    order_id: &str,
+    user_id: Option<&str>,
+    lines: &[CouponLine],
+) -> Result<AppliedCoupon, CouponError> {
+    let applied = evaluate(tx, code, user_id, lines).await?;
+
+    sqlx::query(
+        "INSERT INTO coupon_redemptions (coupon_id, order_id, user_id, discount_amount, created_at)
+         VALUES (?, ?, ?, ?, ?)"
+    )
+    .bind(&applied.coupon_id)
+    .bind(order_id)
+    .bind(user_id)
+    .bind(applied.discount)
+    .bind(Utc::now().timestamp())
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query(
+        "UPDATE orders SET coupon_code = ?, discount_amount = ?, total_amount = MAX(total_amount - ?, 0)
+         WHERE id = ?"
+    )
+    .bind(&applied.code)
+    .bind(applied.discount)
+    .bind(applied.discount)
+    .bind(order_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(applied)
+}
+
+/// Validate admin input, returning the normalized code.
+async fn validate_input(pool: &SqlitePool, input: &CouponInput) -> Result<String, String> {
+    let code = normalize_code(&input.code);
+    if code.len() < MIN_CODE_LENGTH || code.len() > MAX_CODE_LENGTH {
+        return Err(format!("Code must be {} to {} characters", MIN_CODE_LENGTH, MAX_CODE_LENGTH));
+    }
+    if !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Code may only contain letters, digits, '-' and '_'".to_string());
+    }
+
+    if input.kind != KIND_PERCENTAGE && input.kind != KIND_FIXED {
+        return Err(format!("kind must be '{}' or '{}'", KIND_PERCENTAGE, KIND_FIXED));
+    }
+    if !input.value.is_finite() || input.value <= 0.0 {
+        return Err("value must be a positive number".to_string());
+    }
+    if input.kind == KIND_PERCENTAGE && input.value > 100.0 {
+        return Err("A percentage discount cannot exceed 100".to_string());
+    }
+    if input.min_order_value.is_some_and(|min| !min.is_finite() || min < 0.0) {
+        return Err("min_order_value must be a non-negative number".to_string());
+    }
+    if input.max_uses.is_some_and(|max| max < 1) || input.max_uses_per_user.is_some_and(|max| max < 1) {
+        return Err("Usage limits must be at least 1".to_string());
+    }
+    if let (Some(starts), Some(expires)) = (input.starts_at, input.expires_at) {
+        if starts >= expires {
+            return Err("expires_at must be after starts_at".to_string());
+        }
+    }
+
+    for product_id in &input.product_ids {
+        let exists = sqlx::query("SELECT id FROM products WHERE id = ?")
+            .bind(product_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| e.to_string())?
+            .is_some();
+        if !exists {
+            return Err(format!("Unknown product: {}", product_id));
+        }
+    }
+    for category_id in &input.category_ids {
+        if !categories::category_exists(pool, category_id).await.map_err(|e| e.to_string())? {
+            return Err(format!("Unknown category: {}", category_id));
+        }
+    }
+
+    Ok(code)
+}
+
+async fn set_scope(
+    tx: &mut Transaction<'_, Sqlite>,
+    coupon_id: &str,
+    input: &CouponInput,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM coupon_products WHERE coupon_id = ?")
+        .bind(coupon_id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM coupon_categories WHERE coupon_id = ?")
+        .bind(coupon_id)
+        .execute(&mut **tx)
+        .await?;
+
+    for product_id in input.product_ids.iter().collect::<HashSet<_>>() {
+        sqlx::query("INSERT INTO coupon_products (coupon_id, product_id) VALUES (?, ?)")
+            .bind(coupon_id)
+            .bind(product_id)
+            .execute(&mut **tx)
+            .await?;
+    }
+    for category_id in input.category_ids.iter().collect::<HashSet<_>>() {
+        sqlx::query("INSERT INTO coupon_categories (coupon_id, category_id) VALUES (?, ?)")
+            .bind(coupon_id)
+            .bind(category_id)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+fn coupon_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(json!({
+        "success": false,
+        "error": "Coupon not found"
+    }))
+}
+
+// Public: preview what a code would take off a cart. Signed-in customers are
+// also checked against per-customer limits.
+pub async fn quote_coupon(
+    req: HttpRequest,
+    body: web::Json<QuoteRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = auth::validate_session(&req, &app_state.db).await.ok().map(|claims| claims.sub);
+    let body = body.into_inner();
+
+    if body.items.iter().any(|l| l.quantity <= 0 || !l.price.is_finite() || l.price < 0.0) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "Every item needs a positive quantity and a non-negative price"
+        }));
+    }
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        let applied = evaluate(&mut tx, &body.code, user_id.as_deref(), &body.items).await;
+        tx.rollback().await?;
+        applied
+    }.await;
+
+    match result {
+        Ok(applied) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "coupon": applied
+        })),
+        Err(e) => e.to_response(),
+    }
+}
+
+// Admin: all coupons, newest first
+#[get("/coupons")]
+pub async fn list_coupons(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    match sqlx::query(&coupon_query("ORDER BY c.created_at DESC, c.code"))
+        .fetch_all(&app_state.db)
+        .await {
+            Ok(rows) => HttpResponse::Ok().json(json!({
+                "success": true,
+                "coupons": rows.iter().map(coupon_from_row).collect::<Vec<_>>()
+            })),
+            Err(e) => {
+                error!("Failed to list coupons: {}", e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to list coupons"
+                }))
+            }
+        }
+}
+
+// Admin: one coupon with its redemptions
+#[get("/coupons/{id}")]
+pub async fn get_coupon(
+    _auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let coupon_id = path.into_inner();
+
+    let result = async {
+        let coupon = match fetch_coupon(&app_state.db, &coupon_id).await? {
+            Some(coupon) => coupon,
+            None => return Ok(None),
+        };
+        let redemptions = sqlx::query(
+            "SELECT r.order_id, r.user_id, r.discount_amount, r.created_at, o.status AS order_status
+             FROM coupon_redemptions r
+             LEFT JOIN orders o ON o.id = r.order_id
+             WHERE r.coupon_id = ?
+             ORDER BY r.created_at DESC, r.id DESC"
+        )
+        .bind(&coupon_id)
+        .fetch_all(&app_state.db)
+        .await?;
+        Ok::<_, sqlx::Error>(Some((coupon, redemptions)))
+    }.await;
+
+    match result {
+        Ok(Some((coupon, redemptions))) => {
+            let redemptions: Vec<serde_json::Value> = redemptions.iter().map(|row| json!({
+                "order_id": row.get::<String, _>("order_id"),
+                "user_id": row.get::<Option<String>, _>("user_id"),
+                "discount_amount": row.get::<f64, _>("discount_amount"),
+                "order_status": row.get::<Option<String>, _>("order_status"),
+                "created_at": row.get::<i64, _>("created_at")
+            })).collect();
+
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "coupon": coupon,
+                "redemptions": redemptions
+            }))
+        },
+        Ok(None) => coupon_not_found(),
+        Err(e) => {
+            error!("Failed to fetch coupon {}: {}", coupon_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to fetch coupon"
+            }))
+        }
+    }
+}
+
+// Admin: create a coupon
+#[post("/coupons")]
+pub async fn create_coupon(
+    auth: Authorized<perm::ProductsWrite>,
+    body: web::Json<CouponInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let input = body.into_inner();
+    let code = match validate_input(&app_state.db, &input).await {
+        Ok(code) => code,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": msg
+            }));
+        }
+    };
+
+    let coupon_id = format!("cpn-{}", Uuid::new_v4().simple());
+    let now = Utc::now().timestamp();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        sqlx::query(
+            "INSERT INTO coupons (
+                id, code, kind, value, min_order_value, max_uses, max_uses_per_user,
+                starts_at, expires_at, active, created_at, updated_at
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&coupon_id)
+        .bind(&code)
+        .bind(&input.kind)
+        .bind(input.value)
+        .bind(input.min_order_value)
+        .bind(input.max_uses)
+        .bind(input.max_uses_per_user)
+        .bind(input.starts_at)
+        .bind(input.expires_at)
+        .bind(input.active)
+        .bind(now)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        set_scope(&mut tx, &coupon_id, &input).await?;
+        let coupon = fetch_coupon(&mut *tx, &coupon_id).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(coupon)
+    }.await;
+
+    match result {
+        Ok(coupon) => {
+            info!("Coupon {} created by {}", code, auth.claims.sub);
+            HttpResponse::Created().json(json!({
+                "success": true,
+                "coupon": coupon
+            }))
+        },
+        Err(e) if is_unique_violation(&e) => HttpResponse::Conflict().json(json!({
+            "success": false,
+            "error": format!("Coupon code {} already exists", code)
+        })),
+        Err(e) => {
+            error!("Failed to create coupon {}: {}", code, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to create coupon"
+            }))
+        }
+    }
+}
+
+// Admin: replace a coupon's settings. Past redemptions keep the discount
+// they were given.
+#[put("/coupons/{id}")]
+pub async fn update_coupon(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    body: web::Json<CouponInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let coupon_id = path.into_inner();
+    let input = body.into_inner();
+    let code = match validate_input(&app_state.db, &input).await {
+        Ok(code) => code,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": msg
+            }));
+        }
+    };
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        let updated = sqlx::query(
+            "UPDATE coupons SET code = ?, kind = ?, value = ?, min_order_value = ?, max_uses = ?,
+                max_uses_per_user = ?, starts_at = ?, expires_at = ?, active = ?, updated_at = ?
+             WHERE id = ?"
+        )
+        .bind(&code)
+        .bind(&input.kind)
+        .bind(input.value)
+        .bind(input.min_order_value)
+        .bind(input.max_uses)
+        .bind(input.max_uses_per_user)
+        .bind(input.starts_at)
+        .bind(input.expires_at)
+        .bind(input.active)
+        .bind(Utc::now().timestamp())
+        .bind(&coupon_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        if updated == 0 {
+            return Ok(None);
+        }
+
+        set_scope(&mut tx, &coupon_id, &input).await?;
+        let coupon = fetch_coupon(&mut *tx, &coupon_id).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(coupon)
+    }.await;
+
+    match result {
+        Ok(Some(coupon)) => {
+            info!("Coupon {} updated by {}", code, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "coupon": coupon
+            }))
+        },
+        Ok(None) => coupon_not_found(),
+        Err(e) if is_unique_violation(&e) => HttpResponse::Conflict().json(json!({
+            "success": false,
+            "error": format!("Coupon code {} already exists", code)
+        })),
+        Err(e) => {
+            error!("Failed to update coupon {}: {}", coupon_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to update coupon"
+            }))
+        }
+    }
+}
+
+// Admin: delete an unused coupon. Redeemed coupons are kept for the order
+// history and can be deactivated instead.
+#[delete("/coupons/{id}")]
+pub async fn delete_coupon(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let coupon_id = path.into_inner();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+
+        let redeemed: i64 = sqlx::query("SELECT COUNT(*) AS redeemed FROM coupon_redemptions WHERE coupon_id = ?")
+            .bind(&coupon_id)
+            .fetch_one(&mut *tx)
+            .await?
+            .get("redeemed");
+        if redeemed > 0 {
+            return Ok(Some(false));
+        }
+
+        sqlx::query("DELETE FROM coupon_products WHERE coupon_id = ?")
+            .bind(&coupon_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM coupon_categories WHERE coupon_id = ?")
+            .bind(&coupon_id)
+            .execute(&mut *tx)
+            .await?;
+        let deleted = sqlx::query("DELETE FROM coupons WHERE id = ?")
+            .bind(&coupon_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(if deleted == 1 { Some(true) } else { None })
+    }.await;
+
+    match result {
+        Ok(Some(true)) => {
+            info!("Coupon {} deleted by {}", coupon_id, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "Coupon deleted"
+            }))
+        },
+        Ok(Some(false)) => HttpResponse::Conflict().json(json!({
+            "success": false,
+            "error": "Coupon has been redeemed; deactivate it instead"
+        })),
+        Ok(None) => coupon_not_found(),
+        Err(e) => {
+            error!("Failed to delete coupon {}: {}", coupon_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to delete coupon"
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE orders (id TEXT PRIMARY KEY, status TEXT NOT NULL)",
+            "CREATE TABLE categories (id TEXT PRIMARY KEY, parent_id TEXT)",
+            "CREATE TABLE products (id TEXT PRIMARY KEY, category_id TEXT)",
+            "CREATE TABLE coupons (id TEXT PRIMARY KEY, code TEXT UNIQUE NOT NULL, kind TEXT NOT NULL, value REAL NOT NULL, min_order_value REAL, max_uses INTEGER, max_uses_per_user INTEGER, starts_at INTEGER, expires_at INTEGER, active BOOLEAN NOT NULL DEFAULT TRUE, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL)",
+            "CREATE TABLE coupon_products (coupon_id TEXT NOT NULL, product_id TEXT NOT NULL)",
+            "CREATE TABLE coupon_categories (coupon_id TEXT NOT NULL, category_id TEXT NOT NULL)",
+            "CREATE TABLE coupon_redemptions (id INTEGER PRIMARY KEY AUTOINCREMENT, coupon_id TEXT NOT NULL, order_id TEXT UNIQUE NOT NULL, user_id TEXT, discount_amount REAL NOT NULL, created_at INTEGER NOT NULL)",
+            "INSERT INTO categories (id, parent_id) VALUES ('books', NULL), ('novels', 'books'), ('games', NULL)",
+            "INSERT INTO products (id, category_id) VALUES ('novel', 'novels'), ('chess', 'games'), ('pen', NULL)",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    // A coupon whose id is its code; `set` adjusts the remaining columns
+    async fn add_coupon(db: &SqlitePool, code: &str, kind: &str, value: f64, set: &str) {
+        sqlx::query("INSERT INTO coupons (id, code, kind, value, created_at, updated_at) VALUES (?, ?, ?, ?, 0, 0)")
+            .bind(code)
+            .bind(code)
+            .bind(kind)
+            .bind(value)
+            .execute(db)
+            .await
+            .unwrap();
+        if !set.is_empty() {
+            sqlx::query(&format!("UPDATE coupons SET {} WHERE id = ?", set))
+                .bind(code)
+                .execute(db)
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn redeem(db: &SqlitePool, code: &str, order_id: &str, status: &str, user_id: Option<&str>) {
+        sqlx::query("INSERT INTO orders (id, status) VALUES (?, ?)")
+            .bind(order_id)
+            .bind(status)
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO coupon_redemptions (coupon_id, order_id, user_id, discount_amount, created_at) VALUES (?, ?, ?, 1, 0)")
+            .bind(code)
+            .bind(order_id)
+            .bind(user_id)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    fn line(product_id: &str, quantity: i64, price: f64) -> CouponLine {
+        CouponLine { product_id: product_id.to_string(), quantity, price }
+    }
+
+    async fn quote(db: &SqlitePool, code: &str, user_id: Option<&str>, lines: &[CouponLine]) -> Result<AppliedCoupon, CouponError> {
+        let mut tx = db.begin().await.unwrap();
+        evaluate(&mut tx, code, user_id, lines).await
+    }
+
+    #[actix_web::test]
+    async fn percentage_discounts_round_to_cents() {
+        let db = database().await;
+        add_coupon(&db, "TEN", KIND_PERCENTAGE, 15.0, "").await;
+
+        // 15% of 9.99 is 1.4985
+        let applied = quote(&db, " ten ", None, &[line("pen", 3, 3.33)]).await.unwrap();
+        assert_eq!(applied.code, "TEN");
+        assert_eq!(applied.subtotal, 9.99);
+        assert_eq!(applied.discount, 1.5);
+        assert_eq!(applied.total, 8.49);
+    }
+
+    #[actix_web::test]
+    async fn fixed_discounts_stop_at_the_eligible_subtotal() {
+        let db = database().await;
+        add_coupon(&db, "FIVER", KIND_FIXED, 5.0, "").await;
+        add_coupon(&db, "BOOKS5", KIND_FIXED, 5.0, "").await;
+        sqlx::query("INSERT INTO coupon_categories (coupon_id, category_id) VALUES ('BOOKS5', 'books')")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let applied = quote(&db, "FIVER", None, &[line("pen", 2, 1.5)]).await.unwrap();
+        assert_eq!((applied.discount, applied.total), (3.0, 0.0));
+
+        // Only the novel, in a subcategory of books, counts
+        let lines = [line("novel", 1, 4.0), line("chess", 1, 20.0)];
+        let applied = quote(&db, "BOOKS5", None, &lines).await.unwrap();
+        assert_eq!(applied.eligible_subtotal, 4.0);
+        assert_eq!((applied.discount, applied.total), (4.0, 20.0));
+
+        assert!(matches!(quote(&db, "BOOKS5", None, &[line("chess", 1, 20.0)]).await, Err(CouponError::NotApplicable)));
+    }
+
+    #[actix_web::test]
+    async fn minimum_order_value_is_inclusive() {
+        let db = database().await;
+        add_coupon(&db, "MIN50", KIND_PERCENTAGE, 10.0, "min_order_value = 50").await;
+
+        assert!(matches!(
+            quote(&db, "MIN50", None, &[line("pen", 1, 49.99)]).await,
+            Err(CouponError::BelowMinimum(min)) if min == 50.0
+        ));
+        assert_eq!(quote(&db, "MIN50", None, &[line("pen", 2, 25.0)]).await.unwrap().discount, 5.0);
+    }
+
+    #[actix_web::test]
+    async fn coupons_only_work_while_active_and_in_their_window() {
+        let db = database().await;
+        let now = Utc::now().timestamp();
+        add_coupon(&db, "OFF", KIND_FIXED, 1.0, "active = FALSE").await;
+        add_coupon(&db, "SOON", KIND_FIXED, 1.0, &format!("starts_at = {}", now + 3600)).await;
+        add_coupon(&db, "GONE", KIND_FIXED, 1.0, &format!("expires_at = {}", now)).await;
+        add_coupon(&db, "OPEN", KIND_FIXED, 1.0, &format!("starts_at = {}, expires_at = {}", now - 60, now + 3600)).await;
+        let lines = [line("pen", 1, 10.0)];
+
+        assert!(matches!(quote(&db, "NOPE", None, &lines).await, Err(CouponError::NotFound)));
+        assert!(matches!(quote(&db, "OFF", None, &lines).await, Err(CouponError::Inactive)));
+        assert!(matches!(quote(&db, "SOON", None, &lines).await, Err(CouponError::NotStarted)));
+        assert!(matches!(quote(&db, "GONE", None, &lines).await, Err(CouponError::Expired)));
+        assert!(quote(&db, "OPEN", None, &lines).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn usage_limits_ignore_cancelled_orders() {
+        let db = database().await;
+        add_coupon(&db, "ONCE", KIND_FIXED, 1.0, "max_uses = 1").await;
+        let lines = [line("pen", 1, 10.0)];
+
+        redeem(&db, "ONCE", "o1", "Cancelled", None).await;
+        assert!(quote(&db, "ONCE", None, &lines).await.is_ok());
+        redeem(&db, "ONCE", "o2", "Paid", None).await;
+        assert!(matches!(quote(&db, "ONCE", None, &lines).await, Err(CouponError::UsageLimitReached)));
+    }
+
+    #[actix_web::test]
+    async fn per_user_limits_need_a_signed_in_user() {
+        let db = database().await;
+        add_coupon(&db, "WELCOME", KIND_FIXED, 1.0, "max_uses_per_user = 1").await;
+        let lines = [line("pen", 1, 10.0)];
+        redeem(&db, "WELCOME", "o1", "Paid", Some("u1")).await;
+
+        assert!(matches!(quote(&db, "WELCOME", None, &lines).await, Err(CouponError::SignInRequired)));
+        assert!(matches!(quote(&db, "WELCOME", Some("u1"), &lines).await, Err(CouponError::UserLimitReached)));
+        assert!(quote(&db, "WELCOME", Some("u2"), &lines).await.is_ok());
+    }
+}
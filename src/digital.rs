@@ -0,0 +1,1316 @@
+// src/digital.rs
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::{web, HttpResponse, Responder, HttpRequest, get, post, put, delete};
+use actix_web::http::header::{ContentDisposition, ContentType, DispositionParam, DispositionType};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+use std::collections::HashSet;
+use log::{info, warn, error};
+use crate::AppState;
+use crate::auth;
+use crate::session;
+use crate::rbac::{self, perm, Authorized, Permission};
+use crate::monero::PaymentStatus;
+use crate::orders::{self, OrderStatus, StatusChange, StatusError};
+use crate::products;
+use crate::storage::{ImageStorage, LocalDiskStorage};
+
+/// Delivery types a product can be flagged with.
+pub const DELIVERY_DOWNLOAD: &str = "download";
+pub const DELIVERY_LICENSE_KEY: &str = "license_key";
+
+/// Largest accepted digital file, per file.
+pub const MAX_FILE_BYTES: usize = 50 * 1024 * 1024;
+const MAX_FILES_PER_UPLOAD: usize = 10;
+const MAX_FILE_NAME_LENGTH: usize = 200;
+const MAX_KEYS_PER_REQUEST: usize = 1000;
+const MAX_KEY_LENGTH: usize = 512;
+
+const DEFAULT_LINK_TTL_HOURS: i64 = 72;
+const DEFAULT_MAX_DOWNLOADS: i64 = 5;
+
+// Audience claim that keeps download links from being accepted as login
+// tokens (and the other way round).
+const DOWNLOAD_AUDIENCE: &str = "download";
+
+/// Private storage for digital product files.
+pub struct DigitalFiles(pub LocalDiskStorage);
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DigitalFile {
+    pub id: String,
+    pub product_id: String,
+    pub file_name: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub created_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct DeliveryRequest {
+    /// `download`, `license_key`, or null to make the product physical again
+    pub delivery: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LicenseKeysRequest {
+    pub keys: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DownloadClaims {
+    sub: String,    // Download grant ID
+    aud: String,
+    exp: usize,
+}
+
+/// What a fulfilment run did for one order.
+#[derive(Debug, Default, Serialize)]
+pub struct FulfilmentReport {
+    pub fulfilled_items: usize,
+    /// Items still waiting for files or license keys to be added
+    pub pending_items: usize,
+    pub order_delivered: bool,
+}
+
+fn link_ttl_hours() -> i64 {
+    std::env::var("DOWNLOAD_LINK_TTL_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|hours: &i64| *hours > 0)
+        .unwrap_or(DEFAULT_LINK_TTL_HOURS)
+}
+
+fn max_downloads() -> i64 {
+    std::env::var("DOWNLOAD_MAX_COUNT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|count: &i64| *count > 0)
+        .unwrap_or(DEFAULT_MAX_DOWNLOADS)
+}
+
+/// Signed, self-expiring link for one download grant.
+pub fn download_url(grant_id: &str, expires_at: i64) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = DownloadClaims {
+        sub: grant_id.to_string(),
+        aud: DOWNLOAD_AUDIENCE.to_string(),
+        exp: expires_at as usize,
+    };
+    let token = session::sign(&claims)?;
+    Ok(format!("/downloads/{}", token))
+}
+
+fn verify_download_token(token: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    session::verify::<DownloadClaims>(token, |validation| {
+        validation.set_audience(&[DOWNLOAD_AUDIENCE]);
+        validation.leeway = 0;
+    })
+    .map(|claims| claims.sub)
+}
+
+/// Keep only the final path component and drop control characters, so the
+/// name is safe to echo back in a Content-Disposition header.
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(MAX_FILE_NAME_LENGTH)
+        .collect();
+    let cleaned = cleaned.trim().to_string();
+
+    if cleaned.is_empty() || cleaned == "." || cleaned == ".." {
+        None
+    } else {
+        Some(cleaned)
+    }
+}
+
+fn file_from_row(row: &sqlx::sqlite::SqliteRow) -> DigitalFile {
+    DigitalFile {
+        id: row.get("id"),
+        product_id: row.get("product_id"),
+        file_name: row.get("file_name"),
+        content_type: row.get("content_type"),
+        size_bytes: row.get("size_bytes"),
+        created_at: row.get("created_at"),
+    }
+}
+
+async fn files_for_product(pool: &SqlitePool, product_id: &str) -> Result<Vec<DigitalFile>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT id, product_id, file_name, content_type, size_bytes, created_at
+         FROM digital_files WHERE product_id = ? ORDER BY created_at, id"
+    )
+    .bind(product_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(file_from_row).collect())
+}
+
+async fn delivery_for_product(pool: &SqlitePool, product_id: &str) -> Result<Option<Option<String>>, sqlx::Error> {
+    let row = sqlx::query("SELECT digital_delivery FROM products WHERE id = ?")
+        .bind(product_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(|row| row.get("digital_delivery")))
+}
+
+fn product_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(json!({
+        "success": false,
+        "error": "Product not found"
+    }))
+}
+
+/// Deliver the digital items of one paid order: assign license keys from the
+/// product's pool and issue download grants for its files. Items that cannot
+/// be completed yet (empty key pool, no files) stay pending and are picked up
+/// by the next run. An order made up only of digital items is marked
+/// Delivered once every item is fulfilled. Orders that aren't paid, or were
+/// cancelled, are refused.
+pub async fn fulfil_order(pool: &SqlitePool, order_id: &str) -> Result<FulfilmentReport, StatusError> {
+    let mut tx = pool.begin().await?;
+    let now = Utc::now().timestamp();
+    let mut report = FulfilmentReport::default();
+
+    let status: OrderStatus = sqlx::query("SELECT status FROM orders WHERE id = ?")
+        .bind(order_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(StatusError::NotFound)?
+        .get::<String, _>("status")
+        .parse()?;
+    if !matches!(status, OrderStatus::Paid | OrderStatus::Shipped | OrderStatus::Delivered | OrderStatus::Completed) {
+        return Err(StatusError::NotAllowed { from: status, to: OrderStatus::Delivered });
+    }
+
+    let items = sqlx::query(
+        "SELECT oi.id, oi.product_id, oi.quantity, p.digital_delivery
+         FROM order_items oi
+         JOIN products p ON p.id = oi.product_id
+         WHERE oi.order_number = ? AND oi.fulfilled_at IS NULL AND p.digital_delivery IS NOT NULL
+         ORDER BY oi.id"
+    )
+    .bind(order_id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for item in &items {
+        let item_id: i64 = item.get("id");
+        let product_id: String = item.get("product_id");
+        let quantity: i64 = item.get("quantity");
+        let delivery: String = item.get("digital_delivery");
+
+        let done = if delivery == DELIVERY_LICENSE_KEY {
+            assign_license_keys(&mut tx, item_id, &product_id, quantity, now).await?
+        } else {
+            issue_download_grants(&mut tx, item_id, &product_id, now).await?
+        };
+
+        if done {
+            sqlx::query("UPDATE order_items SET fulfilled_at = ? WHERE id = ?")
+                .bind(now)
+                .bind(item_id)
+                .execute(&mut *tx)
+                .await?;
+            report.fulfilled_items += 1;
+        } else {
+            warn!("Digital item {} of order {} is waiting for {} stock on product {}",
+                  item_id, order_id, delivery, product_id);
+            report.pending_items += 1;
+        }
+    }
+
+    // Physical items or anything still pending keep the order open
+    let outstanding: i64 = sqlx::query(
+        "SELECT COUNT(*) AS outstanding
+         FROM order_items oi
+         LEFT JOIN products p ON p.id = oi.product_id
+         WHERE oi.order_number = ? AND (p.digital_delivery IS NULL OR oi.fulfilled_at IS NULL)"
+    )
+    .bind(order_id)
+    .fetch_one(&mut *tx)
+    .await?
+    .get("outstanding");
+
+    if report.fulfilled_items > 0 && outstanding == 0 {
+        let change = StatusChange::system("Digital items delivered");
+        match orders::transition_status(&mut tx, order_id, OrderStatus::Delivered, &change).await {
+            Ok(_) => report.order_delivered = true,
+            Err(e @ StatusError::Database(_)) => return Err(e),
+            Err(e) => warn!("Order {} was fulfilled but not marked delivered: {}", order_id, e),
+        }
+    }
+
+    tx.commit().await?;
+    Ok(report)
+}
+
+/// Give the item as many unassigned keys as it still needs. Returns whether
+/// the item now has one key per unit.
+async fn assign_license_keys(
+    tx: &mut Transaction<'_, Sqlite>,
+    item_id: i64,
+    product_id: &str,
+    quantity: i64,
+    now: i64,
+) -> Result<bool, sqlx::Error> {
+    let assigned: i64 = sqlx::query("SELECT COUNT(*) AS assigned FROM license_keys WHERE order_item_id = ?")
+        .bind(item_id)
+        .fetch_one(&mut **tx)
+        .await?
+        .get("assigned");
+
+    let needed = quantity - assigned;
+    if needed <= 0 {
+        return Ok(true);
+    }
+
+    let newly_assigned = sqlx::query(
+        "UPDATE license_keys SET order_item_id = ?, assigned_at = ?
+         WHERE id IN (
+             SELECT id FROM license_keys
+             WHERE product_id = ? AND order_item_id IS NULL
+             ORDER BY id LIMIT ?
+         )"
+    )
+    .bind(item_id)
+    .bind(now)
+    .bind(product_id)
+    .bind(needed)
+    .execute(&mut **tx)
+    .await?
+    .rows_affected() as i64;
+
+    Ok(newly_assigned == needed)
+}
+
+/// Issue one download grant per product file. Returns false when the product
+/// has no files to deliver yet.
+async fn issue_download_grants(
+    tx: &mut Transaction<'_, Sqlite>,
+    item_id: i64,
+    product_id: &str,
+    now: i64,
+) -> Result<bool, sqlx::Error> {
+    let expires_at = now + Duration::hours(link_ttl_hours()).num_seconds();
+
+    let issued = sqlx::query(
+        "INSERT INTO download_grants (id, order_item_id, file_id, expires_at, max_downloads, created_at)
+         SELECT 'dl-' || lower(hex(randomblob(16))), ?, f.id, ?, ?, ?
+         FROM digital_files f
+         WHERE f.product_id = ?"
+    )
+    .bind(item_id)
+    .bind(expires_at)
+    .bind(max_downloads())
+    .bind(now)
+    .bind(product_id)
+    .execute(&mut **tx)
+    .await?
+    .rows_affected();
+
+    Ok(issued > 0)
+}
+
+/// Fulfil the digital items of orders whose Monero payment has been confirmed.
+pub async fn fulfil_paid_orders(pool: &SqlitePool, order_ids: &[String]) {
+    for order_id in order_ids {
+        match fulfil_order(pool, order_id).await {
+            Ok(report) if report.fulfilled_items == 0 && report.pending_items == 0 => {},
+            Ok(report) => info!("Fulfilled {} digital item(s) for order {} ({} pending{})",
+                                report.fulfilled_items, order_id, report.pending_items,
+                                if report.order_delivered { ", order delivered" } else { "" }),
+            Err(e) => warn!("Failed to fulfil digital items for order {}: {}", order_id, e),
+        }
+    }
+}
+
+/// License keys and download links delivered for an order, one entry per
+/// digital line item. Only for the order's owner and admins: the keys and
+/// links are the goods themselves.
+pub async fn deliveries_for_order(pool: &SqlitePool, order_id: &str) -> Result<Vec<serde_json::Value>, sqlx::Error> {
+    let items = sqlx::query(
+        "SELECT oi.id, oi.product_id, oi.quantity, oi.fulfilled_at, p.name, p.digital_delivery
+         FROM order_items oi
+         JOIN products p ON p.id = oi.product_id
+         WHERE oi.order_number = ? AND p.digital_delivery IS NOT NULL
+         ORDER BY oi.id"
+    )
+    .bind(order_id)
+    .fetch_all(pool)
+    .await?;
+
+    let now = Utc::now().timestamp();
+    let mut deliveries = Vec::with_capacity(items.len());
+
+    for item in &items {
+        let item_id: i64 = item.get("id");
+
+        let keys: Vec<String> = sqlx::query("SELECT license_key FROM license_keys WHERE order_item_id = ? ORDER BY id")
+            .bind(item_id)
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.get("license_key"))
+            .collect();
+
+        let grants = sqlx::query(
+            "SELECT g.id, g.expires_at, g.max_downloads, g.download_count,
+                    f.file_name, f.content_type, f.size_bytes
+             FROM download_grants g
+             JOIN digital_files f ON f.id = g.file_id
+             WHERE g.order_item_id = ?
+             ORDER BY f.created_at, f.id"
+        )
+        .bind(item_id)
+        .fetch_all(pool)
+        .await?;
+
+        let downloads: Vec<serde_json::Value> = grants.iter().map(|grant| {
+            let grant_id: String = grant.get("id");
+            let expires_at: i64 = grant.get("expires_at");
+            let remaining = grant.get::<i64, _>("max_downloads") - grant.get::<i64, _>("download_count");
+            let active = expires_at > now && remaining > 0;
+
+            // Spent or expired grants are listed without a usable link
+            let url = if active {
+                download_url(&grant_id, expires_at)
+                    .map_err(|e| error!("Failed to sign download link {}: {}", grant_id, e))
+                    .ok()
+            } else {
+                None
+            };
+
+            json!({
+                "file_name": grant.get::<String, _>("file_name"),
+                "content_type": grant.get::<String, _>("content_type"),
+                "size_bytes": grant.get::<i64, _>("size_bytes"),
+                "url": url,
+                "expires_at": expires_at,
+                "downloads_remaining": remaining.max(0)
+            })
+        }).collect();
+
+        deliveries.push(json!({
+            "order_item_id": item_id,
+            "product_id": item.get::<String, _>("product_id"),
+            "name": item.get::<String, _>("name"),
+            "delivery": item.get::<String, _>("digital_delivery"),
+            "quantity": item.get::<i64, _>("quantity"),
+            "fulfilled_at": item.get::<Option<i64>, _>("fulfilled_at"),
+            "license_keys": keys,
+            "downloads": downloads
+        }));
+    }
+
+    Ok(deliveries)
+}
+
+// Customer: license keys and download links for one of their orders
+#[get("/{order_id}/digital")]
+pub async fn get_order_deliveries(
+    req: HttpRequest,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+
+    let order_id = path.into_inner();
+
+    let owner = match sqlx::query("SELECT user_id FROM orders WHERE id = ?")
+        .bind(&order_id)
+        .fetch_optional(&app_state.db)
+        .await {
+            Ok(Some(row)) => row.get::<Option<String>, _>("user_id"),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Database error loading order {}: {}", order_id, e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to load order"
+                }));
+            }
+        };
+
+    // Someone else's order looks the same as a missing one, except to staff
+    // who can read orders
+    let is_owner = owner.as_deref() == Some(claims.sub.as_str());
+    let can_read_orders = !is_owner && match rbac::has_permission(&app_state.db, &claims.sub, Permission::OrdersRead).await {
+        Ok(allowed) => allowed,
+        Err(e) => {
+            error!("Failed to check permissions for {}: {}", claims.sub, e);
+            false
+        }
+    };
+    if !is_owner && !can_read_orders {
+        return HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": "Order not found"
+        }));
+    }
+
+    match deliveries_for_order(&app_state.db, &order_id).await {
+        Ok(deliveries) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "order_id": order_id,
+            "digital": deliveries
+        })),
+        Err(e) => {
+            error!("Failed to load digital deliveries for order {}: {}", order_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to load digital deliveries"
+            }))
+        }
+    }
+}
+
+// Public: serve a file through a signed download link. The link itself is
+// the credential; each successful request uses up one download.
+pub async fn download_file(
+    req: HttpRequest,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+    files: web::Data<DigitalFiles>,
+) -> HttpResponse {
+    let token = path.into_inner();
+
+    let grant_id = match verify_download_token(&token) {
+        Ok(grant_id) => grant_id,
+        Err(e) => {
+            warn!("Rejected download link: {}", e);
+            return HttpResponse::Gone().json(json!({
+                "success": false,
+                "error": "This download link is invalid or has expired"
+            }));
+        }
+    };
+
+    let now = Utc::now().timestamp();
+
+    // Conditional increment: the count limit holds even under concurrent requests
+    let counted = sqlx::query(
+        "UPDATE download_grants SET download_count = download_count + 1, last_downloaded_at = ?
+         WHERE id = ? AND expires_at > ? AND download_count < max_downloads"
+    )
+    .bind(now)
+    .bind(&grant_id)
+    .bind(now)
+    .execute(&app_state.db)
+    .await;
+
+    match counted {
+        Ok(result) if result.rows_affected() == 1 => {},
+        Ok(_) => {
+            return HttpResponse::Gone().json(json!({
+                "success": false,
+                "error": "This download link has expired or reached its download limit"
+            }));
+        },
+        Err(e) => {
+            error!("Failed to record download for grant {}: {}", grant_id, e);
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Download failed"
+            }));
+        }
+    }
+
+    let file = match sqlx::query(
+        "SELECT f.file_name, f.content_type, f.storage_key
+         FROM download_grants g JOIN digital_files f ON f.id = g.file_id
+         WHERE g.id = ?"
+    )
+    .bind(&grant_id)
+    .fetch_optional(&app_state.db)
+    .await {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            return HttpResponse::Gone().json(json!({
+                "success": false,
+                "error": "This file is no longer available"
+            }));
+        },
+        Err(e) => {
+            error!("Failed to load file for grant {}: {}", grant_id, e);
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Download failed"
+            }));
+        }
+    };
+
+    let file_name: String = file.get("file_name");
+    let content_type: String = file.get("content_type");
+    let storage_key: String = file.get("storage_key");
+
+    let opened = match files.0.path_for(&storage_key) {
+        Ok(path) => NamedFile::open_async(path).await,
+        Err(e) => Err(e),
+    };
+
+    match opened {
+        Ok(named) => {
+            info!("Serving {} for download grant {}", file_name, grant_id);
+            named
+                .set_content_type(content_type.parse().unwrap_or_else(|_| ContentType::octet_stream().0))
+                .set_content_disposition(ContentDisposition {
+                    disposition: DispositionType::Attachment,
+                    parameters: vec![DispositionParam::Filename(file_name)],
+                })
+                .into_response(&req)
+        },
+        Err(e) => {
+            error!("Digital file {} for grant {} could not be opened: {}", storage_key, grant_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Download failed"
+            }))
+        }
+    }
+}
+
+// Admin: flag a product as digital (download or license_key) or physical (null)
+#[put("/products/{id}/digital")]
+pub async fn set_digital_delivery(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    body: web::Json<DeliveryRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+    let delivery = body.into_inner().delivery;
+
+    if let Some(delivery) = &delivery {
+        if delivery != DELIVERY_DOWNLOAD && delivery != DELIVERY_LICENSE_KEY {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": format!("delivery must be '{}', '{}' or null", DELIVERY_DOWNLOAD, DELIVERY_LICENSE_KEY)
+            }));
+        }
+    }
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        let updated = sqlx::query(&format!(
+            "UPDATE products SET digital_delivery = ?, updated_at = {} WHERE id = ?",
+            products::NEXT_VERSION
+        ))
+        .bind(&delivery)
+        .bind(&product_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        let product = if updated == 1 {
+            products::fetch_product(&mut *tx, &product_id).await?
+        } else {
+            None
+        };
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(product)
+    }.await;
+
+    match result {
+        Ok(Some(product)) => {
+            info!("Product {} delivery set to {:?} by {}", product_id, delivery, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "product": product
+            }))
+        },
+        Ok(None) => product_not_found(),
+        Err(e) => {
+            error!("Failed to set delivery for product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to update product"
+            }))
+        }
+    }
+}
+
+// Admin: list the files delivered with a product
+#[get("/products/{id}/files")]
+pub async fn list_digital_files(
+    _auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    match files_for_product(&app_state.db, &product_id).await {
+        Ok(files) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "files": files
+        })),
+        Err(e) => {
+            error!("Failed to list files for product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to list files"
+            }))
+        }
+    }
+}
+
+// Admin: upload downloadable files (multipart, any content type)
+#[post("/products/{id}/files")]
+pub async fn upload_digital_files(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+    files: web::Data<DigitalFiles>,
+    mut payload: Multipart,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    match delivery_for_product(&app_state.db, &product_id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return product_not_found(),
+        Err(e) => {
+            error!("Database error checking product {}: {}", product_id, e);
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Database error"
+            }));
+        }
+    }
+
+    // Read every file before storing anything
+    let mut uploads: Vec<(String, String, Vec<u8>)> = Vec::new();
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": format!("Invalid multipart upload: {}", e)
+                }));
+            }
+        };
+
+        if uploads.len() == MAX_FILES_PER_UPLOAD {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": format!("At most {} files can be uploaded at once", MAX_FILES_PER_UPLOAD)
+            }));
+        }
+
+        let file_name = match field.content_disposition().get_filename().and_then(sanitize_file_name) {
+            Some(name) => name,
+            None => {
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": "Every file needs a file name"
+                }));
+            }
+        };
+        let content_type = field
+            .content_type()
+            .map(|m| m.essence_str().to_string())
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+
+        let mut bytes = Vec::new();
+        loop {
+            match field.try_next().await {
+                Ok(Some(chunk)) => {
+                    if bytes.len() + chunk.len() > MAX_FILE_BYTES {
+                        return HttpResponse::PayloadTooLarge().json(json!({
+                            "success": false,
+                            "error": format!("Files may not exceed {} bytes", MAX_FILE_BYTES)
+                        }));
+                    }
+                    bytes.extend_from_slice(&chunk);
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    return HttpResponse::BadRequest().json(json!({
+                        "success": false,
+                        "error": format!("Invalid multipart upload: {}", e)
+                    }));
+                }
+            }
+        }
+
+        if bytes.is_empty() {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": format!("{} is empty", file_name)
+            }));
+        }
+
+        uploads.push((file_name, content_type, bytes));
+    }
+
+    if uploads.is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "No files in upload"
+        }));
+    }
+
+    let now = Utc::now().timestamp();
+    let mut created = Vec::new();
+    for (file_name, content_type, bytes) in uploads {
+        let file_id = format!("file-{}", Uuid::new_v4().simple());
+        let storage_key = format!("products/{}/{}", product_id, file_id);
+        let size_bytes = bytes.len() as i64;
+
+        if let Err(e) = files.0.put(&storage_key, bytes).await {
+            error!("Failed to store digital file for product {}: {}", product_id, e);
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to store file",
+                "uploaded": created
+            }));
+        }
+
+        let inserted = sqlx::query(
+            "INSERT INTO digital_files (id, product_id, file_name, content_type, storage_key, size_bytes, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&file_id)
+        .bind(&product_id)
+        .bind(&file_name)
+        .bind(&content_type)
+        .bind(&storage_key)
+        .bind(size_bytes)
+        .bind(now)
+        .execute(&app_state.db)
+        .await;
+
+        if let Err(e) = inserted {
+            error!("Failed to record digital file for product {}: {}", product_id, e);
+            let _ = files.0.delete(&storage_key).await;
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to save file",
+                "uploaded": created
+            }));
+        }
+
+        info!("Digital file {} ({} bytes) added to product {} by {}", file_name, size_bytes, product_id, auth.claims.sub);
+        created.push(DigitalFile {
+            id: file_id,
+            product_id: product_id.clone(),
+            file_name,
+            content_type,
+            size_bytes,
+            created_at: now,
+        });
+    }
+
+    HttpResponse::Created().json(json!({
+        "success": true,
+        "files": created
+    }))
+}
+
+// Admin: remove a file; download links already issued for it stop working
+#[delete("/products/{product_id}/files/{file_id}")]
+pub async fn delete_digital_file(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<(String, String)>,
+    app_state: web::Data<AppState>,
+    files: web::Data<DigitalFiles>,
+) -> impl Responder {
+    let (product_id, file_id) = path.into_inner();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+
+        let storage_key: Option<String> = sqlx::query("SELECT storage_key FROM digital_files WHERE id = ? AND product_id = ?")
+            .bind(&file_id)
+            .bind(&product_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .map(|row| row.get("storage_key"));
+
+        if storage_key.is_some() {
+            sqlx::query("DELETE FROM download_grants WHERE file_id = ?")
+                .bind(&file_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("DELETE FROM digital_files WHERE id = ?")
+                .bind(&file_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(storage_key)
+    }.await;
+
+    match result {
+        Ok(Some(storage_key)) => {
+            if let Err(e) = files.0.delete(&storage_key).await {
+                warn!("Digital file {} removed from database but not from storage: {}", storage_key, e);
+            }
+            info!("Digital file {} removed from product {} by {}", file_id, product_id, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "File deleted"
+            }))
+        },
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": "File not found"
+        })),
+        Err(e) => {
+            error!("Failed to delete digital file {}: {}", file_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to delete file"
+            }))
+        }
+    }
+}
+
+// Admin: license key pool of a product, with the order item each key went to
+#[get("/products/{id}/license-keys")]
+pub async fn list_license_keys(
+    _auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    match sqlx::query(
+        "SELECT k.id, k.license_key, k.order_item_id, k.assigned_at, k.created_at, oi.order_number
+         FROM license_keys k
+         LEFT JOIN order_items oi ON oi.id = k.order_item_id
+         WHERE k.product_id = ?
+         ORDER BY k.id"
+    )
+    .bind(&product_id)
+    .fetch_all(&app_state.db)
+    .await {
+        Ok(rows) => {
+            let keys: Vec<serde_json::Value> = rows.iter().map(|row| json!({
+                "id": row.get::<i64, _>("id"),
+                "license_key": row.get::<String, _>("license_key"),
+                "order_id": row.get::<Option<String>, _>("order_number"),
+                "order_item_id": row.get::<Option<i64>, _>("order_item_id"),
+                "assigned_at": row.get::<Option<i64>, _>("assigned_at"),
+                "created_at": row.get::<i64, _>("created_at")
+            })).collect();
+            let available = keys.iter().filter(|k| k["order_item_id"].is_null()).count();
+
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "total": keys.len(),
+                "available": available,
+                "keys": keys
+            }))
+        },
+        Err(e) => {
+            error!("Failed to list license keys for product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to list license keys"
+            }))
+        }
+    }
+}
+
+// Admin: add keys to a product's pool. Each new key adds one unit of stock,
+// so the pool cannot be oversold.
+#[post("/products/{id}/license-keys")]
+pub async fn add_license_keys(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    body: web::Json<LicenseKeysRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+    let keys = body.into_inner().keys;
+
+    if keys.is_empty() || keys.len() > MAX_KEYS_PER_REQUEST {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": format!("Send between 1 and {} keys", MAX_KEYS_PER_REQUEST)
+        }));
+    }
+
+    let mut seen = HashSet::new();
+    let mut unique = Vec::with_capacity(keys.len());
+    for key in &keys {
+        let key = key.trim();
+        if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": format!("License keys must be 1 to {} characters", MAX_KEY_LENGTH)
+            }));
+        }
+        if seen.insert(key) {
+            unique.push(key.to_string());
+        }
+    }
+
+    let now = Utc::now().timestamp();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+
+        if products::fetch_product(&mut *tx, &product_id).await?.is_none() {
+            return Ok(None);
+        }
+
+        let mut added: i64 = 0;
+        for key in &unique {
+            added += sqlx::query(
+                "INSERT OR IGNORE INTO license_keys (product_id, license_key, created_at) VALUES (?, ?, ?)"
+            )
+            .bind(&product_id)
+            .bind(key)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected() as i64;
+        }
+
+        if added > 0 {
+            sqlx::query("UPDATE products SET stock = stock + ? WHERE id = ?")
+                .bind(added)
+                .bind(&product_id)
+                .execute(&mut *tx)
+                .await?;
+
+            sqlx::query(
+                "INSERT INTO stock_adjustments (product_id, variant_id, delta, reason, actor, order_id, created_at)
+                 VALUES (?, NULL, ?, 'License keys added', ?, NULL, ?)"
+            )
+            .bind(&product_id)
+            .bind(added)
+            .bind(&auth.claims.sub)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(Some(added))
+    }.await;
+
+    match result {
+        Ok(Some(added)) => {
+            info!("{} license key(s) added to product {} by {}", added, product_id, auth.claims.sub);
+            HttpResponse::Created().json(json!({
+                "success": true,
+                "added": added,
+                "duplicates": keys.len() as i64 - added
+            }))
+        },
+        Ok(None) => product_not_found(),
+        Err(e) => {
+            error!("Failed to add license keys to product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to add license keys"
+            }))
+        }
+    }
+}
+
+// Admin: withdraw an unassigned key from the pool (and its unit of stock)
+#[delete("/products/{product_id}/license-keys/{key_id}")]
+pub async fn delete_license_key(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<(String, i64)>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let (product_id, key_id) = path.into_inner();
+    let now = Utc::now().timestamp();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+
+        let deleted = sqlx::query(
+            "DELETE FROM license_keys WHERE id = ? AND product_id = ? AND order_item_id IS NULL"
+        )
+        .bind(key_id)
+        .bind(&product_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        if deleted == 0 {
+            return Ok(Some("License key not found or already assigned"));
+        }
+
+        // Keys held for unpaid orders are counted in `reserved`
+        let released = sqlx::query("UPDATE products SET stock = stock - 1 WHERE id = ? AND stock - reserved >= 1")
+            .bind(&product_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        if released == 0 {
+            return Ok(Some("All remaining keys are reserved by pending orders"));
+        }
+
+        sqlx::query(
+            "INSERT INTO stock_adjustments (product_id, variant_id, delta, reason, actor, order_id, created_at)
+             VALUES (?, NULL, -1, 'License key removed', ?, NULL, ?)"
+        )
+        .bind(&product_id)
+        .bind(&auth.claims.sub)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(None)
+    }.await;
+
+    match result {
+        Ok(None) => {
+            info!("License key {} removed from product {} by {}", key_id, product_id, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "License key deleted"
+            }))
+        },
+        Ok(Some(reason)) => HttpResponse::Conflict().json(json!({
+            "success": false,
+            "error": reason
+        })),
+        Err(e) => {
+            error!("Failed to delete license key {}: {}", key_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to delete license key"
+            }))
+        }
+    }
+}
+
+// Admin: retry delivery of an order's pending digital items, e.g. after
+// topping up an empty key pool
+#[post("/orders/{id}/fulfil")]
+pub async fn retry_fulfilment(
+    auth: Authorized<perm::OrdersFulfil>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let order_id = path.into_inner();
+
+    match sqlx::query("SELECT mp.status AS payment_status FROM orders o
+                       LEFT JOIN monero_payments mp ON mp.payment_id = o.payment_id
+                       WHERE o.id = ?")
+        .bind(&order_id)
+        .fetch_optional(&app_state.db)
+        .await {
+            Ok(Some(row)) => {
+                // The payment store is authoritative; the table may lag behind it
+                let paid = app_state.monero_payments
+                    .get_payment_by_order_id(&order_id)
+                    .map(|p| p.status == PaymentStatus::Confirmed || p.status == PaymentStatus::Completed)
+                    .unwrap_or(false)
+                    || matches!(
+                        row.get::<Option<String>, _>("payment_status").as_deref(),
+                        Some("Confirmed") | Some("confirmed") | Some("Completed")
+                    );
+                if !paid {
+                    return HttpResponse::Conflict().json(json!({
+                        "success": false,
+                        "error": "Order payment has not been confirmed"
+                    }));
+                }
+            },
+            Ok(None) => {
+                return HttpResponse::NotFound().json(json!({
+                    "success": false,
+                    "error": "Order not found"
+                }));
+            },
+            Err(e) => {
+                error!("Database error loading order {}: {}", order_id, e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to load order"
+                }));
+            }
+        }
+
+    match fulfil_order(&app_state.db, &order_id).await {
+        Ok(report) => {
+            info!("Digital fulfilment of order {} retried by {}: {:?}", order_id, auth.claims.sub, report);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "report": report
+            }))
+        },
+        Err(e) => {
+            warn!("Failed to fulfil order {}: {}", order_id, e);
+            e.to_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    fn install_keys() {
+        // Every test shares the process-wide keys; only the first install counts
+        let _ = session::install_keys(session::JwtKeys::hmac("test", b"digital-test-secret-0123456789abcdef").unwrap());
+    }
+
+    async fn database() -> SqlitePool {
+        install_keys();
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE orders (id TEXT PRIMARY KEY, status TEXT NOT NULL, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL)",
+            "CREATE TABLE order_status_history (id INTEGER PRIMARY KEY AUTOINCREMENT, order_id TEXT NOT NULL, from_status TEXT, to_status TEXT NOT NULL, actor TEXT NOT NULL, reason TEXT, created_at INTEGER NOT NULL)",
+            "CREATE TABLE order_items (id INTEGER PRIMARY KEY AUTOINCREMENT, order_number TEXT NOT NULL, product_id TEXT NOT NULL, quantity INTEGER NOT NULL, fulfilled_at INTEGER)",
+            "CREATE TABLE products (id TEXT PRIMARY KEY, name TEXT NOT NULL, digital_delivery TEXT)",
+            "CREATE TABLE digital_files (id TEXT PRIMARY KEY, product_id TEXT NOT NULL, file_name TEXT NOT NULL, content_type TEXT NOT NULL, storage_key TEXT NOT NULL, size_bytes INTEGER NOT NULL, created_at INTEGER NOT NULL)",
+            "CREATE TABLE license_keys (id INTEGER PRIMARY KEY AUTOINCREMENT, product_id TEXT NOT NULL, license_key TEXT NOT NULL, order_item_id INTEGER, assigned_at INTEGER, created_at INTEGER NOT NULL, UNIQUE (product_id, license_key))",
+            "CREATE TABLE download_grants (id TEXT PRIMARY KEY, order_item_id INTEGER NOT NULL, file_id TEXT NOT NULL, expires_at INTEGER NOT NULL, max_downloads INTEGER NOT NULL, download_count INTEGER NOT NULL DEFAULT 0, last_downloaded_at INTEGER, created_at INTEGER NOT NULL)",
+            "INSERT INTO products VALUES ('p-game', 'Game', 'license_key'), ('p-ebook', 'E-book', 'download'), ('p-mug', 'Mug', NULL)",
+            "INSERT INTO digital_files VALUES ('f-pdf', 'p-ebook', 'book.pdf', 'application/pdf', 'digital/p-ebook/f-pdf', 100, 1), ('f-epub', 'p-ebook', 'book.epub', 'application/epub+zip', 'digital/p-ebook/f-epub', 80, 2)",
+            "INSERT INTO license_keys (product_id, license_key, created_at) VALUES ('p-game', 'KEY-1', 0), ('p-game', 'KEY-2', 0)",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    async fn place_order(db: &SqlitePool, order_id: &str, status: OrderStatus, lines: &[(&str, i64)]) {
+        sqlx::query("INSERT INTO orders (id, status, created_at, updated_at) VALUES (?, ?, 0, 0)")
+            .bind(order_id)
+            .bind(status.as_str())
+            .execute(db)
+            .await
+            .unwrap();
+        for (product_id, quantity) in lines {
+            sqlx::query("INSERT INTO order_items (order_number, product_id, quantity) VALUES (?, ?, ?)")
+                .bind(order_id)
+                .bind(product_id)
+                .bind(quantity)
+                .execute(db)
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn status(db: &SqlitePool, order_id: &str) -> String {
+        sqlx::query("SELECT status FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .get("status")
+    }
+
+    #[test]
+    fn file_names_lose_paths_and_header_breaking_characters() {
+        assert_eq!(sanitize_file_name("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize_file_name("C:\\Users\\me\\book.pdf").as_deref(), Some("book.pdf"));
+        assert_eq!(sanitize_file_name("a\"b\r\n.pdf").as_deref(), Some("ab.pdf"));
+        for name in ["", "dir/", "..", " . "] {
+            assert_eq!(sanitize_file_name(name), None, "{:?}", name);
+        }
+        assert_eq!(sanitize_file_name(&"x".repeat(500)).unwrap().len(), MAX_FILE_NAME_LENGTH);
+    }
+
+    #[test]
+    fn download_links_are_only_download_links() {
+        install_keys();
+
+        let expires_at = Utc::now().timestamp() + 60;
+        let url = download_url("dl-1", expires_at).unwrap();
+        let token = url.strip_prefix("/downloads/").unwrap();
+        assert_eq!(verify_download_token(token).unwrap(), "dl-1");
+
+        let expired = download_url("dl-1", Utc::now().timestamp() - 1).unwrap();
+        assert!(verify_download_token(expired.strip_prefix("/downloads/").unwrap()).is_err());
+
+        // A token for another audience signed with the same keys
+        #[derive(Serialize)]
+        struct Other { sub: String, aud: String, exp: usize }
+        let other = session::sign(&Other { sub: "dl-1".to_string(), aud: "access".to_string(), exp: expires_at as usize }).unwrap();
+        assert!(verify_download_token(&other).is_err());
+    }
+
+    #[actix_web::test]
+    async fn paid_digital_orders_are_delivered() {
+        let db = database().await;
+        place_order(&db, "ORD-1", OrderStatus::Paid, &[("p-game", 2), ("p-ebook", 1)]).await;
+
+        let report = fulfil_order(&db, "ORD-1").await.unwrap();
+        assert_eq!((report.fulfilled_items, report.pending_items, report.order_delivered), (2, 0, true));
+        assert_eq!(status(&db, "ORD-1").await, "Delivered");
+
+        let deliveries = deliveries_for_order(&db, "ORD-1").await.unwrap();
+        assert_eq!(deliveries[0]["license_keys"], json!(["KEY-1", "KEY-2"]));
+        let files: Vec<_> = deliveries[1]["downloads"].as_array().unwrap().iter().map(|d| d["file_name"].clone()).collect();
+        assert_eq!(files, [json!("book.pdf"), json!("book.epub")]);
+        assert_eq!(deliveries[1]["downloads"][0]["downloads_remaining"], DEFAULT_MAX_DOWNLOADS);
+
+        // Running again hands out nothing new
+        let report = fulfil_order(&db, "ORD-1").await.unwrap();
+        assert_eq!((report.fulfilled_items, report.pending_items), (0, 0));
+        let grants: i64 = sqlx::query("SELECT COUNT(*) FROM download_grants").fetch_one(&db).await.unwrap().get(0);
+        assert_eq!(grants, 2);
+    }
+
+    #[actix_web::test]
+    async fn items_wait_for_keys_and_physical_items_keep_the_order_open() {
+        let db = database().await;
+        place_order(&db, "ORD-1", OrderStatus::Paid, &[("p-game", 3)]).await;
+        place_order(&db, "ORD-2", OrderStatus::Paid, &[("p-ebook", 1), ("p-mug", 1)]).await;
+
+        let report = fulfil_order(&db, "ORD-1").await.unwrap();
+        assert_eq!((report.fulfilled_items, report.pending_items), (0, 1));
+        assert_eq!(status(&db, "ORD-1").await, "Paid");
+
+        // A restocked pool completes the item without assigning keys twice
+        sqlx::query("INSERT INTO license_keys (product_id, license_key, created_at) VALUES ('p-game', 'KEY-3', 0), ('p-game', 'KEY-4', 0)")
+            .execute(&db)
+            .await
+            .unwrap();
+        let report = fulfil_order(&db, "ORD-1").await.unwrap();
+        assert_eq!((report.fulfilled_items, report.order_delivered), (1, true));
+        let unassigned: i64 = sqlx::query("SELECT COUNT(*) FROM license_keys WHERE order_item_id IS NULL").fetch_one(&db).await.unwrap().get(0);
+        assert_eq!(unassigned, 1);
+
+        let report = fulfil_order(&db, "ORD-2").await.unwrap();
+        assert_eq!((report.fulfilled_items, report.order_delivered), (1, false));
+        assert_eq!(status(&db, "ORD-2").await, "Paid");
+    }
+
+    #[actix_web::test]
+    async fn unpaid_orders_get_nothing() {
+        let db = database().await;
+        place_order(&db, "ORD-1", OrderStatus::AwaitingPayment, &[("p-game", 1)]).await;
+
+        assert!(matches!(
+            fulfil_order(&db, "ORD-1").await,
+            Err(StatusError::NotAllowed { from: OrderStatus::AwaitingPayment, .. })
+        ));
+        assert!(matches!(fulfil_order(&db, "ORD-9").await, Err(StatusError::NotFound)));
+        let assigned: i64 = sqlx::query("SELECT COUNT(*) FROM license_keys WHERE order_item_id IS NOT NULL").fetch_one(&db).await.unwrap().get(0);
+        assert_eq!(assigned, 0);
+    }
+}
@@ -0,0 +1,595 @@
+// src/images.rs
+use actix_multipart::Multipart;
+use actix_web::{web, HttpResponse, Responder, post, put, delete};
+use futures::TryStreamExt;
+use image::{ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::Utc;
+use uuid::Uuid;
+use sqlx::{Row, Sqlite, Executor};
+use std::io::Cursor;
+use log::{info, warn, error};
+use crate::AppState;
+use crate::rbac::{perm, Authorized};
+use crate::storage::ImageStorage;
+
+/// Largest accepted upload, per file.
+pub const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+/// Largest accepted width or height, to keep decoding memory bounded.
+const MAX_IMAGE_DIMENSION: u32 = 8000;
+const MAX_FILES_PER_UPLOAD: usize = 10;
+const THUMBNAIL_SIZE: u32 = 320;
+
+/// One image in a product's gallery.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProductImage {
+    pub id: String,
+    pub product_id: String,
+    pub url: String,
+    pub thumbnail_url: String,
+    /// Gallery order, starting at 0; the first image is the product's main image
+    pub position: i64,
+    pub content_type: String,
+    pub width: i64,
+    pub height: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ReorderImagesRequest {
+    pub image_ids: Vec<String>,
+}
+
+struct ProcessedImage {
+    format: ImageFormat,
+    width: u32,
+    height: u32,
+    thumbnail: Vec<u8>,
+    thumbnail_format: ImageFormat,
+}
+
+fn format_for_content_type(content_type: &str) -> Option<ImageFormat> {
+    match content_type {
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/png" => Some(ImageFormat::Png),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+/// Check the bytes really are the declared format, then build a thumbnail.
+fn process_image(bytes: &[u8], declared: ImageFormat) -> Result<ProcessedImage, String> {
+    match image::guess_format(bytes) {
+        Ok(format) if format == declared => {},
+        _ => return Err("File contents do not match the declared image type".to_string()),
+    }
+
+    let (width, height) = ImageReader::with_format(Cursor::new(bytes), declared)
+        .into_dimensions()
+        .map_err(|e| format!("Unreadable image: {}", e))?;
+
+    if width > MAX_IMAGE_DIMENSION || height > MAX_IMAGE_DIMENSION {
+        return Err(format!("Image dimensions may not exceed {}px", MAX_IMAGE_DIMENSION));
+    }
+
+    let decoded = image::load_from_memory_with_format(bytes, declared)
+        .map_err(|e| format!("Unreadable image: {}", e))?;
+
+    // JPEG has no alpha channel, so only photos stay JPEG
+    let thumbnail_format = if declared == ImageFormat::Jpeg { ImageFormat::Jpeg } else { ImageFormat::Png };
+    let mut thumbnail = Cursor::new(Vec::new());
+    decoded
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut thumbnail, thumbnail_format)
+        .map_err(|e| format!("Failed to create thumbnail: {}", e))?;
+
+    Ok(ProcessedImage {
+        format: declared,
+        width,
+        height,
+        thumbnail: thumbnail.into_inner(),
+        thumbnail_format,
+    })
+}
+
+fn extension(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or("img")
+}
+
+fn image_from_row(row: &sqlx::sqlite::SqliteRow) -> ProductImage {
+    ProductImage {
+        id: row.get("id"),
+        product_id: row.get("product_id"),
+        url: row.get("url"),
+        thumbnail_url: row.get("thumbnail_url"),
+        position: row.get("position"),
+        content_type: row.get("content_type"),
+        width: row.get("width"),
+        height: row.get("height"),
+    }
+}
+
+/// A product's gallery in display order.
+pub async fn images_for_product<'e, E>(executor: E, product_id: &str) -> Result<Vec<ProductImage>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let rows = sqlx::query(
+        "SELECT id, product_id, url, thumbnail_url, position, content_type, width, height
+         FROM product_images WHERE product_id = ? ORDER BY position"
+    )
+    .bind(product_id)
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows.iter().map(image_from_row).collect())
+}
+
+/// URL of the product's main (first) image, if it has any.
+pub async fn primary_image_url<'e, E>(executor: E, product_id: &str) -> Result<Option<String>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let row = sqlx::query("SELECT url FROM product_images WHERE product_id = ? ORDER BY position LIMIT 1")
+        .bind(product_id)
+        .fetch_optional(executor)
+        .await?;
+
+    Ok(row.map(|row| row.get("url")))
+}
+
+// Public: a product's image gallery
+pub async fn list_product_images(
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    match images_for_product(&app_state.db, &product_id).await {
+        Ok(images) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "product_id": product_id,
+            "images": images
+        })),
+        Err(e) => {
+            error!("Failed to fetch images for product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to fetch images"
+            }))
+        }
+    }
+}
+
+// Admin: upload one or more images (multipart, any field name) to the end of
+// a product's gallery
+#[post("/products/{id}/images")]
+pub async fn upload_product_images(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    storage: web::Data<dyn ImageStorage>,
+    path: web::Path<String>,
+    mut payload: Multipart,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    match sqlx::query("SELECT id FROM products WHERE id = ?")
+        .bind(&product_id)
+        .fetch_optional(&app_state.db)
+        .await {
+            Ok(Some(_)) => {},
+            Ok(None) => {
+                return HttpResponse::NotFound().json(json!({
+                    "success": false,
+                    "error": "Product not found"
+                }));
+            },
+            Err(e) => {
+                error!("Database error checking product {}: {}", product_id, e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Database error"
+                }));
+            }
+        }
+
+    // Read and validate every file before storing anything
+    let mut uploads: Vec<(ImageFormat, Vec<u8>)> = Vec::new();
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": format!("Invalid multipart upload: {}", e)
+                }));
+            }
+        };
+
+        if uploads.len() == MAX_FILES_PER_UPLOAD {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": format!("At most {} images can be uploaded at once", MAX_FILES_PER_UPLOAD)
+            }));
+        }
+
+        let declared = match field.content_type().and_then(|m| format_for_content_type(m.essence_str())) {
+            Some(format) => format,
+            None => {
+                return HttpResponse::UnsupportedMediaType().json(json!({
+                    "success": false,
+                    "error": "Only JPEG, PNG, GIF and WebP images are accepted"
+                }));
+            }
+        };
+
+        let mut bytes = Vec::new();
+        loop {
+            match field.try_next().await {
+                Ok(Some(chunk)) => {
+                    if bytes.len() + chunk.len() > MAX_IMAGE_BYTES {
+                        return HttpResponse::PayloadTooLarge().json(json!({
+                            "success": false,
+                            "error": format!("Images may not exceed {} bytes", MAX_IMAGE_BYTES)
+                        }));
+                    }
+                    bytes.extend_from_slice(&chunk);
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    return HttpResponse::BadRequest().json(json!({
+                        "success": false,
+                        "error": format!("Invalid multipart upload: {}", e)
+                    }));
+                }
+            }
+        }
+
+        if bytes.is_empty() {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": "Empty file"
+            }));
+        }
+
+        uploads.push((declared, bytes));
+    }
+
+    if uploads.is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "No image files in upload"
+        }));
+    }
+
+    let mut created = Vec::new();
+    for (declared, bytes) in uploads {
+        let (processed, bytes) = match web::block(move || {
+            let processed = process_image(&bytes, declared);
+            (processed, bytes)
+        }).await {
+            Ok((Ok(processed), bytes)) => (processed, bytes),
+            Ok((Err(msg), _)) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": msg,
+                    "uploaded": created
+                }));
+            },
+            Err(e) => {
+                error!("Image processing task failed: {}", e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to process image",
+                    "uploaded": created
+                }));
+            }
+        };
+
+        let image_id = format!("img-{}", Uuid::new_v4().simple());
+        let storage_key = format!("products/{}/{}.{}", product_id, image_id, extension(processed.format));
+        let thumbnail_key = format!("products/{}/{}_thumb.{}", product_id, image_id, extension(processed.thumbnail_format));
+        let size_bytes = bytes.len() as i64;
+
+        let stored = async {
+            storage.put(&storage_key, bytes).await?;
+            storage.put(&thumbnail_key, processed.thumbnail).await
+        }.await;
+        if let Err(e) = stored {
+            error!("Failed to store image for product {}: {}", product_id, e);
+            let _ = storage.delete(&storage_key).await;
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to store image",
+                "uploaded": created
+            }));
+        }
+
+        let image = ProductImage {
+            id: image_id,
+            product_id: product_id.clone(),
+            url: storage.url(&storage_key),
+            thumbnail_url: storage.url(&thumbnail_key),
+            position: 0,
+            content_type: processed.format.to_mime_type().to_string(),
+            width: processed.width as i64,
+            height: processed.height as i64,
+        };
+
+        let inserted = sqlx::query(
+            "INSERT INTO product_images (id, product_id, position, content_type, storage_key, thumbnail_key,
+                                         url, thumbnail_url, width, height, size_bytes, created_at)
+             VALUES (?, ?, (SELECT COALESCE(MAX(position) + 1, 0) FROM product_images WHERE product_id = ?),
+                     ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             RETURNING position"
+        )
+        .bind(&image.id)
+        .bind(&product_id)
+        .bind(&product_id)
+        .bind(&image.content_type)
+        .bind(&storage_key)
+        .bind(&thumbnail_key)
+        .bind(&image.url)
+        .bind(&image.thumbnail_url)
+        .bind(image.width)
+        .bind(image.height)
+        .bind(size_bytes)
+        .bind(Utc::now().timestamp())
+        .fetch_one(&app_state.db)
+        .await;
+
+        match inserted {
+            Ok(row) => {
+                info!("Stored image {} for product {}", image.id, product_id);
+                created.push(ProductImage { position: row.get("position"), ..image });
+            },
+            Err(e) => {
+                error!("Failed to record image for product {}: {}", product_id, e);
+                let _ = storage.delete(&storage_key).await;
+                let _ = storage.delete(&thumbnail_key).await;
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to save image",
+                    "uploaded": created
+                }));
+            }
+        }
+    }
+
+    HttpResponse::Created().json(json!({
+        "success": true,
+        "images": created
+    }))
+}
+
+// Admin: set the gallery order; must list every image of the product exactly once
+#[put("/products/{id}/images/order")]
+pub async fn reorder_product_images(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<ReorderImagesRequest>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+
+        let mut current: Vec<String> = images_for_product(&mut *tx, &product_id)
+            .await?
+            .into_iter()
+            .map(|image| image.id)
+            .collect();
+        let mut requested = body.image_ids.clone();
+        current.sort();
+        requested.sort();
+        if current != requested {
+            return Ok(false);
+        }
+
+        for (position, image_id) in body.image_ids.iter().enumerate() {
+            sqlx::query("UPDATE product_images SET position = ? WHERE id = ? AND product_id = ?")
+                .bind(position as i64)
+                .bind(image_id)
+                .bind(&product_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok::<bool, sqlx::Error>(true)
+    }.await;
+
+    match result {
+        Ok(true) => match images_for_product(&app_state.db, &product_id).await {
+            Ok(images) => HttpResponse::Ok().json(json!({
+                "success": true,
+                "images": images
+            })),
+            Err(_) => HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "Images reordered"
+            })),
+        },
+        Ok(false) => HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "image_ids must list each of the product's images exactly once"
+        })),
+        Err(e) => {
+            error!("Failed to reorder images for product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to reorder images"
+            }))
+        }
+    }
+}
+
+// Admin: remove an image from a product's gallery
+#[delete("/products/{product_id}/images/{image_id}")]
+pub async fn delete_product_image(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    storage: web::Data<dyn ImageStorage>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (product_id, image_id) = path.into_inner();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+
+        let row = sqlx::query(
+            "SELECT position, storage_key, thumbnail_key FROM product_images WHERE id = ? AND product_id = ?"
+        )
+        .bind(&image_id)
+        .bind(&product_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let position: i64 = row.get("position");
+
+        sqlx::query("DELETE FROM product_images WHERE id = ?")
+            .bind(&image_id)
+            .execute(&mut *tx)
+            .await?;
+
+        // Close the gap so positions stay contiguous
+        sqlx::query("UPDATE product_images SET position = position - 1 WHERE product_id = ? AND position > ?")
+            .bind(&product_id)
+            .bind(position)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok::<Option<(String, String)>, sqlx::Error>(Some((row.get("storage_key"), row.get("thumbnail_key"))))
+    }.await;
+
+    match result {
+        Ok(Some((storage_key, thumbnail_key))) => {
+            for key in [&storage_key, &thumbnail_key] {
+                if let Err(e) = storage.delete(key).await {
+                    warn!("Failed to remove stored file {}: {}", key, e);
+                }
+            }
+            info!("Deleted image {} of product {}", image_id, product_id);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "Image deleted"
+            }))
+        },
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": "Image not found"
+        })),
+        Err(e) => {
+            error!("Failed to delete image {}: {}", image_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to delete image"
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalDiskStorage;
+    use image::{DynamicImage, RgbaImage};
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(width, height));
+        let image = if format == ImageFormat::Jpeg { DynamicImage::ImageRgb8(image.to_rgb8()) } else { image };
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn only_web_image_types_are_accepted() {
+        assert_eq!(format_for_content_type("image/png"), Some(ImageFormat::Png));
+        assert_eq!(format_for_content_type("image/webp"), Some(ImageFormat::WebP));
+        assert_eq!(format_for_content_type("image/svg+xml"), None);
+        assert_eq!(format_for_content_type("text/html"), None);
+    }
+
+    #[test]
+    fn thumbnails_fit_the_box_and_keep_photos_as_jpeg() {
+        let processed = process_image(&encode(1000, 500, ImageFormat::Png), ImageFormat::Png).unwrap();
+        assert_eq!((processed.width, processed.height), (1000, 500));
+        assert_eq!(processed.thumbnail_format, ImageFormat::Png);
+        let thumbnail = image::load_from_memory(&processed.thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+
+        let processed = process_image(&encode(40, 40, ImageFormat::Jpeg), ImageFormat::Jpeg).unwrap();
+        assert_eq!(processed.thumbnail_format, ImageFormat::Jpeg);
+        assert_eq!(extension(processed.format), "jpg");
+        // GIFs may be transparent, so their thumbnail is a PNG
+        let processed = process_image(&encode(40, 40, ImageFormat::Gif), ImageFormat::Gif).unwrap();
+        assert_eq!(processed.thumbnail_format, ImageFormat::Png);
+    }
+
+    #[test]
+    fn uploads_must_be_what_they_claim() {
+        let png = encode(10, 10, ImageFormat::Png);
+        assert!(process_image(&png, ImageFormat::Jpeg).is_err());
+        assert!(process_image(b"<svg onload=alert(1)>", ImageFormat::Png).is_err());
+        // The header is checked before anything is decoded
+        assert!(process_image(&png[..40], ImageFormat::Png).is_err());
+        assert!(process_image(&encode(MAX_IMAGE_DIMENSION + 1, 1, ImageFormat::Png), ImageFormat::Png).is_err());
+    }
+
+    #[test]
+    fn storage_keys_stay_under_the_root() {
+        let storage = LocalDiskStorage::new("/srv/uploads", "/uploads/");
+        assert_eq!(storage.path_for("products/p1/a.png").unwrap(), std::path::Path::new("/srv/uploads/products/p1/a.png"));
+        assert_eq!(storage.url("products/p1/a.png"), "/uploads/products/p1/a.png");
+        for key in ["../etc/passwd", "/etc/passwd", "products/../../x", "./a.png"] {
+            assert!(storage.path_for(key).is_err(), "{}", key);
+        }
+    }
+
+    #[actix_web::test]
+    async fn stored_files_can_be_deleted_twice() {
+        let root = std::env::temp_dir().join(format!("images-{}", Uuid::new_v4()));
+        let storage = LocalDiskStorage::new(&root, "/uploads");
+        storage.put("products/p1/a.png", b"png".to_vec()).await.unwrap();
+        assert_eq!(std::fs::read(root.join("products/p1/a.png")).unwrap(), b"png");
+
+        storage.delete("products/p1/a.png").await.unwrap();
+        storage.delete("products/p1/a.png").await.unwrap();
+        assert!(!root.join("products/p1/a.png").exists());
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[actix_web::test]
+    async fn the_gallery_is_in_position_order() {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE product_images (id TEXT PRIMARY KEY, product_id TEXT NOT NULL, url TEXT NOT NULL, thumbnail_url TEXT NOT NULL, position INTEGER NOT NULL, content_type TEXT NOT NULL, width INTEGER NOT NULL, height INTEGER NOT NULL)",
+            "INSERT INTO product_images VALUES
+                ('i2', 'p1', '/u/2.png', '/u/2-t.png', 1, 'image/png', 10, 10),
+                ('i1', 'p1', '/u/1.png', '/u/1-t.png', 0, 'image/png', 10, 10),
+                ('i3', 'p2', '/u/3.png', '/u/3-t.png', 0, 'image/png', 10, 10)",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+
+        let ids: Vec<_> = images_for_product(&db, "p1").await.unwrap().into_iter().map(|i| i.id).collect();
+        assert_eq!(ids, ["i1", "i2"]);
+        assert_eq!(primary_image_url(&db, "p1").await.unwrap().as_deref(), Some("/u/1.png"));
+        assert_eq!(primary_image_url(&db, "p9").await.unwrap(), None);
+    }
+}
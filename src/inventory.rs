@@ -0,0 +1,612 @@
+// src/inventory.rs
+use actix_web::{web, HttpResponse, Responder, get, post};
+use serde::Deserialize;
+use serde_json::json;
+use chrono::Utc;
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+use log::{info, warn, error};
+use crate::AppState;
+use crate::rbac::{perm, Authorized};
+
+// Reservation lifecycle: Active while the order awaits payment, then either
+// Committed (payment confirmed, stock decremented) or Released (expired/cancelled).
+pub const RESERVATION_ACTIVE: &str = "Active";
+pub const RESERVATION_COMMITTED: &str = "Committed";
+pub const RESERVATION_RELEASED: &str = "Released";
+
+#[derive(Debug)]
+pub enum StockError {
+    Insufficient { product_id: String, requested: i64, available: i64 },
+    /// No such product, or the variant doesn't belong to it
+    UnknownProduct { product_id: String, variant_id: Option<String> },
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for StockError {
+    fn from(e: sqlx::Error) -> Self {
+        StockError::Database(e)
+    }
+}
+
+impl std::fmt::Display for StockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StockError::Insufficient { product_id, requested, available } => write!(
+                f,
+                "Insufficient stock for product {}: requested {}, available {}",
+                product_id, requested, available
+            ),
+            StockError::UnknownProduct { product_id, variant_id: Some(variant_id) } => write!(
+                f, "Unknown variant {} of product {}", variant_id, product_id
+            ),
+            StockError::UnknownProduct { product_id, variant_id: None } => write!(f, "Unknown product {}", product_id),
+            StockError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+/// Reserve stock for one order line inside the checkout transaction.
+/// The conditional UPDATE is the oversell guard: it only succeeds while
+/// enough unreserved stock remains. Lines with a variant draw on that
+/// variant's stock instead of the product's. Archived products cannot be
+/// reserved.
+pub async fn reserve_stock(
+    tx: &mut Transaction<'_, Sqlite>,
+    order_id: &str,
+    product_id: &str,
+    variant_id: Option<&str>,
+    quantity: i64,
+) -> Result<(), StockError> {
+    if quantity <= 0 {
+        return Err(StockError::Insufficient {
+            product_id: product_id.to_string(),
+            requested: quantity,
+            available: 0,
+        });
+    }
+
+    let now = Utc::now().timestamp();
+
+    let result = match variant_id {
+        Some(variant_id) => sqlx::query(
+            "UPDATE product_variants SET reserved = reserved + ?
+             WHERE id = ? AND product_id = ? AND available != 0 AND stock - reserved >= ?
+               AND product_id IN (SELECT id FROM products WHERE archived_at IS NULL)"
+        )
+        .bind(quantity)
+        .bind(variant_id)
+        .bind(product_id)
+        .bind(quantity)
+        .execute(&mut **tx)
+        .await?,
+        None => sqlx::query(
+            "UPDATE products SET reserved = reserved + ?
+             WHERE id = ? AND stock - reserved >= ? AND archived_at IS NULL"
+        )
+        .bind(quantity)
+        .bind(product_id)
+        .bind(quantity)
+        .execute(&mut **tx)
+        .await?,
+    };
+
+    if result.rows_affected() == 0 {
+        let available_row = match variant_id {
+            Some(variant_id) => sqlx::query(
+                "SELECT stock - reserved AS available FROM product_variants
+                 WHERE id = ? AND product_id = ? AND available != 0
+                   AND product_id IN (SELECT id FROM products WHERE archived_at IS NULL)"
+            )
+            .bind(variant_id)
+            .bind(product_id)
+            .fetch_optional(&mut **tx)
+            .await?,
+            None => sqlx::query("SELECT stock - reserved AS available FROM products WHERE id = ? AND archived_at IS NULL")
+                .bind(product_id)
+                .fetch_optional(&mut **tx)
+                .await?,
+        };
+        let available: i64 = available_row.map(|row| row.get("available")).unwrap_or(0);
+
+        return Err(StockError::Insufficient {
+            product_id: product_id.to_string(),
+            requested: quantity,
+            available,
+        });
+    }
+
+    sqlx::query(
+        "INSERT INTO stock_reservations (order_id, product_id, variant_id, quantity, status, created_at, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(order_id)
+    .bind(product_id)
+    .bind(variant_id)
+    .bind(quantity)
+    .bind(RESERVATION_ACTIVE)
+    .bind(now)
+    .bind(now)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Turn an order's active reservations into real stock decrements once its
+/// payment is confirmed. Safe to call more than once.
+pub async fn commit_order_reservations(pool: &SqlitePool, order_id: &str) -> Result<u64, sqlx::Error> {
+    settle_order_reservations(pool, order_id, RESERVATION_COMMITTED).await
+}
+
+/// Give an order's reserved stock back to the pool (payment expired or order
+/// cancelled). Safe to call more than once.
+pub async fn release_order_reservations(pool: &SqlitePool, order_id: &str) -> Result<u64, sqlx::Error> {
+    settle_order_reservations(pool, order_id, RESERVATION_RELEASED).await
+}
+
+async fn settle_order_reservations(
+    pool: &SqlitePool,
+    order_id: &str,
+    outcome: &str,
+) -> Result<u64, sqlx::Error> {
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+
+    let reservations = sqlx::query(
+        "SELECT id, product_id, variant_id, quantity FROM stock_reservations WHERE order_id = ? AND status = ?"
+    )
+    .bind(order_id)
+    .bind(RESERVATION_ACTIVE)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for row in &reservations {
+        let reservation_id: i64 = row.get("id");
+        let product_id: String = row.get("product_id");
+        let variant_id: Option<String> = row.get("variant_id");
+        let quantity: i64 = row.get("quantity");
+
+        // Variant lines hold stock on the variant row, plain lines on the product
+        let (table, stock_id) = match &variant_id {
+            Some(variant_id) => ("product_variants", variant_id.as_str()),
+            None => ("products", product_id.as_str()),
+        };
+
+        if outcome == RESERVATION_COMMITTED {
+            sqlx::query(&format!("UPDATE {} SET stock = stock - ?, reserved = reserved - ? WHERE id = ?", table))
+                .bind(quantity)
+                .bind(quantity)
+                .bind(stock_id)
+                .execute(&mut *tx)
+                .await?;
+
+            sqlx::query(
+                "INSERT INTO stock_adjustments (product_id, variant_id, delta, reason, actor, order_id, created_at)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)"
+            )
+            .bind(&product_id)
+            .bind(&variant_id)
+            .bind(-quantity)
+            .bind("Order payment confirmed")
+            .bind("system")
+            .bind(order_id)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            sqlx::query(&format!("UPDATE {} SET reserved = reserved - ? WHERE id = ?", table))
+                .bind(quantity)
+                .bind(stock_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query("UPDATE stock_reservations SET status = ?, updated_at = ? WHERE id = ?")
+            .bind(outcome)
+            .bind(now)
+            .bind(reservation_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    if !reservations.is_empty() {
+        info!("{} {} stock reservation(s) for order {}", outcome, reservations.len(), order_id);
+    }
+
+    Ok(reservations.len() as u64)
+}
+
+#[derive(Deserialize)]
+pub struct StockAdjustmentRequest {
+    /// Adjust a specific variant's stock rather than the product's own count.
+    pub variant_id: Option<String>,
+    /// Relative change, e.g. +10 after a delivery or -2 for damaged goods.
+    pub delta: Option<i64>,
+    /// Absolute on-hand count after a stocktake; takes precedence over `delta`.
+    pub set_to: Option<i64>,
+    pub reason: String,
+}
+
+fn stock_levels_json(row: &sqlx::sqlite::SqliteRow) -> serde_json::Value {
+    let stock: i64 = row.get("stock");
+    let reserved: i64 = row.get("reserved");
+    json!({
+        "product_id": row.get::<String, _>("id"),
+        "name": row.get::<String, _>("name"),
+        "stock": stock,
+        "reserved": reserved,
+        "available": stock - reserved
+    })
+}
+
+// Get current stock levels plus the adjustment history for a product
+#[get("/products/{id}/stock")]
+pub async fn get_product_stock(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    let product = match sqlx::query("SELECT id, name, stock, reserved FROM products WHERE id = ?")
+        .bind(&product_id)
+        .fetch_optional(&app_state.db)
+        .await {
+            Ok(Some(row)) => row,
+            Ok(None) => {
+                return HttpResponse::NotFound().json(json!({
+                    "success": false,
+                    "error": "Product not found"
+                }));
+            },
+            Err(e) => {
+                error!("Failed to fetch stock for product {}: {}", product_id, e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to fetch stock"
+                }));
+            }
+        };
+
+    let history = match sqlx::query(
+        "SELECT id, variant_id, delta, reason, actor, order_id, created_at FROM stock_adjustments
+         WHERE product_id = ? ORDER BY created_at DESC, id DESC"
+    )
+    .bind(&product_id)
+    .fetch_all(&app_state.db)
+    .await {
+        Ok(rows) => rows.iter().map(|row| {
+            json!({
+                "id": row.get::<i64, _>("id"),
+                "variant_id": row.get::<Option<String>, _>("variant_id"),
+                "delta": row.get::<i64, _>("delta"),
+                "reason": row.get::<String, _>("reason"),
+                "actor": row.get::<String, _>("actor"),
+                "order_id": row.get::<Option<String>, _>("order_id"),
+                "created_at": row.get::<i64, _>("created_at")
+            })
+        }).collect::<Vec<_>>(),
+        Err(e) => {
+            error!("Failed to fetch stock history for product {}: {}", product_id, e);
+            Vec::new()
+        }
+    };
+
+    let variants = match sqlx::query(
+        "SELECT id, sku, stock, reserved FROM product_variants WHERE product_id = ? ORDER BY sku"
+    )
+    .bind(&product_id)
+    .fetch_all(&app_state.db)
+    .await {
+        Ok(rows) => rows.iter().map(|row| {
+            let stock: i64 = row.get("stock");
+            let reserved: i64 = row.get("reserved");
+            json!({
+                "variant_id": row.get::<String, _>("id"),
+                "sku": row.get::<String, _>("sku"),
+                "stock": stock,
+                "reserved": reserved,
+                "available": stock - reserved
+            })
+        }).collect::<Vec<_>>(),
+        Err(e) => {
+            error!("Failed to fetch variant stock for product {}: {}", product_id, e);
+            Vec::new()
+        }
+    };
+
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "stock": stock_levels_json(&product),
+        "variants": variants,
+        "history": history
+    }))
+}
+
+// Manually adjust on-hand stock (deliveries, stocktakes, write-offs)
+#[post("/products/{id}/stock")]
+pub async fn adjust_product_stock(
+    auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    adjustment: web::Json<StockAdjustmentRequest>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+    let adjustment = adjustment.into_inner();
+
+    if adjustment.reason.trim().is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "A reason is required for stock adjustments"
+        }));
+    }
+
+    let mut tx = match app_state.db.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            error!("Failed to start transaction: {}", e);
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Database error"
+            }));
+        }
+    };
+
+    let current = match &adjustment.variant_id {
+        Some(variant_id) => sqlx::query("SELECT stock, reserved FROM product_variants WHERE id = ? AND product_id = ?")
+            .bind(variant_id)
+            .bind(&product_id)
+            .fetch_optional(&mut *tx)
+            .await,
+        None => sqlx::query("SELECT stock, reserved FROM products WHERE id = ?")
+            .bind(&product_id)
+            .fetch_optional(&mut *tx)
+            .await,
+    };
+
+    let (stock, reserved): (i64, i64) = match current {
+        Ok(Some(row)) => (row.get("stock"), row.get("reserved")),
+        Ok(None) => {
+            return HttpResponse::NotFound().json(json!({
+                "success": false,
+                "error": if adjustment.variant_id.is_some() { "Variant not found" } else { "Product not found" }
+            }));
+        },
+        Err(e) => {
+            error!("Failed to fetch stock for product {}: {}", product_id, e);
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Database error"
+            }));
+        }
+    };
+
+    let delta = match (adjustment.set_to, adjustment.delta) {
+        (Some(target), _) => target - stock,
+        (None, Some(delta)) => delta,
+        (None, None) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": "Either delta or set_to must be provided"
+            }));
+        }
+    };
+
+    let new_stock = stock + delta;
+    if new_stock < reserved {
+        return HttpResponse::Conflict().json(json!({
+            "success": false,
+            "error": format!(
+                "Stock cannot drop below the {} unit(s) currently reserved by pending orders",
+                reserved
+            )
+        }));
+    }
+
+    let now = Utc::now().timestamp();
+
+    let result = async {
+        match &adjustment.variant_id {
+            Some(variant_id) => sqlx::query("UPDATE product_variants SET stock = ? WHERE id = ?")
+                .bind(new_stock)
+                .bind(variant_id)
+                .execute(&mut *tx)
+                .await?,
+            None => sqlx::query("UPDATE products SET stock = ? WHERE id = ?")
+                .bind(new_stock)
+                .bind(&product_id)
+                .execute(&mut *tx)
+                .await?,
+        };
+
+        sqlx::query(
+            "INSERT INTO stock_adjustments (product_id, variant_id, delta, reason, actor, order_id, created_at)
+             VALUES (?, ?, ?, ?, ?, NULL, ?)"
+        )
+        .bind(&product_id)
+        .bind(&adjustment.variant_id)
+        .bind(delta)
+        .bind(adjustment.reason.trim())
+        .bind(&auth.claims.sub)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => {
+            info!("Stock for product {} adjusted by {} ({} -> {}) by {}",
+                  product_id, delta, stock, new_stock, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "stock": {
+                    "product_id": product_id,
+                    "variant_id": adjustment.variant_id,
+                    "stock": new_stock,
+                    "reserved": reserved,
+                    "available": new_stock - reserved
+                }
+            }))
+        },
+        Err(e) => {
+            error!("Failed to adjust stock for product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to adjust stock"
+            }))
+        }
+    }
+}
+
+/// Commit reservations for orders whose Monero payment has been confirmed.
+pub async fn commit_confirmed_orders(pool: &SqlitePool, order_ids: &[String]) {
+    for order_id in order_ids {
+        match commit_order_reservations(pool, order_id).await {
+            Ok(0) => {},
+            Ok(n) => info!("Committed {} reservation(s) for paid order {}", n, order_id),
+            Err(e) => warn!("Failed to commit reservations for order {}: {}", order_id, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    const SCHEMA: [&str; 4] = [
+        "CREATE TABLE products (id TEXT PRIMARY KEY, stock INTEGER NOT NULL CHECK (stock >= 0), reserved INTEGER NOT NULL DEFAULT 0 CHECK (reserved >= 0 AND reserved <= stock), archived_at INTEGER)",
+        "CREATE TABLE product_variants (id TEXT PRIMARY KEY, product_id TEXT NOT NULL, available BOOLEAN NOT NULL DEFAULT TRUE, stock INTEGER NOT NULL CHECK (stock >= 0), reserved INTEGER NOT NULL DEFAULT 0 CHECK (reserved >= 0 AND reserved <= stock))",
+        "CREATE TABLE stock_reservations (id INTEGER PRIMARY KEY AUTOINCREMENT, order_id TEXT NOT NULL, product_id TEXT NOT NULL, variant_id TEXT, quantity INTEGER NOT NULL, status TEXT NOT NULL, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL)",
+        "CREATE TABLE stock_adjustments (id INTEGER PRIMARY KEY AUTOINCREMENT, product_id TEXT NOT NULL, variant_id TEXT, delta INTEGER NOT NULL, reason TEXT NOT NULL, actor TEXT NOT NULL, order_id TEXT, created_at INTEGER NOT NULL)",
+    ];
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in SCHEMA.into_iter().chain([
+            "INSERT INTO products (id, stock) VALUES ('plain', 5), ('shirt', 0)",
+            "INSERT INTO products (id, stock, archived_at) VALUES ('old', 5, 1)",
+            "INSERT INTO product_variants (id, product_id, stock) VALUES ('shirt-m', 'shirt', 3)",
+            "INSERT INTO product_variants (id, product_id, stock, available) VALUES ('shirt-l', 'shirt', 3, FALSE)",
+        ]) {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    async fn reserve(db: &SqlitePool, order_id: &str, product_id: &str, variant_id: Option<&str>, quantity: i64) -> Result<(), StockError> {
+        let mut tx = db.begin().await.unwrap();
+        let result = reserve_stock(&mut tx, order_id, product_id, variant_id, quantity).await;
+        tx.commit().await.unwrap();
+        result
+    }
+
+    // (stock, reserved) of a product or variant row
+    async fn levels(db: &SqlitePool, table: &str, id: &str) -> (i64, i64) {
+        let row = sqlx::query(&format!("SELECT stock, reserved FROM {} WHERE id = ?", table))
+            .bind(id)
+            .fetch_one(db)
+            .await
+            .unwrap();
+        (row.get("stock"), row.get("reserved"))
+    }
+
+    #[actix_web::test]
+    async fn reservations_never_oversell() {
+        let db = database().await;
+
+        reserve(&db, "ORD-1", "plain", None, 3).await.unwrap();
+        assert!(matches!(
+            reserve(&db, "ORD-2", "plain", None, 3).await,
+            Err(StockError::Insufficient { requested: 3, available: 2, .. })
+        ));
+        reserve(&db, "ORD-2", "plain", None, 2).await.unwrap();
+        assert_eq!(levels(&db, "products", "plain").await, (5, 5));
+
+        assert!(matches!(reserve(&db, "ORD-3", "plain", None, 0).await, Err(StockError::Insufficient { .. })));
+        assert!(matches!(
+            reserve(&db, "ORD-3", "old", None, 1).await,
+            Err(StockError::Insufficient { available: 0, .. })
+        ));
+    }
+
+    #[actix_web::test]
+    async fn variant_lines_draw_on_the_variant() {
+        let db = database().await;
+
+        reserve(&db, "ORD-1", "shirt", Some("shirt-m"), 2).await.unwrap();
+        assert_eq!(levels(&db, "product_variants", "shirt-m").await, (3, 2));
+        assert_eq!(levels(&db, "products", "shirt").await, (0, 0));
+
+        assert!(reserve(&db, "ORD-2", "shirt", Some("shirt-m"), 2).await.is_err());
+        assert!(reserve(&db, "ORD-2", "shirt", Some("shirt-l"), 1).await.is_err(), "unavailable variant");
+        assert!(reserve(&db, "ORD-2", "plain", Some("shirt-m"), 1).await.is_err(), "another product's variant");
+    }
+
+    #[actix_web::test]
+    async fn commit_and_release_settle_once() {
+        let db = database().await;
+        reserve(&db, "ORD-paid", "plain", None, 2).await.unwrap();
+        reserve(&db, "ORD-paid", "shirt", Some("shirt-m"), 1).await.unwrap();
+        reserve(&db, "ORD-gone", "plain", None, 1).await.unwrap();
+
+        assert_eq!(commit_order_reservations(&db, "ORD-paid").await.unwrap(), 2);
+        assert_eq!(commit_order_reservations(&db, "ORD-paid").await.unwrap(), 0);
+        assert_eq!(levels(&db, "products", "plain").await, (3, 1));
+        assert_eq!(levels(&db, "product_variants", "shirt-m").await, (2, 0));
+        let logged: i64 = sqlx::query("SELECT SUM(delta) AS n FROM stock_adjustments WHERE order_id = 'ORD-paid'")
+            .fetch_one(&db)
+            .await
+            .unwrap()
+            .get("n");
+        assert_eq!(logged, -3);
+
+        assert_eq!(release_order_reservations(&db, "ORD-gone").await.unwrap(), 1);
+        assert_eq!(release_order_reservations(&db, "ORD-gone").await.unwrap(), 0);
+        // Committed reservations can't be released afterwards
+        assert_eq!(release_order_reservations(&db, "ORD-paid").await.unwrap(), 0);
+        assert_eq!(levels(&db, "products", "plain").await, (3, 0));
+    }
+
+    #[actix_web::test]
+    async fn parallel_checkouts_share_the_last_units() {
+        // A database file, so several connections can race on it
+        let path = std::env::temp_dir().join(format!("inventory-{}.db", uuid::Uuid::new_v4()));
+        let db = SqlitePoolOptions::new()
+            .max_connections(8)
+            .connect_with(SqliteConnectOptions::new().filename(&path).create_if_missing(true))
+            .await
+            .unwrap();
+        for sql in SCHEMA.into_iter().chain(["INSERT INTO products (id, stock) VALUES ('plain', 5)"]) {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+
+        let tasks: Vec<_> = (0..20).map(|i| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                let mut tx = db.begin().await.unwrap();
+                let result = reserve_stock(&mut tx, &format!("ORD-{}", i), "plain", None, 1).await;
+                tx.commit().await.unwrap();
+                result.is_ok()
+            })
+        }).collect();
+        let mut reserved = 0;
+        for task in tasks {
+            reserved += task.await.unwrap() as i64;
+        }
+
+        assert_eq!(reserved, 5);
+        assert_eq!(levels(&db, "products", "plain").await, (5, 5));
+        db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+}
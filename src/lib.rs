@@ -8,6 +8,12 @@ pub struct AppState {
     pub monero_payments: MoneroPaymentManager,
 }
 
+impl AppState {
+    // Websocket clients only connect to the server binary, so there is no
+    // one to tell here
+    pub fn notify_order_cancelled(&self, _order_id: &str, _reason: &str) {}
+}
+
 // Update the get_db_path function to point to the data directory
 pub fn get_db_path() -> String {
     "data/secure_store.db".to_string()
@@ -15,13 +21,32 @@ pub fn get_db_path() -> String {
 
 pub mod admin;
 pub mod auth;
+pub mod catalog_io;
+pub mod categories;
+pub mod coupons;
+pub mod shipping;
+pub mod shipments;
+pub mod tax;
+pub mod digital;
+pub mod images;
+pub mod inventory;
 pub mod middleware;
 pub mod monero;
 pub mod monero_api;
+pub mod monero_admin;
 pub mod orders;
+pub mod rbac;
 pub mod products;
+pub mod search;
 pub mod types;
+pub mod variants;
 pub mod session;
+pub mod passwords;
+pub mod login_guard;
+pub mod two_factor;
+pub mod pgp;
+pub mod account;
+pub mod storage;
 pub mod monero_wallet;
 
 // Re-export types for easier access
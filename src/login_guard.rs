@@ -0,0 +1,518 @@
+//! Brute-force protection for `/auth/login`.
+//!
+//! Failed logins are counted per username and per client address. After a
+//! few free attempts each further failure blocks that username or address
+//! for twice as long as the one before; enough failures on one username lock
+//! it until the lockout expires or an admin unlocks it. Usernames that don't
+//! exist are counted the same way, so the responses don't reveal which
+//! accounts exist. Every attempt is written to `login_events`.
+
+use actix_web::{web, HttpResponse, Responder, get, post};
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Row, SqliteConnection, SqlitePool};
+use crate::AppState;
+use crate::rbac::{perm, Authorized};
+
+/// Failures per username before backoff starts
+pub const ACCOUNT_FREE_ATTEMPTS: i64 = 3;
+/// Failures per username that lock it
+pub const ACCOUNT_LOCKOUT_THRESHOLD: i64 = 10;
+/// How long a locked username stays locked
+pub const LOCKOUT_SECONDS: i64 = 30 * 60;
+/// Failures per address before backoff starts; one address may serve many people
+pub const IP_FREE_ATTEMPTS: i64 = 20;
+/// First backoff delay; it doubles with every further failure
+pub const BACKOFF_BASE_SECONDS: i64 = 1;
+/// Longest backoff delay
+pub const BACKOFF_MAX_SECONDS: i64 = 15 * 60;
+/// Failures older than this are forgotten
+pub const FAILURE_WINDOW_SECONDS: i64 = 24 * 60 * 60;
+
+const ACCOUNT: &str = "account";
+const IP: &str = "ip";
+
+/// What happened on a login attempt, as stored in `login_events.event`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginEvent {
+    Success,
+    Failure,
+    /// Right password on an account with 2FA; the code is still to come
+    SecondFactorPending,
+    /// Refused without checking the password, during backoff or lockout
+    Blocked,
+    Locked,
+    Unlocked,
+}
+
+impl LoginEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginEvent::Success => "success",
+            LoginEvent::Failure => "failure",
+            LoginEvent::SecondFactorPending => "2fa_pending",
+            LoginEvent::Blocked => "blocked",
+            LoginEvent::Locked => "locked",
+            LoginEvent::Unlocked => "unlocked",
+        }
+    }
+}
+
+/// Where a login attempt came from
+pub struct Attempt<'a> {
+    pub username: &'a str,
+    pub ip: &'a str,
+    pub device: &'a str,
+}
+
+/// A login attempt refused before the password was checked
+#[derive(Debug)]
+pub struct Blocked {
+    /// Seconds until another attempt is allowed
+    pub retry_after: i64,
+    /// The username is locked rather than backing off
+    pub locked: bool,
+}
+
+impl Blocked {
+    pub fn to_response(&self) -> HttpResponse {
+        let message = if self.locked {
+            "Too many failed logins; this account is temporarily locked"
+        } else {
+            "Too many failed logins; try again later"
+        };
+        HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", self.retry_after.to_string()))
+            .json(json!({
+                "success": false,
+                "error": message,
+                "retry_after": self.retry_after
+            }))
+    }
+}
+
+/// Delay after the `failures`th failure, or 0 while attempts are still free
+fn backoff_seconds(failures: i64, free_attempts: i64) -> i64 {
+    if failures < free_attempts {
+        return 0;
+    }
+    let doublings = (failures - free_attempts).min(30) as u32;
+    (BACKOFF_BASE_SECONDS << doublings).min(BACKOFF_MAX_SECONDS)
+}
+
+/// Write an entry to the audit table. Failing to write it is logged, not fatal.
+pub async fn record_event(
+    pool: &SqlitePool,
+    attempt: &Attempt<'_>,
+    user_id: Option<&str>,
+    event: LoginEvent,
+    detail: Option<&str>,
+) {
+    let result = sqlx::query(
+        "INSERT INTO login_events (user_id, username, ip, device, event, detail, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(user_id)
+    .bind(attempt.username)
+    .bind(attempt.ip)
+    .bind(attempt.device)
+    .bind(event.as_str())
+    .bind(detail)
+    .bind(Utc::now().timestamp())
+    .execute(pool)
+    .await;
+    if let Err(e) = result {
+        error!("Failed to record login event for {}: {}", attempt.username, e);
+    }
+}
+
+/// Refuse the attempt if its username or address is backing off or locked.
+pub async fn check(pool: &SqlitePool, attempt: &Attempt<'_>) -> Result<Option<Blocked>, sqlx::Error> {
+    let now = Utc::now().timestamp();
+    let rows = sqlx::query(
+        "SELECT scope, blocked_until, locked FROM login_failures
+         WHERE ((scope = ? AND key = ?) OR (scope = ? AND key = ?)) AND blocked_until > ?"
+    )
+    .bind(ACCOUNT)
+    .bind(attempt.username)
+    .bind(IP)
+    .bind(attempt.ip)
+    .bind(now)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter()
+        .map(|row| Blocked {
+            retry_after: row.get::<i64, _>("blocked_until") - now,
+            locked: row.get::<bool, _>("locked"),
+        })
+        .max_by_key(|blocked| blocked.retry_after))
+}
+
+/// Count a failure against one username or address and return the new count.
+/// The count is bumped in a single statement so parallel attempts can't
+/// overwrite each other's failures.
+async fn add_failure(pool: &SqlitePool, scope: &str, key: &str, free_attempts: i64, lockout_threshold: Option<i64>)
+    -> Result<(i64, bool), sqlx::Error>
+{
+    let now = Utc::now().timestamp();
+    let failures: i64 = sqlx::query(
+        "INSERT INTO login_failures (scope, key, failures, last_failure_at, blocked_until, locked)
+         VALUES (?, ?, 1, ?, 0, FALSE)
+         ON CONFLICT(scope, key) DO UPDATE SET
+            failures = CASE WHEN excluded.last_failure_at - last_failure_at < ? THEN failures + 1 ELSE 1 END,
+            last_failure_at = excluded.last_failure_at
+         RETURNING failures"
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(now)
+    .bind(FAILURE_WINDOW_SECONDS)
+    .fetch_one(pool)
+    .await?
+    .get("failures");
+
+    let locked = lockout_threshold.is_some_and(|threshold| failures >= threshold);
+    let blocked_until = if locked {
+        now + LOCKOUT_SECONDS
+    } else {
+        now + backoff_seconds(failures, free_attempts)
+    };
+
+    // A slower attempt with a lower count must not shorten a longer block
+    sqlx::query(
+        "UPDATE login_failures SET
+            locked = (? OR (locked AND blocked_until > ?)),
+            blocked_until = MAX(blocked_until, ?)
+         WHERE scope = ? AND key = ?"
+    )
+    .bind(locked)
+    .bind(now)
+    .bind(blocked_until)
+    .bind(scope)
+    .bind(key)
+    .execute(pool)
+    .await?;
+    Ok((failures, locked))
+}
+
+/// Record a wrong password, or a username that doesn't exist.
+pub async fn record_failure(pool: &SqlitePool, attempt: &Attempt<'_>, user_id: Option<&str>) -> Result<(), sqlx::Error> {
+    let (failures, locked) = add_failure(
+        pool, ACCOUNT, attempt.username, ACCOUNT_FREE_ATTEMPTS, Some(ACCOUNT_LOCKOUT_THRESHOLD),
+    ).await?;
+    add_failure(pool, IP, attempt.ip, IP_FREE_ATTEMPTS, None).await?;
+
+    record_event(pool, attempt, user_id, LoginEvent::Failure, None).await;
+    if locked {
+        warn!("Username {} locked after {} failed logins", attempt.username, failures);
+        record_event(pool, attempt, user_id, LoginEvent::Locked, Some(&format!("{} failed logins", failures))).await;
+    }
+    Ok(())
+}
+
+/// Record a successful login; the username's failures are forgotten. The
+/// address keeps its count, so signing in to one account doesn't reset
+/// guessing at others.
+pub async fn record_success(pool: &SqlitePool, attempt: &Attempt<'_>, user_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM login_failures WHERE scope = ? AND key = ?")
+        .bind(ACCOUNT)
+        .bind(attempt.username)
+        .execute(pool)
+        .await?;
+    record_event(pool, attempt, Some(user_id), LoginEvent::Success, None).await;
+    Ok(())
+}
+
+/// Clear a username's failures and lockout. Returns false if there were none.
+pub async fn unlock(pool: &SqlitePool, username: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM login_failures WHERE scope = ? AND key = ?")
+        .bind(ACCOUNT)
+        .bind(username)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Forget a deleted account: its audit entries keep the event but lose the
+/// account and username, and the username's failure count is dropped.
+/// Usernames are matched case-insensitively, as typed at login.
+pub async fn delete_user_records(conn: &mut SqliteConnection, user_id: &str, username: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE login_events SET user_id = NULL, username = 'deleted user'
+         WHERE user_id = ? OR username = ? COLLATE NOCASE"
+    )
+    .bind(user_id)
+    .bind(username)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query("DELETE FROM login_failures WHERE scope = ? AND key = ? COLLATE NOCASE")
+        .bind(ACCOUNT)
+        .bind(username)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+// Admin: lift a lockout or backoff on a user's account
+#[post("/users/{id}/unlock")]
+pub async fn unlock_user(
+    auth: Authorized<perm::UsersManage>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    let username: String = match sqlx::query("SELECT username FROM users WHERE id = ?")
+        .bind(&user_id)
+        .fetch_optional(&app_state.db)
+        .await {
+        Ok(Some(row)) => row.get("username"),
+        Ok(None) => return HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": "User not found"
+        })),
+        Err(e) => {
+            error!("Failed to look up user {}: {}", user_id, e);
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to unlock user"
+            }));
+        }
+    };
+
+    match unlock(&app_state.db, &username).await {
+        Ok(was_blocked) => {
+            let attempt = Attempt { username: &username, ip: "", device: "" };
+            let detail = format!("by {}", auth.claims.username);
+            record_event(&app_state.db, &attempt, Some(&user_id), LoginEvent::Unlocked, Some(&detail)).await;
+            info!("User {} unlocked by {}", user_id, auth.claims.username);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "was_blocked": was_blocked
+            }))
+        },
+        Err(e) => {
+            error!("Failed to unlock user {}: {}", user_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to unlock user"
+            }))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginEventQuery {
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub event: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginEventRecord {
+    pub id: i64,
+    pub user_id: Option<String>,
+    pub username: String,
+    pub ip: String,
+    pub device: String,
+    pub event: String,
+    pub detail: Option<String>,
+    pub created_at: i64,
+}
+
+// Admin: the login audit trail, newest first
+#[get("/login-events")]
+pub async fn list_login_events(
+    _auth: Authorized<perm::UsersManage>,
+    query: web::Query<LoginEventQuery>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    let rows = sqlx::query(
+        "SELECT id, user_id, username, ip, device, event, detail, created_at FROM login_events
+         WHERE (? IS NULL OR username = ?) AND (? IS NULL OR ip = ?) AND (? IS NULL OR event = ?)
+         ORDER BY id DESC LIMIT ?"
+    )
+    .bind(&query.username)
+    .bind(&query.username)
+    .bind(&query.ip)
+    .bind(&query.ip)
+    .bind(&query.event)
+    .bind(&query.event)
+    .bind(limit)
+    .fetch_all(&app_state.db)
+    .await;
+
+    match rows {
+        Ok(rows) => {
+            let events: Vec<LoginEventRecord> = rows.iter().map(|row| LoginEventRecord {
+                id: row.get("id"),
+                user_id: row.get("user_id"),
+                username: row.get("username"),
+                ip: row.get("ip"),
+                device: row.get("device"),
+                event: row.get("event"),
+                detail: row.get("detail"),
+                created_at: row.get("created_at"),
+            }).collect();
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "events": events
+            }))
+        },
+        Err(e) => {
+            error!("Failed to list login events: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to list login events"
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    // A database file, so several connections can race on it
+    async fn database(connections: u32) -> (SqlitePool, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("login-guard-{}.db", uuid::Uuid::new_v4()));
+        let db = SqlitePoolOptions::new()
+            .max_connections(connections)
+            .connect_with(SqliteConnectOptions::new().filename(&path).create_if_missing(true))
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE login_failures (scope TEXT NOT NULL, key TEXT NOT NULL, failures INTEGER NOT NULL, last_failure_at INTEGER NOT NULL, blocked_until INTEGER NOT NULL, locked BOOLEAN NOT NULL DEFAULT FALSE, PRIMARY KEY (scope, key))",
+            "CREATE TABLE login_events (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id TEXT, username TEXT NOT NULL, ip TEXT NOT NULL, device TEXT NOT NULL, event TEXT NOT NULL, detail TEXT, created_at INTEGER NOT NULL)",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        (db, path)
+    }
+
+    async fn cleanup(db: SqlitePool, path: std::path::PathBuf) {
+        db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    const ATTEMPT: Attempt<'static> = Attempt { username: "alice", ip: "10.0.0.1", device: "test" };
+
+    async fn failures(db: &SqlitePool, scope: &str, key: &str) -> i64 {
+        sqlx::query("SELECT failures FROM login_failures WHERE scope = ? AND key = ?")
+            .bind(scope)
+            .bind(key)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+            .map_or(0, |row| row.get("failures"))
+    }
+
+    #[test]
+    fn backoff_doubles_after_the_free_attempts() {
+        assert_eq!(backoff_seconds(2, 3), 0);
+        assert_eq!(backoff_seconds(3, 3), BACKOFF_BASE_SECONDS);
+        assert_eq!(backoff_seconds(5, 3), BACKOFF_BASE_SECONDS * 4);
+        assert_eq!(backoff_seconds(1000, 3), BACKOFF_MAX_SECONDS);
+    }
+
+    #[actix_web::test]
+    async fn failures_back_off_then_lock() {
+        let (db, path) = database(1).await;
+
+        for _ in 1..ACCOUNT_FREE_ATTEMPTS {
+            record_failure(&db, &ATTEMPT, None).await.unwrap();
+        }
+        assert!(check(&db, &ATTEMPT).await.unwrap().is_none());
+
+        // The first backoff is a single second, which `check` can miss if
+        // the clock ticks, so look at the stored block instead
+        record_failure(&db, &ATTEMPT, None).await.unwrap();
+        let row = sqlx::query("SELECT last_failure_at, blocked_until, locked FROM login_failures WHERE scope = ? AND key = ?")
+            .bind(ACCOUNT)
+            .bind("alice")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert!(!row.get::<bool, _>("locked"));
+        assert_eq!(
+            row.get::<i64, _>("blocked_until"),
+            row.get::<i64, _>("last_failure_at") + BACKOFF_BASE_SECONDS
+        );
+
+        for _ in ACCOUNT_FREE_ATTEMPTS..ACCOUNT_LOCKOUT_THRESHOLD {
+            record_failure(&db, &ATTEMPT, None).await.unwrap();
+        }
+        let blocked = check(&db, &ATTEMPT).await.unwrap().unwrap();
+        assert!(blocked.locked);
+        assert!(blocked.retry_after > LOCKOUT_SECONDS - 5);
+
+        // Another address only sees the username's lock
+        let elsewhere = Attempt { ip: "10.0.0.2", ..ATTEMPT };
+        assert!(check(&db, &elsewhere).await.unwrap().unwrap().locked);
+        let someone_else = Attempt { username: "bob", ..elsewhere };
+        assert!(check(&db, &someone_else).await.unwrap().is_none());
+
+        assert!(unlock(&db, "alice").await.unwrap());
+        assert!(check(&db, &elsewhere).await.unwrap().is_none());
+        cleanup(db, path).await;
+    }
+
+    #[actix_web::test]
+    async fn success_clears_the_username_but_not_the_address() {
+        let (db, path) = database(1).await;
+        for _ in 0..ACCOUNT_FREE_ATTEMPTS {
+            record_failure(&db, &ATTEMPT, Some("u1")).await.unwrap();
+        }
+        record_success(&db, &ATTEMPT, "u1").await.unwrap();
+
+        assert_eq!(failures(&db, ACCOUNT, "alice").await, 0);
+        assert_eq!(failures(&db, IP, "10.0.0.1").await, ACCOUNT_FREE_ATTEMPTS);
+        cleanup(db, path).await;
+    }
+
+    #[actix_web::test]
+    async fn parallel_failures_are_all_counted() {
+        let (db, path) = database(8).await;
+        let attempts = ACCOUNT_LOCKOUT_THRESHOLD * 2;
+
+        let tasks: Vec<_> = (0..attempts)
+            .map(|_| {
+                let db = db.clone();
+                tokio::spawn(async move { record_failure(&db, &ATTEMPT, None).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        assert_eq!(failures(&db, ACCOUNT, "alice").await, attempts);
+        assert_eq!(failures(&db, IP, "10.0.0.1").await, attempts);
+        assert!(check(&db, &ATTEMPT).await.unwrap().unwrap().locked);
+        cleanup(db, path).await;
+    }
+
+    #[actix_web::test]
+    async fn deleting_a_user_scrubs_their_records() {
+        let (db, path) = database(1).await;
+        record_failure(&db, &Attempt { username: "Alice", ..ATTEMPT }, Some("u1")).await.unwrap();
+        record_event(&db, &ATTEMPT, Some("u1"), LoginEvent::Success, None).await;
+
+        let mut conn = db.acquire().await.unwrap();
+        delete_user_records(&mut conn, "u1", "alice").await.unwrap();
+        drop(conn);
+
+        assert_eq!(failures(&db, ACCOUNT, "Alice").await, 0);
+        let left: i64 = sqlx::query("SELECT COUNT(*) AS n FROM login_events WHERE user_id IS NOT NULL OR username <> 'deleted user'")
+            .fetch_one(&db)
+            .await
+            .unwrap()
+            .get("n");
+        assert_eq!(left, 0);
+        cleanup(db, path).await;
+    }
+}
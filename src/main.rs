@@ -10,6 +10,11 @@ mod chat;
 mod auto_purge;
 mod payment;
 mod session;
+mod passwords;
+mod login_guard;
+mod two_factor;
+mod pgp;
+mod account;
 mod products;
 mod db; // New database module
 mod setup_db;
@@ -20,6 +25,20 @@ mod monero_api;
 mod monero_admin;
 mod monero_wallet;
 mod db_reset;  // Add at the top with other mod declarations
+mod inventory;
+mod variants;
+mod categories;
+mod coupons;
+mod shipping;
+mod shipments;
+mod tax;
+mod search;
+mod storage;
+mod images;
+mod digital;
+mod catalog_io;
+mod rbac;
+mod cli;
 use secure_store::get_db_path;
 
 use actix_web::{web, App, HttpResponse, HttpServer, Responder, middleware::Logger};
@@ -49,6 +68,16 @@ pub struct AppState {
     pub ws_connections: Arc<Mutex<WebsocketConnections>>,
 }
 
+impl AppState {
+    // Tell websocket clients watching an order that it was cancelled
+    pub fn notify_order_cancelled(&self, order_id: &str, reason: &str) {
+        match self.ws_connections.lock() {
+            Ok(connections) => connections.notify_order_cancelled(order_id, reason),
+            Err(e) => log::error!("Websocket connection store is poisoned: {}", e),
+        }
+    }
+}
+
 impl Clone for AppState {
     fn clone(&self) -> Self {
         Self {
@@ -109,33 +138,6 @@ pub async fn checkout(cart_items: web::Json<Vec<CartItem>>) -> impl Responder {
     }))
 }
 
-#[post("/api/direct-checkout")]
-pub async fn direct_checkout(
-    app_state: web::Data<AppState>,
-    checkout_data: web::Json<serde_json::Value>,
-) -> impl Responder {
-    println!("Received direct checkout request: {:?}", checkout_data);
-    
-    // Generate a unique order ID
-    let order_id = Uuid::new_v4().to_string();
-    
-    // Extract total amount from checkout data or use a default
-    let total_amount = checkout_data.get("total")
-        .and_then(|v| v.as_f64())
-        .unwrap_or(10.0); // Default to 10.0 if total is not provided
-    
-    // Create Monero payment request
-    let payment = app_state.monero_payments.create_payment_usd(order_id.clone(), total_amount);
-    
-    // Return the checkout response
-    HttpResponse::Ok().json(serde_json::json!({
-        "success": true,
-        "order_id": order_id,
-        "payment": payment,
-        "message": "Please send Monero to the provided address"
-    }))
-}
-
 // Modify the existing setup_database_directly function
 async fn setup_database_directly(pool: &SqlitePool) -> Result<(), std::io::Error> {
     log::info!("Setting up database directly");
@@ -151,14 +153,39 @@ async fn setup_database_directly(pool: &SqlitePool) -> Result<(), std::io::Error
         }
     }
     
-    // Drop tables in reverse dependency order
+    // Drop tables in reverse dependency order. `users` is kept so accounts,
+    // including admins created with `secure_store admin bootstrap`, survive
+    // a restart.
     let tables = [
-        "order_items",     // Drop child tables first
+        "shipment_items",
+        "shipments",
+        "carriers",
+        "order_status_history",
+        "order_tax_lines",
+        "tax_rules",
+        "shipping_rate_tiers",
+        "shipping_methods",
+        "shipping_zone_countries",
+        "shipping_zones",
+        "coupon_redemptions",
+        "coupon_products",
+        "coupon_categories",
+        "coupons",
+        "download_grants",
+        "license_keys",
+        "digital_files",
+        "products_fts",
+        "stock_adjustments",
+        "stock_reservations",
+        "product_tags",
+        "product_images",
+        "order_items",
+        "product_variants",     // Drop child tables first
         "addresses",       // Add this line
         "orders",
         "monero_payments",
         "products",
-        "users",
+        "categories",
         "transactions"
     ];
 
@@ -192,7 +219,9 @@ async fn setup_database_directly(pool: &SqlitePool) -> Result<(), std::io::Error
             id TEXT PRIMARY KEY,
             user_id TEXT,
             payment_id TEXT UNIQUE,
-            status TEXT NOT NULL,
+            status TEXT NOT NULL CHECK (status IN (
+                'Pending', 'AwaitingPayment', 'Paid', 'Shipped', 'Delivered', 'Completed', 'Cancelled'
+            )),
             shipping_name TEXT NOT NULL,
             shipping_address TEXT NOT NULL,
             shipping_city TEXT NOT NULL,
@@ -201,6 +230,13 @@ async fn setup_database_directly(pool: &SqlitePool) -> Result<(), std::io::Error
             shipping_country TEXT NOT NULL,
             shipping_email TEXT NOT NULL,
             total_amount REAL NOT NULL,
+            coupon_code TEXT,
+            discount_amount REAL NOT NULL DEFAULT 0,
+            shipping_method_id TEXT,
+            shipping_method_name TEXT,
+            shipping_cost REAL NOT NULL DEFAULT 0,
+            tax_amount REAL NOT NULL DEFAULT 0,
+            prices_include_tax BOOLEAN NOT NULL DEFAULT FALSE,
             created_at INTEGER NOT NULL,
             updated_at INTEGER NOT NULL,
             FOREIGN KEY(payment_id) REFERENCES monero_payments(payment_id)
@@ -213,32 +249,162 @@ async fn setup_database_directly(pool: &SqlitePool) -> Result<(), std::io::Error
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             order_number TEXT NOT NULL,
             product_id TEXT NOT NULL,
+            variant_id TEXT,
+            sku TEXT,
+            variant_name TEXT,
+            variant_options TEXT,
             quantity INTEGER NOT NULL,
             price REAL NOT NULL,
+            fulfilled_at INTEGER,
             FOREIGN KEY (order_number) REFERENCES orders(id)
         )
         "#,
         
-        // 4. Create users table
+        // Signed-in devices and their refresh tokens (kept across restarts)
         r#"
-        CREATE TABLE users (
+        CREATE TABLE IF NOT EXISTS sessions (
             id TEXT PRIMARY KEY NOT NULL,
-            username TEXT UNIQUE NOT NULL,
-            password_hash TEXT NOT NULL,
-            role TEXT NOT NULL,
+            user_id TEXT NOT NULL REFERENCES users(id),
+            device TEXT NOT NULL,
+            ip TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            last_used_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            revoked_at INTEGER,
+            revoked_reason TEXT
+        )
+        "#,
+        
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_sessions_user ON sessions(user_id)
+        "#,
+        
+        r#"
+        CREATE TABLE IF NOT EXISTS refresh_tokens (
+            token_hash TEXT PRIMARY KEY NOT NULL,
+            session_id TEXT NOT NULL REFERENCES sessions(id),
+            created_at INTEGER NOT NULL,
+            used_at INTEGER
+        )
+        "#,
+        
+        // Failed logins per username and address, and the login audit trail
+        // (kept across restarts)
+        r#"
+        CREATE TABLE IF NOT EXISTS login_failures (
+            scope TEXT NOT NULL CHECK (scope IN ('account', 'ip')),
+            key TEXT NOT NULL,
+            failures INTEGER NOT NULL,
+            last_failure_at INTEGER NOT NULL,
+            blocked_until INTEGER NOT NULL,
+            locked BOOLEAN NOT NULL DEFAULT FALSE,
+            PRIMARY KEY (scope, key)
+        )
+        "#,
+        
+        r#"
+        CREATE TABLE IF NOT EXISTS login_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id TEXT,
+            username TEXT NOT NULL,
+            ip TEXT NOT NULL,
+            device TEXT NOT NULL,
+            event TEXT NOT NULL,
+            detail TEXT,
             created_at INTEGER NOT NULL
         )
         "#,
         
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_login_events_username ON login_events(username)
+        "#,
+        
+        // TOTP secrets and recovery codes (kept across restarts)
+        r#"
+        CREATE TABLE IF NOT EXISTS user_totp (
+            user_id TEXT PRIMARY KEY NOT NULL REFERENCES users(id),
+            secret TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            enabled_at INTEGER,
+            last_used_step INTEGER
+        )
+        "#,
+        
+        r#"
+        CREATE TABLE IF NOT EXISTS totp_recovery_codes (
+            user_id TEXT NOT NULL REFERENCES users(id),
+            code_hash TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            used_at INTEGER,
+            PRIMARY KEY (user_id, code_hash)
+        )
+        "#,
+
+        // OpenPGP keys, login/recovery challenges and pending key changes (kept across restarts)
+        r#"
+        CREATE TABLE IF NOT EXISTS user_pgp_keys (
+            user_id TEXT PRIMARY KEY NOT NULL REFERENCES users(id),
+            fingerprint TEXT NOT NULL,
+            public_key TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        )
+        "#,
+
+        // user_id is NULL for decoys issued to usernames without a key
+        r#"
+        CREATE TABLE IF NOT EXISTS pgp_challenges (
+            id TEXT PRIMARY KEY NOT NULL,
+            user_id TEXT REFERENCES users(id),
+            username TEXT NOT NULL,
+            purpose TEXT NOT NULL,
+            code_hash TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            used_at INTEGER
+        )
+        "#,
+
+        r#"
+        CREATE TABLE IF NOT EXISTS pgp_key_changes (
+            user_id TEXT PRIMARY KEY NOT NULL REFERENCES users(id),
+            fingerprint TEXT,
+            public_key TEXT,
+            statement TEXT NOT NULL,
+            expires_at INTEGER NOT NULL
+        )
+        "#,
+
+        // Hierarchical product categories
+        r#"
+        CREATE TABLE categories (
+            id TEXT PRIMARY KEY NOT NULL,
+            name TEXT NOT NULL,
+            slug TEXT UNIQUE NOT NULL,
+            parent_id TEXT,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (parent_id) REFERENCES categories(id)
+        )
+        "#,
+
         // 5. Create products table
         r#"
         CREATE TABLE products (
             id TEXT PRIMARY KEY NOT NULL,
+            sku TEXT UNIQUE,
             name TEXT NOT NULL,
             description TEXT NOT NULL,
             price REAL NOT NULL,
             available BOOLEAN NOT NULL DEFAULT TRUE,
-            created_at INTEGER NOT NULL
+            digital_delivery TEXT CHECK (digital_delivery IN ('download', 'license_key')),
+            weight_grams INTEGER NOT NULL DEFAULT 0 CHECK (weight_grams >= 0),
+            tax_class TEXT NOT NULL DEFAULT 'standard',
+            stock INTEGER NOT NULL DEFAULT 0 CHECK (stock >= 0),
+            reserved INTEGER NOT NULL DEFAULT 0 CHECK (reserved >= 0 AND reserved <= stock),
+            category_id TEXT,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            archived_at INTEGER,
+            FOREIGN KEY (category_id) REFERENCES categories(id)
         )
         "#,
         
@@ -278,10 +444,419 @@ async fn setup_database_directly(pool: &SqlitePool) -> Result<(), std::io::Error
         r#"
         CREATE INDEX idx_addresses_user_id ON addresses(user_id)
         "#,
+
+        // Sellable variations of a product (size, edition, ...)
+        r#"
+        CREATE TABLE product_variants (
+            id TEXT PRIMARY KEY NOT NULL,
+            product_id TEXT NOT NULL,
+            sku TEXT UNIQUE NOT NULL,
+            name TEXT NOT NULL,
+            options TEXT NOT NULL DEFAULT '{}',
+            price_override REAL CHECK (price_override IS NULL OR price_override >= 0),
+            stock INTEGER NOT NULL DEFAULT 0 CHECK (stock >= 0),
+            reserved INTEGER NOT NULL DEFAULT 0 CHECK (reserved >= 0 AND reserved <= stock),
+            available BOOLEAN NOT NULL DEFAULT TRUE,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (product_id) REFERENCES products(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_product_variants_product_id ON product_variants(product_id)
+        "#,
+
+        // Stock held for orders awaiting payment
+        r#"
+        CREATE TABLE stock_reservations (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            product_id TEXT NOT NULL,
+            variant_id TEXT,
+            quantity INTEGER NOT NULL CHECK (quantity > 0),
+            status TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY (order_id) REFERENCES orders(id),
+            FOREIGN KEY (product_id) REFERENCES products(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_stock_reservations_order_id ON stock_reservations(order_id)
+        "#,
+
+        // Audit trail of every change to on-hand stock
+        r#"
+        CREATE TABLE stock_adjustments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            product_id TEXT NOT NULL,
+            variant_id TEXT,
+            delta INTEGER NOT NULL,
+            reason TEXT NOT NULL,
+            actor TEXT NOT NULL,
+            order_id TEXT,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (product_id) REFERENCES products(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_stock_adjustments_product_id ON stock_adjustments(product_id)
+        "#,
+
+        // Product image galleries; files live in the configured image storage
+        r#"
+        CREATE TABLE product_images (
+            id TEXT PRIMARY KEY NOT NULL,
+            product_id TEXT NOT NULL,
+            position INTEGER NOT NULL,
+            content_type TEXT NOT NULL,
+            storage_key TEXT NOT NULL,
+            thumbnail_key TEXT NOT NULL,
+            url TEXT NOT NULL,
+            thumbnail_url TEXT NOT NULL,
+            width INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            size_bytes INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (product_id) REFERENCES products(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_product_images_product_id ON product_images(product_id, position)
+        "#,
+
+        // Free-form product tags, stored lower-case
+        r#"
+        CREATE TABLE product_tags (
+            product_id TEXT NOT NULL,
+            tag TEXT NOT NULL,
+            PRIMARY KEY (product_id, tag),
+            FOREIGN KEY (product_id) REFERENCES products(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_product_tags_tag ON product_tags(tag)
+        "#,
+
+        r#"
+        CREATE INDEX idx_products_category_id ON products(category_id)
+        "#,
+
+        // Files delivered for digital products; stored outside the public uploads
+        r#"
+        CREATE TABLE digital_files (
+            id TEXT PRIMARY KEY NOT NULL,
+            product_id TEXT NOT NULL,
+            file_name TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            storage_key TEXT NOT NULL,
+            size_bytes INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (product_id) REFERENCES products(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_digital_files_product_id ON digital_files(product_id)
+        "#,
+
+        // License key pool; a key belongs to an order item once assigned
+        r#"
+        CREATE TABLE license_keys (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            product_id TEXT NOT NULL,
+            license_key TEXT NOT NULL,
+            order_item_id INTEGER,
+            assigned_at INTEGER,
+            created_at INTEGER NOT NULL,
+            UNIQUE (product_id, license_key),
+            FOREIGN KEY (product_id) REFERENCES products(id),
+            FOREIGN KEY (order_item_id) REFERENCES order_items(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_license_keys_product_id ON license_keys(product_id, order_item_id)
+        "#,
+
+        // Per-file download allowance issued when a digital order item is fulfilled
+        r#"
+        CREATE TABLE download_grants (
+            id TEXT PRIMARY KEY NOT NULL,
+            order_item_id INTEGER NOT NULL,
+            file_id TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            max_downloads INTEGER NOT NULL CHECK (max_downloads > 0),
+            download_count INTEGER NOT NULL DEFAULT 0 CHECK (download_count >= 0),
+            last_downloaded_at INTEGER,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (order_item_id) REFERENCES order_items(id),
+            FOREIGN KEY (file_id) REFERENCES digital_files(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_download_grants_order_item_id ON download_grants(order_item_id)
+        "#,
+
+        // Discount codes; codes are stored upper-case
+        r#"
+        CREATE TABLE coupons (
+            id TEXT PRIMARY KEY NOT NULL,
+            code TEXT UNIQUE NOT NULL,
+            kind TEXT NOT NULL CHECK (kind IN ('percentage', 'fixed')),
+            value REAL NOT NULL CHECK (value > 0),
+            min_order_value REAL,
+            max_uses INTEGER,
+            max_uses_per_user INTEGER,
+            starts_at INTEGER,
+            expires_at INTEGER,
+            active BOOLEAN NOT NULL DEFAULT TRUE,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )
+        "#,
+
+        // Optional coupon scope: with no rows here the whole order is eligible
+        r#"
+        CREATE TABLE coupon_products (
+            coupon_id TEXT NOT NULL,
+            product_id TEXT NOT NULL,
+            PRIMARY KEY (coupon_id, product_id),
+            FOREIGN KEY (coupon_id) REFERENCES coupons(id),
+            FOREIGN KEY (product_id) REFERENCES products(id)
+        )
+        "#,
+
+        r#"
+        CREATE TABLE coupon_categories (
+            coupon_id TEXT NOT NULL,
+            category_id TEXT NOT NULL,
+            PRIMARY KEY (coupon_id, category_id),
+            FOREIGN KEY (coupon_id) REFERENCES coupons(id),
+            FOREIGN KEY (category_id) REFERENCES categories(id)
+        )
+        "#,
+
+        // One row per order a coupon was used on
+        r#"
+        CREATE TABLE coupon_redemptions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            coupon_id TEXT NOT NULL,
+            order_id TEXT UNIQUE NOT NULL,
+            user_id TEXT,
+            discount_amount REAL NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (coupon_id) REFERENCES coupons(id),
+            FOREIGN KEY (order_id) REFERENCES orders(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_coupon_redemptions_coupon_id ON coupon_redemptions(coupon_id, user_id)
+        "#,
+
+        // Shipping destinations; a country belongs to at most one zone and
+        // the '*' zone covers every country not listed elsewhere
+        r#"
+        CREATE TABLE shipping_zones (
+            id TEXT PRIMARY KEY NOT NULL,
+            name TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        )
+        "#,
+
+        r#"
+        CREATE TABLE shipping_zone_countries (
+            zone_id TEXT NOT NULL,
+            country_code TEXT UNIQUE NOT NULL,
+            FOREIGN KEY (zone_id) REFERENCES shipping_zones(id)
+        )
+        "#,
+
+        // `rate` prices flat methods; weight and price methods use tiers
+        r#"
+        CREATE TABLE shipping_methods (
+            id TEXT PRIMARY KEY NOT NULL,
+            zone_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            rate_type TEXT NOT NULL CHECK (rate_type IN ('flat', 'weight', 'price')),
+            rate REAL,
+            free_over REAL,
+            active BOOLEAN NOT NULL DEFAULT TRUE,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (zone_id) REFERENCES shipping_zones(id)
+        )
+        "#,
+
+        // Grams for weight methods, shipped-items subtotal for price methods
+        r#"
+        CREATE TABLE shipping_rate_tiers (
+            method_id TEXT NOT NULL,
+            min_value REAL NOT NULL CHECK (min_value >= 0),
+            rate REAL NOT NULL CHECK (rate >= 0),
+            PRIMARY KEY (method_id, min_value),
+            FOREIGN KEY (method_id) REFERENCES shipping_methods(id)
+        )
+        "#,
+
+        // Tax rates by destination and product tax class; `rate` is a
+        // percentage and a NULL region covers the whole country
+        r#"
+        CREATE TABLE tax_rules (
+            id TEXT PRIMARY KEY NOT NULL,
+            country TEXT NOT NULL,
+            region TEXT,
+            tax_class TEXT NOT NULL DEFAULT 'standard',
+            name TEXT NOT NULL,
+            rate REAL NOT NULL CHECK (rate >= 0 AND rate <= 100),
+            tax_shipping BOOLEAN NOT NULL DEFAULT FALSE,
+            created_at INTEGER NOT NULL
+        )
+        "#,
+
+        // Tax charged on an order, one row per rule. Rule details are copied
+        // so the lines stay correct for reporting after rules change.
+        r#"
+        CREATE TABLE order_tax_lines (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            rule_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            country TEXT NOT NULL,
+            region TEXT,
+            tax_class TEXT NOT NULL,
+            rate REAL NOT NULL,
+            taxable_amount REAL NOT NULL,
+            tax_amount REAL NOT NULL,
+            FOREIGN KEY (order_id) REFERENCES orders(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_order_tax_lines_order_id ON order_tax_lines(order_id)
+        "#,
+
+        // Every status an order has been in, with who moved it there and why.
+        // `from_status` is NULL for the status the order was created with.
+        r#"
+        CREATE TABLE order_status_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            from_status TEXT,
+            to_status TEXT NOT NULL,
+            actor TEXT NOT NULL,
+            reason TEXT,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (order_id) REFERENCES orders(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_order_status_history_order_id ON order_status_history(order_id, created_at)
+        "#,
+
+        // Carriers orders ship with; `{tracking_number}` in the template is
+        // replaced to build a shipment's tracking link
+        r#"
+        CREATE TABLE carriers (
+            id TEXT PRIMARY KEY,
+            code TEXT NOT NULL UNIQUE,
+            name TEXT NOT NULL,
+            tracking_url_template TEXT,
+            created_at INTEGER NOT NULL
+        )
+        "#,
+
+        // Parcels sent for an order. An order can ship in several parcels,
+        // each covering some quantity of its physical lines.
+        r#"
+        CREATE TABLE shipments (
+            id TEXT PRIMARY KEY,
+            order_id TEXT NOT NULL,
+            carrier_id TEXT,
+            carrier_name TEXT NOT NULL,
+            tracking_number TEXT NOT NULL,
+            shipped_at INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (order_id) REFERENCES orders(id),
+            FOREIGN KEY (carrier_id) REFERENCES carriers(id)
+        )
+        "#,
+
+        r#"
+        CREATE INDEX idx_shipments_order_id ON shipments(order_id)
+        "#,
+
+        r#"
+        CREATE TABLE shipment_items (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            shipment_id TEXT NOT NULL,
+            order_item_id INTEGER NOT NULL,
+            quantity INTEGER NOT NULL CHECK (quantity > 0),
+            FOREIGN KEY (shipment_id) REFERENCES shipments(id),
+            FOREIGN KEY (order_item_id) REFERENCES order_items(id)
+        )
+        "#,
+
+        // Full-text index over product name, description and tags. Kept in
+        // sync by the triggers below; `search::rebuild_index` repopulates it.
+        r#"
+        CREATE VIRTUAL TABLE products_fts USING fts5(
+            product_id UNINDEXED,
+            name,
+            description,
+            tags,
+            tokenize = 'unicode61 remove_diacritics 2'
+        )
+        "#,
+
+        r#"
+        CREATE TRIGGER products_fts_insert AFTER INSERT ON products BEGIN
+            INSERT INTO products_fts (product_id, name, description, tags)
+            VALUES (
+                new.id, new.name, new.description,
+                (SELECT GROUP_CONCAT(tag, ' ') FROM product_tags WHERE product_id = new.id)
+            );
+        END
+        "#,
+
+        r#"
+        CREATE TRIGGER products_fts_update AFTER UPDATE OF name, description ON products BEGIN
+            UPDATE products_fts SET name = new.name, description = new.description
+            WHERE product_id = new.id;
+        END
+        "#,
+
+        r#"
+        CREATE TRIGGER products_fts_delete AFTER DELETE ON products BEGIN
+            DELETE FROM products_fts WHERE product_id = old.id;
+        END
+        "#,
+
+        r#"
+        CREATE TRIGGER product_tags_fts_insert AFTER INSERT ON product_tags BEGIN
+            UPDATE products_fts
+            SET tags = (SELECT GROUP_CONCAT(tag, ' ') FROM product_tags WHERE product_id = new.product_id)
+            WHERE product_id = new.product_id;
+        END
+        "#,
+
+        r#"
+        CREATE TRIGGER product_tags_fts_delete AFTER DELETE ON product_tags BEGIN
+            UPDATE products_fts
+            SET tags = (SELECT GROUP_CONCAT(tag, ' ') FROM product_tags WHERE product_id = old.product_id)
+            WHERE product_id = old.product_id;
+        END
+        "#,
     ];
     
-    // Create tables in dependency order
-    for (i, sql) in create_tables.iter().enumerate() {
+    // Create tables in dependency order, accounts first
+    for (i, sql) in account::ACCOUNT_TABLES.iter().chain(create_tables.iter()).enumerate() {
         log::info!("Creating table #{}", i+1);
         match sqlx::query(sql).execute(pool).await {
             Ok(_) => log::info!("Successfully created table #{}", i+1),
@@ -309,8 +884,13 @@ async fn setup_database_directly(pool: &SqlitePool) -> Result<(), std::io::Error
     // Add sample data using dynamic SQL (bypassing compile-time checks)
     let now = chrono::Utc::now().timestamp();
     
+    if let Err(e) = rbac::seed_built_in_roles(pool).await {
+        log::error!("Failed to create built-in roles: {}", e);
+        return Err(std::io::Error::other(format!("Failed to create built-in roles: {}", e)));
+    }
+    
     // Add sample user
-    match sqlx::query("INSERT INTO users (id, username, password_hash, role, created_at) VALUES (?, ?, ?, ?, ?)")
+    match sqlx::query("INSERT OR IGNORE INTO users (id, username, password_hash, role, created_at) VALUES (?, ?, ?, ?, ?)")
         .bind("usr-user1")
         .bind("testuser")
         .bind("password123") // In a real app, this would be hashed
@@ -324,12 +904,15 @@ async fn setup_database_directly(pool: &SqlitePool) -> Result<(), std::io::Error
     }
     
     // Add sample product
-    match sqlx::query("INSERT INTO products (id, name, description, price, available, created_at) VALUES (?, ?, ?, ?, ?, ?)")
+    match sqlx::query("INSERT INTO products (id, sku, name, description, price, available, stock, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
         .bind("prod-1")
+        .bind("TEST-001")
         .bind("Test Product")
         .bind("A sample product for testing")
         .bind(99.99)
         .bind(true)
+        .bind(100)
+        .bind(now)
         .bind(now)
         .execute(pool)
         .await
@@ -403,6 +986,22 @@ async fn main() -> std::io::Result<()> {
     dotenv().ok();
     env_logger::init();
 
+    // Maintenance commands run instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args).await;
+    }
+
+    // Without signing keys no token could be issued or checked
+    if let Err(e) = session::load_keys_from_env() {
+        log::error!("{}", e);
+        return Err(std::io::Error::other(e));
+    }
+    if let Err(e) = passwords::load_config_from_env() {
+        log::error!("{}", e);
+        return Err(std::io::Error::other(e));
+    }
+
     // Update the database connection string to use the correct path
     let db_url = format!("sqlite:{}", get_db_path());
     let pool = SqlitePool::connect(&db_url)
@@ -420,6 +1019,14 @@ async fn main() -> std::io::Result<()> {
     }
     log::info!("Direct database setup completed successfully");
 
+    // Connections that lived through the drop/recreate can keep a stale view
+    // of the schema (seen as "no such table" when triggers fire), so serve
+    // requests from a fresh pool.
+    pool.close().await;
+    let pool = SqlitePool::connect(&db_url)
+        .await
+        .expect("Failed to create pool");
+
     // Initialize chat history
     let chat_history = Arc::new(Mutex::new(Vec::new()));
     
@@ -453,6 +1060,21 @@ async fn main() -> std::io::Result<()> {
         monero_api::start_payment_checker(app_state_clone);
     });
 
+    // Product images are stored on local disk and served under /uploads
+    let image_storage = storage::local_storage_from_env();
+    if let Err(e) = std::fs::create_dir_all(image_storage.root()) {
+        log::error!("Failed to create upload directory {:?}: {}", image_storage.root(), e);
+    }
+    let upload_dir = image_storage.root().to_path_buf();
+    let image_storage: web::Data<dyn storage::ImageStorage> = web::Data::from(image_storage as Arc<dyn storage::ImageStorage>);
+
+    // Digital product files live in a private directory, reachable only
+    // through signed download links
+    let digital_files = web::Data::new(digital::DigitalFiles(storage::digital_storage_from_env()));
+    if let Err(e) = std::fs::create_dir_all(digital_files.0.root()) {
+        log::error!("Failed to create digital files directory {:?}: {}", digital_files.0.root(), e);
+    }
+
     // Add the waiting delay before starting the server
     log::info!("Server starting, waiting for all components to initialize...");
     tokio::time::sleep(std::time::Duration::from_millis(500)).await;
@@ -471,8 +1093,9 @@ async fn main() -> std::io::Result<()> {
             .wrap(Logger::default())
             .wrap(cors)
             .app_data(app_state.clone())
+            .app_data(image_storage.clone())
+            .app_data(digital_files.clone())
             .configure(orders::init_orders_routes)
-            .service(direct_checkout)
             .service(cart::checkout)
             .route("/", web::get().to(index))
             .route("/health", web::get().to(health_check))
@@ -480,12 +1103,19 @@ async fn main() -> std::io::Result<()> {
             .service(auth::init_routes())
             // Admin routes
             .service(admin::init_routes())
+            .route("/categories", web::get().to(categories::list_categories))
+            .route("/coupons/quote", web::post().to(coupons::quote_coupon))
+            .route("/shipping/quote", web::post().to(shipping::quote_shipping))
+            .route("/tax/quote", web::post().to(tax::quote_tax))
             // Product routes
             .service(
                 web::scope("/products")
                     .route("", web::get().to(products::list_products))
                     .route("", web::post().to(products::add_product))
-                    .route("/purchase", web::post().to(products::purchase_product))
+                    .route("/catalog", web::get().to(products::browse_products))
+                    .route("/search", web::get().to(search::search_products))
+                    .route("/{id}/variants", web::get().to(variants::list_product_variants))
+                    .route("/{id}/images", web::get().to(images::list_product_images))
             )
             // Payment routes
             .service(
@@ -514,6 +1144,10 @@ async fn main() -> std::io::Result<()> {
                 web::resource("/ws/payment/{order_id}")
                     .route(web::get().to(payment_ws))
             )
+            // Signed download links for digital goods
+            .route("/downloads/{token}", web::get().to(digital::download_file))
+            // Uploaded product images
+            .service(actix_files::Files::new("/uploads", upload_dir.clone()))
             // Serve static files from the frontend dist directory
             .service(actix_files::Files::new("/", "./frontend/public").index_file("index.html"))
             // Handle all other routes by returning index.html (for client-side routing)
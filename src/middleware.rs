@@ -1,14 +1,16 @@
 use actix_web::{
     body::{EitherBody, MessageBody},
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    error::Error, HttpMessage, HttpResponse,
+    error::Error, HttpMessage,
 };
 use futures::future::{ok, Ready};
 use std::future::Future;
 use std::pin::Pin;
 use std::rc::Rc;
 use crate::session;
-use serde_json::json;
+use crate::auth::AuthError;
+use crate::rbac;
+use log::warn;
 
 // Define our middleware struct
 pub struct JwtAuthentication;
@@ -84,34 +86,34 @@ where
     }
 }
 
-// Admin authorization middleware
-// pub fn admin_required() -> AdminMiddleware { ... }
+// Staff authorization middleware. Wrap a scope with `StaffMiddleware` to
+// refuse every request from customers and anonymous callers with a 401/403
+// before routing; handlers inside still name the permission they need with
+// the `rbac::Authorized` extractor.
+pub struct StaffMiddleware;
 
-pub struct AdminMiddleware {}
-
-// Update the Transform implementation to return EitherBody responses
-impl<S, B> Transform<S, ServiceRequest> for AdminMiddleware
+impl<S, B> Transform<S, ServiceRequest> for StaffMiddleware
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     B: MessageBody + 'static,
 {
     type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
-    type Transform = AdminMiddlewareService<S>;
+    type Transform = StaffMiddlewareService<S>;
     type InitError = ();
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(AdminMiddlewareService { service })
+        ok(StaffMiddlewareService { service: Rc::new(service) })
     }
 }
 
-pub struct AdminMiddlewareService<S> {
-    service: S,
+pub struct StaffMiddlewareService<S> {
+    service: Rc<S>,
 }
 
 // Implement Service with EitherBody as the response body type
-impl<S, B> Service<ServiceRequest> for AdminMiddlewareService<S>
+impl<S, B> Service<ServiceRequest> for StaffMiddlewareService<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     B: MessageBody + 'static,
@@ -123,43 +125,31 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let auth_header = req.headers().get("Authorization");
-        
-        // Check if user is admin
-        let is_admin = auth_header
-            .and_then(|header| header.to_str().ok())
-            .and_then(|header_str| {
-                if header_str.starts_with("Bearer ") {
-                    Some(header_str[7..].to_string())
-                } else {
-                    None
-                }
-            })
-            .map(|token| token.contains("admin"))
-            .unwrap_or(false);
-
-        if is_admin {
-            // User is an admin, proceed with the request
-            let fut = self.service.call(req);
-            Box::pin(async move {
-                match fut.await {
-                    Ok(res) => Ok(res.map_into_left_body()),
-                    Err(err) => Err(err),
+        let srv = self.service.clone();
+
+        Box::pin(async move {
+            // The principal is cached on the request for the handler's extractor
+            let refusal = match rbac::principal(req.request()).await {
+                Ok(principal) if principal.is_staff() => None,
+                Ok(principal) => {
+                    warn!("User {} with role {} refused staff access to {}",
+                        principal.claims.sub, principal.claims.role, req.path());
+                    Some(AuthError::Forbidden("Staff access required".to_string()))
+                },
+                Err(e) => Some(e),
+            };
+
+            match refusal {
+                None => {
+                    let res = srv.call(req).await?;
+                    Ok(res.map_into_left_body())
+                },
+                Some(e) => {
+                    let response = e.to_response();
+                    let (req, _) = req.into_parts();
+                    Ok(ServiceResponse::new(req, response).map_into_right_body())
                 }
-            })
-        } else {
-            // User is not an admin, return forbidden
-            Box::pin(async move {
-                let response = HttpResponse::Forbidden()
-                    .json(json!({
-                        "error": "Admin access required",
-                        "message": "You don't have permission to access this resource"
-                    }));
-                
-                let (req, _) = req.into_parts();
-                // Using right body for the custom response
-                Ok(ServiceResponse::new(req, response).map_into_right_body())
-            })
-        }
+            }
+        })
     }
 }
@@ -152,7 +152,8 @@ impl MoneroPaymentStore {
     }
 
     // Non-async wrapper for backward compatibility
-    pub fn check_payments(&self) {
+    // Returns the payments that were confirmed by this check
+    pub fn check_payments(&self) -> Vec<MoneroPaymentRequest> {
         // In production, you'd use tokio::runtime::Runtime to properly handle async calls
         // This is a simplified approach for demonstration
         println!("Running payment check (non-async wrapper)");
@@ -160,17 +161,21 @@ impl MoneroPaymentStore {
         // Just fall back to the random confirmation logic for now
         let pending_payments = self.get_pending_payments();
         if pending_payments.is_empty() {
-            return;
+            return Vec::new();
         }
         
         println!("Found {} pending payments to check", pending_payments.len());
         
+        let mut confirmed = Vec::new();
         for payment in pending_payments {
             if rand::random::<f64>() < 0.3 {  // 30% chance of confirming for testing
                 println!("Payment {} confirmed!", payment.payment_id);
-                self.update_payment_status(&payment.payment_id, PaymentStatus::Confirmed);
+                if let Some(updated) = self.update_payment_status(&payment.payment_id, PaymentStatus::Confirmed) {
+                    confirmed.push(updated);
+                }
             }
         }
+        confirmed
     }
 
     // In a real implementation, this would convert USD to XMR
@@ -216,9 +221,11 @@ impl MoneroPaymentStore {
     }
 
     // Add method to expire old pending payments
-    pub fn expire_old_payments(&self) {
+    // Returns the payments that expired during this pass
+    pub fn expire_old_payments(&self) -> Vec<MoneroPaymentRequest> {
         let now = chrono::Utc::now().timestamp();
         let mut payments = self.payments.lock().unwrap();
+        let mut expired = Vec::new();
         
         for payment in payments.values_mut() {
             // If payment is more than 2 hours old and still pending, mark as expired
@@ -226,8 +233,10 @@ impl MoneroPaymentStore {
                 payment.status = PaymentStatus::Expired;
                 payment.updated_at = now;
                 println!("Payment {} expired", payment.payment_id);
+                expired.push(payment.clone());
             }
         }
+        expired
     }
 
     // Add this method to get user's payment history
@@ -1,7 +1,10 @@
 use actix_web::{web, HttpResponse, Responder, get, post};
 use serde::Serialize;
 use crate::AppState;
+use crate::middleware::StaffMiddleware;
+use crate::rbac::{perm, Authorized};
 use crate::monero::{PaymentStatus, MoneroPaymentRequest};
+use crate::orders;
 
 #[derive(Serialize)]
 pub struct AdminPaymentResponse {
@@ -10,12 +13,11 @@ pub struct AdminPaymentResponse {
     pub transactions: Option<Vec<MoneroPaymentRequest>>,
 }
 
-#[get("/admin/transactions")]
+#[get("/transactions")]
 pub async fn list_transactions(
+    _auth: Authorized<perm::OrdersRead>,
     app_state: web::Data<AppState>,
 ) -> impl Responder {
-    // PLACEHOLDER: In production, check admin permissions here
-    
     // Get all transactions from storage
     let transactions = app_state.monero_payments.get_all_payments();
     
@@ -26,18 +28,19 @@ pub async fn list_transactions(
     })
 }
 
-#[post("/admin/confirm/{payment_id}")]
+#[post("/confirm/{payment_id}")]
 pub async fn admin_confirm_payment(
+    auth: Authorized<perm::PaymentsConfirm>,
     app_state: web::Data<AppState>,
     path: web::Path<String>,
 ) -> impl Responder {
-    // PLACEHOLDER: In production, check admin permissions here
-    
     let payment_id = path.into_inner();
     
     if let Some(updated_payment) = app_state.monero_payments.update_payment_status(&payment_id, PaymentStatus::Confirmed) {
         // Log this admin action for audit purposes
-        println!("ADMIN ACTION: Manual payment confirmation for payment {}", payment_id);
+        println!("ADMIN ACTION: Manual payment confirmation for payment {} by {}", payment_id, auth.claims.username);
+        
+        orders::process_paid_orders(&app_state.db, std::slice::from_ref(&updated_payment.order_id)).await;
         
         HttpResponse::Ok().json(AdminPaymentResponse {
             success: true,
@@ -53,17 +56,22 @@ pub async fn admin_confirm_payment(
     }
 }
 
-#[post("/admin/refresh_wallet")]
+#[post("/refresh_wallet")]
 pub async fn refresh_wallet(
+    auth: Authorized<perm::PaymentsConfirm>,
     app_state: web::Data<AppState>,
 ) -> impl Responder {
     // PLACEHOLDER: In production, this would connect to your Monero wallet
     // and refresh/scan for new transactions
     
-    println!("ADMIN ACTION: Manual wallet refresh triggered");
+    println!("ADMIN ACTION: Manual wallet refresh triggered by {}", auth.claims.username);
     
     // For the mock implementation, just run the payment checker
-    app_state.monero_payments.check_payments();
+    let confirmed: Vec<String> = app_state.monero_payments.check_payments()
+        .into_iter()
+        .map(|p| p.order_id)
+        .collect();
+    orders::process_paid_orders(&app_state.db, &confirmed).await;
     
     HttpResponse::Ok().json(AdminPaymentResponse {
         success: true,
@@ -73,8 +81,11 @@ pub async fn refresh_wallet(
 }
 
 pub fn init_routes() -> actix_web::Scope {
-    web::scope("/monero")
-        .service(list_transactions)
-        .service(admin_confirm_payment)
-        .service(refresh_wallet)
+    web::scope("/monero").service(
+        web::scope("/admin")
+            .wrap(StaffMiddleware)
+            .service(list_transactions)
+            .service(admin_confirm_payment)
+            .service(refresh_wallet)
+    )
 } 
\ No newline at end of file
@@ -1,13 +1,16 @@
 use actix_web::{web, HttpResponse, Responder, get, post, HttpRequest};
 use serde::{Deserialize, Serialize};
 use crate::AppState;
+use crate::middleware::StaffMiddleware;
+use crate::rbac::{perm, Authorized};
 use crate::monero::{PaymentStatus, MoneroPaymentRequest};
 use serde_json::json;
 use rand;
 use log;
 use crate::orders::create_order;
 use crate::types::ShippingInfo;
-use crate::orders::OrderItem;
+use crate::orders::{self, OrderItem, OrderStatus, StatusChange};
+use crate::inventory::StockError;
 use sqlx::Row;
 
 #[derive(Deserialize)]
@@ -99,15 +102,19 @@ pub async fn check_payment(
     }
 }
 
-// Mock endpoint to simulate payment confirmation (for testing)
+// Mock endpoint to simulate payment confirmation (for testing); it marks
+// orders paid, so only staff who may confirm payments can use it
 #[post("/api/monero/mock_confirm/{payment_id}")]
 pub async fn mock_confirm_payment(
+    _auth: Authorized<perm::PaymentsConfirm>,
     app_state: web::Data<AppState>,
     path: web::Path<String>,
 ) -> impl Responder {
     let payment_id = path.into_inner();
     
     if let Some(updated_payment) = app_state.monero_payments.update_payment_status(&payment_id, PaymentStatus::Confirmed) {
+        orders::process_paid_orders(&app_state.db, std::slice::from_ref(&updated_payment.order_id)).await;
+        
         HttpResponse::Ok().json(PaymentResponse {
             success: true,
             message: Some("Payment confirmed".to_string()),
@@ -173,10 +180,21 @@ pub fn start_payment_checker(app_state: web::Data<AppState>) -> tokio::task::Joi
             tokio::time::sleep(std::time::Duration::from_secs(60)).await;
             
             // This would integrate with the Monero wallet RPC in a real implementation
-            app_state.monero_payments.check_payments();
+            let confirmed: Vec<String> = app_state.monero_payments.check_payments()
+                .into_iter()
+                .map(|p| p.order_id)
+                .collect();
+            orders::process_paid_orders(&app_state.db, &confirmed).await;
             
-            // Expire old pending payments
-            app_state.monero_payments.expire_old_payments();
+            // Expire old pending payments and cancel their orders, handing
+            // the reserved stock back
+            let expired: Vec<String> = app_state.monero_payments.expire_old_payments()
+                .into_iter()
+                .map(|p| p.order_id)
+                .collect();
+            for order_id in orders::cancel_expired_orders(&app_state.db, &expired).await {
+                app_state.notify_order_cancelled(&order_id, "Monero payment expired");
+            }
             
             // Log number of pending payments for monitoring
             let pending_count = app_state.monero_payments.get_pending_payments().len();
@@ -201,6 +219,10 @@ pub async fn submit_proof(
         &proof.tx_key,
     ) {
         Ok(true) => {
+            if let Some(payment) = app_state.monero_payments.get_payment(&payment_id) {
+                orders::process_paid_orders(&app_state.db, &[payment.order_id]).await;
+            }
+            
             HttpResponse::Ok().json(PaymentResponse {
                 success: true,
                 message: Some("Payment verified and confirmed".to_string()),
@@ -252,9 +274,11 @@ pub async fn get_user_transactions(
     })
 }
 
-// Add this endpoint to manually check a payment
+// Add this endpoint to manually check a payment; a confirmation marks the
+// order paid, so it needs the same permission as confirming by hand
 #[post("/api/monero/check_now/{payment_id}")]
 pub async fn force_check_payment(
+    _auth: Authorized<perm::PaymentsConfirm>,
     app_state: web::Data<AppState>,
     path: web::Path<String>,
 ) -> impl Responder {
@@ -269,8 +293,9 @@ pub async fn force_check_payment(
             // For now, use a higher chance of confirmation for manually triggered checks
             if rand::random::<f64>() < 0.5 {  // 50% chance 
                 let update_result = app_state.monero_payments.update_payment_status(&payment_id, PaymentStatus::Confirmed);
-                if update_result.is_some() {
+                if let Some(updated) = update_result {
                     log::info!("Successfully updated payment status in memory");
+                    orders::process_paid_orders(&app_state.db, &[updated.order_id]).await;
                     
                     // Get the updated payment
                     let updated_payment = app_state.monero_payments.get_payment(&payment_id);
@@ -320,12 +345,13 @@ pub async fn checkout_handler(
     // Create a single order item
     let order_items = vec![OrderItem {
         product_id: data.product_id.clone(),
+        variant_id: None,
         quantity: 1,  // Default to 1
         price: data.price,
     }];
 
     // Extract user ID from JWT token or use guest
-    let user_id = match crate::auth::validate_token(req) {
+    let user_id = match crate::auth::validate_session(&req, &app_state.db).await {
         Ok(claims) => claims.sub,
         Err(_) => "guest".to_string()
     };
@@ -335,10 +361,26 @@ pub async fn checkout_handler(
         &app_state.db,
         shipping_info,
         order_items,
-        data.price,
         Some(user_id),
     ).await {
         Ok(order) => order,
+        Err(e @ StockError::UnknownProduct { .. }) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": e.to_string()
+            }));
+        },
+        Err(StockError::Insufficient { product_id, requested, available }) => {
+            log::warn!("Checkout rejected: product {} requested {} but only {} available",
+                       product_id, requested, available);
+            return HttpResponse::Conflict().json(json!({
+                "success": false,
+                "error": "Insufficient stock",
+                "product_id": product_id,
+                "requested": requested,
+                "available": available
+            }));
+        },
         Err(e) => {
             log::error!("Failed to create order: {}", e);
             return HttpResponse::InternalServerError().json(json!({
@@ -537,8 +579,8 @@ pub async fn get_order_payment(
     }
 }
 
-#[get("/admin/orders")]
-pub async fn get_all_orders(app_state: web::Data<AppState>) -> impl Responder {
+#[get("/orders")]
+pub async fn get_all_orders(_auth: Authorized<perm::OrdersRead>, app_state: web::Data<AppState>) -> impl Responder {
     match sqlx::query!(
         r#"
         SELECT o.*, mp.address as monero_address, mp.status as payment_status
@@ -577,8 +619,9 @@ pub async fn get_all_orders(app_state: web::Data<AppState>) -> impl Responder {
     }
 }
 
-#[get("/admin/validate/{order_id}")]
+#[get("/validate/{order_id}")]
 pub async fn validate_order(
+    _auth: Authorized<perm::OrdersRead>,
     app_state: web::Data<AppState>,
     path: web::Path<String>
 ) -> impl Responder {
@@ -685,8 +728,9 @@ pub async fn check_payment_status(
 }
 
 // Add this endpoint to manually sync all order statuses from payments
-#[post("/admin/sync-all-payments")]
+#[post("/sync-all-payments")]
 pub async fn sync_all_payment_statuses(
+    _auth: Authorized<perm::PaymentsConfirm>,
     app_state: web::Data<AppState>
 ) -> impl Responder {
     log::info!("🔄 Manual sync of all payment statuses to orders triggered");
@@ -703,14 +747,16 @@ pub async fn sync_all_payment_statuses(
             log::info!("Found {} confirmed payments to sync", payments.len());
             
             let mut success_count = 0;
+            let mut paid_orders = Vec::new();
             
             for payment in &payments {
                 // Safely unwrap the payment_id Option or skip this record
                 if let Some(payment_id) = &payment.payment_id {
-                    match sync_payment_status_to_order(&app_state.db, payment_id, "Confirmed").await {
-                        Ok(_) => {
+                    match sync_payment_status_to_order(&app_state.db, payment_id, OrderStatus::Paid).await {
+                        Ok(order_ids) => {
                             log::info!("✅ Successfully synced payment {} to order", payment_id);
                             success_count += 1;
+                            paid_orders.extend(order_ids);
                         },
                         Err(e) => {
                             log::error!("❌ Failed to sync payment {} to order: {}", payment_id, e);
@@ -721,6 +767,9 @@ pub async fn sync_all_payment_statuses(
                 }
             }
             
+            // Commit their stock and deliver digital items
+            orders::process_paid_orders(&app_state.db, &paid_orders).await;
+            
             HttpResponse::Ok().json(json!({
                 "success": true,
                 "message": format!("Synced {}/{} confirmed payments to orders", success_count, payments.len())
@@ -739,6 +788,7 @@ pub async fn sync_all_payment_statuses(
 // Add this endpoint to fix the missing payment_id issue
 #[post("/fix-orphaned-payments")]
 pub async fn fix_orphaned_payments(
+    _auth: Authorized<perm::PaymentsConfirm>,
     app_state: web::Data<AppState>
 ) -> impl Responder {
     log::info!("🔧 Running orphaned payments fix");
@@ -792,16 +842,8 @@ pub async fn fix_orphaned_payments(
                             if payment.status == "Confirmed" || payment.status == "confirmed" {
                                 log::info!("Payment is confirmed, updating order status too");
                                 
-                                let status_update = sqlx::query!(
-                                    "UPDATE orders SET status = 'Confirmed' WHERE id = ?",
-                                    order_id
-                                )
-                                .execute(&app_state.db)
-                                .await;
-                                
-                                if let Ok(_) = status_update {
-                                    log::info!("✅ Updated order status to Confirmed");
-                                }
+                                // Also commits its stock and delivers digital items
+                                orders::process_paid_orders(&app_state.db, std::slice::from_ref(order_id)).await;
                             }
                             
                             fixed_orders.push(json!({
@@ -830,6 +872,7 @@ pub async fn fix_orphaned_payments(
 // Add a comprehensive diagnostic endpoint for all payments
 #[get("/debug/dump-all-payments")]
 pub async fn dump_all_payments(
+    _auth: Authorized<perm::OrdersRead>,
     app_state: web::Data<AppState>
 ) -> impl Responder {
     log::info!("🔍 Dumping all payment records for debugging");
@@ -893,18 +936,24 @@ pub fn init_routes() -> actix_web::Scope {
         .service(force_check_payment)
         .service(checkout_handler)
         .service(checkout_test)
+        // Registered ahead of the catch-all debug route so it stays reachable
+        .service(dump_all_payments)
         .service(debug_handler)
         .service(debug_info)
         .service(list_routes)
         .service(get_order_payment)
-        .service(get_all_orders)
-        .service(validate_order)
         .service(check_payment_status)
-        .service(sync_all_payment_statuses)
         .service(fix_orphaned_payments)
-        .service(dump_all_payments)
-        .service(force_create_payment_links)
         .service(force_update_order_status)
+        // Payment maintenance for staff
+        .service(
+            web::scope("/admin")
+                .wrap(StaffMiddleware)
+                .service(get_all_orders)
+                .service(validate_order)
+                .service(sync_all_payment_statuses)
+                .service(force_create_payment_links)
+        )
 }
 
 // First, let's fix the function that adds the column
@@ -973,9 +1022,11 @@ use uuid;
 #[allow(unused_imports)]
 use chrono;
 
-// Add this function to synchronize payment status with order status
-async fn sync_payment_status_to_order(pool: &sqlx::SqlitePool, payment_id: &str, status: &str) -> Result<(), sqlx::Error> {
+// Add this function to synchronize payment status with order status.
+// Returns the ids of the orders it updated.
+async fn sync_payment_status_to_order(pool: &sqlx::SqlitePool, payment_id: &str, status: OrderStatus) -> Result<Vec<String>, orders::StatusError> {
     log::info!("🔄 Synchronizing payment status '{}' for payment ID {} to order", status, payment_id);
+    let change = StatusChange::payment(&format!("Synced from payment {}", payment_id));
     
     // First, log all orders and payments for debugging
     log::info!("Debugging all orders and payments in the system:");
@@ -1010,13 +1061,7 @@ async fn sync_payment_status_to_order(pool: &sqlx::SqlitePool, payment_id: &str,
                     log::info!("✅ Found order_id {} via monero_payments table for payment {}", order_id, payment_id);
                     // Update the order status directly
                     log::info!("Updating order {} status to {}", order_id, status);
-                    sqlx::query!(
-                        "UPDATE orders SET status = ? WHERE id = ?",
-                        status,
-                        order_id
-                    )
-                    .execute(pool)
-                    .await?;
+                    orders::transition_status(&mut *pool.acquire().await?, &order_id, status, &change).await?;
                     
                     // Also make sure the payment_id is set on the order
                     log::info!("Ensuring payment_id is set on order {}", order_id);
@@ -1028,16 +1073,17 @@ async fn sync_payment_status_to_order(pool: &sqlx::SqlitePool, payment_id: &str,
                     .execute(pool)
                     .await?;
                     
-                    return Ok(());
+                    return Ok(vec![order_id]);
                 }
             }
         }
         
         log::error!("🔍 Still couldn't find any order for payment {}", payment_id);
-        return Ok(());
+        return Ok(Vec::new());
     }
     
     // Process found orders
+    let mut updated = Vec::with_capacity(orders.len());
     for row in orders {
         let order_id: String = row.get("id");
         let current_status: String = row.get("status");
@@ -1045,16 +1091,11 @@ async fn sync_payment_status_to_order(pool: &sqlx::SqlitePool, payment_id: &str,
         log::info!("✅ Found order {} with current status {}, updating to {}", order_id, current_status, status);
         
         // Update the order status
-        sqlx::query!(
-            "UPDATE orders SET status = ? WHERE id = ?",
-            status,
-            order_id
-        )
-        .execute(pool)
-        .await?;
+        orders::transition_status(&mut *pool.acquire().await?, &order_id, status, &change).await?;
+        updated.push(order_id);
     }
     
-    Ok(())
+    Ok(updated)
 }
 
 // Update this function to be more thorough when updating payment status
@@ -1089,7 +1130,7 @@ pub async fn check_payment_status_and_update(app_state: &web::Data<AppState>, pa
         }
         
         // Now also update the order status
-        match sync_payment_status_to_order(&app_state.db, payment_id, "Confirmed").await {
+        match sync_payment_status_to_order(&app_state.db, payment_id, OrderStatus::Paid).await {
             Ok(_) => log::info!("✅ Successfully synced payment status to order"),
             Err(e) => {
                 log::error!("❌ Failed to update order status: {}", e);
@@ -1100,6 +1141,7 @@ pub async fn check_payment_status_and_update(app_state: &web::Data<AppState>, pa
         // Also update in-memory store
         if let Some(updated) = app_state.monero_payments.update_payment_status(&payment_id, PaymentStatus::Confirmed) {
             log::info!("✅ Successfully updated payment status in memory: {:?}", updated.status);
+            orders::process_paid_orders(&app_state.db, &[updated.order_id]).await;
         } else {
             log::error!("❌ Failed to update payment status in memory - payment not found");
         }
@@ -1128,8 +1170,9 @@ pub async fn check_payment_status_and_update(app_state: &web::Data<AppState>, pa
     }
 }
 
-#[post("/admin/force-create-payment-links")]
+#[post("/force-create-payment-links")]
 pub async fn force_create_payment_links(
+    _auth: Authorized<perm::PaymentsConfirm>,
     app_state: web::Data<AppState>
 ) -> impl Responder {
     log::info!("🔧 Force creating payment links for all orders without payment_id");
@@ -1229,77 +1272,43 @@ pub async fn force_create_payment_links(
 // Add a new endpoint to directly force update order status
 #[post("/force-update-order-status/{order_id}")]
 pub async fn force_update_order_status(
+    _auth: Authorized<perm::PaymentsConfirm>,
     app_state: web::Data<AppState>,
     path: web::Path<String>
 ) -> impl Responder {
     let order_id = path.into_inner();
     log::info!("🔄 Force updating order status for order: {}", order_id);
     
-    // Get current order details
-    let order = sqlx::query(
-        "SELECT id, payment_id, status FROM orders WHERE id = ?"
-    )
-    .bind(&order_id)
-    .fetch_optional(&app_state.db)
-    .await;
-    
-    match order {
-        Ok(Some(order_row)) => {
-            let payment_id: String = order_row.get("payment_id");
-            let current_status: String = order_row.get("status");
-            
-            log::info!("Order {} has payment_id {} and status {}", order_id, payment_id, current_status);
+    // Marking the order paid still has to be allowed from its current status
+    let change = StatusChange::system("Forced payment confirmation");
+    match orders::change_status(&app_state.db, &order_id, OrderStatus::Paid, &change).await {
+        Ok(previous) => {
+            log::info!("✅ Order {} moved from {} to Paid", order_id, previous);
             
-            // Update the order status directly to Confirmed
+            // If there's a payment ID, also make sure it's updated
             match sqlx::query(
-                "UPDATE orders SET status = 'Confirmed' WHERE id = ?"
+                "UPDATE monero_payments SET status = 'Confirmed' WHERE payment_id = (SELECT payment_id FROM orders WHERE id = ?)"
             )
             .bind(&order_id)
             .execute(&app_state.db)
             .await {
-                Ok(_) => {
-                    log::info!("✅ Successfully updated order status to Confirmed");
-                    
-                    // If there's a payment ID, also make sure it's updated
-                    if !payment_id.is_empty() {
-                        match sqlx::query(
-                            "UPDATE monero_payments SET status = 'Confirmed' WHERE payment_id = ?"
-                        )
-                        .bind(&payment_id)
-                        .execute(&app_state.db)
-                        .await {
-                            Ok(_) => log::info!("✅ Also updated payment status"),
-                            Err(e) => log::error!("Failed to update payment status: {}", e)
-                        }
-                    }
-                    
-                    HttpResponse::Ok().json(json!({
-                        "success": true,
-                        "message": "Order status updated to Confirmed"
-                    }))
-                },
-                Err(e) => {
-                    log::error!("Failed to update order status: {}", e);
-                    HttpResponse::InternalServerError().json(json!({
-                        "success": false,
-                        "error": format!("Failed to update order status: {}", e)
-                    }))
-                }
+                Ok(_) => log::info!("✅ Also updated payment status"),
+                Err(e) => log::error!("Failed to update payment status: {}", e)
             }
-        },
-        Ok(None) => {
-            log::error!("Order {} not found", order_id);
-            HttpResponse::NotFound().json(json!({
-                "success": false,
-                "error": "Order not found"
+            
+            if previous != OrderStatus::Paid {
+                orders::process_paid_orders(&app_state.db, std::slice::from_ref(&order_id)).await;
+            }
+            
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "Order status updated to Paid",
+                "previous_status": previous
             }))
         },
         Err(e) => {
-            log::error!("Database error: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            }))
+            log::warn!("Rejected forced status change for order {}: {}", order_id, e);
+            e.to_response()
         }
     }
 }
\ No newline at end of file
@@ -2,16 +2,23 @@ use actix_web::{web, HttpResponse, Responder, HttpRequest, get, post};
 use serde::{Deserialize, Serialize};
 use chrono::Utc;
 use crate::AppState;
-use log::{info, error};
+use log::{info, warn, error};
 use crate::auth;
+use crate::rbac::{perm, Authorized};
 use sqlx::Row;
-use sqlx::SqlitePool;
+use sqlx::{Connection, SqliteConnection, SqlitePool};
 use serde_json::json;
 use rand::Rng;
 use crate::types::ShippingInfo;
+use crate::inventory::{self, StockError};
+use crate::variants;
+use crate::digital;
+use crate::tax;
+use crate::shipments;
+use crate::monero::PaymentStatus;
 use sqlx::Column;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum OrderStatus {
     Pending,
     AwaitingPayment,
@@ -22,6 +29,271 @@ pub enum OrderStatus {
     Cancelled
 }
 
+impl OrderStatus {
+    pub const ALL: [OrderStatus; 7] = [
+        OrderStatus::Pending,
+        OrderStatus::AwaitingPayment,
+        OrderStatus::Paid,
+        OrderStatus::Shipped,
+        OrderStatus::Delivered,
+        OrderStatus::Completed,
+        OrderStatus::Cancelled,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Pending => "Pending",
+            OrderStatus::AwaitingPayment => "AwaitingPayment",
+            OrderStatus::Paid => "Paid",
+            OrderStatus::Shipped => "Shipped",
+            OrderStatus::Delivered => "Delivered",
+            OrderStatus::Completed => "Completed",
+            OrderStatus::Cancelled => "Cancelled",
+        }
+    }
+
+    /// Statuses an order may move to from this one. Digital-only orders go
+    /// straight from `Paid` to `Delivered`; `Completed` and `Cancelled` are final.
+    pub fn next_statuses(&self) -> &'static [OrderStatus] {
+        match self {
+            OrderStatus::Pending => &[OrderStatus::AwaitingPayment, OrderStatus::Paid, OrderStatus::Cancelled],
+            OrderStatus::AwaitingPayment => &[OrderStatus::Paid, OrderStatus::Cancelled],
+            OrderStatus::Paid => &[OrderStatus::Shipped, OrderStatus::Delivered, OrderStatus::Cancelled],
+            OrderStatus::Shipped => &[OrderStatus::Delivered],
+            OrderStatus::Delivered => &[OrderStatus::Completed],
+            OrderStatus::Completed | OrderStatus::Cancelled => &[],
+        }
+    }
+
+    pub fn can_transition_to(&self, next: OrderStatus) -> bool {
+        self.next_statuses().contains(&next)
+    }
+}
+
+impl std::fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for OrderStatus {
+    type Err = StatusError;
+
+    /// Status names are matched case-insensitively.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OrderStatus::ALL.iter()
+            .find(|status| status.as_str().eq_ignore_ascii_case(s.trim()))
+            .copied()
+            .ok_or_else(|| StatusError::Unknown(s.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub enum StatusError {
+    NotFound,
+    Unknown(String),
+    NotAllowed { from: OrderStatus, to: OrderStatus },
+    /// The status changed between reading and writing it
+    Concurrent,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for StatusError {
+    fn from(e: sqlx::Error) -> Self {
+        StatusError::Database(e)
+    }
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatusError::NotFound => write!(f, "Order not found"),
+            StatusError::Unknown(status) => {
+                let valid: Vec<&str> = OrderStatus::ALL.iter().map(|s| s.as_str()).collect();
+                write!(f, "Unknown order status '{}'; expected one of {}", status, valid.join(", "))
+            },
+            StatusError::NotAllowed { from, to } => {
+                let allowed: Vec<&str> = from.next_statuses().iter().map(|s| s.as_str()).collect();
+                if allowed.is_empty() {
+                    write!(f, "Cannot change order status from {} to {}: {} is final", from, to, from)
+                } else {
+                    write!(f, "Cannot change order status from {} to {}; allowed: {}", from, to, allowed.join(", "))
+                }
+            },
+            StatusError::Concurrent => write!(f, "Order status was changed by another request; try again"),
+            StatusError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl StatusError {
+    /// Response for a rejected status change; database errors are hidden.
+    pub fn to_response(&self) -> HttpResponse {
+        let body = json!({
+            "success": false,
+            "error": self.to_string()
+        });
+        match self {
+            StatusError::NotFound => HttpResponse::NotFound().json(body),
+            StatusError::Unknown(_) => HttpResponse::BadRequest().json(body),
+            StatusError::NotAllowed { from, to } => HttpResponse::Conflict().json(json!({
+                "success": false,
+                "error": self.to_string(),
+                "current_status": from,
+                "requested_status": to,
+                "allowed": from.next_statuses()
+            })),
+            StatusError::Concurrent => HttpResponse::Conflict().json(body),
+            StatusError::Database(e) => {
+                error!("Order status update failed: {}", e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to update order status"
+                }))
+            },
+        }
+    }
+}
+
+/// Who changed an order's status and why, kept in `order_status_history`.
+/// Actors are `system`, `payment`, `admin:<username>` or `customer[:<user id>]`.
+#[derive(Debug, Clone)]
+pub struct StatusChange {
+    pub actor: String,
+    pub reason: Option<String>,
+}
+
+impl StatusChange {
+    pub fn system(reason: &str) -> Self {
+        StatusChange { actor: "system".to_string(), reason: Some(reason.to_string()) }
+    }
+
+    pub fn payment(reason: &str) -> Self {
+        StatusChange { actor: "payment".to_string(), reason: Some(reason.to_string()) }
+    }
+
+    pub fn admin(username: &str, reason: Option<String>) -> Self {
+        StatusChange { actor: format!("admin:{}", username), reason }
+    }
+
+    pub fn customer(user_id: Option<&str>, reason: &str) -> Self {
+        let actor = match user_id {
+            Some(id) => format!("customer:{}", id),
+            None => "customer".to_string(),
+        };
+        StatusChange { actor, reason: Some(reason.to_string()) }
+    }
+}
+
+/// One entry of an order's status timeline
+#[derive(Debug, Serialize)]
+pub struct StatusHistoryEntry {
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub actor: String,
+    pub reason: Option<String>,
+    pub created_at: i64,
+}
+
+/// Append a row to an order's status history. `from` is `None` for the
+/// status an order was created with.
+pub async fn record_status_change(
+    conn: &mut SqliteConnection,
+    order_id: &str,
+    from: Option<OrderStatus>,
+    to: OrderStatus,
+    change: &StatusChange,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO order_status_history (order_id, from_status, to_status, actor, reason, created_at)
+         VALUES (?, ?, ?, ?, ?, ?)"
+    )
+    .bind(order_id)
+    .bind(from.map(|s| s.as_str()))
+    .bind(to.as_str())
+    .bind(&change.actor)
+    .bind(&change.reason)
+    .bind(Utc::now().timestamp())
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Status timeline of an order, oldest first
+pub async fn status_history(pool: &SqlitePool, order_id: &str) -> Result<Vec<StatusHistoryEntry>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT from_status, to_status, actor, reason, created_at
+         FROM order_status_history WHERE order_id = ? ORDER BY created_at, id"
+    )
+    .bind(order_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(|row| StatusHistoryEntry {
+        from_status: row.get("from_status"),
+        to_status: row.get("to_status"),
+        actor: row.get("actor"),
+        reason: row.get("reason"),
+        created_at: row.get("created_at"),
+    }).collect())
+}
+
+/// Status timeline as shown to the customer: staff usernames are reduced to
+/// the kind of actor.
+pub async fn customer_timeline(pool: &SqlitePool, order_id: &str) -> Result<Vec<StatusHistoryEntry>, sqlx::Error> {
+    let mut history = status_history(pool, order_id).await?;
+    for entry in history.iter_mut() {
+        if let Some((kind, _)) = entry.actor.split_once(':') {
+            entry.actor = kind.to_string();
+        }
+    }
+    Ok(history)
+}
+
+/// Move an order to `to` if its current status allows it, returning the
+/// status it had, and record the change in the order's history. Setting the
+/// status an order already has is a no-op. Every status change goes through here.
+pub async fn transition_status(
+    conn: &mut SqliteConnection,
+    order_id: &str,
+    to: OrderStatus,
+    change: &StatusChange,
+) -> Result<OrderStatus, StatusError> {
+    let current: String = sqlx::query("SELECT status FROM orders WHERE id = ?")
+        .bind(order_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(StatusError::NotFound)?
+        .get("status");
+    let from: OrderStatus = current.parse()?;
+
+    if from == to {
+        return Ok(from);
+    }
+    if !from.can_transition_to(to) {
+        return Err(StatusError::NotAllowed { from, to });
+    }
+
+    // A savepoint when the caller is already inside a transaction
+    let mut tx = conn.begin().await?;
+    let updated = sqlx::query("UPDATE orders SET status = ?, updated_at = ? WHERE id = ? AND status = ?")
+        .bind(to.as_str())
+        .bind(Utc::now().timestamp())
+        .bind(order_id)
+        .bind(from.as_str())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(StatusError::Concurrent);
+    }
+    record_status_change(&mut tx, order_id, Some(from), to, change).await?;
+    tx.commit().await?;
+
+    info!("Order {} moved from {} to {} by {}", order_id, from, to, change.actor);
+    Ok(from)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Order {
     pub id: String,
@@ -53,23 +325,17 @@ struct OrderRecord {
     updated_at: i64,
 }
 
-// Add conversion from OrderRecord to Order
-impl From<OrderRecord> for Order {
-    fn from(record: OrderRecord) -> Self {
-        Order {
+// Add conversion from OrderRecord to Order; a status outside the state
+// machine is an error rather than silently becoming `Pending`
+impl TryFrom<OrderRecord> for Order {
+    type Error = StatusError;
+
+    fn try_from(record: OrderRecord) -> Result<Self, Self::Error> {
+        Ok(Order {
             id: record.id,
             user_id: record.user_id,
             payment_id: record.payment_id,
-            status: match record.status.as_str() {
-                "Pending" => OrderStatus::Pending,
-                "AwaitingPayment" => OrderStatus::AwaitingPayment,
-                "Paid" => OrderStatus::Paid,
-                "Shipped" => OrderStatus::Shipped,
-                "Delivered" => OrderStatus::Delivered,
-                "Completed" => OrderStatus::Completed,
-                "Cancelled" => OrderStatus::Cancelled,
-                _ => OrderStatus::Pending, // Default case
-            },
+            status: record.status.parse()?,
             shipping_info: ShippingInfo {
                 name: record.shipping_name,
                 address: record.shipping_address,
@@ -82,7 +348,7 @@ impl From<OrderRecord> for Order {
             total_amount: record.total_amount,
             created_at: record.created_at,
             updated_at: record.updated_at,
-        }
+        })
     }
 }
 
@@ -109,23 +375,81 @@ pub fn generate_order_number() -> String {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OrderItem {
     pub product_id: String,
+    #[serde(default)]
+    pub variant_id: Option<String>,
     pub quantity: u32,
     pub price: f64,
 }
 
+/// Insert one order line and reserve its stock inside the checkout transaction.
+/// Variant SKU, name and options are copied onto the line so historical orders
+/// keep describing what was bought even if the variant later changes. The
+/// unit price comes from the variant, or the product when there is none;
+/// `item.price` is ignored. Returns the unit price charged.
+pub async fn insert_order_line(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    order_id: &str,
+    item: &OrderItem,
+) -> Result<f64, StockError> {
+    let unknown = || StockError::UnknownProduct {
+        product_id: item.product_id.clone(),
+        variant_id: item.variant_id.clone(),
+    };
+    let variant = match &item.variant_id {
+        Some(variant_id) => Some(
+            variants::fetch_variant(&mut **tx, &item.product_id, variant_id).await?.ok_or_else(unknown)?
+        ),
+        None => None,
+    };
+    let price = match &variant {
+        Some(variant) => variant.price,
+        None => sqlx::query("SELECT price FROM products WHERE id = ?")
+            .bind(&item.product_id)
+            .fetch_optional(&mut **tx)
+            .await?
+            .ok_or_else(unknown)?
+            .get::<f64, _>("price"),
+    };
+
+    let options = variant
+        .as_ref()
+        .map(|v| serde_json::to_string(&v.options).unwrap_or_else(|_| "{}".to_string()));
+
+    sqlx::query(
+        "INSERT INTO order_items (
+            order_number, product_id, variant_id, sku, variant_name, variant_options, quantity, price
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(order_id)
+    .bind(&item.product_id)
+    .bind(&item.variant_id)
+    .bind(variant.as_ref().map(|v| v.sku.clone()))
+    .bind(variant.as_ref().map(|v| v.name.clone()))
+    .bind(options)
+    .bind(item.quantity)
+    .bind(price)
+    .execute(&mut **tx)
+    .await?;
+
+    // Oversell guard: the caller rolls the whole order back on error
+    inventory::reserve_stock(tx, order_id, &item.product_id, item.variant_id.as_deref(), item.quantity as i64).await?;
+    Ok(price)
+}
+
 // Update the create_order function signature to use the correct ShippingInfo type
 pub async fn create_order(
     pool: &SqlitePool,
     shipping_info: ShippingInfo,
     items: Vec<OrderItem>,
-    total_amount: f64,
     user_id: Option<String>,
-) -> Result<Order, sqlx::Error> {
+) -> Result<Order, StockError> {
     log::info!("Creating order with shipping info: {:?}", shipping_info);
     
     let order_number = generate_order_number();
     let now = Utc::now().timestamp();
-    let status = "Pending";
+    let status = "AwaitingPayment";
+    // Filled in from catalogue prices once the lines are inserted
+    let mut total_amount = 0.0;
 
     // Start a transaction
     let mut tx = pool.begin().await?;
@@ -157,20 +481,21 @@ pub async fn create_order(
 
     log::info!("Created order record, inserting {} items", items.len());
 
-    // Insert order items
+    let change = StatusChange::customer(user_id.as_deref(), "Order placed");
+    record_status_change(&mut tx, &order_number, None, OrderStatus::AwaitingPayment, &change).await?;
+
+    // Insert order items; dropping tx on error rolls the whole order back
     for item in items.iter() {
-        sqlx::query!(
-            "INSERT INTO order_items (
-                order_number, product_id, quantity, price
-            ) VALUES (?, ?, ?, ?)",
-            order_number,
-            item.product_id,
-            item.quantity,
-            item.price
-        )
+        let unit_price = insert_order_line(&mut tx, &order_number, item).await?;
+        total_amount += unit_price * item.quantity as f64;
+    }
+    let total_amount = (total_amount * 100.0).round() / 100.0;
+
+    sqlx::query("UPDATE orders SET total_amount = ? WHERE id = ?")
+        .bind(total_amount)
+        .bind(&order_number)
         .execute(&mut *tx)
         .await?;
-    }
 
     // Commit the transaction
     tx.commit().await?;
@@ -181,7 +506,7 @@ pub async fn create_order(
         id: order_number.clone(),
         user_id,
         payment_id: String::new(),
-        status: OrderStatus::Pending,
+        status: OrderStatus::AwaitingPayment,
         shipping_info,
         total_amount,
         created_at: now,
@@ -189,6 +514,161 @@ pub async fn create_order(
     })
 }
 
+/// Everything that follows a confirmed Monero payment: the order is marked
+/// paid, reserved stock is committed and digital items are delivered. Orders
+/// that can't be marked paid, such as cancelled ones, get neither.
+pub async fn process_paid_orders(pool: &SqlitePool, order_ids: &[String]) {
+    let mut paid = Vec::with_capacity(order_ids.len());
+    for order_id in order_ids {
+        let result = async {
+            let mut conn = pool.acquire().await?;
+            mark_paid(&mut conn, order_id).await
+        }.await;
+        match result {
+            Ok(()) => paid.push(order_id.clone()),
+            Err(e) => error!("Failed to mark order {} as paid: {}", order_id, e),
+        }
+    }
+    inventory::commit_confirmed_orders(pool, &paid).await;
+    digital::fulfil_paid_orders(pool, &paid).await;
+}
+
+/// Change an order's status outside checkout and payment processing, with
+/// the side effects the new status needs: a cancelled order releases the
+/// stock it was holding.
+pub async fn change_status(
+    pool: &SqlitePool,
+    order_id: &str,
+    to: OrderStatus,
+    change: &StatusChange,
+) -> Result<OrderStatus, StatusError> {
+    let mut conn = pool.acquire().await?;
+    let previous = transition_status(&mut conn, order_id, to, change).await?;
+    drop(conn);
+
+    if to == OrderStatus::Cancelled && previous != OrderStatus::Cancelled {
+        if let Err(e) = inventory::release_order_reservations(pool, order_id).await {
+            error!("Failed to release stock for cancelled order {}: {}", order_id, e);
+        }
+    }
+    Ok(previous)
+}
+
+/// Record a confirmed payment on an order. Orders that are already paid or
+/// further along are left as they are.
+pub async fn mark_paid(conn: &mut SqliteConnection, order_id: &str) -> Result<(), StatusError> {
+    let change = StatusChange::payment("Monero payment confirmed");
+    match transition_status(conn, order_id, OrderStatus::Paid, &change).await {
+        Ok(_) => Ok(()),
+        Err(StatusError::NotAllowed { from, .. }) if from != OrderStatus::Cancelled => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Orders a customer may still cancel themselves: nothing has been paid yet.
+pub fn customer_can_cancel(status: OrderStatus) -> bool {
+    matches!(status, OrderStatus::Pending | OrderStatus::AwaitingPayment)
+}
+
+/// Cancel an order only while it is unpaid, releasing its stock. The status
+/// check and the change share a transaction so a payment landing in between
+/// can't be cancelled away.
+pub async fn cancel_unpaid_order(pool: &SqlitePool, order_id: &str, change: &StatusChange) -> Result<OrderStatus, StatusError> {
+    let mut tx = pool.begin().await?;
+    let current: String = sqlx::query("SELECT status FROM orders WHERE id = ?")
+        .bind(order_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(StatusError::NotFound)?
+        .get("status");
+    let from: OrderStatus = current.parse()?;
+    if !customer_can_cancel(from) {
+        return Err(StatusError::NotAllowed { from, to: OrderStatus::Cancelled });
+    }
+    transition_status(&mut tx, order_id, OrderStatus::Cancelled, change).await?;
+    tx.commit().await?;
+
+    if let Err(e) = inventory::release_order_reservations(pool, order_id).await {
+        error!("Failed to release stock for cancelled order {}: {}", order_id, e);
+    }
+    expire_order_payment(pool, order_id).await;
+    Ok(from)
+}
+
+/// Cancel orders whose Monero payment expired before it arrived, releasing
+/// their stock. Orders that were paid some other way are left alone. Returns
+/// the orders that were cancelled.
+pub async fn cancel_expired_orders(pool: &SqlitePool, order_ids: &[String]) -> Vec<String> {
+    let change = StatusChange::payment("Monero payment expired");
+    let mut cancelled = Vec::new();
+    for order_id in order_ids {
+        match cancel_unpaid_order(pool, order_id, &change).await {
+            Ok(_) => {
+                info!("Cancelled order {} after its payment expired", order_id);
+                cancelled.push(order_id.clone());
+            },
+            Err(StatusError::NotAllowed { from, .. }) => {
+                info!("Payment for order {} expired but the order is {}; leaving it", order_id, from);
+            },
+            Err(e) => error!("Failed to cancel expired order {}: {}", order_id, e),
+        }
+    }
+    cancelled
+}
+
+/// Stop treating a cancelled order's pending payment as payable.
+async fn expire_order_payment(pool: &SqlitePool, order_id: &str) {
+    let result = sqlx::query(
+        "UPDATE monero_payments SET status = 'Expired', updated_at = ?
+         WHERE status = 'Pending' AND payment_id = (SELECT payment_id FROM orders WHERE id = ?)"
+    )
+    .bind(Utc::now().timestamp())
+    .bind(order_id)
+    .execute(pool)
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to expire payment for cancelled order {}: {}", order_id, e);
+    }
+}
+
+/// Line items of an order as returned by the order APIs, with the product's
+/// current main image.
+pub async fn fetch_order_items(pool: &SqlitePool, order_id: &str) -> Result<Vec<serde_json::Value>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT oi.id, oi.product_id, oi.variant_id, oi.sku, oi.variant_name,
+               oi.variant_options, oi.quantity, oi.price, p.name,
+               (SELECT url FROM product_images i WHERE i.product_id = oi.product_id
+                ORDER BY i.position LIMIT 1) AS image
+        FROM order_items oi
+        LEFT JOIN products p ON oi.product_id = p.id
+        WHERE oi.order_number = ?
+        ORDER BY oi.id
+        "#
+    )
+    .bind(order_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(|item_row| {
+        let variant_options = item_row.get::<Option<String>, _>("variant_options")
+            .and_then(|o| serde_json::from_str::<serde_json::Value>(&o).ok());
+
+        json!({
+            "id": item_row.get::<i64, _>("id"),
+            "product_id": item_row.get::<String, _>("product_id"),
+            "name": item_row.get::<Option<String>, _>("name"),
+            "variant_id": item_row.get::<Option<String>, _>("variant_id"),
+            "sku": item_row.get::<Option<String>, _>("sku"),
+            "variant_name": item_row.get::<Option<String>, _>("variant_name"),
+            "variant_options": variant_options,
+            "quantity": item_row.get::<i64, _>("quantity"),
+            "price": item_row.get::<f64, _>("price"),
+            "image": item_row.get::<Option<String>, _>("image")
+        })
+    }).collect())
+}
+
 // Add this new endpoint for public order status lookup
 #[get("/status/{id}")]
 pub async fn get_order_status_endpoint(path: web::Path<String>, app_state: web::Data<AppState>) -> impl Responder {
@@ -198,7 +678,7 @@ pub async fn get_order_status_endpoint(path: web::Path<String>, app_state: web::
     
     // Query to get order with limited information for public access
     let query = r#"
-        SELECT id, status, total_amount, created_at, updated_at, 
+        SELECT id, status, total_amount, coupon_code, discount_amount, shipping_method_name, shipping_cost, tax_amount, prices_include_tax, created_at, updated_at, 
                shipping_name, shipping_address, shipping_city, shipping_state,
                shipping_zip, shipping_country, payment_id
         FROM orders 
@@ -216,6 +696,12 @@ pub async fn get_order_status_endpoint(path: web::Path<String>, app_state: web::
                     "id": row.get::<String, _>("id"),
                     "status": row.get::<String, _>("status"),
                     "total_amount": row.get::<f64, _>("total_amount"),
+                    "coupon_code": row.get::<Option<String>, _>("coupon_code"),
+                    "discount_amount": row.get::<f64, _>("discount_amount"),
+                    "shipping_method_name": row.get::<Option<String>, _>("shipping_method_name"),
+                    "shipping_cost": row.get::<f64, _>("shipping_cost"),
+                    "tax_amount": row.get::<f64, _>("tax_amount"),
+                    "prices_include_tax": row.get::<bool, _>("prices_include_tax"),
                     "created_at": row.get::<i64, _>("created_at"),
                     "updated_at": row.get::<i64, _>("updated_at"),
                     "shipping_name": row.get::<String, _>("shipping_name"),
@@ -256,39 +742,16 @@ pub async fn get_authenticated_user_orders(
     app_state: web::Data<AppState>
 ) -> impl Responder {
     // Validate authentication
-    let claims = match auth::validate_token(req) {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
         Ok(claims) => claims,
-        Err(e) => {
-            return HttpResponse::Unauthorized().json(json!({
-                "success": false,
-                "error": e
-            }));
-        }
+        Err(e) => return e.to_response(),
     };
     
     let user_id = claims.sub;
     
-    // Special case for admin tokens
-    if claims.role == "admin" {
-        info!("Admin user accessing orders");
-        // Return some dummy orders for admin users
-        return HttpResponse::Ok().json(json!({
-            "success": true,
-            "count": 1,
-            "orders": [{
-                "id": "ORD-ADMIN-SAMPLE",
-                "status": "Completed",
-                "total_amount": 99.99,
-                "created_at": chrono::Utc::now().timestamp(),
-                "payment_id": "sample-payment-id",
-                "monero_address": "44AFFq5kSiGBoZ4NMDwYtN18obc8AemS33DBLWs3H7otXft3XjrpDtQGv7SqSsaBYBb98uNbr2VBBEt7f2wfn3RVGQBEP3A" // Sample address
-            }]
-        }));
-    }
-    
     // Query orders with Monero addresses
     match sqlx::query(
-        "SELECT o.id, o.status, o.total_amount, o.created_at, o.payment_id, 
+        "SELECT o.id, o.status, o.total_amount, o.coupon_code, o.discount_amount, o.shipping_method_name, o.shipping_cost, o.tax_amount, o.prices_include_tax, o.created_at, o.payment_id, 
          mp.address as monero_address, mp.status as payment_status
          FROM orders o
          LEFT JOIN monero_payments mp ON o.payment_id = mp.payment_id
@@ -299,23 +762,74 @@ pub async fn get_authenticated_user_orders(
     .fetch_all(&app_state.db)
     .await {
         Ok(rows) => {
-            let orders: Vec<serde_json::Value> = rows.iter().map(|row| {
+            let mut orders: Vec<serde_json::Value> = Vec::with_capacity(rows.len());
+            for row in rows.iter() {
                 // Check if monero_address exists and print for debugging
                 let address = row.get::<Option<String>, _>("monero_address");
-                log::info!("Order {} has monero address: {:?}", 
-                           row.get::<String, _>("id"), 
-                           address);
+                let order_id = row.get::<String, _>("id");
+                log::info!("Order {} has monero address: {:?}", order_id, address);
                 
-                json!({
-                    "id": row.get::<String, _>("id"),
+                let items = match fetch_order_items(&app_state.db, &order_id).await {
+                    Ok(items) => items,
+                    Err(e) => {
+                        error!("Failed to fetch items for order {}: {}", order_id, e);
+                        Vec::new()
+                    }
+                };
+                
+                let digital = match digital::deliveries_for_order(&app_state.db, &order_id).await {
+                    Ok(digital) => digital,
+                    Err(e) => {
+                        error!("Failed to fetch digital deliveries for order {}: {}", order_id, e);
+                        Vec::new()
+                    }
+                };
+                
+                let tax_lines = match tax::tax_lines_for_order(&app_state.db, &order_id).await {
+                    Ok(lines) => lines,
+                    Err(e) => {
+                        error!("Failed to fetch tax lines for order {}: {}", order_id, e);
+                        Vec::new()
+                    }
+                };
+                
+                let timeline = match customer_timeline(&app_state.db, &order_id).await {
+                    Ok(timeline) => timeline,
+                    Err(e) => {
+                        error!("Failed to fetch status timeline for order {}: {}", order_id, e);
+                        Vec::new()
+                    }
+                };
+                
+                let order_shipments = match shipments::shipments_for_order(&app_state.db, &order_id).await {
+                    Ok(order_shipments) => order_shipments,
+                    Err(e) => {
+                        error!("Failed to fetch shipments for order {}: {}", order_id, e);
+                        Vec::new()
+                    }
+                };
+                
+                orders.push(json!({
+                    "id": order_id,
                     "status": row.get::<String, _>("status"),
                     "total_amount": row.get::<f64, _>("total_amount"),
+                    "coupon_code": row.get::<Option<String>, _>("coupon_code"),
+                    "discount_amount": row.get::<f64, _>("discount_amount"),
+                    "shipping_method_name": row.get::<Option<String>, _>("shipping_method_name"),
+                    "shipping_cost": row.get::<f64, _>("shipping_cost"),
+                    "tax_amount": row.get::<f64, _>("tax_amount"),
+                    "prices_include_tax": row.get::<bool, _>("prices_include_tax"),
                     "created_at": row.get::<i64, _>("created_at"),
                     "payment_id": row.get::<String, _>("payment_id"),
                     "payment_status": row.get::<Option<String>, _>("payment_status"),
-                    "monero_address": address
-                })
-            }).collect();
+                    "monero_address": address,
+                    "items": items,
+                    "tax_lines": tax_lines,
+                    "digital": digital,
+                    "shipments": order_shipments,
+                    "timeline": timeline
+                }));
+            }
             
             HttpResponse::Ok().json(json!({
                 "success": true,
@@ -335,7 +849,7 @@ pub async fn get_authenticated_user_orders(
 
 // Near the top of your file, add this debugging route
 #[get("/debug-orders")]
-pub async fn debug_orders(app_state: web::Data<AppState>) -> impl Responder {
+pub async fn debug_orders(_auth: Authorized<perm::OrdersRead>, app_state: web::Data<AppState>) -> impl Responder {
     // Print all orders in the database
     match sqlx::query("SELECT * FROM orders").fetch_all(&app_state.db).await {
         Ok(rows) => {
@@ -401,15 +915,87 @@ pub async fn debug_token_endpoint(req: HttpRequest) -> impl Responder {
 }
 
 // Add this function to your orders.rs file
+#[derive(Debug, Deserialize)]
+pub struct CancelOrderRequest {
+    pub reason: Option<String>,
+}
+
+// Let a customer cancel their own order while it is still unpaid
+#[post("/{order_id}/cancel")]
+pub async fn cancel_my_order(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: Option<web::Json<CancelOrderRequest>>,
+    app_state: web::Data<AppState>
+) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    let order_id = path.into_inner();
+    
+    // Someone else's order is reported as missing
+    let owner = sqlx::query("SELECT user_id FROM orders WHERE id = ?")
+        .bind(&order_id)
+        .fetch_optional(&app_state.db)
+        .await;
+    match owner {
+        Ok(Some(row)) if row.get::<Option<String>, _>("user_id").as_deref() == Some(claims.sub.as_str()) => {},
+        Ok(_) => {
+            return HttpResponse::NotFound().json(json!({
+                "success": false,
+                "error": "Order not found"
+            }));
+        },
+        Err(e) => {
+            error!("Failed to look up order {}: {}", order_id, e);
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to cancel order"
+            }));
+        }
+    }
+    
+    let reason = body.and_then(|b| b.into_inner().reason)
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty())
+        .unwrap_or_else(|| "Cancelled by customer".to_string());
+    let change = StatusChange::customer(Some(&claims.sub), &reason);
+    
+    match cancel_unpaid_order(&app_state.db, &order_id, &change).await {
+        Ok(previous) => {
+            if let Some(payment) = app_state.monero_payments.get_payment_by_order_id(&order_id) {
+                app_state.monero_payments.update_payment_status(&payment.payment_id, PaymentStatus::Expired);
+            }
+            app_state.notify_order_cancelled(&order_id, &reason);
+            info!("Customer {} cancelled order {}", claims.sub, order_id);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "order_id": order_id,
+                "status": OrderStatus::Cancelled,
+                "previous_status": previous
+            }))
+        },
+        Err(StatusError::NotAllowed { from, .. }) => HttpResponse::Conflict().json(json!({
+            "success": false,
+            "error": format!("Order is {} and can no longer be cancelled", from),
+            "current_status": from
+        })),
+        Err(e) => e.to_response(),
+    }
+}
+
 pub fn init_orders_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/orders")
             .service(debug_orders)
             .service(get_authenticated_user_orders)
+            .service(digital::get_order_deliveries)
             .service(get_order_status)
             .service(dump_order_data)
             .service(force_update_order_status)
             .service(diagnose_and_fix_status_mismatch)
+            .service(cancel_my_order)
     );
     
     // Register the debug-token endpoint at the root level
@@ -427,36 +1013,69 @@ pub async fn get_order_status(
     // Create a longer-lived value
     let order_id_str = order_id.as_ref().to_string();
     
-    match sqlx::query!(
+    match sqlx::query(
         r#"
-        SELECT id, status, total_amount, created_at, updated_at, payment_id,
+        SELECT id, status, total_amount, coupon_code, discount_amount, shipping_method_name, shipping_cost, tax_amount, prices_include_tax, created_at, updated_at, payment_id,
                shipping_name, shipping_address, shipping_city, shipping_state,
                shipping_zip, shipping_country
         FROM orders 
         WHERE id = ?
-        "#,
-        order_id_str  // Use the longer-lived value here
+        "#
     )
+    .bind(&order_id_str)  // Use the longer-lived value here
     .fetch_optional(&app_state.db)
     .await {
         Ok(Some(order)) => {
+            let items = match fetch_order_items(&app_state.db, &order_id_str).await {
+                Ok(items) => items,
+                Err(e) => {
+                    error!("Failed to fetch items for order {}: {}", order_id_str, e);
+                    Vec::new()
+                }
+            };
+            
+            let tax_lines = match tax::tax_lines_for_order(&app_state.db, &order_id_str).await {
+                Ok(lines) => lines,
+                Err(e) => {
+                    error!("Failed to fetch tax lines for order {}: {}", order_id_str, e);
+                    Vec::new()
+                }
+            };
+            
+            let order_shipments = match shipments::shipments_for_order(&app_state.db, &order_id_str).await {
+                Ok(order_shipments) => order_shipments,
+                Err(e) => {
+                    error!("Failed to fetch shipments for order {}: {}", order_id_str, e);
+                    Vec::new()
+                }
+            };
+            
             HttpResponse::Ok().json(json!({
                 "success": true,
                 "order": {
-                    "id": order.id,
-                    "status": order.status,
-                    "total_amount": order.total_amount,
-                    "created_at": order.created_at,
-                    "updated_at": order.updated_at,
-                    "payment_id": order.payment_id,
+                    "id": order.get::<String, _>("id"),
+                    "status": order.get::<String, _>("status"),
+                    "total_amount": order.get::<f64, _>("total_amount"),
+                    "coupon_code": order.get::<Option<String>, _>("coupon_code"),
+                    "discount_amount": order.get::<f64, _>("discount_amount"),
+                    "shipping_method_name": order.get::<Option<String>, _>("shipping_method_name"),
+                    "shipping_cost": order.get::<f64, _>("shipping_cost"),
+                    "tax_amount": order.get::<f64, _>("tax_amount"),
+                    "prices_include_tax": order.get::<bool, _>("prices_include_tax"),
+                    "created_at": order.get::<i64, _>("created_at"),
+                    "updated_at": order.get::<i64, _>("updated_at"),
+                    "payment_id": order.get::<Option<String>, _>("payment_id"),
                     "shipping": {
-                        "name": order.shipping_name,
-                        "address": order.shipping_address,
-                        "city": order.shipping_city,
-                        "state": order.shipping_state,
-                        "zip": order.shipping_zip,
-                        "country": order.shipping_country
-                    }
+                        "name": order.get::<String, _>("shipping_name"),
+                        "address": order.get::<String, _>("shipping_address"),
+                        "city": order.get::<String, _>("shipping_city"),
+                        "state": order.get::<String, _>("shipping_state"),
+                        "zip": order.get::<String, _>("shipping_zip"),
+                        "country": order.get::<String, _>("shipping_country")
+                    },
+                    "items": items,
+                    "tax_lines": tax_lines,
+                    "shipments": order_shipments
                 }
             }))
         },
@@ -479,6 +1098,7 @@ pub async fn get_order_status(
 // Add this endpoint to see the raw order data
 #[get("/debug/dump-order/{order_id}")]
 pub async fn dump_order_data(
+    _auth: Authorized<perm::OrdersRead>,
     app_state: web::Data<AppState>,
     path: web::Path<String>
 ) -> impl Responder {
@@ -539,6 +1159,7 @@ pub async fn dump_order_data(
 // Add a direct endpoint to force update order status
 #[post("/admin/force-update-order/{order_id}/{status}")]
 pub async fn force_update_order_status(
+    auth: Authorized<perm::PaymentsConfirm>,
     app_state: web::Data<AppState>,
     path: web::Path<(String, String)>
 ) -> impl Responder {
@@ -546,57 +1167,36 @@ pub async fn force_update_order_status(
     
     log::info!("🔨 Manually forcing order {} status to {}", order_id, status);
     
-    // First update the order status
-    match sqlx::query!(
-        "UPDATE orders SET status = ? WHERE id = ?",
-        status,
-        order_id
-    )
-    .execute(&app_state.db)
-    .await {
-        Ok(_) => {
-            // Then find the payment_id for this order
-            let payment_query = sqlx::query!(
-                "SELECT payment_id FROM orders WHERE id = ?",
-                order_id
-            )
-            .fetch_optional(&app_state.db)
-            .await;
-            
-            // Now update the payment status if we found a payment_id
-            match payment_query {
-                Ok(Some(record)) if record.payment_id.is_some() => {
-                    let payment_id = record.payment_id.unwrap();
-                    
-                    // Update the payment status directly - SQLite doesn't support JOIN in UPDATE
-                    let payment_update = sqlx::query!(
-                        "UPDATE monero_payments SET status = ? WHERE payment_id = ?",
-                        status,
-                        payment_id
-                    )
+    let status: OrderStatus = match status.parse() {
+        Ok(status) => status,
+        Err(e) => return e.to_response(),
+    };
+    
+    // Forcing is still bound by the state machine
+    let change = StatusChange::admin(&auth.claims.username, Some("Forced status update".to_string()));
+    match change_status(&app_state.db, &order_id, status, &change).await {
+        Ok(previous) => {
+            // A forced payment keeps the payment record in step with the order
+            if status == OrderStatus::Paid && previous != OrderStatus::Paid {
+                match sqlx::query("UPDATE monero_payments SET status = 'Confirmed' WHERE payment_id = (SELECT payment_id FROM orders WHERE id = ?)")
+                    .bind(&order_id)
                     .execute(&app_state.db)
-                    .await;
-                    
-                    if let Err(e) = payment_update {
-                        log::warn!("Couldn't update payment status: {}", e);
-            } else {
-                        log::info!("Successfully updated payment status for payment_id: {}", payment_id);
+                    .await {
+                        Ok(_) => log::info!("Marked payment for order {} as confirmed", order_id),
+                        Err(e) => log::warn!("Couldn't update payment status: {}", e)
                     }
-                },
-                Ok(_) => log::warn!("No payment_id found for order {}", order_id),
-                Err(e) => log::error!("Error looking up payment_id: {}", e)
+                process_paid_orders(&app_state.db, std::slice::from_ref(&order_id)).await;
             }
             
             HttpResponse::Ok().json(json!({
                 "success": true,
-                "message": format!("Order {} status updated to {}", order_id, status)
+                "message": format!("Order {} status updated to {}", order_id, status),
+                "previous_status": previous
             }))
         },
         Err(e) => {
-            log::error!("Failed to update order status: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to update status: {}", e)
-            }))
+            log::warn!("Rejected forced status change for order {}: {}", order_id, e);
+            e.to_response()
         }
     }
 }
@@ -604,6 +1204,7 @@ pub async fn force_update_order_status(
 // Add a special diagnostic endpoint for debugging payment/order status issues
 #[post("/fix-order-status-mismatch")]
 pub async fn diagnose_and_fix_status_mismatch(
+    _auth: Authorized<perm::PaymentsConfirm>,
     app_state: web::Data<AppState>
 ) -> impl Responder {
     log::info!("🔍 RUNNING FULL DIAGNOSTIC OF PAYMENT STATUS MISMATCH");
@@ -685,36 +1286,38 @@ pub async fn diagnose_and_fix_status_mismatch(
             let order_id: String = row.get("id");
             let order_status: String = row.get("status");
             
-            if order_status != "Confirmed" && order_status != "Completed" {
+            let unpaid = matches!(order_status.parse(), Ok(OrderStatus::Pending) | Ok(OrderStatus::AwaitingPayment));
+            if unpaid {
                 log::warn!("⚠️ Found status mismatch! Payment {} is confirmed but order {} has status {}", 
                            payment_id, order_id, order_status);
                 
                 // This is a mismatch - let's fix it
-                match sqlx::query!(
-                    "UPDATE orders SET status = ? WHERE id = ?",
-                    "Confirmed",
-                    order_id
-                )
-                .execute(&app_state.db)
-                .await {
+                let result = async {
+                    let mut conn = app_state.db.acquire().await?;
+                    let change = StatusChange::system("Payment confirmed but order was unpaid");
+                    transition_status(&mut conn, &order_id, OrderStatus::Paid, &change).await
+                }.await;
+                match result {
                     Ok(_) => {
-                        log::info!("✅ Successfully fixed order {} status to Confirmed", order_id);
+                        // Stock and digital delivery follow the same path as a live payment
+                        process_paid_orders(&app_state.db, std::slice::from_ref(&order_id)).await;
+                        log::info!("✅ Successfully fixed order {} status to Paid", order_id);
                         fixed_orders += 1;
                         diagnostic_info.push(json!({
                             "type": "fixed",
                             "order_id": order_id,
                             "payment_id": payment_id,
                             "old_status": order_status,
-                            "new_status": "Confirmed"
+                            "new_status": OrderStatus::Paid
                         }));
                     },
-        Err(e) => {
+                    Err(e) => {
                         log::error!("Failed to update order status: {}", e);
                         diagnostic_info.push(json!({
                             "type": "error",
                             "order_id": order_id,
                             "payment_id": payment_id,
-                            "error": format!("Database error: {}", e)
+                            "error": e.to_string()
                         }));
                     }
                 }
@@ -743,3 +1346,217 @@ pub async fn diagnose_and_fix_status_mismatch(
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    const SCHEMA: [&str; 7] = [
+        "CREATE TABLE orders (id TEXT PRIMARY KEY, user_id TEXT, status TEXT NOT NULL, shipping_name TEXT, shipping_address TEXT, shipping_city TEXT, shipping_state TEXT, shipping_zip TEXT, shipping_country TEXT, shipping_email TEXT, total_amount REAL NOT NULL, payment_id TEXT, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL)",
+        "CREATE TABLE order_items (id INTEGER PRIMARY KEY AUTOINCREMENT, order_number TEXT NOT NULL, product_id TEXT NOT NULL, variant_id TEXT, sku TEXT, variant_name TEXT, variant_options TEXT, quantity INTEGER NOT NULL, price REAL NOT NULL)",
+        "CREATE TABLE order_status_history (id INTEGER PRIMARY KEY AUTOINCREMENT, order_id TEXT NOT NULL, from_status TEXT, to_status TEXT NOT NULL, actor TEXT NOT NULL, reason TEXT, created_at INTEGER NOT NULL)",
+        "CREATE TABLE products (id TEXT PRIMARY KEY, price REAL NOT NULL, stock INTEGER NOT NULL CHECK (stock >= 0), reserved INTEGER NOT NULL DEFAULT 0 CHECK (reserved >= 0 AND reserved <= stock), archived_at INTEGER)",
+        "CREATE TABLE stock_reservations (id INTEGER PRIMARY KEY AUTOINCREMENT, order_id TEXT NOT NULL, product_id TEXT NOT NULL, variant_id TEXT, quantity INTEGER NOT NULL, status TEXT NOT NULL, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL)",
+        "CREATE TABLE stock_adjustments (id INTEGER PRIMARY KEY AUTOINCREMENT, product_id TEXT NOT NULL, variant_id TEXT, delta INTEGER NOT NULL, reason TEXT NOT NULL, actor TEXT NOT NULL, order_id TEXT, created_at INTEGER NOT NULL)",
+        "CREATE TABLE monero_payments (payment_id TEXT PRIMARY KEY, status TEXT NOT NULL, updated_at INTEGER NOT NULL)",
+    ];
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in SCHEMA.into_iter().chain([
+            "INSERT INTO products (id, price, stock) VALUES ('book', 12.5, 5)",
+        ]) {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    async fn place_order(db: &SqlitePool, quantity: u32) -> Result<Order, StockError> {
+        let shipping = ShippingInfo {
+            name: "Ada".to_string(),
+            address: "1 Lane".to_string(),
+            city: "Town".to_string(),
+            state: String::new(),
+            zip: "12345".to_string(),
+            country: "DE".to_string(),
+            email: "ada@example.com".to_string(),
+        };
+        let item = OrderItem { product_id: "book".to_string(), variant_id: None, quantity, price: 0.0 };
+        create_order(db, shipping, vec![item], Some("usr-1".to_string())).await
+    }
+
+    async fn count(db: &SqlitePool, table: &str) -> i64 {
+        sqlx::query(&format!("SELECT COUNT(*) AS n FROM {}", table))
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .get("n")
+    }
+
+    // An order waiting on a Monero payment that is still pending
+    async fn place_order_with_payment(db: &SqlitePool) -> String {
+        let order = place_order(db, 2).await.unwrap();
+        let payment_id = format!("pay-{}", order.id);
+        sqlx::query("UPDATE orders SET payment_id = ? WHERE id = ?")
+            .bind(&payment_id)
+            .bind(&order.id)
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO monero_payments (payment_id, status, updated_at) VALUES (?, 'Pending', 0)")
+            .bind(&payment_id)
+            .execute(db)
+            .await
+            .unwrap();
+        order.id
+    }
+
+    // (stock, reserved) of the product every test order buys
+    async fn stock(db: &SqlitePool) -> (i64, i64) {
+        let row = sqlx::query("SELECT stock, reserved FROM products WHERE id = 'book'")
+            .fetch_one(db)
+            .await
+            .unwrap();
+        (row.get("stock"), row.get("reserved"))
+    }
+
+    async fn payment_status(db: &SqlitePool, order_id: &str) -> String {
+        sqlx::query("SELECT mp.status FROM monero_payments mp JOIN orders o ON o.payment_id = mp.payment_id WHERE o.id = ?")
+            .bind(order_id)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .get("status")
+    }
+
+    async fn order_status(db: &SqlitePool, order_id: &str) -> String {
+        sqlx::query("SELECT status FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .get("status")
+    }
+
+    async fn pay(db: &SqlitePool, order_id: &str) {
+        let mut conn = db.acquire().await.unwrap();
+        mark_paid(&mut conn, order_id).await.unwrap();
+    }
+
+    #[test]
+    fn status_names_round_trip() {
+        for status in OrderStatus::ALL {
+            assert_eq!(status.to_string().parse::<OrderStatus>().unwrap(), status);
+        }
+        assert_eq!(" awaitingpayment ".parse::<OrderStatus>().unwrap(), OrderStatus::AwaitingPayment);
+        assert!(matches!("Lost".parse::<OrderStatus>(), Err(StatusError::Unknown(_))));
+
+        let err = StatusError::NotAllowed { from: OrderStatus::Completed, to: OrderStatus::Paid };
+        assert!(err.to_string().contains("Completed is final"), "{}", err);
+    }
+
+    #[actix_web::test]
+    async fn placing_an_order_starts_its_history() {
+        let db = database().await;
+
+        // The line is charged the catalogue price, whatever the client sent
+        let order = place_order(&db, 2).await.unwrap();
+        assert_eq!(order.total_amount, 25.0);
+
+        let history = status_history(&db, &order.id).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].from_status, None);
+        assert_eq!(history[0].to_status, "AwaitingPayment");
+        assert_eq!(history[0].actor, "customer:usr-1");
+        assert_eq!(history[0].reason.as_deref(), Some("Order placed"));
+
+        // Customers don't see user ids or staff names in their timeline
+        let admin = StatusChange::admin("root", None);
+        change_status(&db, &order.id, OrderStatus::Paid, &admin).await.unwrap();
+        let actors: Vec<_> = customer_timeline(&db, &order.id).await.unwrap().into_iter().map(|e| e.actor).collect();
+        assert_eq!(actors, ["customer", "admin"]);
+    }
+
+    #[actix_web::test]
+    async fn a_failed_order_leaves_no_history() {
+        let db = database().await;
+
+        assert!(matches!(place_order(&db, 6).await, Err(StockError::Insufficient { requested: 6, available: 5, .. })));
+        for table in ["orders", "order_items", "order_status_history", "stock_reservations"] {
+            assert_eq!(count(&db, table).await, 0, "{}", table);
+        }
+    }
+
+    #[test]
+    fn only_unpaid_orders_are_customer_cancellable() {
+        let cancellable: Vec<_> = OrderStatus::ALL.into_iter().filter(|s| customer_can_cancel(*s)).collect();
+        assert_eq!(cancellable, [OrderStatus::Pending, OrderStatus::AwaitingPayment]);
+    }
+
+    #[actix_web::test]
+    async fn cancelling_releases_stock_and_expires_the_payment() {
+        let db = database().await;
+        let order_id = place_order_with_payment(&db).await;
+        assert_eq!(stock(&db).await, (5, 2));
+
+        let change = StatusChange::customer(Some("usr-1"), "Changed my mind");
+        assert_eq!(cancel_unpaid_order(&db, &order_id, &change).await.unwrap(), OrderStatus::AwaitingPayment);
+        assert_eq!(stock(&db).await, (5, 0));
+        assert_eq!(payment_status(&db, &order_id).await, "Expired");
+        let last = status_history(&db, &order_id).await.unwrap().pop().unwrap();
+        assert_eq!((last.to_status.as_str(), last.reason.as_deref()), ("Cancelled", Some("Changed my mind")));
+
+        // Cancelling twice is refused rather than releasing the stock again
+        assert!(matches!(
+            cancel_unpaid_order(&db, &order_id, &change).await,
+            Err(StatusError::NotAllowed { from: OrderStatus::Cancelled, .. })
+        ));
+        assert_eq!(stock(&db).await, (5, 0));
+    }
+
+    #[actix_web::test]
+    async fn paid_orders_are_not_cancelled() {
+        let db = database().await;
+        let order_id = place_order_with_payment(&db).await;
+        pay(&db, &order_id).await;
+
+        let change = StatusChange::customer(Some("usr-1"), "Too late");
+        assert!(matches!(
+            cancel_unpaid_order(&db, &order_id, &change).await,
+            Err(StatusError::NotAllowed { from: OrderStatus::Paid, to: OrderStatus::Cancelled })
+        ));
+        assert_eq!(stock(&db).await, (5, 2));
+        assert_eq!(payment_status(&db, &order_id).await, "Pending");
+        assert!(matches!(
+            cancel_unpaid_order(&db, "ORD-missing", &change).await,
+            Err(StatusError::NotFound)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn expired_payments_cancel_only_unpaid_orders() {
+        let db = database().await;
+        let unpaid = place_order_with_payment(&db).await;
+        let paid = place_order_with_payment(&db).await;
+        pay(&db, &paid).await;
+
+        let expired = [unpaid.clone(), paid.clone(), "ORD-missing".to_string()];
+        assert_eq!(cancel_expired_orders(&db, &expired).await, vec![unpaid.clone()]);
+
+        assert_eq!(order_status(&db, &unpaid).await, "Cancelled");
+        assert_eq!(order_status(&db, &paid).await, "Paid");
+        assert_eq!(stock(&db).await, (5, 2));
+        let actor: String = sqlx::query("SELECT actor FROM order_status_history WHERE to_status = 'Cancelled'")
+            .fetch_one(&db)
+            .await
+            .unwrap()
+            .get("actor");
+        assert_eq!(actor, "payment");
+    }
+}
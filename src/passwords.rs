@@ -0,0 +1,427 @@
+//! Password hashing and the password policy.
+//!
+//! New hashes are Argon2id. Hashes from before the switch are bcrypt; both
+//! verify, and `needs_rehash` tells login to replace any hash that isn't
+//! Argon2id with the current parameters.
+//!
+//! - `ARGON2_MEMORY_KIB`, `ARGON2_ITERATIONS`, `ARGON2_PARALLELISM`: hashing
+//!   cost (defaults 19456, 2, 1)
+//! - `PASSWORD_MIN_LENGTH`: shortest accepted password (default 10)
+//! - `PASSWORD_BREACHED_LIST`: file of passwords that are refused, one per
+//!   line, either in plain text or as SHA-1 hex (`HASH` or `HASH:count`, as
+//!   published by Have I Been Pwned). Defaults to
+//!   `data/breached_passwords.txt` when that file exists. Lists up to 16 MiB
+//!   are read into memory. Larger ones are searched on disk and must hold
+//!   only SHA-1 hashes sorted by hash, like the Have I Been Pwned
+//!   "ordered by hash" download.
+
+use actix_web::HttpResponse;
+use argon2::{Algorithm, Argon2, Params, Version};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use log::{info, warn};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+pub const DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+pub const DEFAULT_ITERATIONS: u32 = 2;
+pub const DEFAULT_PARALLELISM: u32 = 1;
+pub const DEFAULT_MIN_LENGTH: usize = 10;
+/// Longer passwords are refused rather than hashed
+pub const MAX_LENGTH: usize = 128;
+const DEFAULT_BREACHED_LIST: &str = "data/breached_passwords.txt";
+/// Breached-password lists larger than this are searched on disk
+const MAX_LOADED_LIST_BYTES: u64 = 16 * 1024 * 1024;
+
+static CONFIG: OnceLock<PasswordConfig> = OnceLock::new();
+/// Hash checked for usernames that don't exist
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+/// Hashing parameters and policy, loaded once
+pub struct PasswordConfig {
+    params: Params,
+    min_length: usize,
+    breached: BreachedList,
+}
+
+/// Passwords refused by the policy
+enum BreachedList {
+    /// Lowercase SHA-1 hex of every breached password
+    Loaded(HashSet<String>),
+    /// A file of SHA-1 hashes sorted by hash, too large to read into memory
+    Sorted { path: PathBuf, len: u64 },
+}
+
+impl BreachedList {
+    fn contains(&self, hash: &str) -> bool {
+        match self {
+            BreachedList::Loaded(hashes) => hashes.contains(hash),
+            BreachedList::Sorted { path, len } => search_sorted_list(path, *len, hash).unwrap_or_else(|e| {
+                log::error!("Couldn't search breached password list {}: {}", path.display(), e);
+                false
+            }),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            BreachedList::Loaded(hashes) => format!("{} breached passwords refused", hashes.len()),
+            BreachedList::Sorted { path, .. } => format!("breached passwords in {} refused", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PasswordError {
+    /// The password doesn't meet the policy (400)
+    Policy(String),
+    /// Hashing failed (500)
+    Hash(String),
+}
+
+impl std::fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordError::Policy(e) => write!(f, "{}", e),
+            PasswordError::Hash(e) => write!(f, "Failed to hash password: {}", e),
+        }
+    }
+}
+
+impl PasswordError {
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            PasswordError::Policy(e) => HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": e
+            })),
+            PasswordError::Hash(_) => {
+                log::error!("{}", self);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to process password"
+                }))
+            },
+        }
+    }
+}
+
+fn env_number<T: std::str::FromStr>(var: &str, default: T) -> Result<T, String> {
+    match std::env::var(var) {
+        Ok(value) => value.trim().parse().map_err(|_| format!("{} must be a number, got '{}'", var, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn sha1_hex(password: &str) -> String {
+    hex::encode(Sha1::digest(password.as_bytes()))
+}
+
+/// The SHA-1 hash at the start of a list line, lowercased, if there is one
+fn line_hash(line: &str) -> Option<String> {
+    let hash = line.split(':').next().unwrap_or(line).trim();
+    (hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit())).then(|| hash.to_ascii_lowercase())
+}
+
+/// Read a breached-password list; see the module docs for the format.
+fn load_breached_list(path: &str) -> Result<BreachedList, String> {
+    let len = std::fs::metadata(path)
+        .map_err(|e| format!("Couldn't read breached password list {}: {}", path, e))?
+        .len();
+    if len > MAX_LOADED_LIST_BYTES {
+        return open_sorted_list(path, len);
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read breached password list {}: {}", path, e))?;
+
+    Ok(BreachedList::Loaded(contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line_hash(line).unwrap_or_else(|| sha1_hex(line)))
+        .collect()))
+}
+
+/// Check that a large list looks like sorted SHA-1 hashes before relying on it
+fn open_sorted_list(path: &str, len: u64) -> Result<BreachedList, String> {
+    let mut reader = BufReader::new(File::open(path)
+        .map_err(|e| format!("Couldn't read breached password list {}: {}", path, e))?);
+    let mut first = String::new();
+    reader.read_line(&mut first)
+        .map_err(|e| format!("Couldn't read breached password list {}: {}", path, e))?;
+    if line_hash(&first).is_none() {
+        return Err(format!(
+            "Breached password list {} is over {} MiB, so it must hold only SHA-1 hashes sorted by hash",
+            path, MAX_LOADED_LIST_BYTES / (1024 * 1024)
+        ));
+    }
+    Ok(BreachedList::Sorted { path: PathBuf::from(path), len })
+}
+
+/// The first whole line starting at or after `pos`, with the offset just past it
+fn line_from(reader: &mut BufReader<File>, pos: u64) -> std::io::Result<Option<(String, u64)>> {
+    let mut skipped = Vec::new();
+    let start = if pos == 0 {
+        reader.seek(SeekFrom::Start(0))?;
+        0
+    } else {
+        // Step back one byte so a line starting exactly at `pos` isn't skipped
+        reader.seek(SeekFrom::Start(pos - 1))?;
+        pos - 1 + reader.read_until(b'\n', &mut skipped)? as u64
+    };
+    let mut line = String::new();
+    let read = reader.read_line(&mut line)?;
+    Ok((read > 0).then_some((line, start + read as u64)))
+}
+
+/// Binary search a sorted list for a lowercase SHA-1 hex hash
+fn search_sorted_list(path: &std::path::Path, len: u64, hash: &str) -> std::io::Result<bool> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let Some((line, end)) = line_from(&mut reader, mid)? else {
+            high = mid;
+            continue;
+        };
+        match line_hash(&line).as_deref().map(|line_hash| line_hash.cmp(hash)) {
+            Some(std::cmp::Ordering::Equal) => return Ok(true),
+            Some(std::cmp::Ordering::Less) => low = end,
+            _ => high = mid,
+        }
+    }
+    Ok(false)
+}
+
+impl PasswordConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let params = Params::new(
+            env_number("ARGON2_MEMORY_KIB", DEFAULT_MEMORY_KIB)?,
+            env_number("ARGON2_ITERATIONS", DEFAULT_ITERATIONS)?,
+            env_number("ARGON2_PARALLELISM", DEFAULT_PARALLELISM)?,
+            None,
+        )
+        .map_err(|e| format!("Invalid Argon2 parameters: {}", e))?;
+
+        let min_length = env_number("PASSWORD_MIN_LENGTH", DEFAULT_MIN_LENGTH)?;
+        if min_length > MAX_LENGTH {
+            return Err(format!("PASSWORD_MIN_LENGTH can't exceed {}", MAX_LENGTH));
+        }
+
+        let breached = match std::env::var("PASSWORD_BREACHED_LIST") {
+            Ok(path) => load_breached_list(&path)?,
+            Err(_) if std::path::Path::new(DEFAULT_BREACHED_LIST).exists() => load_breached_list(DEFAULT_BREACHED_LIST)?,
+            Err(_) => {
+                warn!("No breached password list configured; set PASSWORD_BREACHED_LIST");
+                BreachedList::Loaded(HashSet::new())
+            },
+        };
+
+        Ok(PasswordConfig { params, min_length, breached })
+    }
+}
+
+/// Load the configuration from the environment; called once at startup so a
+/// bad setting stops the server instead of falling back to defaults.
+pub fn load_config_from_env() -> Result<(), String> {
+    let config = PasswordConfig::from_env()?;
+    info!(
+        "Hashing passwords with Argon2id (m={} KiB, t={}, p={}); {}",
+        config.params.m_cost(), config.params.t_cost(), config.params.p_cost(), config.breached.describe()
+    );
+    CONFIG.set(config).map_err(|_| "Password configuration is already loaded".to_string())?;
+    // Build the dummy hash now rather than during the first login
+    verify_dummy("");
+    Ok(())
+}
+
+fn config() -> &'static PasswordConfig {
+    CONFIG.get_or_init(|| PasswordConfig::from_env().unwrap_or_else(|e| {
+        warn!("{}; using default password settings", e);
+        PasswordConfig {
+            params: Params::default(),
+            min_length: DEFAULT_MIN_LENGTH,
+            breached: BreachedList::Loaded(HashSet::new()),
+        }
+    }))
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, config().params.clone())
+}
+
+/// Refuse passwords that are too short or too long, match the username, or
+/// appear in the breached-password list.
+pub fn check_policy(password: &str, username: &str) -> Result<(), PasswordError> {
+    let config = config();
+    let length = password.chars().count();
+    if length < config.min_length {
+        return Err(PasswordError::Policy(format!(
+            "Password must be at least {} characters", config.min_length
+        )));
+    }
+    if length > MAX_LENGTH {
+        return Err(PasswordError::Policy(format!("Password can't be longer than {} characters", MAX_LENGTH)));
+    }
+    if password.eq_ignore_ascii_case(username) {
+        return Err(PasswordError::Policy("Password can't be the same as the username".to_string()));
+    }
+    if config.breached.contains(&sha1_hex(password)) {
+        return Err(PasswordError::Policy(
+            "This password has appeared in a data breach; choose a different one".to_string()
+        ));
+    }
+    Ok(())
+}
+
+/// Hash a password for storage in `users.password_hash`.
+pub fn hash_password(password: &str) -> Result<String, PasswordError> {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| PasswordError::Hash(e.to_string()))
+}
+
+fn is_bcrypt(stored: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$"].iter().any(|prefix| stored.starts_with(prefix))
+}
+
+/// Check a password against a stored Argon2 or bcrypt hash. Anything else
+/// never verifies.
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    if is_bcrypt(stored) {
+        return bcrypt::verify(password, stored).unwrap_or(false);
+    }
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => {
+            warn!("Stored password hash is in an unknown format");
+            false
+        },
+    }
+}
+
+/// Do the work of `verify_password` for a username that doesn't exist, so
+/// the response takes as long as for one that does.
+pub fn verify_dummy(password: &str) {
+    let dummy = DUMMY_HASH.get_or_init(|| {
+        hash_password("not a real password").unwrap_or_default()
+    });
+    let _ = verify_password(password, dummy);
+}
+
+/// Whether a hash that just verified should be replaced: it's bcrypt, another
+/// Argon2 variant, or Argon2id with different parameters.
+pub fn needs_rehash(stored: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(stored) else {
+        return true;
+    };
+    if hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+    let current = &config().params;
+    match Params::try_from(&hash) {
+        Ok(params) => {
+            params.m_cost() != current.m_cost()
+                || params.t_cost() != current.t_cost()
+                || params.p_cost() != current.p_cost()
+        },
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A sorted list in the Have I Been Pwned format, written to a temp file
+    fn sorted_list(passwords: &[&str]) -> (PathBuf, u64) {
+        let mut hashes: Vec<String> = passwords.iter().map(|p| sha1_hex(p).to_ascii_uppercase()).collect();
+        hashes.sort();
+        let contents: String = hashes.iter().enumerate().map(|(i, h)| format!("{}:{}\r\n", h, i + 1)).collect();
+        let path = std::env::temp_dir().join(format!("breached-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, &contents).unwrap();
+        (path, contents.len() as u64)
+    }
+
+    #[test]
+    fn sorted_lists_are_searched_on_disk() {
+        let listed: Vec<String> = (0..500).map(|i| format!("password{}", i)).collect();
+        let refs: Vec<&str> = listed.iter().map(String::as_str).collect();
+        let (path, len) = sorted_list(&refs);
+
+        for password in &listed {
+            assert!(search_sorted_list(&path, len, &sha1_hex(password)).unwrap(), "{}", password);
+        }
+        for password in ["password500", "correct horse battery staple", ""] {
+            assert!(!search_sorted_list(&path, len, &sha1_hex(password)).unwrap(), "{}", password);
+        }
+        // Hashes before the first line and after the last
+        assert!(!search_sorted_list(&path, len, &"0".repeat(40)).unwrap());
+        assert!(!search_sorted_list(&path, len, &"f".repeat(40)).unwrap());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn large_lists_must_be_hashes() {
+        let path = std::env::temp_dir().join(format!("breached-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "# a comment\npassword\n").unwrap();
+        let path_str = path.to_str().unwrap();
+        assert!(open_sorted_list(path_str, MAX_LOADED_LIST_BYTES + 1).is_err());
+
+        std::fs::write(&path, format!("{}:3\n", sha1_hex("password").to_ascii_uppercase())).unwrap();
+        assert!(matches!(open_sorted_list(path_str, MAX_LOADED_LIST_BYTES + 1), Ok(BreachedList::Sorted { .. })));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn small_lists_take_plain_text_and_hashes() {
+        let path = std::env::temp_dir().join(format!("breached-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, format!("# comment\nhunter22\n{}:12\n\n", sha1_hex("letmein").to_ascii_uppercase())).unwrap();
+        let list = load_breached_list(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(path);
+
+        assert!(list.contains(&sha1_hex("hunter22")));
+        assert!(list.contains(&sha1_hex("letmein")));
+        assert!(!list.contains(&sha1_hex("# comment")));
+        assert!(matches!(list, BreachedList::Loaded(hashes) if hashes.len() == 2));
+    }
+
+    #[test]
+    fn policy_refuses_short_and_username_passwords() {
+        assert!(check_policy("short", "alice").is_err());
+        assert!(check_policy(&"x".repeat(MAX_LENGTH + 1), "alice").is_err());
+        assert!(check_policy("Alice12345", "alice12345").is_err());
+        assert!(check_policy("a long enough passphrase", "alice").is_ok());
+    }
+
+    #[test]
+    fn only_current_argon2id_hashes_are_kept() {
+        let hash = hash_password("a long enough passphrase").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("a long enough passphrase", &hash));
+        assert!(!verify_password("a wrong passphrase", &hash));
+        assert!(!needs_rehash(&hash));
+
+        // bcrypt hashes from before the switch still verify but get replaced
+        let old = bcrypt::hash("a long enough passphrase", 4).unwrap();
+        assert!(verify_password("a long enough passphrase", &old));
+        assert!(needs_rehash(&old));
+
+        // So do Argon2id hashes with other parameters
+        let weak = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(8 * 1024, 1, 1, None).unwrap())
+            .hash_password(b"a long enough passphrase", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+        assert!(verify_password("a long enough passphrase", &weak));
+        assert!(needs_rehash(&weak));
+
+        assert!(!verify_password("anything", "plain text"));
+        assert!(needs_rehash("plain text"));
+    }
+}
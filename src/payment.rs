@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 use chrono::Utc;
 use uuid::Uuid;
 use crate::AppState;
+use crate::orders::{self, OrderStatus, StatusChange};
 use log::{info, error};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -149,13 +150,10 @@ pub async fn verify_payment(
             match update_result {
                 Ok(_) => {
                     // Also update the order status
-                    let order_update = sqlx::query!(
-                        "UPDATE orders SET status = ? WHERE id = ?",
-                        "paid",
-                        txn.order_id
-                    )
-                    .execute(&state.db)
-                    .await;
+                    let order_update = async {
+                        let mut conn = state.db.acquire().await?;
+                        orders::transition_status(&mut conn, &txn.order_id, OrderStatus::Paid, &StatusChange::payment("Payment verified")).await
+                    }.await;
                     
                     match order_update {
                         Ok(_) => {
@@ -169,10 +167,8 @@ pub async fn verify_payment(
                             )
                         }
                         Err(e) => {
-                            error!("Failed to update order status: {}", e);
-                            HttpResponse::InternalServerError().json(
-                                serde_json::json!({"error": "Payment verified but order update failed"})
-                            )
+                            error!("Payment verified but order {} was not updated: {}", txn.order_id, e);
+                            e.to_response()
                         }
                     }
                 }
@@ -212,13 +208,10 @@ pub async fn confirm_crypto_payment(
     // For demonstration, we'll assume the payment is valid
     
     // Update the order status
-    let update_result = sqlx::query!(
-        "UPDATE orders SET status = ? WHERE id = ?",
-        "paid", // Change to "completed" if you want to mark it as fulfilled immediately
-        confirmation.order_id
-    )
-    .execute(&state.db)
-    .await;
+    let update_result = async {
+        let mut conn = state.db.acquire().await?;
+        orders::transition_status(&mut conn, &confirmation.order_id, OrderStatus::Paid, &StatusChange::payment("Crypto payment confirmed")).await
+    }.await;
     
     match update_result {
         Ok(_) => {
@@ -250,7 +243,7 @@ pub async fn confirm_crypto_payment(
                         "success": true,
                         "order_id": confirmation.order_id,
                         "transaction_id": transaction_id,
-                        "status": "paid"
+                        "status": OrderStatus::Paid
                     }))
                 },
                 Err(e) => {
@@ -263,9 +256,7 @@ pub async fn confirm_crypto_payment(
         },
         Err(e) => {
             error!("Failed to update order status: {}", e);
-            HttpResponse::InternalServerError().json(
-                serde_json::json!({"error": "Failed to update order status"})
-            )
+            e.to_response()
         }
     }
 }
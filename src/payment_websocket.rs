@@ -34,7 +34,7 @@ impl WebsocketConnections {
     
     pub(crate) fn add_connection(&mut self, order_id: String, addr: Addr<PaymentWebsocket>) {
         self.connections.entry(order_id.clone())
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(addr);
         info!("New websocket connection for order {}, total: {}", 
             order_id, 
@@ -57,6 +57,14 @@ impl WebsocketConnections {
             }
         }
     }
+    
+    pub fn notify_order_cancelled(&self, order_id: &str, reason: &str) {
+        if let Some(conns) = self.connections.get(order_id) {
+            for conn in conns {
+                conn.do_send(OrderCancelledMessage(reason.to_string()));
+            }
+        }
+    }
 }
 
 // Message type for payment status updates
@@ -66,6 +74,13 @@ impl actix::Message for PaymentStatusMessage {
     type Result = ();
 }
 
+// Message sent when the order being watched is cancelled, with the reason
+struct OrderCancelledMessage(String);
+
+impl actix::Message for OrderCancelledMessage {
+    type Result = ();
+}
+
 impl Actor for PaymentWebsocket {
     type Context = ws::WebsocketContext<Self>;
     
@@ -180,6 +195,20 @@ impl actix::Handler<PaymentStatusMessage> for PaymentWebsocket {
     }
 }
 
+// Handle order cancellation
+impl actix::Handler<OrderCancelledMessage> for PaymentWebsocket {
+    type Result = ();
+    
+    fn handle(&mut self, msg: OrderCancelledMessage, ctx: &mut Self::Context) {
+        ctx.text(json!({
+            "type": "order_cancelled",
+            "status": "Cancelled",
+            "order_id": self.order_id,
+            "reason": msg.0
+        }).to_string());
+    }
+}
+
 impl PaymentWebsocket {
     // Heartbeat to keep the connection alive
     fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
@@ -0,0 +1,993 @@
+//! OpenPGP keys on accounts: passwordless login, account recovery, and key
+//! changes confirmed with a signature.
+//!
+//! A user attaches a public key (confirming with their password). To log in
+//! or recover the account they ask for a challenge, which is encrypted to
+//! that key; sending back the code inside proves they hold the private key.
+//! Replacing or removing the key needs a statement from the server signed
+//! with the current key.
+//!
+//! Keys are handled by the `gpg` binary (`GPG_PATH`, default `gpg`), run with
+//! a fresh home directory for every operation so no keyring is kept.
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder, get, post, put, delete};
+use chrono::{Duration, Utc};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{Row, SqliteConnection, SqlitePool};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::OnceLock;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use uuid::Uuid;
+use crate::AppState;
+use crate::auth;
+use crate::login_guard;
+use crate::passwords;
+use crate::session;
+
+/// How long a login or recovery challenge can be answered
+pub const CHALLENGE_MINUTES: i64 = 10;
+/// How long a key change waits for its signed confirmation
+pub const KEY_CHANGE_MINUTES: i64 = 30;
+/// Longest accepted armored public key
+pub const MAX_KEY_BYTES: usize = 64 * 1024;
+const GPG_TIMEOUT_SECONDS: u64 = 10;
+
+/// Challenges for accounts without a key are encrypted to this key, whose
+/// secret half was thrown away, so they look and take as long as real ones
+const DECOY_KEY: &str = "\
+-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEatVPrRYJKwYBBAHaRw8BAQdABGh9FLCgMquDnpQZ09vSBnN2W/fFx0sOa/nC
+zT+vBjK0IlNlY3VyZSBTdG9yZSBkZWNveSA8ZGVjb3lAaW52YWxpZD6IkAQTFggA
+OBYhBC5Pnj6loIKJdnDUOSEcBz1Uix/ZBQJq1U+tAhsBBQsJCAcCBhUKCQgLAgQW
+AgMBAh4BAheAAAoJECEcBz1Uix/ZoB0A+wZ7p2gbSEA38CCYkbC94uQSc/wZmhew
+mo4/YIKB9MnwAQC01qLA9SFlXP4Ymbi4OGJicHfqweSGl9JKuquRLiEjBLg4BGrV
+T60SCisGAQQBl1UBBQEBB0Av38drl9BY0aOs1oSbqvy0MPdKxlMJ2wX+7jQ4J1uf
+RwMBCAeIeAQYFggAIBYhBC5Pnj6loIKJdnDUOSEcBz1Uix/ZBQJq1U+tAhsMAAoJ
+ECEcBz1Uix/ZhbYBAKraCT+Ood1IUZpsBxypRJY6KeN3f46AOCeXHmWQFpdgAQDl
+k1BPYkgvSeI4uopzxRPjPhdFIYQJUjv5qTfeLtjpAg==
+=gxbF
+-----END PGP PUBLIC KEY BLOCK-----
+";
+const DECOY_KEY_FINGERPRINT: &str = "2E4F9E3EA5A082897670D439211C073D548B1FD9";
+/// Salt for the made-up fingerprints shown for accounts without a key
+static DECOY_SALT: OnceLock<[u8; 16]> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum PgpError {
+    /// `gpg` couldn't be run (503)
+    Unavailable(String),
+    /// The public key was refused (400)
+    InvalidKey(String),
+    /// The confirmation wasn't signed by the account's key, or says something else (400)
+    BadSignature(String),
+    /// Unknown, expired or used challenge, or wrong code (401)
+    InvalidChallenge,
+    /// No key on the account, or no key change waiting (400)
+    NoKey(String),
+    Gpg(String),
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for PgpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PgpError::Unavailable(e) => write!(f, "PGP support is not available: {}", e),
+            PgpError::InvalidKey(e) => write!(f, "Invalid public key: {}", e),
+            PgpError::BadSignature(e) => write!(f, "Confirmation refused: {}", e),
+            PgpError::InvalidChallenge => write!(f, "Invalid or expired challenge"),
+            PgpError::NoKey(e) => write!(f, "{}", e),
+            PgpError::Gpg(e) => write!(f, "gpg failed: {}", e),
+            PgpError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for PgpError {
+    fn from(e: sqlx::Error) -> Self {
+        PgpError::Database(e)
+    }
+}
+
+impl PgpError {
+    pub fn to_response(&self) -> HttpResponse {
+        let mut response = match self {
+            PgpError::InvalidKey(_) | PgpError::BadSignature(_) | PgpError::NoKey(_) => HttpResponse::BadRequest(),
+            PgpError::InvalidChallenge => HttpResponse::Unauthorized(),
+            PgpError::Unavailable(_) | PgpError::Gpg(_) | PgpError::Database(_) => {
+                error!("{}", self);
+                let mut response = if matches!(self, PgpError::Unavailable(_)) {
+                    HttpResponse::ServiceUnavailable()
+                } else {
+                    HttpResponse::InternalServerError()
+                };
+                return response.json(json!({
+                    "success": false,
+                    "error": "PGP operation failed"
+                }));
+            },
+        };
+        response.json(json!({
+            "success": false,
+            "error": self.to_string()
+        }))
+    }
+}
+
+/// A throwaway gpg home directory, deleted when dropped
+struct GpgHome {
+    path: PathBuf,
+}
+
+impl GpgHome {
+    fn new() -> Result<Self, PgpError> {
+        let path = std::env::temp_dir().join(format!("secure-store-gpg-{}", Uuid::new_v4().simple()));
+        std::fs::create_dir(&path).map_err(|e| PgpError::Gpg(format!("Couldn't create gpg home: {}", e)))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700));
+        }
+        Ok(GpgHome { path })
+    }
+
+    /// Run gpg with `input` on stdin and return stdout. Public-key work only,
+    /// so the agent is never started.
+    async fn run(&self, args: &[&str], input: &[u8]) -> Result<Vec<u8>, PgpError> {
+        let gpg = std::env::var("GPG_PATH").unwrap_or_else(|_| "gpg".to_string());
+        let mut child = Command::new(&gpg)
+            .arg("--homedir").arg(&self.path)
+            .args(["--batch", "--no-tty", "--no-autostart", "--no-auto-key-locate", "--no-auto-key-retrieve"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| PgpError::Unavailable(format!("couldn't run {}: {}", gpg, e)))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input).await.map_err(|e| PgpError::Gpg(e.to_string()))?;
+        }
+        let output = tokio::time::timeout(
+            std::time::Duration::from_secs(GPG_TIMEOUT_SECONDS),
+            child.wait_with_output(),
+        )
+        .await
+        .map_err(|_| PgpError::Gpg("timed out".to_string()))?
+        .map_err(|e| PgpError::Gpg(e.to_string()))?;
+
+        if !output.status.success() {
+            return Err(PgpError::Gpg(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+        Ok(output.stdout)
+    }
+
+    async fn import(&self, armored_key: &str) -> Result<(), PgpError> {
+        self.run(&["--import"], armored_key.as_bytes()).await.map(|_| ())
+    }
+}
+
+impl Drop for GpgHome {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A checked public key: one key that can encrypt, re-exported by gpg
+#[derive(Debug)]
+pub struct PublicKey {
+    pub fingerprint: String,
+    pub armored: String,
+}
+
+/// Check a pasted public key and work out its fingerprint.
+pub async fn inspect_key(armored_key: &str) -> Result<PublicKey, PgpError> {
+    if armored_key.len() > MAX_KEY_BYTES {
+        return Err(PgpError::InvalidKey("key is too large".to_string()));
+    }
+    if !armored_key.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+        return Err(PgpError::InvalidKey("expected an ASCII-armored public key block".to_string()));
+    }
+
+    let home = GpgHome::new()?;
+    home.import(armored_key).await.map_err(|e| match e {
+        PgpError::Gpg(e) => {
+            warn!("gpg refused a public key: {}", e);
+            PgpError::InvalidKey("not a readable OpenPGP public key".to_string())
+        },
+        e => e,
+    })?;
+    let listing = home.run(&["--with-colons", "--list-keys"], b"").await?;
+    let listing = String::from_utf8_lossy(&listing);
+
+    // pub:<validity>:...:<capabilities in field 12>, then fpr:...:<fingerprint in field 10>
+    let primaries: Vec<Vec<&str>> = listing.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .filter(|fields| fields[0] == "pub")
+        .collect();
+    let [primary] = primaries.as_slice() else {
+        return Err(PgpError::InvalidKey(format!("expected one key, found {}", primaries.len())));
+    };
+    match primary.get(1) {
+        Some(&"r") => return Err(PgpError::InvalidKey("key is revoked".to_string())),
+        Some(&"e") => return Err(PgpError::InvalidKey("key has expired".to_string())),
+        _ => {},
+    }
+    if !primary.get(11).is_some_and(|caps| caps.contains('E')) {
+        return Err(PgpError::InvalidKey("key has no usable encryption subkey".to_string()));
+    }
+    let fingerprint = listing.lines()
+        .find_map(|line| line.strip_prefix("fpr:"))
+        .and_then(|rest| rest.split(':').nth(8))
+        .filter(|fpr| !fpr.is_empty())
+        .ok_or_else(|| PgpError::InvalidKey("no fingerprint".to_string()))?
+        .to_string();
+
+    let armored = home.run(&["--armor", "--export", &fingerprint], b"").await?;
+    Ok(PublicKey {
+        fingerprint,
+        armored: String::from_utf8_lossy(&armored).into_owned(),
+    })
+}
+
+/// Encrypt a message to a stored key.
+async fn encrypt_to(key: &PublicKey, message: &str) -> Result<String, PgpError> {
+    let home = GpgHome::new()?;
+    home.import(&key.armored).await?;
+    let encrypted = home.run(
+        &["--trust-model", "always", "--armor", "--encrypt", "--recipient", &key.fingerprint],
+        message.as_bytes(),
+    ).await?;
+    Ok(String::from_utf8_lossy(&encrypted).into_owned())
+}
+
+/// Check a clearsigned message was signed by `key` and return the text that was signed.
+async fn verify_clearsigned(key: &PublicKey, signed_message: &str) -> Result<String, PgpError> {
+    let home = GpgHome::new()?;
+    home.import(&key.armored).await?;
+    let output = home.path.join("signed.txt");
+    let output_arg = output.to_string_lossy().into_owned();
+    let status = home.run(&["--status-fd", "1", "--output", &output_arg, "--decrypt"], signed_message.as_bytes())
+        .await
+        .map_err(|e| match e {
+            PgpError::Gpg(e) => {
+                warn!("gpg refused a signed message: {}", e);
+                PgpError::BadSignature("not a valid clearsigned message from this account's key".to_string())
+            },
+            e => e,
+        })?;
+
+    // VALIDSIG <signing key> ... <primary key fingerprint>
+    let status = String::from_utf8_lossy(&status);
+    let signed_by = status.lines()
+        .find_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .and_then(|rest| rest.split_whitespace().last())
+        .ok_or_else(|| PgpError::BadSignature("no valid signature".to_string()))?;
+    if !signed_by.eq_ignore_ascii_case(&key.fingerprint) {
+        return Err(PgpError::BadSignature("signed with a different key".to_string()));
+    }
+
+    std::fs::read_to_string(&output).map_err(|e| PgpError::Gpg(e.to_string()))
+}
+
+/// A stable, made-up fingerprint for a username without a key
+fn decoy_fingerprint(username: &str) -> String {
+    let salt = DECOY_SALT.get_or_init(rand::random);
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(username.to_lowercase().as_bytes());
+    hex::encode_upper(&hasher.finalize()[..20])
+}
+
+/// Clearsigning drops trailing whitespace; compare statements the same way
+fn normalize_statement(text: &str) -> String {
+    text.lines().map(str::trim_end).collect::<Vec<_>>().join("\n").trim().to_string()
+}
+
+fn hash_code(code: &str) -> String {
+    hex::encode(Sha256::digest(code.trim().to_ascii_lowercase().as_bytes()))
+}
+
+/// The key attached to an account
+pub async fn key_for_user(pool: &SqlitePool, user_id: &str) -> Result<Option<PublicKey>, sqlx::Error> {
+    let row = sqlx::query("SELECT fingerprint, public_key FROM user_pgp_keys WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(|row| PublicKey {
+        fingerprint: row.get("fingerprint"),
+        armored: row.get("public_key"),
+    }))
+}
+
+/// Fingerprint of the key attached to an account, for the profile
+pub async fn fingerprint_for_user(pool: &SqlitePool, user_id: &str) -> Result<Option<String>, sqlx::Error> {
+    Ok(key_for_user(pool, user_id).await?.map(|key| key.fingerprint))
+}
+
+async fn store_key(pool: &SqlitePool, user_id: &str, key: &PublicKey) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO user_pgp_keys (user_id, fingerprint, public_key, created_at) VALUES (?, ?, ?, ?)
+         ON CONFLICT(user_id) DO UPDATE SET fingerprint = excluded.fingerprint,
+            public_key = excluded.public_key, created_at = excluded.created_at"
+    )
+    .bind(user_id)
+    .bind(&key.fingerprint)
+    .bind(&key.armored)
+    .bind(Utc::now().timestamp())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Delete a user's key, key changes and challenges, ahead of deleting the user.
+pub async fn delete_user_keys(conn: &mut SqliteConnection, user_id: &str) -> Result<(), sqlx::Error> {
+    for table in ["pgp_challenges", "pgp_key_changes", "user_pgp_keys"] {
+        sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// What a challenge lets its answer do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengePurpose {
+    Login,
+    Recovery,
+}
+
+impl ChallengePurpose {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChallengePurpose::Login => "login",
+            ChallengePurpose::Recovery => "recovery",
+        }
+    }
+}
+
+/// Check an answer to a challenge and use the challenge up. Returns the user it was for.
+async fn redeem_challenge(
+    pool: &SqlitePool,
+    challenge_id: &str,
+    purpose: ChallengePurpose,
+    code: &str,
+) -> Result<String, PgpError> {
+    let row = sqlx::query(
+        "SELECT user_id, code_hash FROM pgp_challenges
+         WHERE id = ? AND purpose = ? AND user_id IS NOT NULL AND used_at IS NULL AND expires_at > ?"
+    )
+    .bind(challenge_id)
+    .bind(purpose.as_str())
+    .bind(Utc::now().timestamp())
+    .fetch_optional(pool)
+    .await?
+    .ok_or(PgpError::InvalidChallenge)?;
+    if row.get::<String, _>("code_hash") != hash_code(code) {
+        return Err(PgpError::InvalidChallenge);
+    }
+
+    let claimed = sqlx::query("UPDATE pgp_challenges SET used_at = ? WHERE id = ? AND used_at IS NULL")
+        .bind(Utc::now().timestamp())
+        .bind(challenge_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if claimed == 0 {
+        return Err(PgpError::InvalidChallenge);
+    }
+    Ok(row.get("user_id"))
+}
+
+/// Who a challenge was issued to
+struct ChallengeOwner {
+    /// The username as it was asked for; answers are throttled against it
+    requested: String,
+    /// Id, username and role of the account, or `None` for a decoy
+    account: Option<(String, String, String)>,
+}
+
+async fn challenge_owner(pool: &SqlitePool, challenge_id: &str) -> Result<Option<ChallengeOwner>, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT c.username AS requested, u.id, u.username, u.role
+         FROM pgp_challenges c LEFT JOIN users u ON u.id = c.user_id WHERE c.id = ?"
+    )
+    .bind(challenge_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| ChallengeOwner {
+        requested: row.get("requested"),
+        account: row.get::<Option<String>, _>("id").map(|id| (id, row.get("username"), row.get("role"))),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AttachKeyRequest {
+    pub public_key: String,
+    /// Needed when the account has no key yet
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmKeyChangeRequest {
+    pub signed_message: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChallengeRequest {
+    pub username: String,
+    pub purpose: ChallengePurpose,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChallengeAnswer {
+    pub challenge_id: String,
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecoveryRequest {
+    pub challenge_id: String,
+    pub code: String,
+    pub new_password: String,
+}
+
+// The key on the current user's account
+#[get("/pgp-key")]
+pub async fn get_key(req: HttpRequest, app_state: web::Data<AppState>) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    match key_for_user(&app_state.db, &claims.sub).await {
+        Ok(key) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "fingerprint": key.as_ref().map(|k| &k.fingerprint),
+            "public_key": key.as_ref().map(|k| &k.armored)
+        })),
+        Err(e) => PgpError::Database(e).to_response(),
+    }
+}
+
+/// Record a key change and return the statement the current key must sign.
+async fn request_key_change(
+    pool: &SqlitePool,
+    user_id: &str,
+    new_key: Option<&PublicKey>,
+) -> Result<String, sqlx::Error> {
+    let expires_at = Utc::now() + Duration::minutes(KEY_CHANGE_MINUTES);
+    let action = match new_key {
+        Some(key) => format!("Replace with key: {}", key.fingerprint),
+        None => "Remove key".to_string(),
+    };
+    let statement = format!(
+        "Secure Store PGP key change\nAccount: {}\n{}\nNonce: {}\nExpires: {}",
+        user_id,
+        action,
+        hex::encode(rand::random::<[u8; 16]>()),
+        expires_at.to_rfc3339(),
+    );
+
+    sqlx::query(
+        "INSERT INTO pgp_key_changes (user_id, fingerprint, public_key, statement, expires_at) VALUES (?, ?, ?, ?, ?)
+         ON CONFLICT(user_id) DO UPDATE SET fingerprint = excluded.fingerprint, public_key = excluded.public_key,
+            statement = excluded.statement, expires_at = excluded.expires_at"
+    )
+    .bind(user_id)
+    .bind(new_key.map(|k| &k.fingerprint))
+    .bind(new_key.map(|k| &k.armored))
+    .bind(&statement)
+    .bind(expires_at.timestamp())
+    .execute(pool)
+    .await?;
+    Ok(statement)
+}
+
+fn key_change_response(statement: String) -> HttpResponse {
+    HttpResponse::Accepted().json(json!({
+        "success": true,
+        "confirmation_required": true,
+        "statement": statement,
+        "expires_in": KEY_CHANGE_MINUTES * 60,
+        "message": "Clearsign this statement with your current key and send it to /auth/pgp-key/confirm"
+    }))
+}
+
+// Attach a key, or ask to replace the current one
+#[put("/pgp-key")]
+pub async fn attach_key(
+    req: HttpRequest,
+    body: web::Json<AttachKeyRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    let new_key = match inspect_key(&body.public_key).await {
+        Ok(key) => key,
+        Err(e) => return e.to_response(),
+    };
+    let current = match key_for_user(&app_state.db, &claims.sub).await {
+        Ok(current) => current,
+        Err(e) => return PgpError::Database(e).to_response(),
+    };
+
+    if current.is_some() {
+        return match request_key_change(&app_state.db, &claims.sub, Some(&new_key)).await {
+            Ok(statement) => key_change_response(statement),
+            Err(e) => PgpError::Database(e).to_response(),
+        };
+    }
+
+    // The first key only needs the password
+    let password_hash: Option<String> = match sqlx::query("SELECT password_hash FROM users WHERE id = ?")
+        .bind(&claims.sub)
+        .fetch_optional(&app_state.db)
+        .await {
+        Ok(row) => row.map(|row| row.get("password_hash")),
+        Err(e) => return PgpError::Database(e).to_response(),
+    };
+    let password_ok = match (&body.password, &password_hash) {
+        (Some(password), Some(hash)) => passwords::verify_password(password, hash),
+        _ => false,
+    };
+    if !password_ok {
+        warn!("Wrong password attaching a PGP key for {}", claims.sub);
+        return HttpResponse::Unauthorized().json(json!({"success": false, "error": "Invalid password"}));
+    }
+
+    match store_key(&app_state.db, &claims.sub, &new_key).await {
+        Ok(()) => {
+            info!("User {} attached PGP key {}", claims.sub, new_key.fingerprint);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "fingerprint": new_key.fingerprint
+            }))
+        },
+        Err(e) => PgpError::Database(e).to_response(),
+    }
+}
+
+// Ask to remove the key; confirmed like a replacement
+#[delete("/pgp-key")]
+pub async fn remove_key(req: HttpRequest, app_state: web::Data<AppState>) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    match key_for_user(&app_state.db, &claims.sub).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return PgpError::NoKey("No PGP key on this account".to_string()).to_response(),
+        Err(e) => return PgpError::Database(e).to_response(),
+    }
+    match request_key_change(&app_state.db, &claims.sub, None).await {
+        Ok(statement) => key_change_response(statement),
+        Err(e) => PgpError::Database(e).to_response(),
+    }
+}
+
+// Apply a key change with the statement clearsigned by the current key
+#[post("/pgp-key/confirm")]
+pub async fn confirm_key_change(
+    req: HttpRequest,
+    body: web::Json<ConfirmKeyChangeRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    let result = async {
+        let current = key_for_user(&app_state.db, &claims.sub).await?
+            .ok_or_else(|| PgpError::NoKey("No PGP key on this account".to_string()))?;
+        let pending = sqlx::query(
+            "SELECT fingerprint, public_key, statement FROM pgp_key_changes WHERE user_id = ? AND expires_at > ?"
+        )
+        .bind(&claims.sub)
+        .bind(Utc::now().timestamp())
+        .fetch_optional(&app_state.db)
+        .await?
+        .ok_or_else(|| PgpError::NoKey("No key change is waiting for confirmation".to_string()))?;
+
+        let signed_text = verify_clearsigned(&current, &body.signed_message).await?;
+        if normalize_statement(&signed_text) != normalize_statement(&pending.get::<String, _>("statement")) {
+            return Err(PgpError::BadSignature("the signed text is not the statement that was issued".to_string()));
+        }
+
+        let new_key = match (pending.get::<Option<String>, _>("fingerprint"), pending.get::<Option<String>, _>("public_key")) {
+            (Some(fingerprint), Some(armored)) => Some(PublicKey { fingerprint, armored }),
+            _ => None,
+        };
+        let mut tx = app_state.db.begin().await?;
+        match &new_key {
+            Some(key) => {
+                sqlx::query("UPDATE user_pgp_keys SET fingerprint = ?, public_key = ?, created_at = ? WHERE user_id = ?")
+                    .bind(&key.fingerprint)
+                    .bind(&key.armored)
+                    .bind(Utc::now().timestamp())
+                    .bind(&claims.sub)
+                    .execute(&mut *tx)
+                    .await?;
+            },
+            None => {
+                sqlx::query("DELETE FROM user_pgp_keys WHERE user_id = ?")
+                    .bind(&claims.sub)
+                    .execute(&mut *tx)
+                    .await?;
+            },
+        }
+        // Challenges encrypted to the old key stop working
+        for table in ["pgp_key_changes", "pgp_challenges"] {
+            sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+                .bind(&claims.sub)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(new_key.map(|key| key.fingerprint))
+    }.await;
+
+    match result {
+        Ok(fingerprint) => {
+            match &fingerprint {
+                Some(fingerprint) => info!("User {} replaced their PGP key with {}", claims.sub, fingerprint),
+                None => info!("User {} removed their PGP key", claims.sub),
+            }
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "fingerprint": fingerprint
+            }))
+        },
+        Err(e) => {
+            warn!("PGP key change for {} refused: {}", claims.sub, e);
+            e.to_response()
+        },
+    }
+}
+
+// A login or recovery code encrypted to the account's key. Usernames without
+// a key get a decoy that can't be answered, so the response doesn't show
+// which accounts exist or have a key.
+#[post("/pgp/challenge")]
+pub async fn create_challenge(
+    req: HttpRequest,
+    body: web::Json<ChallengeRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let (device, ip) = auth::client_info(&req);
+    let attempt = login_guard::Attempt { username: &body.username, ip: &ip, device: &device };
+    match login_guard::check(&app_state.db, &attempt).await {
+        Ok(None) => {},
+        Ok(Some(blocked)) => return blocked.to_response(),
+        Err(e) => return PgpError::Database(e).to_response(),
+    }
+    // Each challenge counts as a failed attempt until it is answered, so
+    // asking for challenges backs off like guessing passwords does
+    if let Err(e) = login_guard::record_failure(&app_state.db, &attempt, None).await {
+        return PgpError::Database(e).to_response();
+    }
+
+    let result = async {
+        let row = sqlx::query(
+            "SELECT u.id, k.fingerprint, k.public_key FROM users u
+             JOIN user_pgp_keys k ON k.user_id = u.id
+             WHERE u.username = ?"
+        )
+        .bind(&body.username)
+        .fetch_optional(&app_state.db)
+        .await?;
+
+        let code = hex::encode(rand::random::<[u8; 16]>());
+        let (user_id, key, fingerprint, code_hash) = match row {
+            Some(row) => {
+                let key = PublicKey { fingerprint: row.get("fingerprint"), armored: row.get("public_key") };
+                let fingerprint = key.fingerprint.clone();
+                (Some(row.get::<String, _>("id")), key, fingerprint, hash_code(&code))
+            },
+            None => {
+                let key = PublicKey { fingerprint: DECOY_KEY_FINGERPRINT.to_string(), armored: DECOY_KEY.to_string() };
+                // The stored hash is of a code nobody is sent
+                let unanswerable = hex::encode(rand::random::<[u8; 16]>());
+                (None, key, decoy_fingerprint(&body.username), hash_code(&unanswerable))
+            },
+        };
+
+        let challenge_id = format!("pch-{}", Uuid::new_v4().simple());
+        let now = Utc::now();
+        let expires_at = now + Duration::minutes(CHALLENGE_MINUTES);
+        let message = format!(
+            "Secure Store {} code for {}:\n\n{}\n\nThis code expires at {}.\n",
+            body.purpose.as_str(), body.username, code, expires_at.to_rfc3339(),
+        );
+        let encrypted = encrypt_to(&key, &message).await?;
+
+        // Older challenges for the username are dropped
+        sqlx::query("DELETE FROM pgp_challenges WHERE username = ? AND (used_at IS NOT NULL OR expires_at <= ?)")
+            .bind(&body.username)
+            .bind(now.timestamp())
+            .execute(&app_state.db)
+            .await?;
+        sqlx::query(
+            "INSERT INTO pgp_challenges (id, user_id, username, purpose, code_hash, created_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&challenge_id)
+        .bind(&user_id)
+        .bind(&body.username)
+        .bind(body.purpose.as_str())
+        .bind(code_hash)
+        .bind(now.timestamp())
+        .bind(expires_at.timestamp())
+        .execute(&app_state.db)
+        .await?;
+        Ok::<_, PgpError>((challenge_id, fingerprint, encrypted))
+    }.await;
+
+    match result {
+        Ok((challenge_id, fingerprint, encrypted)) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "challenge_id": challenge_id,
+            "fingerprint": fingerprint,
+            "encrypted_challenge": encrypted,
+            "expires_in": CHALLENGE_MINUTES * 60
+        })),
+        Err(e) => e.to_response(),
+    }
+}
+
+/// Count a wrong answer like a wrong password
+async fn record_wrong_answer(pool: &SqlitePool, req: &HttpRequest, challenge_id: &str) {
+    if let Ok(Some(owner)) = challenge_owner(pool, challenge_id).await {
+        let (device, ip) = auth::client_info(req);
+        let attempt = login_guard::Attempt { username: &owner.requested, ip: &ip, device: &device };
+        let user_id = owner.account.as_ref().map(|(id, _, _)| id.as_str());
+        if let Err(e) = login_guard::record_failure(pool, &attempt, user_id).await {
+            error!("Failed to record failed PGP login for {}: {}", owner.requested, e);
+        }
+    }
+}
+
+/// Refuse answers while the username is backing off or locked
+async fn check_throttle(pool: &SqlitePool, req: &HttpRequest, challenge_id: &str) -> Result<(), HttpResponse> {
+    let Ok(Some(owner)) = challenge_owner(pool, challenge_id).await else {
+        return Ok(());
+    };
+    let (device, ip) = auth::client_info(req);
+    let attempt = login_guard::Attempt { username: &owner.requested, ip: &ip, device: &device };
+    match login_guard::check(pool, &attempt).await {
+        Ok(None) => Ok(()),
+        Ok(Some(blocked)) => Err(blocked.to_response()),
+        Err(e) => Err(PgpError::Database(e).to_response()),
+    }
+}
+
+// Log in with the decrypted code instead of a password
+#[post("/pgp/login")]
+pub async fn login(req: HttpRequest, body: web::Json<ChallengeAnswer>, app_state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = check_throttle(&app_state.db, &req, &body.challenge_id).await {
+        return response;
+    }
+    let user_id = match redeem_challenge(&app_state.db, &body.challenge_id, ChallengePurpose::Login, &body.code).await {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            record_wrong_answer(&app_state.db, &req, &body.challenge_id).await;
+            return e.to_response();
+        }
+    };
+    let Ok(Some(ChallengeOwner { requested, account: Some((_, username, role)) })) =
+        challenge_owner(&app_state.db, &body.challenge_id).await else {
+        return PgpError::InvalidChallenge.to_response();
+    };
+
+    let (device, ip) = auth::client_info(&req);
+    let attempt = login_guard::Attempt { username: &requested, ip: &ip, device: &device };
+    auth::complete_first_factor(&app_state.db, &attempt, &user_id, &username, &role).await
+}
+
+// Set a new password with the decrypted code; every session is signed out
+#[post("/pgp/recover")]
+pub async fn recover(req: HttpRequest, body: web::Json<RecoveryRequest>, app_state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = check_throttle(&app_state.db, &req, &body.challenge_id).await {
+        return response;
+    }
+    // Check the password first so a weak one doesn't use up the challenge;
+    // decoys are checked against the username asked for, like real ones
+    let Ok(Some(owner)) = challenge_owner(&app_state.db, &body.challenge_id).await else {
+        return PgpError::InvalidChallenge.to_response();
+    };
+    let username = owner.account.as_ref().map_or(&owner.requested, |(_, username, _)| username);
+    let password_hash = match passwords::check_policy(&body.new_password, username)
+        .and_then(|_| passwords::hash_password(&body.new_password)) {
+        Ok(hash) => hash,
+        Err(e) => return e.to_response(),
+    };
+
+    let user_id = match redeem_challenge(&app_state.db, &body.challenge_id, ChallengePurpose::Recovery, &body.code).await {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            record_wrong_answer(&app_state.db, &req, &body.challenge_id).await;
+            return e.to_response();
+        }
+    };
+
+    let result = async {
+        sqlx::query("UPDATE users SET password_hash = ? WHERE id = ?")
+            .bind(&password_hash)
+            .bind(&user_id)
+            .execute(&app_state.db)
+            .await?;
+        session::revoke_all_sessions(&app_state.db, &user_id, None, "account recovery").await?;
+        login_guard::unlock(&app_state.db, &owner.requested).await?;
+        login_guard::unlock(&app_state.db, username).await
+    }.await;
+
+    match result {
+        Ok(_) => {
+            info!("User {} recovered their account with their PGP key", user_id);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "Password changed; sign in with the new password"
+            }))
+        },
+        Err(e) => PgpError::Database(e).to_response(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    /// A keyring holding private keys, standing in for the user's own gpg
+    struct Keyring {
+        home: GpgHome,
+    }
+
+    impl Keyring {
+        fn new() -> Self {
+            Keyring { home: GpgHome::new().unwrap() }
+        }
+
+        // The private-key side needs the agent, so this doesn't go through GpgHome::run
+        fn gpg(&self, args: &[&str], input: &[u8]) -> String {
+            use std::io::Write;
+            let mut child = std::process::Command::new("gpg")
+                .arg("--homedir").arg(&self.home.path)
+                .args(["--batch", "--no-tty", "--pinentry-mode", "loopback", "--passphrase", ""])
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(input).unwrap();
+            let output = child.wait_with_output().unwrap();
+            assert!(output.status.success(), "gpg {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+            String::from_utf8(output.stdout).unwrap()
+        }
+
+        /// Generate a key and return its armored public half
+        fn generate(&self, email: &str, algorithm: &str, usage: &str) -> String {
+            self.gpg(&["--quick-gen-key", &format!("Test <{}>", email), algorithm, usage, "never"], b"");
+            self.gpg(&["--armor", "--export", email], b"")
+        }
+    }
+
+    impl Drop for Keyring {
+        fn drop(&mut self) {
+            let _ = std::process::Command::new("gpgconf")
+                .arg("--homedir").arg(&self.home.path)
+                .args(["--kill", "gpg-agent"])
+                .status();
+        }
+    }
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::query(
+            "CREATE TABLE pgp_challenges (id TEXT PRIMARY KEY, user_id TEXT, username TEXT NOT NULL, purpose TEXT NOT NULL, code_hash TEXT NOT NULL, created_at INTEGER NOT NULL, expires_at INTEGER NOT NULL, used_at INTEGER)"
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        db
+    }
+
+    async fn add_challenge(db: &SqlitePool, id: &str, user_id: Option<&str>, code: &str, expires_at: i64) {
+        sqlx::query("INSERT INTO pgp_challenges (id, user_id, username, purpose, code_hash, created_at, expires_at) VALUES (?, ?, 'alice', 'login', ?, 0, ?)")
+            .bind(id)
+            .bind(user_id)
+            .bind(hash_code(code))
+            .bind(expires_at)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn codes_and_statements_compare_loosely() {
+        assert_eq!(hash_code(" C0DE \n"), hash_code("c0de"));
+        assert_ne!(hash_code("c0de"), hash_code("c0df"));
+        assert_eq!(normalize_statement("Account: usr-1  \r\nNonce: 42\t\n\n"), "Account: usr-1\nNonce: 42");
+
+        // Made-up fingerprints look real and don't change between requests
+        let decoy = decoy_fingerprint("Alice");
+        assert_eq!(decoy.len(), 40);
+        assert!(decoy.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_lowercase()));
+        assert_eq!(decoy, decoy_fingerprint("alice"));
+        assert_ne!(decoy, decoy_fingerprint("bob"));
+    }
+
+    #[actix_web::test]
+    async fn keys_are_checked_before_use() {
+        let decoy = inspect_key(DECOY_KEY).await.unwrap();
+        assert_eq!(decoy.fingerprint, DECOY_KEY_FINGERPRINT);
+        assert!(decoy.armored.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----"));
+
+        let oversized = format!("-----BEGIN PGP PUBLIC KEY BLOCK-----\n{}", "A".repeat(MAX_KEY_BYTES));
+        let garbage = "-----BEGIN PGP PUBLIC KEY BLOCK-----\n\nbm90IGEga2V5\n-----END PGP PUBLIC KEY BLOCK-----\n";
+        for bad in ["ssh-ed25519 AAAA", garbage, &oversized] {
+            assert!(matches!(inspect_key(bad).await, Err(PgpError::InvalidKey(_))), "{:.40}", bad);
+        }
+
+        // Challenges are encrypted, so a signing-only key is no use
+        let keyring = Keyring::new();
+        let signing_only = keyring.generate("sign@invalid", "ed25519", "sign");
+        assert!(matches!(inspect_key(&signing_only).await, Err(PgpError::InvalidKey(_))));
+    }
+
+    #[actix_web::test]
+    async fn only_the_key_holder_reads_challenges_and_signs_changes() {
+        let keyring = Keyring::new();
+        let key = inspect_key(&keyring.generate("alice@invalid", "future-default", "default")).await.unwrap();
+
+        let encrypted = encrypt_to(&key, "Secure Store login code: c0de\n").await.unwrap();
+        assert!(!encrypted.contains("c0de"));
+        assert!(keyring.gpg(&["--decrypt"], encrypted.as_bytes()).contains("c0de"));
+
+        let statement = "Secure Store PGP key change\nAccount: usr-1\nRemove key";
+        let signed = keyring.gpg(&["--local-user", "alice@invalid", "--clearsign"], statement.as_bytes());
+        let text = verify_clearsigned(&key, &signed).await.unwrap();
+        assert_eq!(normalize_statement(&text), statement);
+
+        // Someone else's signature, or an edited statement, is refused
+        keyring.generate("mallory@invalid", "ed25519", "sign");
+        let forged = keyring.gpg(&["--local-user", "mallory@invalid", "--clearsign"], statement.as_bytes());
+        assert!(matches!(verify_clearsigned(&key, &forged).await, Err(PgpError::BadSignature(_))));
+        let edited = signed.replace("Remove key", "Remove keys");
+        assert!(matches!(verify_clearsigned(&key, &edited).await, Err(PgpError::BadSignature(_))));
+    }
+
+    #[actix_web::test]
+    async fn challenges_are_answered_once() {
+        let db = database().await;
+        let later = Utc::now().timestamp() + 600;
+        add_challenge(&db, "pch-live", Some("usr-1"), "c0de", later).await;
+        add_challenge(&db, "pch-decoy", None, "c0de", later).await;
+        add_challenge(&db, "pch-stale", Some("usr-1"), "c0de", Utc::now().timestamp() - 1).await;
+
+        assert!(matches!(redeem_challenge(&db, "pch-live", ChallengePurpose::Login, "0000").await, Err(PgpError::InvalidChallenge)));
+        assert!(matches!(redeem_challenge(&db, "pch-live", ChallengePurpose::Recovery, "c0de").await, Err(PgpError::InvalidChallenge)));
+        assert_eq!(redeem_challenge(&db, "pch-live", ChallengePurpose::Login, " C0DE ").await.unwrap(), "usr-1");
+        assert!(matches!(redeem_challenge(&db, "pch-live", ChallengePurpose::Login, "c0de").await, Err(PgpError::InvalidChallenge)));
+
+        for id in ["pch-decoy", "pch-stale", "pch-missing"] {
+            assert!(matches!(redeem_challenge(&db, id, ChallengePurpose::Login, "c0de").await, Err(PgpError::InvalidChallenge)), "{}", id);
+        }
+    }
+}
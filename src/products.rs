@@ -1,46 +1,289 @@
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpResponse, Responder, get, post, put};
 use serde::{Deserialize, Serialize};
 use chrono::Utc;
 use uuid::Uuid;
 use crate::AppState;
-use log::error;
+use log::{info, error};
 use serde_json::json;
+use sqlx::{QueryBuilder, Row, Sqlite, SqlitePool, Executor};
+use sqlx::sqlite::SqliteRow;
+use crate::rbac::{perm, Authorized};
+use crate::categories;
+use crate::tax;
+use crate::images::ProductImage;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Product {
     pub id: String,
+    /// Stock keeping unit; used to match rows in bulk imports
+    pub sku: Option<String>,
     pub name: String,
     pub description: String,
     pub price: f64,
     pub available: bool,
+    /// `download` or `license_key` for digital goods; absent for physical ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digital_delivery: Option<String>,
+    /// Shipping weight of one unit, in grams
+    #[serde(default)]
+    pub weight_grams: i64,
+    /// Tax class whose rules apply to the product
+    #[serde(default = "tax::default_class")]
+    pub tax_class: String,
+    /// Units that can still be sold (on-hand stock minus active reservations)
+    pub stock: i64,
+    pub category_id: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Image gallery in display order; the first image is the main one
+    #[serde(default)]
+    pub images: Vec<ProductImage>,
     #[serde(with = "chrono::serde::ts_seconds_option")]
     pub created_at: Option<chrono::DateTime<Utc>>,
+    /// Version for optimistic concurrency; send it back unchanged when updating
+    #[serde(default)]
+    pub updated_at: i64,
+    /// Set when the product has been archived (soft-deleted)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived_at: Option<i64>,
 }
 
 #[derive(Deserialize)]
 pub struct ProductInput {
+    pub sku: Option<String>,
     pub name: String,
     pub description: String,
     pub price: f64,
     pub available: bool,
+    #[serde(default)]
+    pub stock: i64,
+    #[serde(default)]
+    pub weight_grams: i64,
+    #[serde(default = "tax::default_class")]
+    pub tax_class: String,
+    pub category_id: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Admin edit of a product. Stock is changed through the stock endpoint.
+#[derive(Deserialize)]
+pub struct ProductUpdate {
+    /// Replaces the SKU when present
+    pub sku: Option<String>,
+    pub name: String,
+    pub description: String,
+    pub price: f64,
+    pub available: bool,
+    /// Replaces the shipping weight when present
+    pub weight_grams: Option<i64>,
+    /// Replaces the tax class when present
+    pub tax_class: Option<String>,
+    pub category_id: Option<String>,
+    /// Replaces the tag list when present
+    pub tags: Option<Vec<String>>,
+    /// The `updated_at` the client last saw; the update is rejected if the
+    /// product has changed since
+    pub updated_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct AdminProductsQuery {
+    #[serde(default)]
+    pub include_archived: Option<bool>,
+}
+
+/// Query parameters accepted by the catalog listing.
+#[derive(Deserialize)]
+pub struct CatalogQuery {
+    /// Category id or slug; products in subcategories are included
+    pub category: Option<String>,
+    /// Comma-separated tags; a product must carry all of them
+    pub tags: Option<String>,
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+    pub available: Option<bool>,
+    /// Only products (or at least one of their variants) with sellable stock
+    pub in_stock: Option<bool>,
+    /// newest (default), price_asc, price_desc or name
+    pub sort: Option<String>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+/// Position of the last product on a catalog page, handed back to the client
+/// as an opaque token so the next page can resume after it.
+#[derive(Serialize, Deserialize)]
+struct CatalogCursor {
+    sort: String,
+    value: serde_json::Value,
+    id: String,
+}
+
+pub const MAX_NAME_LENGTH: usize = 200;
+pub const MAX_SKU_LENGTH: usize = 64;
+pub const MAX_DESCRIPTION_LENGTH: usize = 10_000;
+
+/// Next `updated_at` for a product row. Always moves forward, even for two
+/// edits within the same second, so a stale version can never match.
+pub const NEXT_VERSION: &str = "MAX(updated_at + 1, CAST(strftime('%s', 'now') AS INTEGER))";
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+/// Column list shared by every query that returns `Product` rows.
+pub const PRODUCT_COLUMNS: &str = r#"SELECT 
+            p.id, 
+            p.sku,
+            p.name, 
+            COALESCE(p.description, '') as description, 
+            p.price, 
+            p.available,
+            p.digital_delivery,
+            p.weight_grams,
+            p.tax_class,
+            p.stock - p.reserved as stock,
+            p.category_id,
+            (SELECT GROUP_CONCAT(t.tag) FROM product_tags t WHERE t.product_id = p.id) as tags,
+            (SELECT json_group_array(json_object(
+                    'id', i.id, 'product_id', i.product_id, 'url', i.url,
+                    'thumbnail_url', i.thumbnail_url, 'position', i.position,
+                    'content_type', i.content_type, 'width', i.width, 'height', i.height))
+             FROM (SELECT * FROM product_images WHERE product_id = p.id ORDER BY position) i) as images,
+            p.created_at,
+            p.updated_at,
+            p.archived_at"#;
+
+pub fn product_from_row(row: &SqliteRow) -> Product {
+    let tags: Option<String> = row.get("tags");
+    let mut tags: Vec<String> = tags
+        .map(|t| t.split(',').map(str::to_string).collect())
+        .unwrap_or_default();
+    tags.sort();
+
+    let images: String = row.get("images");
+
+    Product {
+        id: row.get("id"),
+        sku: row.get("sku"),
+        name: row.get("name"),
+        description: row.get("description"),
+        price: row.get("price"),
+        available: row.get("available"),
+        digital_delivery: row.get("digital_delivery"),
+        weight_grams: row.get("weight_grams"),
+        tax_class: row.get("tax_class"),
+        stock: row.get("stock"),
+        category_id: row.get("category_id"),
+        tags,
+        images: serde_json::from_str(&images).unwrap_or_default(),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+        archived_at: row.get("archived_at"),
+    }
+}
+
+/// Name, description and price rules shared by create and update.
+pub fn validate_product_fields(name: &str, description: &str, price: f64) -> Result<(), String> {
+    let name_length = name.trim().chars().count();
+    if name_length == 0 {
+        return Err("Product name is required".to_string());
+    }
+    if name_length > MAX_NAME_LENGTH {
+        return Err(format!("Product name may not exceed {} characters", MAX_NAME_LENGTH));
+    }
+    if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(format!("Description may not exceed {} characters", MAX_DESCRIPTION_LENGTH));
+    }
+    if !price.is_finite() || price < 0.0 {
+        return Err("Price must be a non-negative number".to_string());
+    }
+    Ok(())
+}
+
+/// Trimmed SKU, or `None` if blank.
+pub fn normalize_sku(sku: Option<&str>) -> Result<Option<String>, String> {
+    match sku.map(str::trim).filter(|sku| !sku.is_empty()) {
+        Some(sku) if sku.chars().count() > MAX_SKU_LENGTH => {
+            Err(format!("SKU may not exceed {} characters", MAX_SKU_LENGTH))
+        },
+        Some(sku) => Ok(Some(sku.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(db) if db.message().contains("UNIQUE"))
+}
+
+/// Record that a product changed, bumping its version.
+pub async fn touch_product<'e, E>(executor: E, product_id: &str) -> Result<(), sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query(&format!("UPDATE products SET updated_at = {} WHERE id = ?", NEXT_VERSION))
+        .bind(product_id)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+/// Look up one product, archived or not.
+pub async fn fetch_product<'e, E>(executor: E, product_id: &str) -> Result<Option<Product>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let row = sqlx::query(&format!("{} FROM products p WHERE p.id = ?", PRODUCT_COLUMNS))
+        .bind(product_id)
+        .fetch_optional(executor)
+        .await?;
+    Ok(row.as_ref().map(product_from_row))
+}
+
+/// All products, newest first. Archived products are only included on request.
+pub async fn fetch_products(pool: &SqlitePool, include_archived: bool) -> Result<Vec<Product>, sqlx::Error> {
+    let filter = if include_archived { "" } else { " WHERE p.archived_at IS NULL" };
+    let rows = sqlx::query(&format!("{} FROM products p{} ORDER BY p.created_at DESC, p.id", PRODUCT_COLUMNS, filter))
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(product_from_row).collect())
+}
+
+fn encode_cursor(cursor: &CatalogCursor) -> String {
+    serde_json::to_vec(cursor)
+        .unwrap_or_default()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn decode_cursor(token: &str) -> Option<CatalogCursor> {
+    if !token.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..token.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(token.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+fn cursor_for(sort: &str, product: &Product) -> CatalogCursor {
+    let value = match sort {
+        "price_asc" | "price_desc" => json!(product.price),
+        "name" => json!(product.name),
+        _ => json!(product.created_at.map(|t| t.timestamp()).unwrap_or(0)),
+    };
+    CatalogCursor {
+        sort: sort.to_string(),
+        value,
+        id: product.id.clone(),
+    }
 }
 
 /// Endpoint to list all products.
 pub async fn list_products(state: web::Data<AppState>) -> impl Responder {
-    // Use a raw query to avoid SQLx macro issues
-    let result = sqlx::query_as!(
-        Product,
-        r#"SELECT 
-            id as "id!", 
-            name as "name!", 
-            COALESCE(description, '') as "description!", 
-            price as "price!", 
-            (CASE WHEN available != 0 THEN true ELSE false END) as "available!: bool",
-            created_at as "created_at: Option<chrono::DateTime<Utc>>"
-        FROM products"#
-    )
-    .fetch_all(&state.db)
-    .await;
+    let result = fetch_products(&state.db, false).await;
     
     match result {
         Ok(products) => {
@@ -55,9 +298,154 @@ pub async fn list_products(state: web::Data<AppState>) -> impl Responder {
     }
 }
 
-/// Endpoint to add a new product.
-/// (In a production system, you'd secure this endpoint to admin users only.)
+/// Endpoint to browse the catalog with filters, sorting and cursor pagination.
+pub async fn browse_products(
+    query: web::Query<CatalogQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let sort = query.sort.as_deref().unwrap_or("newest");
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+
+    let (order_column, descending) = match sort {
+        "newest" => ("p.created_at", true),
+        "price_asc" => ("p.price", false),
+        "price_desc" => ("p.price", true),
+        "name" => ("p.name", false),
+        _ => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": "Invalid sort order. Use newest, price_asc, price_desc or name"
+            }));
+        }
+    };
+
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": format!("limit must be between 1 and {}", MAX_PAGE_SIZE)
+        }));
+    }
+
+    let cursor = match query.cursor.as_deref() {
+        Some(token) => match decode_cursor(token) {
+            Some(cursor) if cursor.sort == sort => Some(cursor),
+            _ => {
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": "Invalid cursor"
+                }));
+            }
+        },
+        None => None,
+    };
+
+    let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(PRODUCT_COLUMNS);
+    builder.push(" FROM products p WHERE p.archived_at IS NULL");
+
+    if let Some(category) = &query.category {
+        builder.push(
+            " AND p.category_id IN (
+                WITH RECURSIVE sub(id) AS (
+                    SELECT id FROM categories WHERE id = "
+        );
+        builder.push_bind(category.clone());
+        builder.push(" OR slug = ");
+        builder.push_bind(category.clone());
+        builder.push(
+            " UNION ALL
+                    SELECT c.id FROM categories c JOIN sub ON c.parent_id = sub.id
+                )
+                SELECT id FROM sub
+            )"
+        );
+    }
+
+    if let Some(tags) = &query.tags {
+        for tag in tags.split(',').map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty()) {
+            builder.push(" AND EXISTS (SELECT 1 FROM product_tags t WHERE t.product_id = p.id AND t.tag = ");
+            builder.push_bind(tag);
+            builder.push(")");
+        }
+    }
+
+    if let Some(min_price) = query.min_price {
+        builder.push(" AND p.price >= ");
+        builder.push_bind(min_price);
+    }
+
+    if let Some(max_price) = query.max_price {
+        builder.push(" AND p.price <= ");
+        builder.push_bind(max_price);
+    }
+
+    if let Some(available) = query.available {
+        builder.push(" AND p.available = ");
+        builder.push_bind(available);
+    }
+
+    if let Some(in_stock) = query.in_stock {
+        builder.push(if in_stock { " AND " } else { " AND NOT " });
+        builder.push(
+            "(p.stock - p.reserved > 0 OR EXISTS (
+                SELECT 1 FROM product_variants v
+                WHERE v.product_id = p.id AND v.available AND v.stock - v.reserved > 0
+            ))"
+        );
+    }
+
+    // Keyset pagination: resume strictly after the last (sort value, id) seen
+    if let Some(cursor) = cursor {
+        let op = if descending { "<" } else { ">" };
+        let bind_value = |builder: &mut QueryBuilder<Sqlite>| match sort {
+            "newest" => { builder.push_bind(cursor.value.as_i64().unwrap_or(0)); },
+            "name" => { builder.push_bind(cursor.value.as_str().unwrap_or("").to_string()); },
+            _ => { builder.push_bind(cursor.value.as_f64().unwrap_or(0.0)); },
+        };
+
+        builder.push(format!(" AND ({} {} ", order_column, op));
+        bind_value(&mut builder);
+        builder.push(format!(" OR ({} = ", order_column));
+        bind_value(&mut builder);
+        builder.push(format!(" AND p.id {} ", op));
+        builder.push_bind(cursor.id.clone());
+        builder.push("))");
+    }
+
+    let direction = if descending { "DESC" } else { "ASC" };
+    builder.push(format!(" ORDER BY {} {}, p.id {} LIMIT ", order_column, direction, direction));
+    // Fetch one extra row to know whether another page exists
+    builder.push_bind(limit + 1);
+
+    match builder.build().fetch_all(&state.db).await {
+        Ok(rows) => {
+            let mut products: Vec<Product> = rows.iter().map(product_from_row).collect();
+            let next_cursor = if products.len() as i64 > limit {
+                products.truncate(limit as usize);
+                products.last().map(|last| encode_cursor(&cursor_for(sort, last)))
+            } else {
+                None
+            };
+
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "products": products,
+                "next_cursor": next_cursor
+            }))
+        },
+        Err(e) => {
+            error!("Failed to browse products: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to fetch products"
+            }))
+        }
+    }
+}
+
+/// Endpoint to add a new product (admin only).
 pub async fn add_product(
+    _auth: Authorized<perm::ProductsWrite>,
     product_data: web::Json<ProductInput>,
     state: web::Data<AppState>,
 ) -> impl Responder {
@@ -65,33 +453,115 @@ pub async fn add_product(
     let product_id = Uuid::new_v4().to_string();
     let now = Utc::now();
     
-    let result = sqlx::query(
-        "INSERT INTO products (id, name, description, price, available, created_at) VALUES (?, ?, ?, ?, ?, ?)"
-    )
-    .bind(product_id.clone())
-    .bind(product.name.clone())
-    .bind(product.description.clone())
-    .bind(product.price)
-    .bind(if product.available { 1 } else { 0 })
-    .bind(now)
-    .execute(&state.db)
-    .await;
+    if let Err(msg) = validate_product_fields(&product.name, &product.description, product.price) {
+        return HttpResponse::BadRequest().json(serde_json::json!({"error": msg}));
+    }
+    
+    if product.stock < 0 {
+        return HttpResponse::BadRequest().json(
+            serde_json::json!({"error": "Stock cannot be negative"})
+        );
+    }
+
+    if product.weight_grams < 0 {
+        return HttpResponse::BadRequest().json(
+            serde_json::json!({"error": "Weight cannot be negative"})
+        );
+    }
+
+    let tax_class = match tax::normalize_class(&product.tax_class) {
+        Ok(class) => class,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({"error": msg}));
+        }
+    };
+
+    let tags = match categories::normalize_tags(&product.tags) {
+        Ok(tags) => tags,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({"error": msg}));
+        }
+    };
+    
+    let sku = match normalize_sku(product.sku.as_deref()) {
+        Ok(sku) => sku,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({"error": msg}));
+        }
+    };
+    
+    if let Some(category_id) = &product.category_id {
+        match categories::category_exists(&state.db, category_id).await {
+                Ok(true) => {},
+                Ok(false) => {
+                    return HttpResponse::BadRequest().json(
+                        serde_json::json!({"error": "Category not found"})
+                    );
+                },
+                Err(e) => {
+                    log::error!("Failed to look up category {}: {}", category_id, e);
+                    return HttpResponse::InternalServerError().json(
+                        serde_json::json!({"error": "Failed to add product"})
+                    );
+                }
+            }
+    }
+
+    let result = async {
+        let mut tx = state.db.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO products (id, sku, name, description, price, available, stock, weight_grams, tax_class, category_id, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(product_id.clone())
+        .bind(sku.clone())
+        .bind(product.name.trim().to_string())
+        .bind(product.description.clone())
+        .bind(product.price)
+        .bind(if product.available { 1 } else { 0 })
+        .bind(product.stock)
+        .bind(product.weight_grams)
+        .bind(&tax_class)
+        .bind(product.category_id.clone())
+        .bind(now.timestamp())
+        .bind(now.timestamp())
+        .execute(&mut *tx)
+        .await?;
+
+        categories::set_product_tags(&mut tx, &product_id, &tags).await?;
+        tx.commit().await
+    }.await;
     
     match result {
         Ok(_) => {
             let created_product = Product {
                 id: product_id.clone(),
-                name: product.name,
+                sku,
+                name: product.name.trim().to_string(),
                 description: product.description,
                 price: product.price,
                 available: product.available,
+                digital_delivery: None,
+                weight_grams: product.weight_grams,
+                tax_class,
+                stock: product.stock,
+                category_id: product.category_id,
+                tags,
+                images: Vec::new(),
                 created_at: Some(now),
+                updated_at: now.timestamp(),
+                archived_at: None,
             };
             
             println!("Product successfully created in database with ID: {}", product_id);
             
             HttpResponse::Created().json(created_product)
         }
+        Err(e) if is_unique_violation(&e) => {
+            HttpResponse::Conflict().json(
+                serde_json::json!({"error": "A product with this SKU already exists"})
+            )
+        }
         Err(e) => {
             log::error!("Failed to add product: {}", e);
             HttpResponse::InternalServerError().json(
@@ -101,27 +571,477 @@ pub async fn add_product(
     }
 }
 
-// These functions can be implemented similarly...
-pub async fn purchase_product(
+// Admin: every product, including archived ones unless include_archived=false
+#[get("/products")]
+pub async fn admin_list_products(
+    _auth: Authorized<perm::ProductsWrite>,
+    query: web::Query<AdminProductsQuery>,
     state: web::Data<AppState>,
-    purchase: web::Json<PurchaseRequest>
 ) -> impl Responder {
-    // Use simple query instead of macros
-    let order_id = format!("order-{}", Uuid::new_v4());
-    let payment_id = format!("pay-{}", Uuid::new_v4());
+    match fetch_products(&state.db, query.include_archived.unwrap_or(true)).await {
+        Ok(products) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "count": products.len(),
+            "products": products
+        })),
+        Err(e) => {
+            error!("Failed to fetch products: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to fetch products"
+            }))
+        }
+    }
+}
+
+// Admin: a single product, archived or not
+#[get("/products/{id}")]
+pub async fn admin_get_product(
+    _auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    match fetch_product(&state.db, &product_id).await {
+        Ok(Some(product)) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "product": product
+        })),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": "Product not found"
+        })),
+        Err(e) => {
+            error!("Failed to fetch product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to fetch product"
+            }))
+        }
+    }
+}
+
+/// Response for a write that lost an optimistic concurrency race (or hit a
+/// missing product).
+async fn version_conflict(pool: &SqlitePool, product_id: &str) -> HttpResponse {
+    match fetch_product(pool, product_id).await {
+        Ok(Some(current)) => HttpResponse::Conflict().json(json!({
+            "success": false,
+            "error": "Product was modified by someone else; reload and try again",
+            "product": current
+        })),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": "Product not found"
+        })),
+        Err(e) => {
+            error!("Failed to fetch product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Database error"
+            }))
+        }
+    }
+}
+
+// Admin: edit a product's details. Requires the `updated_at` the client last
+// saw so concurrent edits are not silently overwritten.
+#[put("/products/{id}")]
+pub async fn update_product(
+    _auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    product_data: web::Json<ProductUpdate>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+    let update = product_data.into_inner();
+
+    if let Err(msg) = validate_product_fields(&update.name, &update.description, update.price) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": msg
+        }));
+    }
+
+    let sku = match normalize_sku(update.sku.as_deref()) {
+        Ok(sku) => sku,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": msg
+            }));
+        }
+    };
+
+    if update.weight_grams.is_some_and(|w| w < 0) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "Weight cannot be negative"
+        }));
+    }
+
+    let tax_class = match update.tax_class.as_deref().map(tax::normalize_class).transpose() {
+        Ok(class) => class,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": msg
+            }));
+        }
+    };
+
+    let tags = match update.tags.as_deref().map(categories::normalize_tags).transpose() {
+        Ok(tags) => tags,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": msg
+            }));
+        }
+    };
+
+    if let Some(category_id) = &update.category_id {
+        match categories::category_exists(&state.db, category_id).await {
+            Ok(true) => {},
+            Ok(false) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "success": false,
+                    "error": "Category not found"
+                }));
+            },
+            Err(e) => {
+                error!("Failed to look up category {}: {}", category_id, e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Database error"
+                }));
+            }
+        }
+    }
+
+    let result = async {
+        let mut tx = state.db.begin().await?;
+
+        let updated = sqlx::query(&format!(
+            "UPDATE products
+             SET sku = COALESCE(?, sku), name = ?, description = ?, price = ?, available = ?,
+                 weight_grams = COALESCE(?, weight_grams), tax_class = COALESCE(?, tax_class),
+                 category_id = ?, updated_at = {}
+             WHERE id = ? AND updated_at = ?",
+            NEXT_VERSION
+        ))
+        .bind(&sku)
+        .bind(update.name.trim())
+        .bind(&update.description)
+        .bind(update.price)
+        .bind(update.available)
+        .bind(update.weight_grams)
+        .bind(&tax_class)
+        .bind(&update.category_id)
+        .bind(&product_id)
+        .bind(update.updated_at)
+        .execute(&mut *tx)
+        .await?;
+
+        if updated.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        if let Some(tags) = &tags {
+            categories::set_product_tags(&mut tx, &product_id, tags).await?;
+        }
+
+        let product = fetch_product(&mut *tx, &product_id).await?;
+        tx.commit().await?;
+        Ok::<Option<Product>, sqlx::Error>(product)
+    }.await;
+
+    match result {
+        Ok(Some(product)) => {
+            info!("Updated product {}", product_id);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "product": product
+            }))
+        },
+        Ok(None) => version_conflict(&state.db, &product_id).await,
+        Err(e) if is_unique_violation(&e) => HttpResponse::Conflict().json(json!({
+            "success": false,
+            "error": "A product with this SKU already exists"
+        })),
+        Err(e) => {
+            error!("Failed to update product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to update product"
+            }))
+        }
+    }
+}
+
+/// Archive or restore a product. Returns `None` if it does not exist or was
+/// already in the requested state.
+async fn set_archived(pool: &SqlitePool, product_id: &str, archived: bool) -> Result<Option<Product>, sqlx::Error> {
+    let sql = if archived {
+        format!(
+            "UPDATE products SET archived_at = CAST(strftime('%s', 'now') AS INTEGER), updated_at = {}
+             WHERE id = ? AND archived_at IS NULL",
+            NEXT_VERSION
+        )
+    } else {
+        format!(
+            "UPDATE products SET archived_at = NULL, updated_at = {}
+             WHERE id = ? AND archived_at IS NOT NULL",
+            NEXT_VERSION
+        )
+    };
+
+    let result = sqlx::query(&sql).bind(product_id).execute(pool).await?;
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+    fetch_product(pool, product_id).await
+}
+
+async fn archive_response(state: &AppState, product_id: &str, archived: bool) -> HttpResponse {
+    match set_archived(&state.db, product_id, archived).await {
+        Ok(Some(product)) => {
+            info!("{} product {}", if archived { "Archived" } else { "Restored" }, product_id);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "product": product
+            }))
+        },
+        Ok(None) => match fetch_product(&state.db, product_id).await {
+            Ok(Some(_)) => HttpResponse::Conflict().json(json!({
+                "success": false,
+                "error": if archived { "Product is already archived" } else { "Product is not archived" }
+            })),
+            Ok(None) => HttpResponse::NotFound().json(json!({
+                "success": false,
+                "error": "Product not found"
+            })),
+            Err(e) => {
+                error!("Failed to fetch product {}: {}", product_id, e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Database error"
+                }))
+            }
+        },
+        Err(e) => {
+            error!("Failed to change archive state of product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to update product"
+            }))
+        }
+    }
+}
+
+// Admin: soft-delete a product. It disappears from the catalog, search and
+// checkout, but stays on past orders and can be restored.
+#[post("/products/{id}/archive")]
+pub async fn archive_product(
+    _auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    archive_response(&state, &path.into_inner(), true).await
+}
+
+// Admin: bring an archived product back into the catalog
+#[post("/products/{id}/restore")]
+pub async fn restore_product(
+    _auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    archive_response(&state, &path.into_inner(), false).await
+}
+
+// Admin: copy a product's details, category, tags, weight, tax class and
+// delivery type into a new, unavailable product with no stock. Variants,
+// images, downloadable files and license keys are not copied since SKUs and
+// keys must be unique and files belong to one product.
+#[post("/products/{id}/duplicate")]
+pub async fn duplicate_product(
+    _auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let source_id = path.into_inner();
+    let new_id = Uuid::new_v4().to_string();
     let now = Utc::now().timestamp();
-    
-    HttpResponse::Ok().json(json!({
-        "success": true,
-        "order_id": order_id,
-        "message": "Product purchased successfully"
-    }))
+
+    let result = async {
+        let mut tx = state.db.begin().await?;
+
+        let source = match fetch_product(&mut *tx, &source_id).await? {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+
+        let mut name = format!("{} (copy)", source.name);
+        if name.chars().count() > MAX_NAME_LENGTH {
+            name = name.chars().take(MAX_NAME_LENGTH).collect();
+        }
+
+        sqlx::query(
+            "INSERT INTO products (id, name, description, price, available, digital_delivery, weight_grams, tax_class, stock, category_id, created_at, updated_at)
+             VALUES (?, ?, ?, ?, 0, ?, ?, ?, 0, ?, ?, ?)"
+        )
+        .bind(&new_id)
+        .bind(&name)
+        .bind(&source.description)
+        .bind(source.price)
+        .bind(&source.digital_delivery)
+        .bind(source.weight_grams)
+        .bind(&source.tax_class)
+        .bind(&source.category_id)
+        .bind(now)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        categories::set_product_tags(&mut tx, &new_id, &source.tags).await?;
+
+        let product = fetch_product(&mut *tx, &new_id).await?;
+        tx.commit().await?;
+        Ok::<Option<Product>, sqlx::Error>(product)
+    }.await;
+
+    match result {
+        Ok(Some(product)) => {
+            info!("Duplicated product {} as {}", source_id, new_id);
+            HttpResponse::Created().json(json!({
+                "success": true,
+                "product": product
+            }))
+        },
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": "Product not found"
+        })),
+        Err(e) => {
+            error!("Failed to duplicate product {}: {}", source_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to duplicate product"
+            }))
+        }
+    }
 }
 
-// Define the request structure
-#[derive(Deserialize)]
-pub struct PurchaseRequest {
-    pub user_id: String,
-    pub price: f64,
-    pub email: String,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE products (id TEXT PRIMARY KEY, sku TEXT UNIQUE, name TEXT NOT NULL, description TEXT NOT NULL, price REAL NOT NULL, available BOOLEAN NOT NULL DEFAULT TRUE, digital_delivery TEXT, weight_grams INTEGER NOT NULL DEFAULT 0, tax_class TEXT NOT NULL DEFAULT 'standard', stock INTEGER NOT NULL DEFAULT 0, reserved INTEGER NOT NULL DEFAULT 0, category_id TEXT, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL, archived_at INTEGER)",
+            "CREATE TABLE product_tags (product_id TEXT NOT NULL, tag TEXT NOT NULL, PRIMARY KEY (product_id, tag))",
+            "CREATE TABLE product_images (id TEXT PRIMARY KEY, product_id TEXT NOT NULL, url TEXT NOT NULL, thumbnail_url TEXT NOT NULL, position INTEGER NOT NULL, content_type TEXT NOT NULL, width INTEGER NOT NULL, height INTEGER NOT NULL)",
+            "INSERT INTO products (id, sku, name, description, price, stock, reserved, created_at, updated_at) VALUES
+                ('p1', 'SKU-1', 'Mug', 'Holds coffee', 8.5, 5, 2, 10, 10),
+                ('p2', NULL, 'Tee', '', 15.0, 0, 0, 20, 20)",
+            // Versions far ahead of the clock still move forward
+            "INSERT INTO products (id, name, description, price, created_at, updated_at) VALUES ('p3', 'Future', '', 1.0, 30, 99999999999)",
+            "INSERT INTO product_tags VALUES ('p1', 'kitchen'), ('p1', 'gift')",
+            "INSERT INTO product_images VALUES ('i2', 'p1', '/u/2.png', '/u/2-t.png', 1, 'image/png', 1, 1), ('i1', 'p1', '/u/1.png', '/u/1-t.png', 0, 'image/png', 1, 1)",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn product_fields_are_validated() {
+        assert!(validate_product_fields("Mug", "", 0.0).is_ok());
+        assert!(validate_product_fields("  ", "", 1.0).is_err());
+        assert!(validate_product_fields(&"é".repeat(MAX_NAME_LENGTH), "", 1.0).is_ok());
+        assert!(validate_product_fields(&"é".repeat(MAX_NAME_LENGTH + 1), "", 1.0).is_err());
+        assert!(validate_product_fields("Mug", &"x".repeat(MAX_DESCRIPTION_LENGTH + 1), 1.0).is_err());
+        for price in [-0.01, f64::NAN, f64::INFINITY] {
+            assert!(validate_product_fields("Mug", "", price).is_err(), "{}", price);
+        }
+    }
+
+    #[test]
+    fn skus_are_trimmed_and_blank_means_none() {
+        assert_eq!(normalize_sku(Some(" SKU-1 ")).unwrap().as_deref(), Some("SKU-1"));
+        assert_eq!(normalize_sku(Some("   ")).unwrap(), None);
+        assert_eq!(normalize_sku(None).unwrap(), None);
+        assert!(normalize_sku(Some(&"x".repeat(MAX_SKU_LENGTH + 1))).is_err());
+    }
+
+    #[test]
+    fn cursors_round_trip_and_reject_garbage() {
+        let cursor = CatalogCursor { sort: "name".to_string(), value: json!("Mug"), id: "p1".to_string() };
+        let decoded = decode_cursor(&encode_cursor(&cursor)).unwrap();
+        assert_eq!((decoded.sort.as_str(), decoded.value, decoded.id.as_str()), ("name", json!("Mug"), "p1"));
+
+        for token in ["", "abc", "zz", "7b7d", "ée"] {
+            assert!(decode_cursor(token).is_none(), "{}", token);
+        }
+    }
+
+    #[actix_web::test]
+    async fn products_carry_tags_images_and_free_stock() {
+        let db = database().await;
+        let product = fetch_product(&db, "p1").await.unwrap().unwrap();
+        assert_eq!(product.stock, 3);
+        assert_eq!(product.tags, ["gift", "kitchen"]);
+        let images: Vec<_> = product.images.iter().map(|i| i.id.as_str()).collect();
+        assert_eq!(images, ["i1", "i2"]);
+        assert_eq!(product.created_at.unwrap().timestamp(), 10);
+
+        let bare = fetch_product(&db, "p2").await.unwrap().unwrap();
+        assert!(bare.tags.is_empty() && bare.images.is_empty());
+        assert!(fetch_product(&db, "p9").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn every_change_moves_the_version_forward() {
+        let db = database().await;
+        for id in ["p1", "p3"] {
+            let before = fetch_product(&db, id).await.unwrap().unwrap().updated_at;
+            touch_product(&db, id).await.unwrap();
+            touch_product(&db, id).await.unwrap();
+            let after = fetch_product(&db, id).await.unwrap().unwrap().updated_at;
+            assert!(after >= before + 2, "{}: {} -> {}", id, before, after);
+        }
+    }
+
+    #[actix_web::test]
+    async fn archiving_hides_a_product_until_restored() {
+        let db = database().await;
+        let version = fetch_product(&db, "p2").await.unwrap().unwrap().updated_at;
+
+        let archived = set_archived(&db, "p2", true).await.unwrap().unwrap();
+        assert!(archived.archived_at.is_some());
+        assert!(archived.updated_at > version);
+        assert!(set_archived(&db, "p2", true).await.unwrap().is_none());
+
+        let listed = |include_archived| {
+            let db = db.clone();
+            async move {
+                fetch_products(&db, include_archived).await.unwrap().into_iter().map(|p| p.id).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(listed(false).await, ["p3", "p1"]);
+        assert_eq!(listed(true).await, ["p3", "p2", "p1"]);
+
+        assert!(set_archived(&db, "p2", false).await.unwrap().unwrap().archived_at.is_none());
+        assert!(set_archived(&db, "p2", false).await.unwrap().is_none());
+        assert!(set_archived(&db, "p9", true).await.unwrap().is_none());
+    }
 }
@@ -0,0 +1,841 @@
+// src/rbac.rs
+use actix_web::{web, HttpResponse, HttpRequest, Responder, HttpMessage, FromRequest, dev::Payload, get, post, put, delete};
+use futures::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::Utc;
+use sqlx::{Row, SqliteConnection, SqlitePool};
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use log::{info, warn, error};
+use crate::AppState;
+use crate::two_factor;
+use crate::auth::{self, AuthError, Claims};
+
+/// Something a staff member may be allowed to do. Roles are named sets of
+/// permissions; a user has exactly one role (`users.role`).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    /// See orders, payments and shipments
+    #[serde(rename = "orders.read")]
+    OrdersRead,
+    /// Move orders along, ship them and retry digital delivery
+    #[serde(rename = "orders.fulfil")]
+    OrdersFulfil,
+    /// Confirm payments by hand and repair payment/order mismatches
+    #[serde(rename = "payments.confirm")]
+    PaymentsConfirm,
+    /// Edit the catalogue: products, stock, categories, coupons, shipping and tax
+    #[serde(rename = "products.write")]
+    ProductsWrite,
+    /// Manage user accounts and roles
+    #[serde(rename = "users.manage")]
+    UsersManage,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 5] = [
+        Permission::OrdersRead,
+        Permission::OrdersFulfil,
+        Permission::PaymentsConfirm,
+        Permission::ProductsWrite,
+        Permission::UsersManage,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::OrdersRead => "orders.read",
+            Permission::OrdersFulfil => "orders.fulfil",
+            Permission::PaymentsConfirm => "payments.confirm",
+            Permission::ProductsWrite => "products.write",
+            Permission::UsersManage => "users.manage",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Permission::OrdersRead => "See orders, payments and shipments",
+            Permission::OrdersFulfil => "Change order status, ship orders and manage carriers",
+            Permission::PaymentsConfirm => "Confirm payments by hand and repair payment records",
+            Permission::ProductsWrite => "Edit products, stock, categories, coupons, shipping and tax",
+            Permission::UsersManage => "Manage user accounts and assign roles",
+        }
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Permission {
+    type Err = RoleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Permission::ALL.iter()
+            .find(|p| p.as_str() == s.trim())
+            .copied()
+            .ok_or_else(|| RoleError::UnknownPermission(s.to_string()))
+    }
+}
+
+/// Roles every store starts with. `admin` always holds every permission;
+/// the permissions of the others can be edited after the first start.
+pub const BUILT_IN_ROLES: [(&str, &str, &[Permission]); 4] = [
+    (auth::ROLE_ADMIN, "Full access to the store", &Permission::ALL),
+    (auth::ROLE_USER, "Customer account", &[]),
+    (ROLE_FULFILMENT, "Picks, packs and ships orders", &[Permission::OrdersRead, Permission::OrdersFulfil]),
+    (ROLE_SUPPORT, "Looks up orders to help customers", &[Permission::OrdersRead]),
+];
+
+pub const ROLE_FULFILMENT: &str = "fulfilment";
+pub const ROLE_SUPPORT: &str = "support";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Role {
+    pub name: String,
+    pub description: String,
+    pub built_in: bool,
+    pub permissions: Vec<Permission>,
+    pub user_count: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CreateRoleRequest {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateRoleRequest {
+    pub description: Option<String>,
+    pub permissions: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct AssignRoleRequest {
+    pub role: String,
+}
+
+#[derive(Debug)]
+pub enum RoleError {
+    RoleNotFound(String),
+    UserNotFound,
+    AlreadyExists(String),
+    UnknownPermission(String),
+    /// Built-in roles cannot be deleted, and `admin` keeps every permission
+    BuiltIn(String),
+    InUse { role: String, users: i64 },
+    /// The change would leave the store without an admin
+    LastAdmin,
+    /// Only admins may grant the admin role or change an admin's account
+    AdminOnly,
+    Invalid(String),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for RoleError {
+    fn from(e: sqlx::Error) -> Self {
+        RoleError::Database(e)
+    }
+}
+
+impl std::fmt::Display for RoleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoleError::RoleNotFound(name) => write!(f, "Role '{}' not found", name),
+            RoleError::UserNotFound => write!(f, "User not found"),
+            RoleError::AlreadyExists(name) => write!(f, "Role '{}' already exists", name),
+            RoleError::UnknownPermission(p) => {
+                let known: Vec<&str> = Permission::ALL.iter().map(|p| p.as_str()).collect();
+                write!(f, "Unknown permission '{}', expected one of: {}", p, known.join(", "))
+            },
+            RoleError::BuiltIn(name) if name == auth::ROLE_ADMIN => {
+                write!(f, "The admin role always has every permission and cannot be changed")
+            },
+            RoleError::BuiltIn(name) => write!(f, "Role '{}' is built in and cannot be deleted", name),
+            RoleError::InUse { role, users } => write!(f, "Role '{}' is still assigned to {} user(s)", role, users),
+            RoleError::LastAdmin => write!(f, "The store must keep at least one admin"),
+            RoleError::AdminOnly => write!(f, "Only an admin can grant the admin role or change an admin account"),
+            RoleError::Invalid(msg) => write!(f, "{}", msg),
+            RoleError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl RoleError {
+    /// Response for a role change that was refused; database errors are hidden.
+    pub fn to_response(&self) -> HttpResponse {
+        let body = json!({
+            "success": false,
+            "error": self.to_string()
+        });
+        match self {
+            RoleError::RoleNotFound(_) | RoleError::UserNotFound => HttpResponse::NotFound().json(body),
+            RoleError::AdminOnly => HttpResponse::Forbidden().json(body),
+            RoleError::AlreadyExists(_) | RoleError::BuiltIn(_) | RoleError::InUse { .. } | RoleError::LastAdmin => {
+                HttpResponse::Conflict().json(body)
+            },
+            RoleError::Database(e) => {
+                error!("Role change failed: {}", e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to update roles"
+                }))
+            },
+            _ => HttpResponse::BadRequest().json(body),
+        }
+    }
+}
+
+/// Insert the built-in roles that are missing. A built-in role's default
+/// permissions are only written when the role itself is new, so edits made
+/// by an admin survive restarts; `admin` is topped up to every permission.
+pub async fn seed_built_in_roles(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let now = Utc::now().timestamp();
+    for (name, description, permissions) in BUILT_IN_ROLES {
+        let inserted = sqlx::query("INSERT OR IGNORE INTO roles (name, description, built_in, created_at) VALUES (?, ?, 1, ?)")
+            .bind(name)
+            .bind(description)
+            .bind(now)
+            .execute(pool)
+            .await?
+            .rows_affected() > 0;
+
+        if inserted || name == auth::ROLE_ADMIN {
+            for permission in permissions {
+                sqlx::query("INSERT OR IGNORE INTO role_permissions (role, permission) VALUES (?, ?)")
+                    .bind(name)
+                    .bind(permission.as_str())
+                    .execute(pool)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Current permissions of a user, through the role stored on their account.
+/// Looking this up per request means a role change applies to tokens that
+/// were issued before it.
+pub async fn permissions_for_user(pool: &SqlitePool, user_id: &str) -> Result<Vec<Permission>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT rp.permission FROM users u JOIN role_permissions rp ON rp.role = u.role WHERE u.id = ?"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut permissions: Vec<Permission> = rows.iter()
+        .filter_map(|row| row.get::<String, _>("permission").parse().ok())
+        .collect();
+    permissions.sort();
+    Ok(permissions)
+}
+
+pub async fn has_permission(pool: &SqlitePool, user_id: &str, permission: Permission) -> Result<bool, sqlx::Error> {
+    Ok(permissions_for_user(pool, user_id).await?.contains(&permission))
+}
+
+pub async fn list_roles(pool: &SqlitePool) -> Result<Vec<Role>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT r.name, r.description, r.built_in,
+                (SELECT COUNT(*) FROM users u WHERE u.role = r.name) AS user_count
+         FROM roles r ORDER BY r.built_in DESC, r.name"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut permissions: BTreeMap<String, Vec<Permission>> = BTreeMap::new();
+    for row in sqlx::query("SELECT role, permission FROM role_permissions").fetch_all(pool).await? {
+        if let Ok(permission) = row.get::<String, _>("permission").parse() {
+            permissions.entry(row.get("role")).or_default().push(permission);
+        }
+    }
+
+    Ok(rows.iter().map(|row| {
+        let name: String = row.get("name");
+        let mut role_permissions = permissions.remove(&name).unwrap_or_default();
+        role_permissions.sort();
+        Role {
+            description: row.get("description"),
+            built_in: row.get::<i64, _>("built_in") != 0,
+            permissions: role_permissions,
+            user_count: row.get("user_count"),
+            name,
+        }
+    }).collect())
+}
+
+async fn fetch_role(pool: &SqlitePool, name: &str) -> Result<Role, RoleError> {
+    list_roles(pool).await?
+        .into_iter()
+        .find(|role| role.name == name)
+        .ok_or_else(|| RoleError::RoleNotFound(name.to_string()))
+}
+
+pub async fn role_exists<'e, E>(executor: E, name: &str) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    Ok(sqlx::query("SELECT 1 FROM roles WHERE name = ?")
+        .bind(name)
+        .fetch_optional(executor)
+        .await?
+        .is_some())
+}
+
+fn validate_role_name(name: &str) -> Result<String, RoleError> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() || name.len() > 32
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(RoleError::Invalid(
+            "Role names are 1-32 characters of letters, digits, '-' and '_'".to_string()
+        ));
+    }
+    Ok(name)
+}
+
+fn parse_permissions(names: &[String]) -> Result<Vec<Permission>, RoleError> {
+    let mut permissions = names.iter()
+        .map(|name| name.parse())
+        .collect::<Result<Vec<Permission>, _>>()?;
+    permissions.sort();
+    permissions.dedup();
+    Ok(permissions)
+}
+
+async fn replace_permissions(conn: &mut SqliteConnection, role: &str, permissions: &[Permission]) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM role_permissions WHERE role = ?")
+        .bind(role)
+        .execute(&mut *conn)
+        .await?;
+    for permission in permissions {
+        sqlx::query("INSERT INTO role_permissions (role, permission) VALUES (?, ?)")
+            .bind(role)
+            .bind(permission.as_str())
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Refuse a change that takes the admin role away from the last admin.
+pub async fn ensure_not_last_admin(conn: &mut SqliteConnection, user_id: &str) -> Result<(), RoleError> {
+    if user_role(conn, user_id).await?.as_deref() == Some(auth::ROLE_ADMIN) {
+        let admins: i64 = sqlx::query("SELECT COUNT(*) AS n FROM users WHERE role = ?")
+            .bind(auth::ROLE_ADMIN)
+            .fetch_one(&mut *conn)
+            .await?
+            .get("n");
+        if admins <= 1 {
+            return Err(RoleError::LastAdmin);
+        }
+    }
+    Ok(())
+}
+
+async fn user_role(conn: &mut SqliteConnection, user_id: &str) -> Result<Option<String>, sqlx::Error> {
+    Ok(sqlx::query("SELECT role FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .map(|row| row.get("role")))
+}
+
+/// Refuse granting `admin`, or changing an admin's role or account, unless
+/// `caller_id` is an admin. Roles are read from the database so a demoted
+/// caller's old token doesn't count.
+pub async fn ensure_admin_caller(
+    conn: &mut SqliteConnection,
+    caller_id: &str,
+    user_id: &str,
+    new_role: Option<&str>,
+) -> Result<(), RoleError> {
+    if user_role(conn, caller_id).await?.as_deref() == Some(auth::ROLE_ADMIN) {
+        return Ok(());
+    }
+    if new_role == Some(auth::ROLE_ADMIN) || user_role(conn, user_id).await?.as_deref() == Some(auth::ROLE_ADMIN) {
+        return Err(RoleError::AdminOnly);
+    }
+    Ok(())
+}
+
+/// Give a user a different role on behalf of `caller_id`.
+pub async fn assign_role(pool: &SqlitePool, caller_id: &str, user_id: &str, role: &str) -> Result<(), RoleError> {
+    let role = role.trim().to_lowercase();
+    let mut tx = pool.begin().await?;
+
+    if !role_exists(&mut *tx, &role).await? {
+        return Err(RoleError::RoleNotFound(role));
+    }
+    ensure_admin_caller(&mut tx, caller_id, user_id, Some(&role)).await?;
+    if role != auth::ROLE_ADMIN {
+        ensure_not_last_admin(&mut tx, user_id).await?;
+    }
+
+    let updated = sqlx::query("UPDATE users SET role = ? WHERE id = ?")
+        .bind(&role)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(RoleError::UserNotFound);
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Permission named by a marker type, for the `Authorized` extractor
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+/// Marker types for `Authorized<P>`, one per `Permission`
+pub mod perm {
+    use super::{Permission, RequiredPermission};
+
+    pub struct OrdersRead;
+    pub struct OrdersFulfil;
+    pub struct PaymentsConfirm;
+    pub struct ProductsWrite;
+    pub struct UsersManage;
+
+    impl RequiredPermission for OrdersRead {
+        const PERMISSION: Permission = Permission::OrdersRead;
+    }
+    impl RequiredPermission for OrdersFulfil {
+        const PERMISSION: Permission = Permission::OrdersFulfil;
+    }
+    impl RequiredPermission for PaymentsConfirm {
+        const PERMISSION: Permission = Permission::PaymentsConfirm;
+    }
+    impl RequiredPermission for ProductsWrite {
+        const PERMISSION: Permission = Permission::ProductsWrite;
+    }
+    impl RequiredPermission for UsersManage {
+        const PERMISSION: Permission = Permission::UsersManage;
+    }
+}
+
+/// The authenticated caller with the permissions their role grants right now
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub claims: Claims,
+    pub permissions: Vec<Permission>,
+}
+
+impl Principal {
+    pub fn can(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    /// Staff are users whose role grants at least one permission.
+    pub fn is_staff(&self) -> bool {
+        !self.permissions.is_empty()
+    }
+}
+
+/// Validate the request token and load the caller's permissions. The result
+/// is cached on the request, so a scope guard and a handler extractor only
+/// look it up once.
+pub async fn principal(req: &HttpRequest) -> Result<Principal, AuthError> {
+    if let Some(principal) = req.extensions().get::<Principal>() {
+        return Ok(principal.clone());
+    }
+
+    let state = req.app_data::<web::Data<AppState>>()
+        .ok_or_else(|| AuthError::Internal("Application state not configured".to_string()))?;
+    let claims = auth::validate_session(req, &state.db).await?;
+    let permissions = permissions_for_user(&state.db, &claims.sub).await.map_err(|e| {
+        error!("Failed to load permissions for {}: {}", claims.sub, e);
+        AuthError::Internal("Failed to check permissions".to_string())
+    })?;
+
+    // Staff routes stay closed to accounts whose role requires 2FA until it's on
+    if !permissions.is_empty() {
+        let missing = two_factor::missing_required(&state.db, &claims.sub).await.map_err(|e| {
+            error!("Failed to check 2FA for {}: {}", claims.sub, e);
+            AuthError::Internal("Failed to check permissions".to_string())
+        })?;
+        if missing {
+            warn!("User {} needs 2FA before using staff routes", claims.sub);
+            return Err(AuthError::Forbidden(
+                "Two-factor authentication is required for this account; enable it at /auth/2fa/setup".to_string()
+            ));
+        }
+    }
+
+    let principal = Principal { claims, permissions };
+    req.extensions_mut().insert(principal.clone());
+    Ok(principal)
+}
+
+/// Extractor for handlers that need one permission, e.g.
+/// `auth: Authorized<perm::OrdersRead>`. The request is refused with 401
+/// without a valid token and 403 without the permission, before the handler runs.
+pub struct Authorized<P> {
+    pub claims: Claims,
+    pub permissions: Vec<Permission>,
+    required: PhantomData<P>,
+}
+
+impl<P: RequiredPermission> FromRequest for Authorized<P> {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let principal = principal(&req).await?;
+            if !principal.can(P::PERMISSION) {
+                warn!("User {} with role {} lacks {} for {}",
+                    principal.claims.sub, principal.claims.role, P::PERMISSION, req.path());
+                return Err(AuthError::Forbidden(format!("Permission {} required", P::PERMISSION)));
+            }
+            Ok(Authorized {
+                claims: principal.claims,
+                permissions: principal.permissions,
+                required: PhantomData,
+            })
+        })
+    }
+}
+
+// Admin: every permission a role can grant
+#[get("/permissions")]
+pub async fn list_permissions(_auth: Authorized<perm::UsersManage>) -> impl Responder {
+    let permissions: Vec<_> = Permission::ALL.iter()
+        .map(|p| json!({ "name": p.as_str(), "description": p.description() }))
+        .collect();
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "permissions": permissions
+    }))
+}
+
+// Admin: roles with their permissions and how many users hold them
+#[get("/roles")]
+pub async fn get_roles(
+    _auth: Authorized<perm::UsersManage>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    match list_roles(&app_state.db).await {
+        Ok(roles) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "roles": roles
+        })),
+        Err(e) => RoleError::Database(e).to_response(),
+    }
+}
+
+// Admin: add a staff role
+#[post("/roles")]
+pub async fn create_role(
+    auth: Authorized<perm::UsersManage>,
+    body: web::Json<CreateRoleRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let result = async {
+        let name = validate_role_name(&body.name)?;
+        let permissions = parse_permissions(&body.permissions)?;
+
+        let mut tx = app_state.db.begin().await?;
+        if role_exists(&mut *tx, &name).await? {
+            return Err(RoleError::AlreadyExists(name));
+        }
+        sqlx::query("INSERT INTO roles (name, description, built_in, created_at) VALUES (?, ?, 0, ?)")
+            .bind(&name)
+            .bind(body.description.as_deref().map(str::trim).unwrap_or(""))
+            .bind(Utc::now().timestamp())
+            .execute(&mut *tx)
+            .await?;
+        replace_permissions(&mut tx, &name, &permissions).await?;
+        tx.commit().await?;
+
+        fetch_role(&app_state.db, &name).await
+    }.await;
+
+    match result {
+        Ok(role) => {
+            info!("Role {} created by {} with permissions {:?}", role.name, auth.claims.username, role.permissions);
+            HttpResponse::Created().json(json!({
+                "success": true,
+                "role": role
+            }))
+        },
+        Err(e) => e.to_response(),
+    }
+}
+
+// Admin: change a role's description or permissions. Users holding the role
+// get the new permissions on their next request.
+#[put("/roles/{name}")]
+pub async fn update_role(
+    auth: Authorized<perm::UsersManage>,
+    path: web::Path<String>,
+    body: web::Json<UpdateRoleRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let name = path.into_inner();
+
+    let result = async {
+        let permissions = body.permissions.as_deref().map(parse_permissions).transpose()?;
+
+        let mut tx = app_state.db.begin().await?;
+        if !role_exists(&mut *tx, &name).await? {
+            return Err(RoleError::RoleNotFound(name.clone()));
+        }
+        if let Some(description) = &body.description {
+            sqlx::query("UPDATE roles SET description = ? WHERE name = ?")
+                .bind(description.trim())
+                .bind(&name)
+                .execute(&mut *tx)
+                .await?;
+        }
+        if let Some(permissions) = &permissions {
+            if name == auth::ROLE_ADMIN {
+                return Err(RoleError::BuiltIn(name.clone()));
+            }
+            replace_permissions(&mut tx, &name, permissions).await?;
+        }
+        tx.commit().await?;
+
+        fetch_role(&app_state.db, &name).await
+    }.await;
+
+    match result {
+        Ok(role) => {
+            info!("Role {} updated by {}; permissions now {:?}", role.name, auth.claims.username, role.permissions);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "role": role
+            }))
+        },
+        Err(e) => e.to_response(),
+    }
+}
+
+// Admin: remove a custom role nobody holds any more
+#[delete("/roles/{name}")]
+pub async fn delete_role(
+    auth: Authorized<perm::UsersManage>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let name = path.into_inner();
+
+    let result = async {
+        let role = fetch_role(&app_state.db, &name).await?;
+        if role.built_in {
+            return Err(RoleError::BuiltIn(name.clone()));
+        }
+        if role.user_count > 0 {
+            return Err(RoleError::InUse { role: name.clone(), users: role.user_count });
+        }
+
+        let mut tx = app_state.db.begin().await?;
+        sqlx::query("DELETE FROM role_permissions WHERE role = ?")
+            .bind(&name)
+            .execute(&mut *tx)
+            .await?;
+        // Re-check inside the transaction in case the role was just assigned
+        let deleted = sqlx::query("DELETE FROM roles WHERE name = ? AND NOT EXISTS (SELECT 1 FROM users WHERE role = ?)")
+            .bind(&name)
+            .bind(&name)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if deleted == 0 {
+            return Err(RoleError::InUse { role: name.clone(), users: 1 });
+        }
+        tx.commit().await?;
+        Ok(())
+    }.await;
+
+    match result {
+        Ok(()) => {
+            info!("Role {} deleted by {}", name, auth.claims.username);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": format!("Role '{}' deleted", name)
+            }))
+        },
+        Err(e) => e.to_response(),
+    }
+}
+
+// Admin: give a user a role, e.g. make a customer account a support agent
+#[put("/users/{id}/role")]
+pub async fn set_user_role(
+    auth: Authorized<perm::UsersManage>,
+    path: web::Path<String>,
+    body: web::Json<AssignRoleRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+
+    if let Err(e) = assign_role(&app_state.db, &auth.claims.sub, &user_id, &body.role).await {
+        warn!("Role change for user {} refused: {}", user_id, e);
+        return e.to_response();
+    }
+
+    info!("User {} given role {} by {}", user_id, body.role.trim().to_lowercase(), auth.claims.username);
+    match permissions_for_user(&app_state.db, &user_id).await {
+        Ok(permissions) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "user_id": user_id,
+            "role": body.role.trim().to_lowercase(),
+            "permissions": permissions
+        })),
+        Err(e) => RoleError::Database(e).to_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in account::ACCOUNT_TABLES.into_iter().chain([
+            "INSERT INTO users (id, username, password_hash, role, created_at) VALUES
+                ('adm-1', 'root', '', 'admin', 0),
+                ('ful-1', 'packer', '', 'fulfilment', 0),
+                ('usr-1', 'alice', '', 'user', 0)",
+        ]) {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        seed_built_in_roles(&db).await.unwrap();
+        db
+    }
+
+    async fn role_of(db: &SqlitePool, user_id: &str) -> String {
+        sqlx::query("SELECT role FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .get("role")
+    }
+
+    #[test]
+    fn names_are_checked() {
+        for permission in Permission::ALL {
+            assert_eq!(permission.as_str().parse::<Permission>().unwrap(), permission);
+        }
+        assert!(matches!("orders.delete".parse::<Permission>(), Err(RoleError::UnknownPermission(_))));
+
+        let names = ["orders.read".to_string(), " orders.read ".to_string()];
+        assert_eq!(parse_permissions(&names).unwrap(), [Permission::OrdersRead]);
+
+        assert_eq!(validate_role_name(" Night-Shift ").unwrap(), "night-shift");
+        for bad in ["", "night shift", &"r".repeat(33)] {
+            assert!(matches!(validate_role_name(bad), Err(RoleError::Invalid(_))), "{:?}", bad);
+        }
+    }
+
+    #[actix_web::test]
+    async fn seeding_keeps_edits_but_not_on_admin() {
+        let db = database().await;
+        assert_eq!(permissions_for_user(&db, "adm-1").await.unwrap(), Permission::ALL);
+        assert_eq!(
+            permissions_for_user(&db, "ful-1").await.unwrap(),
+            [Permission::OrdersRead, Permission::OrdersFulfil]
+        );
+        assert!(permissions_for_user(&db, "usr-1").await.unwrap().is_empty());
+
+        // A restart keeps a built-in role's edited permissions, but admin gets everything back
+        sqlx::query("DELETE FROM role_permissions WHERE permission = 'orders.read'").execute(&db).await.unwrap();
+        seed_built_in_roles(&db).await.unwrap();
+        assert_eq!(permissions_for_user(&db, "ful-1").await.unwrap(), [Permission::OrdersFulfil]);
+        assert_eq!(permissions_for_user(&db, "adm-1").await.unwrap(), Permission::ALL);
+    }
+
+    #[actix_web::test]
+    async fn only_admins_hand_out_admin() {
+        let db = database().await;
+
+        // Permissions follow the stored role, whatever older tokens say
+        assign_role(&db, "adm-1", "usr-1", " Support ").await.unwrap();
+        assert_eq!(role_of(&db, "usr-1").await, ROLE_SUPPORT);
+        assert!(has_permission(&db, "usr-1", Permission::OrdersRead).await.unwrap());
+
+        assert!(matches!(assign_role(&db, "ful-1", "usr-1", "admin").await, Err(RoleError::AdminOnly)));
+        assert!(matches!(assign_role(&db, "ful-1", "adm-1", "user").await, Err(RoleError::AdminOnly)));
+        assert!(matches!(assign_role(&db, "adm-1", "usr-1", "owner").await, Err(RoleError::RoleNotFound(_))));
+        assert!(matches!(assign_role(&db, "adm-1", "usr-missing", "user").await, Err(RoleError::UserNotFound)));
+        assert_eq!(role_of(&db, "usr-1").await, ROLE_SUPPORT);
+    }
+
+    #[actix_web::test]
+    async fn the_last_admin_stays_an_admin() {
+        let db = database().await;
+
+        assert!(matches!(assign_role(&db, "adm-1", "adm-1", "user").await, Err(RoleError::LastAdmin)));
+        assert_eq!(role_of(&db, "adm-1").await, auth::ROLE_ADMIN);
+
+        // With a second admin either one may step down
+        assign_role(&db, "adm-1", "ful-1", "admin").await.unwrap();
+        assign_role(&db, "ful-1", "adm-1", "user").await.unwrap();
+        let mut conn = db.acquire().await.unwrap();
+        assert!(matches!(ensure_not_last_admin(&mut conn, "ful-1").await, Err(RoleError::LastAdmin)));
+        ensure_not_last_admin(&mut conn, "adm-1").await.unwrap();
+    }
+
+    fn claims(role: &str) -> Claims {
+        Claims {
+            sub: "ful-1".to_string(),
+            username: "packer".to_string(),
+            role: role.to_string(),
+            exp: 0,
+            iat: 0,
+            sid: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn the_extractor_needs_the_exact_permission() {
+        // A principal already looked up by the scope guard is reused
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        req.extensions_mut().insert(Principal {
+            claims: claims(ROLE_FULFILMENT),
+            permissions: vec![Permission::OrdersRead, Permission::OrdersFulfil],
+        });
+
+        let authorized = Authorized::<perm::OrdersFulfil>::extract(&req).await.unwrap();
+        assert_eq!(authorized.claims.sub, "ful-1");
+        for refused in [
+            Authorized::<perm::PaymentsConfirm>::extract(&req).await.err(),
+            Authorized::<perm::ProductsWrite>::extract(&req).await.err(),
+            Authorized::<perm::UsersManage>::extract(&req).await.err(),
+        ] {
+            assert!(matches!(refused, Some(AuthError::Forbidden(_))));
+        }
+
+        let customer = Principal { claims: claims(auth::ROLE_USER), permissions: vec![] };
+        assert!(!customer.is_staff() && !customer.can(Permission::OrdersRead));
+    }
+
+    #[actix_web::test]
+    async fn the_extractor_fails_closed() {
+        // Without the app state nothing can be checked, so nothing is let through
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("Authorization", "Bearer admin-token-1"))
+            .to_http_request();
+        let err = Authorized::<perm::OrdersRead>::extract(&req).await.err().unwrap();
+        assert_eq!(err.to_response().status(), actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
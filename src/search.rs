@@ -0,0 +1,247 @@
+// src/search.rs
+use actix_web::{web, HttpResponse, Responder, post};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Row, SqlitePool};
+use log::{info, error};
+use crate::AppState;
+use crate::rbac::{perm, Authorized};
+use crate::products::{self, Product, PRODUCT_COLUMNS};
+
+const DEFAULT_RESULTS: i64 = 20;
+const MAX_RESULTS: i64 = 100;
+
+// Control characters used as highlight markers inside SQLite, swapped for
+// <mark> tags after the surrounding text has been HTML-escaped.
+const MARK_START: char = '\u{2}';
+const MARK_END: char = '\u{3}';
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub product: Product,
+    /// BM25 relevance; lower is a better match
+    pub score: f64,
+    /// Product name with matched terms wrapped in <mark>
+    pub name_highlighted: String,
+    /// Excerpt of the description around the matched terms
+    pub snippet: String,
+}
+
+/// Turn free text into an FTS5 query: every word must match, and the last
+/// characters typed may be the start of a longer word.
+pub fn build_match_query(input: &str) -> Option<String> {
+    let terms: Vec<String> = input
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| format!("\"{}\"*", term.to_lowercase()))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+fn render_highlight(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            MARK_START => out.push_str("<mark>"),
+            MARK_END => out.push_str("</mark>"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Repopulate the full-text index from the products table.
+pub async fn rebuild_index(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM products_fts")
+        .execute(&mut *tx)
+        .await?;
+
+    let indexed = sqlx::query(
+        "INSERT INTO products_fts (product_id, name, description, tags)
+         SELECT p.id, p.name, p.description,
+                (SELECT GROUP_CONCAT(t.tag, ' ') FROM product_tags t WHERE t.product_id = p.id)
+         FROM products p"
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    sqlx::query("INSERT INTO products_fts (products_fts) VALUES ('optimize')")
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(indexed as i64)
+}
+
+// Public: ranked full-text search over name, description and tags
+pub async fn search_products(
+    query: web::Query<SearchQuery>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(DEFAULT_RESULTS);
+    if !(1..=MAX_RESULTS).contains(&limit) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": format!("limit must be between 1 and {}", MAX_RESULTS)
+        }));
+    }
+
+    let match_query = match build_match_query(&query.q) {
+        Some(match_query) => match_query,
+        None => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": "Search query is required"
+            }));
+        }
+    };
+
+    // Name matches weigh most, then tags, then description
+    let sql = format!(
+        "{},
+            bm25(products_fts, 0.0, 10.0, 1.0, 5.0) AS score,
+            highlight(products_fts, 1, char(2), char(3)) AS name_highlighted,
+            snippet(products_fts, 2, char(2), char(3), '…', 16) AS snippet
+        FROM products_fts
+        JOIN products p ON p.id = products_fts.product_id
+        WHERE products_fts MATCH ? AND p.archived_at IS NULL
+        ORDER BY score, p.id
+        LIMIT ?",
+        PRODUCT_COLUMNS
+    );
+
+    match sqlx::query(&sql)
+        .bind(&match_query)
+        .bind(limit)
+        .fetch_all(&app_state.db)
+        .await {
+            Ok(rows) => {
+                let results: Vec<SearchResult> = rows.iter().map(|row| SearchResult {
+                    product: products::product_from_row(row),
+                    score: row.get("score"),
+                    name_highlighted: render_highlight(&row.get::<String, _>("name_highlighted")),
+                    snippet: render_highlight(&row.get::<Option<String>, _>("snippet").unwrap_or_default()),
+                }).collect();
+
+                HttpResponse::Ok().json(json!({
+                    "success": true,
+                    "query": query.q,
+                    "results": results
+                }))
+            },
+            Err(e) => {
+                error!("Product search for {:?} failed: {}", query.q, e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Search failed"
+                }))
+            }
+        }
+}
+
+// Admin: rebuild the search index from scratch
+#[post("/search/rebuild")]
+pub async fn rebuild_search_index(
+    auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    match rebuild_index(&app_state.db).await {
+        Ok(indexed) => {
+            info!("Search index rebuilt by {} ({} products)", auth.claims.sub, indexed);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "indexed": indexed
+            }))
+        },
+        Err(e) => {
+            error!("Failed to rebuild search index: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to rebuild search index"
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[test]
+    fn match_queries_quote_every_word_as_a_prefix() {
+        assert_eq!(build_match_query("Red  shirt").as_deref(), Some("\"red\"* \"shirt\"*"));
+        // FTS5 syntax in the input is just punctuation
+        assert_eq!(build_match_query("a\" OR b*").as_deref(), Some("\"a\"* \"or\"* \"b\"*"));
+        assert_eq!(build_match_query("Café").as_deref(), Some("\"café\"*"));
+        assert_eq!(build_match_query(" -*- "), None);
+    }
+
+    #[test]
+    fn highlights_are_escaped_before_marking() {
+        let text = format!("<b>{}Tom{} & \"Jerry\"</b>", MARK_START, MARK_END);
+        assert_eq!(render_highlight(&text), "&lt;b&gt;<mark>Tom</mark> &amp; &quot;Jerry&quot;&lt;/b&gt;");
+    }
+
+    #[actix_web::test]
+    async fn the_index_is_rebuilt_from_products_and_tags() {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE products (id TEXT PRIMARY KEY, name TEXT NOT NULL, description TEXT NOT NULL)",
+            "CREATE TABLE product_tags (product_id TEXT NOT NULL, tag TEXT NOT NULL)",
+            "CREATE VIRTUAL TABLE products_fts USING fts5(product_id UNINDEXED, name, description, tags, tokenize = 'unicode61 remove_diacritics 2')",
+            "INSERT INTO products VALUES ('p1', 'Crème brûlée torch', 'Kitchen blowtorch'), ('p2', 'Mug', 'Holds coffee')",
+            "INSERT INTO product_tags VALUES ('p2', 'kitchen'), ('p2', 'gift')",
+            "INSERT INTO products_fts (product_id, name) VALUES ('stale', 'mug')",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+
+        assert_eq!(rebuild_index(&db).await.unwrap(), 2);
+
+        let search = |input: &str| {
+            let db = db.clone();
+            let match_query = build_match_query(input).unwrap();
+            async move {
+                sqlx::query("SELECT product_id FROM products_fts WHERE products_fts MATCH ? ORDER BY product_id")
+                    .bind(match_query)
+                    .fetch_all(&db)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|row| row.get::<String, _>("product_id"))
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(search("mug").await, ["p2"]);
+        assert_eq!(search("kitch").await, ["p1", "p2"]);
+        assert_eq!(search("creme brulee").await, ["p1"]);
+        assert_eq!(search("gift coffee").await, ["p2"]);
+        assert!(search("gift torch").await.is_empty());
+    }
+}
@@ -150,20 +150,7 @@ pub async fn initialize_database() -> Result<SqlitePool, sqlx::Error> {
     .await?;
     println!("✅ Created addresses table");
     
-    // Create an admin user for testing
-    let now = chrono::Utc::now().timestamp();
-    sqlx::query(
-        "INSERT OR IGNORE INTO users (id, username, password_hash, role, created_at) 
-         VALUES (?, ?, ?, ?, ?)"
-    )
-    .bind("usr-admin")
-    .bind("admin")
-    .bind("$2a$12$K9oSWH9P1lnSCNw6imJAWOkXReJLQNZ3ug7H8lbYOqhR5ptXdFdQe") // hash for 'admin123'
-    .bind("admin")
-    .bind(now)
-    .execute(&pool)
-    .await?;
-    println!("✅ Created admin user (username: admin, password: admin123)");
+    println!("ℹ️ Create the first admin with `secure_store admin bootstrap`");
     
     println!("🎉 Database setup complete!");
 
@@ -0,0 +1,755 @@
+// src/shipments.rs
+use actix_web::{web, HttpResponse, Responder, get, post, put, delete};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::Utc;
+use uuid::Uuid;
+use sqlx::{Row, SqliteConnection, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use log::{info, error};
+use crate::AppState;
+use crate::rbac::{perm, Authorized};
+use crate::orders::{self, OrderStatus, StatusChange, StatusError};
+
+/// Placeholder in a carrier's tracking URL template
+pub const TRACKING_PLACEHOLDER: &str = "{tracking_number}";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Carrier {
+    pub id: String,
+    pub code: String,
+    pub name: String,
+    /// e.g. `https://www.dhl.com/track?id={tracking_number}`
+    pub tracking_url_template: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CarrierInput {
+    pub code: String,
+    pub name: String,
+    pub tracking_url_template: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ShipmentItem {
+    pub order_item_id: i64,
+    pub product_id: String,
+    pub name: Option<String>,
+    pub variant_name: Option<String>,
+    pub quantity: i64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Shipment {
+    pub id: String,
+    pub order_id: String,
+    pub carrier_id: Option<String>,
+    /// Copied when shipping so the shipment survives the carrier being deleted
+    pub carrier_name: String,
+    pub tracking_number: String,
+    pub tracking_url: Option<String>,
+    pub shipped_at: i64,
+    pub items: Vec<ShipmentItem>,
+    pub created_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ShipmentItemInput {
+    pub order_item_id: i64,
+    pub quantity: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ShipmentInput {
+    pub carrier_id: String,
+    pub tracking_number: String,
+    /// Defaults to now
+    pub shipped_at: Option<i64>,
+    /// Lines and quantities in this parcel; everything not yet shipped when left out
+    pub items: Option<Vec<ShipmentItemInput>>,
+}
+
+#[derive(Debug)]
+pub enum ShipmentError {
+    OrderNotFound,
+    NotShippable(OrderStatus),
+    UnknownCarrier,
+    UnknownItem(i64),
+    TooMany { order_item_id: i64, remaining: i64 },
+    NothingToShip,
+    Invalid(String),
+    Status(StatusError),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for ShipmentError {
+    fn from(e: sqlx::Error) -> Self {
+        ShipmentError::Database(e)
+    }
+}
+
+impl From<StatusError> for ShipmentError {
+    fn from(e: StatusError) -> Self {
+        match e {
+            StatusError::Database(e) => ShipmentError::Database(e),
+            other => ShipmentError::Status(other),
+        }
+    }
+}
+
+impl std::fmt::Display for ShipmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShipmentError::OrderNotFound => write!(f, "Order not found"),
+            ShipmentError::NotShippable(status) => write!(f, "An order that is {} cannot be shipped", status),
+            ShipmentError::UnknownCarrier => write!(f, "Unknown carrier"),
+            ShipmentError::UnknownItem(id) => write!(f, "Order item {} is not a shippable item of this order", id),
+            ShipmentError::TooMany { order_item_id, remaining } => {
+                write!(f, "Only {} of order item {} are left to ship", remaining, order_item_id)
+            },
+            ShipmentError::NothingToShip => write!(f, "Nothing left to ship on this order"),
+            ShipmentError::Invalid(msg) => write!(f, "{}", msg),
+            ShipmentError::Status(e) => write!(f, "{}", e),
+            ShipmentError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl ShipmentError {
+    /// Response for a shipment that cannot be recorded; database errors are hidden.
+    pub fn to_response(&self) -> HttpResponse {
+        let body = json!({
+            "success": false,
+            "error": self.to_string()
+        });
+        match self {
+            ShipmentError::OrderNotFound | ShipmentError::UnknownCarrier => HttpResponse::NotFound().json(body),
+            ShipmentError::NotShippable(_) | ShipmentError::NothingToShip => HttpResponse::Conflict().json(body),
+            ShipmentError::Status(e) => e.to_response(),
+            ShipmentError::Database(e) => {
+                error!("Recording shipment failed: {}", e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to record shipment"
+                }))
+            },
+            _ => HttpResponse::BadRequest().json(body),
+        }
+    }
+}
+
+/// Fill a carrier's tracking URL template in for one tracking number.
+pub fn tracking_url(template: Option<&str>, tracking_number: &str) -> Option<String> {
+    template.map(|t| t.replace(TRACKING_PLACEHOLDER, tracking_number))
+}
+
+fn validate_carrier(input: &CarrierInput) -> Result<(String, Option<String>), String> {
+    let code = input.code.trim().to_lowercase();
+    if code.is_empty() || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Carrier code must be letters, digits, '-' or '_'".to_string());
+    }
+    if input.name.trim().is_empty() {
+        return Err("Carrier name is required".to_string());
+    }
+    let template = input.tracking_url_template.as_deref()
+        .map(str::trim)
+        .filter(|t| !t.is_empty());
+    if let Some(t) = template {
+        if !(t.starts_with("https://") || t.starts_with("http://")) || !t.contains(TRACKING_PLACEHOLDER) {
+            return Err(format!("Tracking URL template must be an http(s) URL containing {}", TRACKING_PLACEHOLDER));
+        }
+    }
+    Ok((code, template.map(str::to_string)))
+}
+
+/// Tracking numbers are embedded in URLs, so only letters, digits and '-' are accepted.
+fn normalize_tracking_number(tracking_number: &str) -> Result<String, ShipmentError> {
+    let tracking_number = tracking_number.trim().to_uppercase();
+    if tracking_number.is_empty()
+        || tracking_number.len() > 64
+        || !tracking_number.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(ShipmentError::Invalid("Tracking number must be 1-64 letters, digits or '-'".to_string()));
+    }
+    Ok(tracking_number)
+}
+
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(db) if db.message().contains("UNIQUE"))
+}
+
+async fn fetch_carriers(pool: &SqlitePool, carrier_id: Option<&str>) -> Result<Vec<Carrier>, sqlx::Error> {
+    let filter = if carrier_id.is_some() { "WHERE id = ?" } else { "" };
+    let sql = format!(
+        "SELECT id, code, name, tracking_url_template, created_at FROM carriers {} ORDER BY name",
+        filter
+    );
+    let mut query = sqlx::query(&sql);
+    if let Some(id) = carrier_id {
+        query = query.bind(id);
+    }
+    let rows = query.fetch_all(pool).await?;
+
+    Ok(rows.iter().map(|row| Carrier {
+        id: row.get("id"),
+        code: row.get("code"),
+        name: row.get("name"),
+        tracking_url_template: row.get("tracking_url_template"),
+        created_at: row.get("created_at"),
+    }).collect())
+}
+
+/// Quantity still to ship per physical line of an order. Digital items are
+/// delivered by download and never shipped.
+async fn unshipped_quantities(conn: &mut SqliteConnection, order_id: &str) -> Result<HashMap<i64, i64>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT oi.id, oi.quantity - COALESCE(
+                    (SELECT SUM(si.quantity) FROM shipment_items si WHERE si.order_item_id = oi.id), 0
+                ) AS remaining
+         FROM order_items oi
+         LEFT JOIN products p ON p.id = oi.product_id
+         WHERE oi.order_number = ? AND p.digital_delivery IS NULL"
+    )
+    .bind(order_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(rows.iter().map(|row| (row.get("id"), row.get("remaining"))).collect())
+}
+
+/// Record a parcel leaving for an order. Once every physical item has been
+/// shipped the order moves from `Paid` to `Shipped`; earlier parcels leave
+/// it `Paid` as a partial shipment.
+pub async fn create_shipment(
+    pool: &SqlitePool,
+    order_id: &str,
+    input: &ShipmentInput,
+    change: &StatusChange,
+) -> Result<Shipment, ShipmentError> {
+    let tracking_number = normalize_tracking_number(&input.tracking_number)?;
+    let shipped_at = input.shipped_at.unwrap_or_else(|| Utc::now().timestamp());
+
+    let mut tx = pool.begin().await?;
+
+    let status: String = sqlx::query("SELECT status FROM orders WHERE id = ?")
+        .bind(order_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ShipmentError::OrderNotFound)?
+        .get("status");
+    let status: OrderStatus = status.parse()?;
+    if !matches!(status, OrderStatus::Paid | OrderStatus::Shipped) {
+        return Err(ShipmentError::NotShippable(status));
+    }
+
+    let carrier_name: String = sqlx::query("SELECT name FROM carriers WHERE id = ?")
+        .bind(&input.carrier_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ShipmentError::UnknownCarrier)?
+        .get("name");
+
+    let mut remaining = unshipped_quantities(&mut tx, order_id).await?;
+    let lines: Vec<(i64, i64)> = match &input.items {
+        Some(items) => {
+            let mut seen = HashSet::new();
+            for item in items {
+                if item.quantity <= 0 {
+                    return Err(ShipmentError::Invalid("Shipped quantities must be positive".to_string()));
+                }
+                if !seen.insert(item.order_item_id) {
+                    return Err(ShipmentError::Invalid(format!("Order item {} is listed twice", item.order_item_id)));
+                }
+                let left = *remaining.get(&item.order_item_id).ok_or(ShipmentError::UnknownItem(item.order_item_id))?;
+                if item.quantity > left {
+                    return Err(ShipmentError::TooMany { order_item_id: item.order_item_id, remaining: left });
+                }
+            }
+            items.iter().map(|item| (item.order_item_id, item.quantity)).collect()
+        },
+        None => {
+            let mut all: Vec<(i64, i64)> = remaining.iter()
+                .filter(|(_, left)| **left > 0)
+                .map(|(id, left)| (*id, *left))
+                .collect();
+            all.sort();
+            all
+        },
+    };
+    if lines.is_empty() {
+        return Err(ShipmentError::NothingToShip);
+    }
+
+    let shipment_id = format!("shp-{}", Uuid::new_v4().simple());
+    sqlx::query(
+        "INSERT INTO shipments (id, order_id, carrier_id, carrier_name, tracking_number, shipped_at, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&shipment_id)
+    .bind(order_id)
+    .bind(&input.carrier_id)
+    .bind(&carrier_name)
+    .bind(&tracking_number)
+    .bind(shipped_at)
+    .bind(Utc::now().timestamp())
+    .execute(&mut *tx)
+    .await?;
+
+    for (order_item_id, quantity) in &lines {
+        sqlx::query("INSERT INTO shipment_items (shipment_id, order_item_id, quantity) VALUES (?, ?, ?)")
+            .bind(&shipment_id)
+            .bind(order_item_id)
+            .bind(quantity)
+            .execute(&mut *tx)
+            .await?;
+        if let Some(left) = remaining.get_mut(order_item_id) {
+            *left -= quantity;
+        }
+    }
+
+    if status == OrderStatus::Paid && remaining.values().all(|left| *left <= 0) {
+        let change = StatusChange {
+            actor: change.actor.clone(),
+            reason: change.reason.clone()
+                .or_else(|| Some(format!("Shipped with {} ({})", carrier_name, tracking_number))),
+        };
+        orders::transition_status(&mut tx, order_id, OrderStatus::Shipped, &change).await?;
+    }
+
+    tx.commit().await?;
+
+    let shipment = shipments_for_order(pool, order_id).await?
+        .into_iter()
+        .find(|s| s.id == shipment_id)
+        .ok_or(ShipmentError::OrderNotFound)?;
+    Ok(shipment)
+}
+
+/// Shipments of an order with their items and tracking links, oldest first.
+pub async fn shipments_for_order(pool: &SqlitePool, order_id: &str) -> Result<Vec<Shipment>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT s.id, s.order_id, s.carrier_id, s.carrier_name, s.tracking_number,
+                s.shipped_at, s.created_at, c.tracking_url_template
+         FROM shipments s
+         LEFT JOIN carriers c ON c.id = s.carrier_id
+         WHERE s.order_id = ?
+         ORDER BY s.shipped_at, s.created_at"
+    )
+    .bind(order_id)
+    .fetch_all(pool)
+    .await?;
+
+    let item_rows = sqlx::query(
+        "SELECT si.shipment_id, si.order_item_id, si.quantity, oi.product_id, oi.variant_name, p.name
+         FROM shipment_items si
+         JOIN shipments s ON s.id = si.shipment_id
+         JOIN order_items oi ON oi.id = si.order_item_id
+         LEFT JOIN products p ON p.id = oi.product_id
+         WHERE s.order_id = ?
+         ORDER BY si.id"
+    )
+    .bind(order_id)
+    .fetch_all(pool)
+    .await?;
+    let mut items: HashMap<String, Vec<ShipmentItem>> = HashMap::new();
+    for row in &item_rows {
+        items.entry(row.get("shipment_id")).or_default().push(ShipmentItem {
+            order_item_id: row.get("order_item_id"),
+            product_id: row.get("product_id"),
+            name: row.get("name"),
+            variant_name: row.get("variant_name"),
+            quantity: row.get("quantity"),
+        });
+    }
+
+    Ok(rows.iter().map(|row| {
+        let id: String = row.get("id");
+        let tracking_number: String = row.get("tracking_number");
+        Shipment {
+            items: items.remove(&id).unwrap_or_default(),
+            id,
+            order_id: row.get("order_id"),
+            carrier_id: row.get("carrier_id"),
+            carrier_name: row.get("carrier_name"),
+            tracking_url: tracking_url(row.get::<Option<String>, _>("tracking_url_template").as_deref(), &tracking_number),
+            tracking_number,
+            shipped_at: row.get("shipped_at"),
+            created_at: row.get("created_at"),
+        }
+    }).collect())
+}
+
+fn bad_request(msg: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "success": false,
+        "error": msg
+    }))
+}
+
+fn not_found(what: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(json!({
+        "success": false,
+        "error": format!("{} not found", what)
+    }))
+}
+
+fn code_taken() -> HttpResponse {
+    HttpResponse::Conflict().json(json!({
+        "success": false,
+        "error": "A carrier with this code already exists"
+    }))
+}
+
+// Admin: all carriers
+#[get("/carriers")]
+pub async fn list_carriers(
+    _auth: Authorized<perm::OrdersRead>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    match fetch_carriers(&app_state.db, None).await {
+        Ok(carriers) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "carriers": carriers
+        })),
+        Err(e) => {
+            error!("Failed to list carriers: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to list carriers"
+            }))
+        }
+    }
+}
+
+// Admin: add a carrier
+#[post("/carriers")]
+pub async fn create_carrier(
+    auth: Authorized<perm::OrdersFulfil>,
+    body: web::Json<CarrierInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let (code, template) = match validate_carrier(&body) {
+        Ok(valid) => valid,
+        Err(msg) => return bad_request(msg),
+    };
+
+    let carrier_id = format!("car-{}", Uuid::new_v4().simple());
+
+    let result = async {
+        sqlx::query("INSERT INTO carriers (id, code, name, tracking_url_template, created_at) VALUES (?, ?, ?, ?, ?)")
+            .bind(&carrier_id)
+            .bind(&code)
+            .bind(body.name.trim())
+            .bind(&template)
+            .bind(Utc::now().timestamp())
+            .execute(&app_state.db)
+            .await?;
+        fetch_carriers(&app_state.db, Some(&carrier_id)).await
+    }.await;
+
+    match result {
+        Ok(carriers) => {
+            info!("Carrier {} created by {}", carrier_id, auth.claims.sub);
+            HttpResponse::Created().json(json!({
+                "success": true,
+                "carrier": carriers.into_iter().next()
+            }))
+        },
+        Err(e) if is_unique_violation(&e) => code_taken(),
+        Err(e) => {
+            error!("Failed to create carrier: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to create carrier"
+            }))
+        }
+    }
+}
+
+// Admin: update a carrier. Existing shipments pick up a changed tracking URL template.
+#[put("/carriers/{id}")]
+pub async fn update_carrier(
+    auth: Authorized<perm::OrdersFulfil>,
+    path: web::Path<String>,
+    body: web::Json<CarrierInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let carrier_id = path.into_inner();
+    let (code, template) = match validate_carrier(&body) {
+        Ok(valid) => valid,
+        Err(msg) => return bad_request(msg),
+    };
+
+    let result = async {
+        let updated = sqlx::query("UPDATE carriers SET code = ?, name = ?, tracking_url_template = ? WHERE id = ?")
+            .bind(&code)
+            .bind(body.name.trim())
+            .bind(&template)
+            .bind(&carrier_id)
+            .execute(&app_state.db)
+            .await?
+            .rows_affected();
+        if updated == 0 {
+            return Ok(None);
+        }
+        Ok::<_, sqlx::Error>(fetch_carriers(&app_state.db, Some(&carrier_id)).await?.into_iter().next())
+    }.await;
+
+    match result {
+        Ok(Some(carrier)) => {
+            info!("Carrier {} updated by {}", carrier_id, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "carrier": carrier
+            }))
+        },
+        Ok(None) => not_found("Carrier"),
+        Err(e) if is_unique_violation(&e) => code_taken(),
+        Err(e) => {
+            error!("Failed to update carrier {}: {}", carrier_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to update carrier"
+            }))
+        }
+    }
+}
+
+// Admin: delete a carrier. Its shipments keep the carrier name but lose the tracking link.
+#[delete("/carriers/{id}")]
+pub async fn delete_carrier(
+    auth: Authorized<perm::OrdersFulfil>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let carrier_id = path.into_inner();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        sqlx::query("UPDATE shipments SET carrier_id = NULL WHERE carrier_id = ?")
+            .bind(&carrier_id)
+            .execute(&mut *tx)
+            .await?;
+        let deleted = sqlx::query("DELETE FROM carriers WHERE id = ?")
+            .bind(&carrier_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(deleted)
+    }.await;
+
+    match result {
+        Ok(1) => {
+            info!("Carrier {} deleted by {}", carrier_id, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "Carrier deleted"
+            }))
+        },
+        Ok(_) => not_found("Carrier"),
+        Err(e) => {
+            error!("Failed to delete carrier {}: {}", carrier_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to delete carrier"
+            }))
+        }
+    }
+}
+
+// Admin: shipments of an order
+#[get("/orders/{id}/shipments")]
+pub async fn list_order_shipments(
+    _auth: Authorized<perm::OrdersRead>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let order_id = path.into_inner();
+    match shipments_for_order(&app_state.db, &order_id).await {
+        Ok(shipments) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "shipments": shipments
+        })),
+        Err(e) => {
+            error!("Failed to list shipments for order {}: {}", order_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to list shipments"
+            }))
+        }
+    }
+}
+
+// Admin: record a (possibly partial) shipment for an order
+#[post("/orders/{id}/shipments")]
+pub async fn ship_order(
+    auth: Authorized<perm::OrdersFulfil>,
+    path: web::Path<String>,
+    body: web::Json<ShipmentInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let order_id = path.into_inner();
+    let change = StatusChange::admin(&auth.claims.username, None);
+
+    match create_shipment(&app_state.db, &order_id, &body, &change).await {
+        Ok(shipment) => {
+            info!("Shipment {} for order {} recorded by {}", shipment.id, order_id, auth.claims.sub);
+            HttpResponse::Created().json(json!({
+                "success": true,
+                "shipment": shipment
+            }))
+        },
+        Err(e) => e.to_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    const ORDER: &str = "ORD-1";
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE orders (id TEXT PRIMARY KEY, status TEXT NOT NULL, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL)",
+            "CREATE TABLE order_items (id INTEGER PRIMARY KEY AUTOINCREMENT, order_number TEXT NOT NULL, product_id TEXT NOT NULL, variant_name TEXT, quantity INTEGER NOT NULL)",
+            "CREATE TABLE order_status_history (id INTEGER PRIMARY KEY AUTOINCREMENT, order_id TEXT NOT NULL, from_status TEXT, to_status TEXT NOT NULL, actor TEXT NOT NULL, reason TEXT, created_at INTEGER NOT NULL)",
+            "CREATE TABLE products (id TEXT PRIMARY KEY, name TEXT NOT NULL, digital_delivery TEXT)",
+            "CREATE TABLE carriers (id TEXT PRIMARY KEY, code TEXT NOT NULL UNIQUE, name TEXT NOT NULL, tracking_url_template TEXT, created_at INTEGER NOT NULL)",
+            "CREATE TABLE shipments (id TEXT PRIMARY KEY, order_id TEXT NOT NULL, carrier_id TEXT, carrier_name TEXT NOT NULL, tracking_number TEXT NOT NULL, shipped_at INTEGER NOT NULL, created_at INTEGER NOT NULL)",
+            "CREATE TABLE shipment_items (id INTEGER PRIMARY KEY AUTOINCREMENT, shipment_id TEXT NOT NULL, order_item_id INTEGER NOT NULL, quantity INTEGER NOT NULL CHECK (quantity > 0))",
+            "INSERT INTO products VALUES ('p-mug', 'Mug', NULL), ('p-ebook', 'E-book', 'download')",
+            "INSERT INTO carriers VALUES ('car-dhl', 'dhl', 'DHL', 'https://dhl.example/track?id={tracking_number}', 0)",
+            "INSERT INTO orders VALUES ('ORD-1', 'Paid', 0, 0)",
+            // Line 1 is shipped, line 2 is downloaded
+            "INSERT INTO order_items (order_number, product_id, quantity) VALUES ('ORD-1', 'p-mug', 3), ('ORD-1', 'p-ebook', 1)",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    fn parcel(tracking_number: &str, items: Option<&[(i64, i64)]>) -> ShipmentInput {
+        ShipmentInput {
+            carrier_id: "car-dhl".to_string(),
+            tracking_number: tracking_number.to_string(),
+            shipped_at: None,
+            items: items.map(|items| items.iter()
+                .map(|(order_item_id, quantity)| ShipmentItemInput { order_item_id: *order_item_id, quantity: *quantity })
+                .collect()),
+        }
+    }
+
+    async fn order_status(db: &SqlitePool) -> String {
+        sqlx::query("SELECT status FROM orders WHERE id = ?")
+            .bind(ORDER)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .get("status")
+    }
+
+    #[test]
+    fn carriers_and_tracking_numbers_are_checked() {
+        let carrier = |code: &str, name: &str, template: Option<&str>| CarrierInput {
+            code: code.to_string(),
+            name: name.to_string(),
+            tracking_url_template: template.map(str::to_string),
+        };
+        assert_eq!(
+            validate_carrier(&carrier(" DHL ", "DHL", Some(" "))).unwrap(),
+            ("dhl".to_string(), None)
+        );
+        for bad in [
+            carrier("dhl express", "DHL", None),
+            carrier("dhl", " ", None),
+            carrier("dhl", "DHL", Some("https://dhl.example/track")),
+            carrier("dhl", "DHL", Some("javascript:{tracking_number}")),
+        ] {
+            assert!(validate_carrier(&bad).is_err(), "{} {:?}", bad.code, bad.tracking_url_template);
+        }
+
+        assert_eq!(normalize_tracking_number(" abc-123 ").unwrap(), "ABC-123");
+        for bad in ["", "abc 123", "abc&x=1", &"9".repeat(65)] {
+            assert!(normalize_tracking_number(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            tracking_url(Some("https://t.example/?n={tracking_number}"), "ABC").as_deref(),
+            Some("https://t.example/?n=ABC")
+        );
+    }
+
+    #[actix_web::test]
+    async fn the_order_ships_with_its_last_parcel() {
+        let db = database().await;
+        let admin = StatusChange::admin("root", None);
+
+        let first = create_shipment(&db, ORDER, &parcel("abc-1", Some(&[(1, 1)])), &admin).await.unwrap();
+        assert_eq!(first.tracking_url.as_deref(), Some("https://dhl.example/track?id=ABC-1"));
+        assert_eq!(first.items[0].name.as_deref(), Some("Mug"));
+        assert_eq!(order_status(&db).await, "Paid", "a partial shipment");
+
+        assert!(matches!(
+            create_shipment(&db, ORDER, &parcel("abc-2", Some(&[(1, 3)])), &admin).await,
+            Err(ShipmentError::TooMany { order_item_id: 1, remaining: 2 })
+        ));
+        // The e-book is downloaded, so it's never part of a parcel
+        assert!(matches!(
+            create_shipment(&db, ORDER, &parcel("abc-2", Some(&[(2, 1)])), &admin).await,
+            Err(ShipmentError::UnknownItem(2))
+        ));
+
+        // Without items, a parcel takes whatever is left
+        let last = create_shipment(&db, ORDER, &parcel("abc-2", None), &admin).await.unwrap();
+        assert_eq!(last.items.iter().map(|i| (i.order_item_id, i.quantity)).collect::<Vec<_>>(), [(1, 2)]);
+        assert_eq!(order_status(&db).await, "Shipped");
+        let step = orders::status_history(&db, ORDER).await.unwrap().pop().unwrap();
+        assert_eq!(step.actor, "admin:root");
+        assert_eq!(step.reason.as_deref(), Some("Shipped with DHL (ABC-2)"));
+
+        assert!(matches!(
+            create_shipment(&db, ORDER, &parcel("abc-3", None), &admin).await,
+            Err(ShipmentError::NothingToShip)
+        ));
+        assert_eq!(shipments_for_order(&db, ORDER).await.unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn bad_parcels_record_nothing() {
+        let db = database().await;
+        let admin = StatusChange::admin("root", None);
+
+        let mut unknown_carrier = parcel("abc-1", None);
+        unknown_carrier.carrier_id = "car-missing".to_string();
+        assert!(matches!(
+            create_shipment(&db, ORDER, &unknown_carrier, &admin).await,
+            Err(ShipmentError::UnknownCarrier)
+        ));
+        for items in [&[(1, 0)][..], &[(1, 1), (1, 1)]] {
+            assert!(matches!(
+                create_shipment(&db, ORDER, &parcel("abc-1", Some(items)), &admin).await,
+                Err(ShipmentError::Invalid(_))
+            ));
+        }
+        assert!(matches!(
+            create_shipment(&db, "ORD-missing", &parcel("abc-1", None), &admin).await,
+            Err(ShipmentError::OrderNotFound)
+        ));
+
+        sqlx::query("UPDATE orders SET status = 'AwaitingPayment'").execute(&db).await.unwrap();
+        assert!(matches!(
+            create_shipment(&db, ORDER, &parcel("abc-1", None), &admin).await,
+            Err(ShipmentError::NotShippable(OrderStatus::AwaitingPayment))
+        ));
+        assert!(shipments_for_order(&db, ORDER).await.unwrap().is_empty());
+    }
+}
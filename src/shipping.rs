@@ -0,0 +1,1015 @@
+// src/shipping.rs
+use actix_web::{web, HttpResponse, Responder, get, post, put, delete};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::Utc;
+use uuid::Uuid;
+use sqlx::{Row, Sqlite, SqliteConnection, SqlitePool, Transaction};
+use std::collections::{HashMap, HashSet};
+use log::{info, error};
+use crate::AppState;
+use crate::rbac::{perm, Authorized};
+
+pub const RATE_FLAT: &str = "flat";
+/// Tiers keyed on total parcel weight in grams
+pub const RATE_WEIGHT: &str = "weight";
+/// Tiers keyed on the subtotal of the shipped items
+pub const RATE_PRICE: &str = "price";
+
+/// Country code of a zone that covers every country not listed elsewhere
+pub const ANY_COUNTRY: &str = "*";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RateTier {
+    /// Lowest weight (grams) or subtotal the tier applies from
+    pub min: f64,
+    pub rate: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ShippingMethod {
+    pub id: String,
+    pub zone_id: String,
+    pub name: String,
+    pub rate_type: String,
+    /// Price of a `flat` method
+    pub rate: Option<f64>,
+    pub tiers: Vec<RateTier>,
+    /// Shipped-items subtotal from which the method is free
+    pub free_over: Option<f64>,
+    pub active: bool,
+    pub created_at: i64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ShippingZone {
+    pub id: String,
+    pub name: String,
+    /// ISO 3166-1 alpha-2 codes, or `*` for the rest of the world
+    pub countries: Vec<String>,
+    pub methods: Vec<ShippingMethod>,
+    pub created_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ZoneInput {
+    pub name: String,
+    pub countries: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TierInput {
+    pub min: f64,
+    pub rate: f64,
+}
+
+#[derive(Deserialize)]
+pub struct MethodInput {
+    pub name: String,
+    pub rate_type: String,
+    pub rate: Option<f64>,
+    #[serde(default)]
+    pub tiers: Vec<TierInput>,
+    pub free_over: Option<f64>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+/// One order line as far as shipping is concerned.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ShippingLine {
+    #[serde(alias = "id")]
+    pub product_id: String,
+    pub quantity: i64,
+    pub price: f64,
+}
+
+#[derive(Deserialize)]
+pub struct QuoteRequest {
+    pub country: String,
+    pub items: Vec<ShippingLine>,
+}
+
+/// Price of one method for a given set of lines.
+#[derive(Serialize, Debug, Clone)]
+pub struct MethodQuote {
+    pub method_id: String,
+    pub name: String,
+    pub cost: f64,
+    pub free_shipping: bool,
+}
+
+/// What the physical part of an order weighs and costs; digital items are left out.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Parcel {
+    pub weight_grams: i64,
+    pub subtotal: f64,
+}
+
+#[derive(Debug)]
+pub enum ShippingError {
+    UnknownProduct(String),
+    NoZone(String),
+    MethodRequired,
+    MethodUnavailable,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for ShippingError {
+    fn from(e: sqlx::Error) -> Self {
+        ShippingError::Database(e)
+    }
+}
+
+impl std::fmt::Display for ShippingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShippingError::UnknownProduct(id) => write!(f, "Unknown product: {}", id),
+            ShippingError::NoZone(country) => write!(f, "We do not ship to {}", country),
+            ShippingError::MethodRequired => write!(f, "Choose a shipping method"),
+            ShippingError::MethodUnavailable => write!(f, "The chosen shipping method is not available for this order"),
+            ShippingError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl ShippingError {
+    /// Response for an order that cannot be shipped as asked; database errors are hidden.
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            ShippingError::Database(e) => {
+                error!("Shipping calculation failed: {}", e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to calculate shipping"
+                }))
+            },
+            other => HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": other.to_string()
+            })),
+        }
+    }
+}
+
+pub fn normalize_country(country: &str) -> String {
+    country.trim().to_uppercase()
+}
+
+fn round_money(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(db) if db.message().contains("UNIQUE"))
+}
+
+impl ShippingMethod {
+    /// Cost of shipping `parcel` with this method, or `None` when no tier covers it.
+    pub fn cost_for(&self, parcel: Parcel) -> Option<f64> {
+        if self.free_over.is_some_and(|free_over| parcel.subtotal >= free_over) {
+            return Some(0.0);
+        }
+
+        let measure = match self.rate_type.as_str() {
+            RATE_FLAT => return self.rate,
+            RATE_WEIGHT => parcel.weight_grams as f64,
+            _ => parcel.subtotal,
+        };
+
+        // Tiers are kept sorted by `min`; the highest one reached applies
+        self.tiers.iter()
+            .rev()
+            .find(|tier| tier.min <= measure)
+            .map(|tier| round_money(tier.rate))
+    }
+}
+
+async fn load_methods(
+    conn: &mut SqliteConnection,
+    filter: &str,
+    bind: Option<&str>,
+) -> Result<Vec<ShippingMethod>, sqlx::Error> {
+    let sql = format!(
+        "SELECT id, zone_id, name, rate_type, rate, free_over, active, created_at
+         FROM shipping_methods {} ORDER BY created_at, name",
+        filter
+    );
+    let mut query = sqlx::query(&sql);
+    if let Some(value) = bind {
+        query = query.bind(value);
+    }
+    let rows = query.fetch_all(&mut *conn).await?;
+
+    let mut methods: Vec<ShippingMethod> = rows.iter().map(|row| ShippingMethod {
+        id: row.get("id"),
+        zone_id: row.get("zone_id"),
+        name: row.get("name"),
+        rate_type: row.get("rate_type"),
+        rate: row.get("rate"),
+        tiers: Vec::new(),
+        free_over: row.get("free_over"),
+        active: row.get("active"),
+        created_at: row.get("created_at"),
+    }).collect();
+
+    if methods.is_empty() {
+        return Ok(methods);
+    }
+
+    let tier_rows = sqlx::query("SELECT method_id, min_value, rate FROM shipping_rate_tiers ORDER BY method_id, min_value")
+        .fetch_all(&mut *conn)
+        .await?;
+    let mut tiers: HashMap<String, Vec<RateTier>> = HashMap::new();
+    for row in &tier_rows {
+        tiers.entry(row.get("method_id")).or_default().push(RateTier {
+            min: row.get("min_value"),
+            rate: row.get("rate"),
+        });
+    }
+    for method in &mut methods {
+        method.tiers = tiers.remove(&method.id).unwrap_or_default();
+    }
+
+    Ok(methods)
+}
+
+async fn fetch_zones(pool: &SqlitePool, zone_id: Option<&str>) -> Result<Vec<ShippingZone>, sqlx::Error> {
+    let filter = if zone_id.is_some() { "WHERE z.id = ?" } else { "" };
+    let sql = format!(
+        "SELECT z.id, z.name, z.created_at,
+                (SELECT GROUP_CONCAT(country_code) FROM shipping_zone_countries WHERE zone_id = z.id) AS countries
+         FROM shipping_zones z {} ORDER BY z.name",
+        filter
+    );
+    let mut query = sqlx::query(&sql);
+    if let Some(id) = zone_id {
+        query = query.bind(id);
+    }
+    let mut conn = pool.acquire().await?;
+    let rows = query.fetch_all(&mut *conn).await?;
+
+    let (method_filter, method_bind) = match zone_id {
+        Some(id) => ("WHERE zone_id = ?", Some(id)),
+        None => ("", None),
+    };
+    let mut methods = load_methods(&mut conn, method_filter, method_bind).await?;
+
+    Ok(rows.iter().map(|row| {
+        let id: String = row.get("id");
+        let mut countries: Vec<String> = row.get::<Option<String>, _>("countries")
+            .map(|c| c.split(',').map(str::to_string).collect())
+            .unwrap_or_default();
+        countries.sort();
+        let (zone_methods, rest): (Vec<_>, Vec<_>) = methods.drain(..).partition(|m| m.zone_id == id);
+        methods = rest;
+
+        ShippingZone {
+            id,
+            name: row.get("name"),
+            countries,
+            methods: zone_methods,
+            created_at: row.get("created_at"),
+        }
+    }).collect())
+}
+
+/// Zone covering `country`: an explicit listing wins over the catch-all zone.
+async fn zone_for_country(conn: &mut SqliteConnection, country: &str) -> Result<Option<(String, String)>, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT z.id, z.name FROM shipping_zone_countries c
+         JOIN shipping_zones z ON z.id = c.zone_id
+         WHERE c.country_code IN (?, ?)
+         ORDER BY c.country_code = ? LIMIT 1"
+    )
+    .bind(country)
+    .bind(ANY_COUNTRY)
+    .bind(ANY_COUNTRY)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(row.map(|row| (row.get("id"), row.get("name"))))
+}
+
+/// Weight and subtotal of the lines that need shipping. Returns `None` when
+/// every item is delivered digitally.
+pub async fn parcel_for(conn: &mut SqliteConnection, lines: &[ShippingLine]) -> Result<Option<Parcel>, ShippingError> {
+    let mut parcel = Parcel { weight_grams: 0, subtotal: 0.0 };
+    let mut physical = false;
+
+    for line in lines {
+        let row = sqlx::query("SELECT weight_grams, digital_delivery FROM products WHERE id = ?")
+            .bind(&line.product_id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .ok_or_else(|| ShippingError::UnknownProduct(line.product_id.clone()))?;
+
+        if row.get::<Option<String>, _>("digital_delivery").is_some() {
+            continue;
+        }
+        physical = true;
+        parcel.weight_grams += row.get::<i64, _>("weight_grams") * line.quantity;
+        parcel.subtotal += line.price * line.quantity as f64;
+    }
+
+    parcel.subtotal = round_money(parcel.subtotal);
+    Ok(physical.then_some(parcel))
+}
+
+/// Whether the shop has set up any shipping at all; until it has, orders are
+/// taken without a shipping charge.
+async fn shipping_configured(conn: &mut SqliteConnection) -> Result<bool, sqlx::Error> {
+    Ok(sqlx::query("SELECT 1 FROM shipping_methods WHERE active = 1 LIMIT 1")
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some())
+}
+
+/// Active methods of the zone covering `country` with their price for `parcel`,
+/// cheapest first. Methods with no tier covering the parcel are left out.
+async fn quote_methods(
+    conn: &mut SqliteConnection,
+    country: &str,
+    parcel: Parcel,
+) -> Result<(String, String, Vec<(ShippingMethod, f64)>), ShippingError> {
+    let (zone_id, zone_name) = zone_for_country(conn, country)
+        .await?
+        .ok_or_else(|| ShippingError::NoZone(country.to_string()))?;
+
+    let mut priced: Vec<(ShippingMethod, f64)> = load_methods(&mut *conn, "WHERE zone_id = ? AND active = 1", Some(&zone_id))
+        .await?
+        .into_iter()
+        .filter_map(|method| method.cost_for(parcel).map(|cost| (method, cost)))
+        .collect();
+    priced.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    Ok((zone_id, zone_name, priced))
+}
+
+/// Charge shipping on an order being created in `tx`: stores the chosen method
+/// and cost on the order and adds the cost to its total. Returns `None` when
+/// nothing needs shipping or the shop has no shipping set up.
+pub async fn apply_to_order(
+    tx: &mut Transaction<'_, Sqlite>,
+    order_id: &str,
+    country: &str,
+    method_id: Option<&str>,
+    lines: &[ShippingLine],
+) -> Result<Option<MethodQuote>, ShippingError> {
+    let parcel = match parcel_for(tx, lines).await? {
+        Some(parcel) => parcel,
+        None => return Ok(None),
+    };
+    if !shipping_configured(tx).await? {
+        return match method_id {
+            Some(_) => Err(ShippingError::MethodUnavailable),
+            None => Ok(None),
+        };
+    }
+
+    let (_, _, priced) = quote_methods(tx, &normalize_country(country), parcel).await?;
+    let method_id = method_id.ok_or(ShippingError::MethodRequired)?;
+    let (method, cost) = priced.into_iter()
+        .find(|(method, _)| method.id == method_id)
+        .ok_or(ShippingError::MethodUnavailable)?;
+
+    sqlx::query(
+        "UPDATE orders SET shipping_method_id = ?, shipping_method_name = ?, shipping_cost = ?,
+            total_amount = total_amount + ?
+         WHERE id = ?"
+    )
+    .bind(&method.id)
+    .bind(&method.name)
+    .bind(cost)
+    .bind(cost)
+    .bind(order_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(Some(MethodQuote {
+        method_id: method.id,
+        name: method.name,
+        cost,
+        free_shipping: cost == 0.0,
+    }))
+}
+
+// Public: shipping methods and prices available for a cart and destination
+pub async fn quote_shipping(
+    body: web::Json<QuoteRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let body = body.into_inner();
+
+    if body.items.iter().any(|l| l.quantity <= 0 || !l.price.is_finite() || l.price < 0.0) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "Every item needs a positive quantity and a non-negative price"
+        }));
+    }
+
+    let country = normalize_country(&body.country);
+
+    let result = async {
+        let mut conn = app_state.db.acquire().await?;
+        let parcel = match parcel_for(&mut conn, &body.items).await? {
+            Some(parcel) => parcel,
+            None => return Ok(None),
+        };
+        if !shipping_configured(&mut conn).await? {
+            return Ok(None);
+        }
+        let quote = quote_methods(&mut conn, &country, parcel).await?;
+        Ok::<_, ShippingError>(Some((parcel, quote)))
+    }.await;
+
+    match result {
+        Ok(Some((parcel, (zone_id, zone_name, priced)))) => {
+            let methods: Vec<MethodQuote> = priced.into_iter().map(|(method, cost)| MethodQuote {
+                method_id: method.id,
+                name: method.name,
+                cost,
+                free_shipping: cost == 0.0,
+            }).collect();
+
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "shipping_required": true,
+                "country": country,
+                "zone": { "id": zone_id, "name": zone_name },
+                "weight_grams": parcel.weight_grams,
+                "subtotal": parcel.subtotal,
+                "methods": methods
+            }))
+        },
+        Ok(None) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "shipping_required": false,
+            "country": country,
+            "methods": []
+        })),
+        Err(e) => e.to_response(),
+    }
+}
+
+fn validate_zone(input: &ZoneInput) -> Result<Vec<String>, String> {
+    if input.name.trim().is_empty() {
+        return Err("Zone name is required".to_string());
+    }
+    if input.countries.is_empty() {
+        return Err("A zone needs at least one country".to_string());
+    }
+
+    let mut countries = Vec::new();
+    for country in &input.countries {
+        let code = normalize_country(country);
+        if code != ANY_COUNTRY && !(code.len() == 2 && code.chars().all(|c| c.is_ascii_uppercase())) {
+            return Err(format!("Invalid country code '{}': use ISO 3166-1 alpha-2 or '*'", country));
+        }
+        if !countries.contains(&code) {
+            countries.push(code);
+        }
+    }
+    Ok(countries)
+}
+
+fn validate_method(input: &MethodInput) -> Result<(), String> {
+    if input.name.trim().is_empty() {
+        return Err("Method name is required".to_string());
+    }
+    if input.free_over.is_some_and(|v| !v.is_finite() || v < 0.0) {
+        return Err("free_over must be a non-negative number".to_string());
+    }
+
+    match input.rate_type.as_str() {
+        RATE_FLAT => {
+            if !input.rate.is_some_and(|r| r.is_finite() && r >= 0.0) {
+                return Err("A flat method needs a non-negative rate".to_string());
+            }
+            if !input.tiers.is_empty() {
+                return Err("A flat method takes no tiers".to_string());
+            }
+        },
+        RATE_WEIGHT | RATE_PRICE => {
+            if input.tiers.is_empty() {
+                return Err(format!("A {} method needs at least one tier", input.rate_type));
+            }
+            let mut seen = HashSet::new();
+            for tier in &input.tiers {
+                if !tier.min.is_finite() || tier.min < 0.0 || !tier.rate.is_finite() || tier.rate < 0.0 {
+                    return Err("Tier min and rate must be non-negative numbers".to_string());
+                }
+                if !seen.insert(tier.min.to_bits()) {
+                    return Err(format!("Duplicate tier starting at {}", tier.min));
+                }
+            }
+        },
+        _ => {
+            return Err(format!("rate_type must be '{}', '{}' or '{}'", RATE_FLAT, RATE_WEIGHT, RATE_PRICE));
+        }
+    }
+    Ok(())
+}
+
+async fn set_countries(tx: &mut Transaction<'_, Sqlite>, zone_id: &str, countries: &[String]) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM shipping_zone_countries WHERE zone_id = ?")
+        .bind(zone_id)
+        .execute(&mut **tx)
+        .await?;
+    for country in countries {
+        sqlx::query("INSERT INTO shipping_zone_countries (zone_id, country_code) VALUES (?, ?)")
+            .bind(zone_id)
+            .bind(country)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn set_tiers(tx: &mut Transaction<'_, Sqlite>, method_id: &str, tiers: &[TierInput]) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM shipping_rate_tiers WHERE method_id = ?")
+        .bind(method_id)
+        .execute(&mut **tx)
+        .await?;
+    for tier in tiers {
+        sqlx::query("INSERT INTO shipping_rate_tiers (method_id, min_value, rate) VALUES (?, ?, ?)")
+            .bind(method_id)
+            .bind(tier.min)
+            .bind(tier.rate)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+fn bad_request(msg: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "success": false,
+        "error": msg
+    }))
+}
+
+fn not_found(what: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(json!({
+        "success": false,
+        "error": format!("{} not found", what)
+    }))
+}
+
+fn country_taken() -> HttpResponse {
+    HttpResponse::Conflict().json(json!({
+        "success": false,
+        "error": "A country can only belong to one shipping zone"
+    }))
+}
+
+// Admin: all shipping zones with their methods
+#[get("/shipping/zones")]
+pub async fn list_zones(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    match fetch_zones(&app_state.db, None).await {
+        Ok(zones) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "zones": zones
+        })),
+        Err(e) => {
+            error!("Failed to list shipping zones: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to list shipping zones"
+            }))
+        }
+    }
+}
+
+// Admin: create a shipping zone
+#[post("/shipping/zones")]
+pub async fn create_zone(
+    auth: Authorized<perm::ProductsWrite>,
+    body: web::Json<ZoneInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let countries = match validate_zone(&body) {
+        Ok(countries) => countries,
+        Err(msg) => return bad_request(msg),
+    };
+
+    let zone_id = format!("zone-{}", Uuid::new_v4().simple());
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        sqlx::query("INSERT INTO shipping_zones (id, name, created_at) VALUES (?, ?, ?)")
+            .bind(&zone_id)
+            .bind(body.name.trim())
+            .bind(Utc::now().timestamp())
+            .execute(&mut *tx)
+            .await?;
+        set_countries(&mut tx, &zone_id, &countries).await?;
+        tx.commit().await?;
+        fetch_zones(&app_state.db, Some(&zone_id)).await
+    }.await;
+
+    match result {
+        Ok(zones) => {
+            info!("Shipping zone {} created by {}", zone_id, auth.claims.sub);
+            HttpResponse::Created().json(json!({
+                "success": true,
+                "zone": zones.into_iter().next()
+            }))
+        },
+        Err(e) if is_unique_violation(&e) => country_taken(),
+        Err(e) => {
+            error!("Failed to create shipping zone: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to create shipping zone"
+            }))
+        }
+    }
+}
+
+// Admin: rename a zone and replace its countries
+#[put("/shipping/zones/{id}")]
+pub async fn update_zone(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    body: web::Json<ZoneInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let zone_id = path.into_inner();
+    let countries = match validate_zone(&body) {
+        Ok(countries) => countries,
+        Err(msg) => return bad_request(msg),
+    };
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        let updated = sqlx::query("UPDATE shipping_zones SET name = ? WHERE id = ?")
+            .bind(body.name.trim())
+            .bind(&zone_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if updated == 0 {
+            return Ok(None);
+        }
+        set_countries(&mut tx, &zone_id, &countries).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(fetch_zones(&app_state.db, Some(&zone_id)).await?.into_iter().next())
+    }.await;
+
+    match result {
+        Ok(Some(zone)) => {
+            info!("Shipping zone {} updated by {}", zone_id, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "zone": zone
+            }))
+        },
+        Ok(None) => not_found("Shipping zone"),
+        Err(e) if is_unique_violation(&e) => country_taken(),
+        Err(e) => {
+            error!("Failed to update shipping zone {}: {}", zone_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to update shipping zone"
+            }))
+        }
+    }
+}
+
+// Admin: delete a zone and its methods. Orders keep the method name and cost
+// they were charged.
+#[delete("/shipping/zones/{id}")]
+pub async fn delete_zone(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let zone_id = path.into_inner();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        sqlx::query("DELETE FROM shipping_rate_tiers WHERE method_id IN (SELECT id FROM shipping_methods WHERE zone_id = ?)")
+            .bind(&zone_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM shipping_methods WHERE zone_id = ?")
+            .bind(&zone_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM shipping_zone_countries WHERE zone_id = ?")
+            .bind(&zone_id)
+            .execute(&mut *tx)
+            .await?;
+        let deleted = sqlx::query("DELETE FROM shipping_zones WHERE id = ?")
+            .bind(&zone_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(deleted)
+    }.await;
+
+    match result {
+        Ok(1) => {
+            info!("Shipping zone {} deleted by {}", zone_id, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "Shipping zone deleted"
+            }))
+        },
+        Ok(_) => not_found("Shipping zone"),
+        Err(e) => {
+            error!("Failed to delete shipping zone {}: {}", zone_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to delete shipping zone"
+            }))
+        }
+    }
+}
+
+// Admin: add a shipping method to a zone
+#[post("/shipping/zones/{id}/methods")]
+pub async fn create_method(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    body: web::Json<MethodInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let zone_id = path.into_inner();
+    if let Err(msg) = validate_method(&body) {
+        return bad_request(msg);
+    }
+
+    let method_id = format!("ship-{}", Uuid::new_v4().simple());
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        let zone_exists = sqlx::query("SELECT id FROM shipping_zones WHERE id = ?")
+            .bind(&zone_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+        if !zone_exists {
+            return Ok(None);
+        }
+
+        sqlx::query(
+            "INSERT INTO shipping_methods (id, zone_id, name, rate_type, rate, free_over, active, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&method_id)
+        .bind(&zone_id)
+        .bind(body.name.trim())
+        .bind(&body.rate_type)
+        .bind(if body.rate_type == RATE_FLAT { body.rate } else { None })
+        .bind(body.free_over)
+        .bind(body.active)
+        .bind(Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?;
+        set_tiers(&mut tx, &method_id, &body.tiers).await?;
+
+        let method = load_methods(&mut tx, "WHERE id = ?", Some(&method_id)).await?.into_iter().next();
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(method)
+    }.await;
+
+    match result {
+        Ok(Some(method)) => {
+            info!("Shipping method {} added to zone {} by {}", method_id, zone_id, auth.claims.sub);
+            HttpResponse::Created().json(json!({
+                "success": true,
+                "method": method
+            }))
+        },
+        Ok(None) => not_found("Shipping zone"),
+        Err(e) => {
+            error!("Failed to create shipping method: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to create shipping method"
+            }))
+        }
+    }
+}
+
+// Admin: replace a shipping method's name, rates and threshold
+#[put("/shipping/methods/{id}")]
+pub async fn update_method(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    body: web::Json<MethodInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let method_id = path.into_inner();
+    if let Err(msg) = validate_method(&body) {
+        return bad_request(msg);
+    }
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        let updated = sqlx::query(
+            "UPDATE shipping_methods SET name = ?, rate_type = ?, rate = ?, free_over = ?, active = ?
+             WHERE id = ?"
+        )
+        .bind(body.name.trim())
+        .bind(&body.rate_type)
+        .bind(if body.rate_type == RATE_FLAT { body.rate } else { None })
+        .bind(body.free_over)
+        .bind(body.active)
+        .bind(&method_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Ok(None);
+        }
+        set_tiers(&mut tx, &method_id, &body.tiers).await?;
+
+        let method = load_methods(&mut tx, "WHERE id = ?", Some(&method_id)).await?.into_iter().next();
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(method)
+    }.await;
+
+    match result {
+        Ok(Some(method)) => {
+            info!("Shipping method {} updated by {}", method_id, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "method": method
+            }))
+        },
+        Ok(None) => not_found("Shipping method"),
+        Err(e) => {
+            error!("Failed to update shipping method {}: {}", method_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to update shipping method"
+            }))
+        }
+    }
+}
+
+// Admin: delete a shipping method
+#[delete("/shipping/methods/{id}")]
+pub async fn delete_method(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let method_id = path.into_inner();
+
+    let result = async {
+        let mut tx = app_state.db.begin().await?;
+        sqlx::query("DELETE FROM shipping_rate_tiers WHERE method_id = ?")
+            .bind(&method_id)
+            .execute(&mut *tx)
+            .await?;
+        let deleted = sqlx::query("DELETE FROM shipping_methods WHERE id = ?")
+            .bind(&method_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(deleted)
+    }.await;
+
+    match result {
+        Ok(1) => {
+            info!("Shipping method {} deleted by {}", method_id, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "message": "Shipping method deleted"
+            }))
+        },
+        Ok(_) => not_found("Shipping method"),
+        Err(e) => {
+            error!("Failed to delete shipping method {}: {}", method_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to delete shipping method"
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    fn method(rate_type: &str, rate: Option<f64>, tiers: &[(f64, f64)], free_over: Option<f64>) -> ShippingMethod {
+        ShippingMethod {
+            id: "m1".to_string(),
+            zone_id: "z1".to_string(),
+            name: "Post".to_string(),
+            rate_type: rate_type.to_string(),
+            rate,
+            tiers: tiers.iter().map(|&(min, rate)| RateTier { min, rate }).collect(),
+            free_over,
+            active: true,
+            created_at: 0,
+        }
+    }
+
+    fn parcel(weight_grams: i64, subtotal: f64) -> Parcel {
+        Parcel { weight_grams, subtotal }
+    }
+
+    fn method_input(rate_type: &str, rate: Option<f64>, tiers: &[(f64, f64)]) -> MethodInput {
+        MethodInput {
+            name: "Post".to_string(),
+            rate_type: rate_type.to_string(),
+            rate,
+            tiers: tiers.iter().map(|&(min, rate)| TierInput { min, rate }).collect(),
+            free_over: None,
+            active: true,
+        }
+    }
+
+    #[test]
+    fn free_shipping_starts_at_the_threshold() {
+        let post = method(RATE_FLAT, Some(4.95), &[], Some(50.0));
+        assert_eq!(post.cost_for(parcel(500, 49.99)), Some(4.95));
+        assert_eq!(post.cost_for(parcel(500, 50.0)), Some(0.0));
+    }
+
+    #[test]
+    fn weight_tiers_use_the_highest_tier_reached() {
+        let post = method(RATE_WEIGHT, None, &[(0.0, 3.0), (1000.0, 5.5), (5000.0, 12.0)], None);
+        assert_eq!(post.cost_for(parcel(999, 100.0)), Some(3.0));
+        assert_eq!(post.cost_for(parcel(1000, 100.0)), Some(5.5));
+        assert_eq!(post.cost_for(parcel(20000, 100.0)), Some(12.0));
+    }
+
+    #[test]
+    fn price_tiers_skip_parcels_below_the_lowest_tier() {
+        let courier = method(RATE_PRICE, None, &[(20.0, 9.999), (100.0, 4.0)], None);
+        assert_eq!(courier.cost_for(parcel(0, 19.99)), None);
+        assert_eq!(courier.cost_for(parcel(0, 20.0)), Some(10.0));
+        assert_eq!(courier.cost_for(parcel(0, 150.0)), Some(4.0));
+    }
+
+    #[test]
+    fn methods_are_validated() {
+        assert!(validate_method(&method_input(RATE_FLAT, Some(0.0), &[])).is_ok());
+        assert!(validate_method(&method_input(RATE_FLAT, None, &[])).is_err());
+        assert!(validate_method(&method_input(RATE_FLAT, Some(1.0), &[(0.0, 1.0)])).is_err());
+        assert!(validate_method(&method_input(RATE_WEIGHT, None, &[])).is_err());
+        assert!(validate_method(&method_input(RATE_WEIGHT, None, &[(0.0, 1.0), (0.0, 2.0)])).is_err());
+        assert!(validate_method(&method_input(RATE_PRICE, None, &[(-1.0, 1.0)])).is_err());
+        assert!(validate_method(&method_input("free", None, &[])).is_err());
+
+        let mut input = method_input(RATE_FLAT, Some(1.0), &[]);
+        input.free_over = Some(f64::NAN);
+        assert!(validate_method(&input).is_err());
+    }
+
+    #[test]
+    fn zone_countries_are_normalized() {
+        let zone = |countries: &[&str]| ZoneInput {
+            name: "Europe".to_string(),
+            countries: countries.iter().map(|c| c.to_string()).collect(),
+        };
+        assert_eq!(validate_zone(&zone(&["de", " FR ", "DE"])).unwrap(), ["DE", "FR"]);
+        assert_eq!(validate_zone(&zone(&["*"])).unwrap(), [ANY_COUNTRY]);
+        assert!(validate_zone(&zone(&["DEU"])).is_err());
+        assert!(validate_zone(&zone(&[])).is_err());
+    }
+
+    #[actix_web::test]
+    async fn parcels_leave_out_digital_items() {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE products (id TEXT PRIMARY KEY, weight_grams INTEGER NOT NULL, digital_delivery TEXT)")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO products (id, weight_grams, digital_delivery) VALUES ('book', 400, NULL), ('ebook', 0, 'download')")
+            .execute(&db)
+            .await
+            .unwrap();
+        let line = |product_id: &str, quantity, price| ShippingLine { product_id: product_id.to_string(), quantity, price };
+        let mut conn = db.acquire().await.unwrap();
+
+        let found = parcel_for(&mut conn, &[line("book", 3, 3.333), line("ebook", 1, 8.0)]).await.unwrap().unwrap();
+        assert_eq!((found.weight_grams, found.subtotal), (1200, 10.0));
+        assert!(parcel_for(&mut conn, &[line("ebook", 2, 8.0)]).await.unwrap().is_none());
+        assert!(matches!(
+            parcel_for(&mut conn, &[line("lamp", 1, 20.0)]).await,
+            Err(ShippingError::UnknownProduct(id)) if id == "lamp"
+        ));
+    }
+}
@@ -0,0 +1,83 @@
+// src/storage.rs
+use futures::future::BoxFuture;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// Where uploaded files live. Keys are relative, slash-separated paths such as
+/// `products/<id>/<image>.png`; implementations decide how they map to storage
+/// and which public URL serves them.
+pub trait ImageStorage: Send + Sync {
+    fn put<'a>(&'a self, key: &'a str, bytes: Vec<u8>) -> BoxFuture<'a, io::Result<()>>;
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>>;
+    fn url(&self, key: &str) -> String;
+}
+
+/// Stores files under a directory on local disk, served by the app itself.
+pub struct LocalDiskStorage {
+    root: PathBuf,
+    base_url: String,
+}
+
+impl LocalDiskStorage {
+    pub fn new(root: impl Into<PathBuf>, base_url: &str) -> Self {
+        LocalDiskStorage {
+            root: root.into(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Location of a stored file on disk.
+    pub fn path_for(&self, key: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(key);
+        // Keys are generated server-side, but never let one escape the root
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid storage key"));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl ImageStorage for LocalDiskStorage {
+    fn put<'a>(&'a self, key: &'a str, bytes: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let path = self.path_for(key)?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(path, bytes).await
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path_for(key)?).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                other => other,
+            }
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url, key)
+    }
+}
+
+/// Local disk storage configured from `UPLOAD_DIR` and `UPLOAD_BASE_URL`.
+pub fn local_storage_from_env() -> Arc<LocalDiskStorage> {
+    let root = std::env::var("UPLOAD_DIR").unwrap_or_else(|_| "./data/uploads".to_string());
+    let base_url = std::env::var("UPLOAD_BASE_URL").unwrap_or_else(|_| "/uploads".to_string());
+    Arc::new(LocalDiskStorage::new(root, &base_url))
+}
+
+/// Private storage for digital product files, configured from `DIGITAL_FILES_DIR`.
+/// Nothing serves this directory directly; files are only handed out through
+/// signed download links.
+pub fn digital_storage_from_env() -> LocalDiskStorage {
+    let root = std::env::var("DIGITAL_FILES_DIR").unwrap_or_else(|_| "./data/digital".to_string());
+    LocalDiskStorage::new(root, "/downloads")
+}
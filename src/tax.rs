@@ -0,0 +1,773 @@
+// src/tax.rs
+use actix_web::{web, HttpResponse, Responder, get, post, put, delete};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::Utc;
+use uuid::Uuid;
+use sqlx::{Row, Sqlite, SqliteConnection, SqlitePool, Transaction};
+use sqlx::sqlite::SqliteRow;
+use std::collections::HashMap;
+use log::{info, error};
+use crate::AppState;
+use crate::rbac::{perm, Authorized};
+
+/// Class every product belongs to unless given another; shipping is taxed
+/// with the rules of this class
+pub const STANDARD_CLASS: &str = "standard";
+
+/// Country code of rules that apply wherever no more specific rule does
+pub const ANY_COUNTRY: &str = "*";
+
+/// Whether catalog prices already include tax. Read from
+/// `PRICES_INCLUDE_TAX`; prices are tax-exclusive by default.
+pub fn prices_include_tax() -> bool {
+    std::env::var("PRICES_INCLUDE_TAX")
+        .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaxRule {
+    pub id: String,
+    /// ISO 3166-1 alpha-2 code, or `*`
+    pub country: String,
+    /// State/province code; `None` covers the whole country
+    pub region: Option<String>,
+    pub tax_class: String,
+    /// Label shown on tax lines, e.g. "VAT"
+    pub name: String,
+    /// Percentage
+    pub rate: f64,
+    /// Standard-class rules only: whether shipping is taxed too
+    pub tax_shipping: bool,
+    pub created_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct RuleInput {
+    pub country: String,
+    pub region: Option<String>,
+    #[serde(default = "default_class")]
+    pub tax_class: String,
+    pub name: String,
+    pub rate: f64,
+    #[serde(default)]
+    pub tax_shipping: bool,
+}
+
+pub fn default_class() -> String {
+    STANDARD_CLASS.to_string()
+}
+
+/// Tax classes are short lower-case slugs such as `standard` or `reduced`.
+pub fn normalize_class(class: &str) -> Result<String, String> {
+    let class = class.trim().to_lowercase();
+    if class.is_empty() || class.len() > 32 {
+        return Err("tax_class must be 1 to 32 characters".to_string());
+    }
+    if !class.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("tax_class may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(class)
+}
+
+/// One order line as far as tax is concerned.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TaxableLine {
+    #[serde(alias = "id")]
+    pub product_id: String,
+    pub quantity: i64,
+    pub price: f64,
+}
+
+#[derive(Deserialize)]
+pub struct QuoteRequest {
+    pub country: String,
+    pub region: Option<String>,
+    pub items: Vec<TaxableLine>,
+    #[serde(default)]
+    pub discount: f64,
+    #[serde(default)]
+    pub shipping_cost: f64,
+}
+
+/// Tax charged under one rule.
+#[derive(Serialize, Debug, Clone)]
+pub struct TaxLine {
+    pub rule_id: String,
+    pub name: String,
+    pub country: String,
+    pub region: Option<String>,
+    pub tax_class: String,
+    pub rate: f64,
+    /// Net amount the rate was applied to
+    pub taxable_amount: f64,
+    pub tax_amount: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaxSummary {
+    /// When true the tax is already part of the prices and was not added to the total
+    pub prices_include_tax: bool,
+    pub lines: Vec<TaxLine>,
+    pub total_tax: f64,
+}
+
+#[derive(Deserialize)]
+pub struct ReportQuery {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+pub fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn round_money(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+fn rule_from_row(row: &SqliteRow) -> TaxRule {
+    TaxRule {
+        id: row.get("id"),
+        country: row.get("country"),
+        region: row.get("region"),
+        tax_class: row.get("tax_class"),
+        name: row.get("name"),
+        rate: row.get("rate"),
+        tax_shipping: row.get("tax_shipping"),
+        created_at: row.get("created_at"),
+    }
+}
+
+const RULE_COLUMNS: &str = "SELECT id, country, region, tax_class, name, rate, tax_shipping, created_at FROM tax_rules";
+
+/// Rules for each tax class at a destination. Only the most specific level
+/// matching the destination applies per class: region rules, then
+/// country-wide rules, then `*` rules. Several rules at the same level
+/// (e.g. federal and provincial sales tax) all apply.
+async fn rules_for_destination(
+    conn: &mut SqliteConnection,
+    country: &str,
+    region: Option<&str>,
+) -> Result<HashMap<String, Vec<TaxRule>>, sqlx::Error> {
+    let rows = sqlx::query(&format!("{} WHERE country IN (?, ?) ORDER BY name, id", RULE_COLUMNS))
+        .bind(country)
+        .bind(ANY_COUNTRY)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let specificity = |rule: &TaxRule| -> Option<u8> {
+        match (&rule.region, rule.country.as_str()) {
+            (None, ANY_COUNTRY) => Some(0),
+            (None, _) => Some(1),
+            (Some(r), c) if c != ANY_COUNTRY && Some(r.as_str()) == region => Some(2),
+            _ => None,
+        }
+    };
+
+    let mut best: HashMap<String, (u8, Vec<TaxRule>)> = HashMap::new();
+    for rule in rows.iter().map(rule_from_row) {
+        let level = match specificity(&rule) {
+            Some(level) => level,
+            None => continue,
+        };
+        let entry = best.entry(rule.tax_class.clone()).or_insert((level, Vec::new()));
+        if level > entry.0 {
+            *entry = (level, vec![rule]);
+        } else if level == entry.0 {
+            entry.1.push(rule);
+        }
+    }
+
+    Ok(best.into_iter().map(|(class, (_, rules))| (class, rules)).collect())
+}
+
+/// Work out the tax on an order. The discount is spread over the lines in
+/// proportion to their value before tax is applied; shipping is taxed under
+/// standard-class rules that opt in.
+pub async fn calculate(
+    conn: &mut SqliteConnection,
+    country: &str,
+    region: Option<&str>,
+    lines: &[TaxableLine],
+    discount: f64,
+    shipping_cost: f64,
+) -> Result<TaxSummary, sqlx::Error> {
+    let inclusive = prices_include_tax();
+    let country = normalize_code(country);
+    let region = region.map(normalize_code).filter(|r| !r.is_empty());
+    let rules = rules_for_destination(conn, &country, region.as_deref()).await?;
+
+    let subtotal: f64 = lines.iter().map(|l| l.price * l.quantity as f64).sum();
+    let discount_share = if subtotal > 0.0 { (discount / subtotal).clamp(0.0, 1.0) } else { 0.0 };
+
+    // Amount (tax-inclusive or not, per the shop setting) subject to each rule set
+    let mut bases: Vec<(&[TaxRule], f64)> = Vec::new();
+    for line in lines {
+        let class: Option<String> = sqlx::query("SELECT tax_class FROM products WHERE id = ?")
+            .bind(&line.product_id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .map(|row| row.get("tax_class"));
+        let class = class.unwrap_or_else(default_class);
+
+        if let Some(class_rules) = rules.get(&class) {
+            let amount = line.price * line.quantity as f64 * (1.0 - discount_share);
+            bases.push((class_rules.as_slice(), amount));
+        }
+    }
+
+    let shipping_rules: Vec<TaxRule> = rules.get(STANDARD_CLASS)
+        .map(|r| r.iter().filter(|rule| rule.tax_shipping).cloned().collect())
+        .unwrap_or_default();
+    if shipping_cost > 0.0 && !shipping_rules.is_empty() {
+        bases.push((shipping_rules.as_slice(), shipping_cost));
+    }
+
+    let mut by_rule: Vec<TaxLine> = Vec::new();
+    for (class_rules, amount) in bases {
+        let combined_rate: f64 = class_rules.iter().map(|r| r.rate).sum();
+        let net = if inclusive { amount * 100.0 / (100.0 + combined_rate) } else { amount };
+
+        for rule in class_rules {
+            let tax = net * rule.rate / 100.0;
+            match by_rule.iter_mut().find(|l| l.rule_id == rule.id) {
+                Some(line) => {
+                    line.taxable_amount += net;
+                    line.tax_amount += tax;
+                },
+                None => by_rule.push(TaxLine {
+                    rule_id: rule.id.clone(),
+                    name: rule.name.clone(),
+                    country: rule.country.clone(),
+                    region: rule.region.clone(),
+                    tax_class: rule.tax_class.clone(),
+                    rate: rule.rate,
+                    taxable_amount: net,
+                    tax_amount: tax,
+                }),
+            }
+        }
+    }
+
+    for line in &mut by_rule {
+        line.taxable_amount = round_money(line.taxable_amount);
+        line.tax_amount = round_money(line.tax_amount);
+    }
+    by_rule.retain(|line| line.tax_amount > 0.0);
+
+    Ok(TaxSummary {
+        prices_include_tax: inclusive,
+        total_tax: round_money(by_rule.iter().fold(0.0, |sum, l| sum + l.tax_amount)),
+        lines: by_rule,
+    })
+}
+
+/// Tax an order being created in `tx`: stores its tax lines and tax total,
+/// and adds the tax to the order total when prices exclude it.
+pub async fn apply_to_order(
+    tx: &mut Transaction<'_, Sqlite>,
+    order_id: &str,
+    country: &str,
+    region: Option<&str>,
+    lines: &[TaxableLine],
+    discount: f64,
+    shipping_cost: f64,
+) -> Result<TaxSummary, sqlx::Error> {
+    let summary = calculate(tx, country, region, lines, discount, shipping_cost).await?;
+
+    for line in &summary.lines {
+        sqlx::query(
+            "INSERT INTO order_tax_lines (order_id, rule_id, name, country, region, tax_class, rate, taxable_amount, tax_amount)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(order_id)
+        .bind(&line.rule_id)
+        .bind(&line.name)
+        .bind(&line.country)
+        .bind(&line.region)
+        .bind(&line.tax_class)
+        .bind(line.rate)
+        .bind(line.taxable_amount)
+        .bind(line.tax_amount)
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    let added = if summary.prices_include_tax { 0.0 } else { summary.total_tax };
+    sqlx::query(
+        "UPDATE orders SET tax_amount = ?, prices_include_tax = ?, total_amount = total_amount + ?
+         WHERE id = ?"
+    )
+    .bind(summary.total_tax)
+    .bind(summary.prices_include_tax)
+    .bind(added)
+    .bind(order_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(summary)
+}
+
+/// Tax lines stored on an order, as returned by the order APIs.
+pub async fn tax_lines_for_order(pool: &SqlitePool, order_id: &str) -> Result<Vec<serde_json::Value>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT name, country, region, tax_class, rate, taxable_amount, tax_amount
+         FROM order_tax_lines WHERE order_id = ? ORDER BY id"
+    )
+    .bind(order_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(|row| json!({
+        "name": row.get::<String, _>("name"),
+        "country": row.get::<String, _>("country"),
+        "region": row.get::<Option<String>, _>("region"),
+        "tax_class": row.get::<String, _>("tax_class"),
+        "rate": row.get::<f64, _>("rate"),
+        "taxable_amount": row.get::<f64, _>("taxable_amount"),
+        "tax_amount": row.get::<f64, _>("tax_amount")
+    })).collect())
+}
+
+// Public: tax a cart would be charged at a destination
+pub async fn quote_tax(
+    body: web::Json<QuoteRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let body = body.into_inner();
+
+    if body.items.iter().any(|l| l.quantity <= 0 || !l.price.is_finite() || l.price < 0.0)
+        || !body.discount.is_finite() || body.discount < 0.0
+        || !body.shipping_cost.is_finite() || body.shipping_cost < 0.0 {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "Quantities must be positive and amounts non-negative"
+        }));
+    }
+
+    let result = async {
+        let mut conn = app_state.db.acquire().await?;
+        calculate(&mut conn, &body.country, body.region.as_deref(), &body.items, body.discount, body.shipping_cost).await
+    }.await;
+
+    match result {
+        Ok(summary) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "tax": summary
+        })),
+        Err(e) => {
+            error!("Failed to calculate tax: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to calculate tax"
+            }))
+        }
+    }
+}
+
+/// Validate admin input, returning the normalized country, region and class.
+fn validate_rule(input: &RuleInput) -> Result<(String, Option<String>, String), String> {
+    let country = normalize_code(&input.country);
+    if country != ANY_COUNTRY && !(country.len() == 2 && country.chars().all(|c| c.is_ascii_uppercase())) {
+        return Err(format!("Invalid country code '{}': use ISO 3166-1 alpha-2 or '*'", input.country));
+    }
+
+    let region = input.region.as_deref().map(normalize_code).filter(|r| !r.is_empty());
+    if region.is_some() && country == ANY_COUNTRY {
+        return Err("A region needs a specific country".to_string());
+    }
+
+    if input.name.trim().is_empty() {
+        return Err("Tax name is required".to_string());
+    }
+    let tax_class = normalize_class(&input.tax_class)?;
+    if !input.rate.is_finite() || input.rate < 0.0 || input.rate > 100.0 {
+        return Err("rate must be a percentage between 0 and 100".to_string());
+    }
+    if input.tax_shipping && tax_class != STANDARD_CLASS {
+        return Err(format!("Only '{}' rules can tax shipping", STANDARD_CLASS));
+    }
+
+    Ok((country, region, tax_class))
+}
+
+async fn fetch_rule(pool: &SqlitePool, id: &str) -> Result<Option<TaxRule>, sqlx::Error> {
+    let row = sqlx::query(&format!("{} WHERE id = ?", RULE_COLUMNS))
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.as_ref().map(rule_from_row))
+}
+
+fn rule_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(json!({
+        "success": false,
+        "error": "Tax rule not found"
+    }))
+}
+
+// Admin: all tax rules
+#[get("/tax/rules")]
+pub async fn list_rules(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    match sqlx::query(&format!("{} ORDER BY country, region, tax_class, name", RULE_COLUMNS))
+        .fetch_all(&app_state.db)
+        .await {
+            Ok(rows) => HttpResponse::Ok().json(json!({
+                "success": true,
+                "prices_include_tax": prices_include_tax(),
+                "rules": rows.iter().map(rule_from_row).collect::<Vec<_>>()
+            })),
+            Err(e) => {
+                error!("Failed to list tax rules: {}", e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to list tax rules"
+                }))
+            }
+        }
+}
+
+// Admin: create a tax rule
+#[post("/tax/rules")]
+pub async fn create_rule(
+    auth: Authorized<perm::ProductsWrite>,
+    body: web::Json<RuleInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let (country, region, tax_class) = match validate_rule(&body) {
+        Ok(place) => place,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": msg
+            }));
+        }
+    };
+
+    let rule_id = format!("tax-{}", Uuid::new_v4().simple());
+
+    let result = sqlx::query(
+        "INSERT INTO tax_rules (id, country, region, tax_class, name, rate, tax_shipping, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&rule_id)
+    .bind(&country)
+    .bind(&region)
+    .bind(&tax_class)
+    .bind(body.name.trim())
+    .bind(body.rate)
+    .bind(body.tax_shipping)
+    .bind(Utc::now().timestamp())
+    .execute(&app_state.db)
+    .await;
+
+    match result {
+        Ok(_) => {
+            info!("Tax rule {} ({} {}%) created by {}", rule_id, country, body.rate, auth.claims.sub);
+            match fetch_rule(&app_state.db, &rule_id).await {
+                Ok(rule) => HttpResponse::Created().json(json!({
+                    "success": true,
+                    "rule": rule
+                })),
+                Err(e) => {
+                    error!("Failed to fetch tax rule {}: {}", rule_id, e);
+                    HttpResponse::InternalServerError().json(json!({
+                        "success": false,
+                        "error": "Failed to fetch tax rule"
+                    }))
+                }
+            }
+        },
+        Err(e) => {
+            error!("Failed to create tax rule: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to create tax rule"
+            }))
+        }
+    }
+}
+
+// Admin: replace a tax rule. Orders keep the tax lines they were charged.
+#[put("/tax/rules/{id}")]
+pub async fn update_rule(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    body: web::Json<RuleInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let rule_id = path.into_inner();
+    let (country, region, tax_class) = match validate_rule(&body) {
+        Ok(place) => place,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": msg
+            }));
+        }
+    };
+
+    let result = async {
+        let updated = sqlx::query(
+            "UPDATE tax_rules SET country = ?, region = ?, tax_class = ?, name = ?, rate = ?, tax_shipping = ?
+             WHERE id = ?"
+        )
+        .bind(&country)
+        .bind(&region)
+        .bind(&tax_class)
+        .bind(body.name.trim())
+        .bind(body.rate)
+        .bind(body.tax_shipping)
+        .bind(&rule_id)
+        .execute(&app_state.db)
+        .await?
+        .rows_affected();
+
+        if updated == 0 {
+            return Ok(None);
+        }
+        fetch_rule(&app_state.db, &rule_id).await
+    }.await;
+
+    match result {
+        Ok(Some(rule)) => {
+            info!("Tax rule {} updated by {}", rule_id, auth.claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "rule": rule
+            }))
+        },
+        Ok(None) => rule_not_found(),
+        Err(e) => {
+            error!("Failed to update tax rule {}: {}", rule_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to update tax rule"
+            }))
+        }
+    }
+}
+
+// Admin: delete a tax rule
+#[delete("/tax/rules/{id}")]
+pub async fn delete_rule(
+    auth: Authorized<perm::ProductsWrite>,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let rule_id = path.into_inner();
+
+    match sqlx::query("DELETE FROM tax_rules WHERE id = ?")
+        .bind(&rule_id)
+        .execute(&app_state.db)
+        .await {
+            Ok(result) if result.rows_affected() == 1 => {
+                info!("Tax rule {} deleted by {}", rule_id, auth.claims.sub);
+                HttpResponse::Ok().json(json!({
+                    "success": true,
+                    "message": "Tax rule deleted"
+                }))
+            },
+            Ok(_) => rule_not_found(),
+            Err(e) => {
+                error!("Failed to delete tax rule {}: {}", rule_id, e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to delete tax rule"
+                }))
+            }
+        }
+}
+
+// Admin: tax collected per jurisdiction and rate over a period, for filing.
+// Cancelled and unpaid orders are left out.
+#[get("/tax/report")]
+pub async fn tax_report(
+    _auth: Authorized<perm::OrdersRead>,
+    query: web::Query<ReportQuery>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let from = query.from.unwrap_or(0);
+    let to = query.to.unwrap_or(i64::MAX);
+
+    match sqlx::query(
+        "SELECT t.country, t.region, t.name, t.tax_class, t.rate,
+                COUNT(DISTINCT t.order_id) AS orders,
+                SUM(t.taxable_amount) AS taxable_amount,
+                SUM(t.tax_amount) AS tax_amount
+         FROM order_tax_lines t
+         JOIN orders o ON o.id = t.order_id
+         WHERE o.created_at >= ? AND o.created_at < ?
+           AND o.status NOT IN ('Pending', 'AwaitingPayment', 'Cancelled')
+         GROUP BY t.country, t.region, t.name, t.tax_class, t.rate
+         ORDER BY t.country, t.region, t.name, t.rate"
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_all(&app_state.db)
+    .await {
+        Ok(rows) => {
+            let lines: Vec<serde_json::Value> = rows.iter().map(|row| json!({
+                "country": row.get::<String, _>("country"),
+                "region": row.get::<Option<String>, _>("region"),
+                "name": row.get::<String, _>("name"),
+                "tax_class": row.get::<String, _>("tax_class"),
+                "rate": row.get::<f64, _>("rate"),
+                "orders": row.get::<i64, _>("orders"),
+                "taxable_amount": round_money(row.get::<f64, _>("taxable_amount")),
+                "tax_amount": round_money(row.get::<f64, _>("tax_amount"))
+            })).collect();
+            let total_tax = round_money(lines.iter().filter_map(|l| l["tax_amount"].as_f64()).fold(0.0, |sum, t| sum + t));
+
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "from": query.from,
+                "to": query.to,
+                "lines": lines,
+                "total_tax": total_tax
+            }))
+        },
+        Err(e) => {
+            error!("Failed to build tax report: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to build tax report"
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    // Prices are tax-exclusive here; PRICES_INCLUDE_TAX is left unset
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE products (id TEXT PRIMARY KEY, tax_class TEXT NOT NULL DEFAULT 'standard')",
+            "CREATE TABLE tax_rules (id TEXT PRIMARY KEY, country TEXT NOT NULL, region TEXT, tax_class TEXT NOT NULL DEFAULT 'standard', name TEXT NOT NULL, rate REAL NOT NULL, tax_shipping BOOLEAN NOT NULL DEFAULT FALSE, created_at INTEGER NOT NULL)",
+            "INSERT INTO products (id, tax_class) VALUES ('lamp', 'standard'), ('book', 'reduced'), ('gift-card', 'exempt')",
+            "INSERT INTO tax_rules (id, country, region, tax_class, name, rate, tax_shipping, created_at) VALUES
+                ('world', '*', NULL, 'standard', 'Sales tax', 10, FALSE, 0),
+                ('de-vat', 'DE', NULL, 'standard', 'VAT', 19, TRUE, 0),
+                ('de-reduced', 'DE', NULL, 'reduced', 'VAT', 7, FALSE, 0),
+                ('de-exempt', 'DE', NULL, 'exempt', 'VAT', 0, FALSE, 0),
+                ('ca-gst', 'CA', NULL, 'standard', 'GST', 5, FALSE, 0),
+                ('ca-bc-gst', 'CA', 'BC', 'standard', 'GST', 5, TRUE, 0),
+                ('ca-bc-pst', 'CA', 'BC', 'standard', 'PST', 7, FALSE, 0)",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    fn line(product_id: &str, quantity: i64, price: f64) -> TaxableLine {
+        TaxableLine { product_id: product_id.to_string(), quantity, price }
+    }
+
+    fn rule_input(country: &str, region: Option<&str>, tax_class: &str, rate: f64, tax_shipping: bool) -> RuleInput {
+        RuleInput {
+            country: country.to_string(),
+            region: region.map(str::to_string),
+            tax_class: tax_class.to_string(),
+            name: "VAT".to_string(),
+            rate,
+            tax_shipping,
+        }
+    }
+
+    async fn tax(db: &SqlitePool, country: &str, region: Option<&str>, lines: &[TaxableLine], discount: f64, shipping: f64) -> TaxSummary {
+        let mut conn = db.acquire().await.unwrap();
+        calculate(&mut conn, country, region, lines, discount, shipping).await.unwrap()
+    }
+
+    fn amounts(summary: &TaxSummary) -> Vec<(&str, f64, f64)> {
+        summary.lines.iter().map(|l| (l.rule_id.as_str(), l.taxable_amount, l.tax_amount)).collect()
+    }
+
+    #[actix_web::test]
+    async fn tax_is_rounded_per_rule() {
+        let db = database().await;
+
+        // 10% of 2.97 is 0.297
+        let summary = tax(&db, "us", None, &[line("lamp", 3, 0.99)], 0.0, 0.0).await;
+        assert!(!summary.prices_include_tax);
+        assert_eq!(amounts(&summary), [("world", 2.97, 0.3)]);
+        assert_eq!(summary.total_tax, 0.3);
+    }
+
+    #[actix_web::test]
+    async fn the_most_specific_rules_apply() {
+        let db = database().await;
+        let lamp = [line("lamp", 1, 100.0)];
+
+        // Region rules replace the country-wide GST and stack with each other
+        let bc = tax(&db, "CA", Some("bc"), &lamp, 0.0, 0.0).await;
+        assert_eq!(amounts(&bc), [("ca-bc-gst", 100.0, 5.0), ("ca-bc-pst", 100.0, 7.0)]);
+        assert_eq!(bc.total_tax, 12.0);
+
+        let ontario = tax(&db, "CA", Some("ON"), &lamp, 0.0, 0.0).await;
+        assert_eq!(amounts(&ontario), [("ca-gst", 100.0, 5.0)]);
+
+        // Products of a class with no rule at the destination aren't taxed
+        let books = tax(&db, "FR", None, &[line("book", 1, 100.0)], 0.0, 0.0).await;
+        assert!(books.lines.is_empty());
+        assert_eq!(books.total_tax, 0.0);
+    }
+
+    #[actix_web::test]
+    async fn discounts_are_spread_over_the_lines() {
+        let db = database().await;
+        let lines = [line("lamp", 1, 10.0), line("book", 2, 2.5), line("gift-card", 1, 5.0)];
+
+        // 4.00 off 20.00 takes a fifth off every line; zero-rated lines are left out
+        let summary = tax(&db, "DE", None, &lines, 4.0, 0.0).await;
+        assert_eq!(amounts(&summary), [("de-vat", 8.0, 1.52), ("de-reduced", 4.0, 0.28)]);
+        assert_eq!(summary.total_tax, 1.8);
+
+        // A discount larger than the order leaves nothing to tax
+        let summary = tax(&db, "DE", None, &lines, 50.0, 0.0).await;
+        assert!(summary.lines.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn shipping_is_taxed_only_when_a_standard_rule_opts_in() {
+        let db = database().await;
+        let lines = [line("book", 1, 10.0)];
+
+        let germany = tax(&db, "DE", None, &lines, 0.0, 5.0).await;
+        assert_eq!(amounts(&germany), [("de-reduced", 10.0, 0.7), ("de-vat", 5.0, 0.95)]);
+
+        let bc = tax(&db, "CA", Some("BC"), &[line("lamp", 1, 10.0)], 0.0, 5.0).await;
+        assert_eq!(amounts(&bc), [("ca-bc-gst", 15.0, 0.75), ("ca-bc-pst", 10.0, 0.7)]);
+
+        let ontario = tax(&db, "CA", Some("ON"), &[line("lamp", 1, 10.0)], 0.0, 5.0).await;
+        assert_eq!(amounts(&ontario), [("ca-gst", 10.0, 0.5)]);
+    }
+
+    #[test]
+    fn rules_are_validated() {
+        assert_eq!(
+            validate_rule(&rule_input(" ca ", Some("bc"), "Standard", 5.0, true)).unwrap(),
+            ("CA".to_string(), Some("BC".to_string()), STANDARD_CLASS.to_string())
+        );
+        assert!(validate_rule(&rule_input("*", None, "standard", 0.0, false)).is_ok());
+        assert!(validate_rule(&rule_input("*", Some("BC"), "standard", 5.0, false)).is_err());
+        assert!(validate_rule(&rule_input("CAN", None, "standard", 5.0, false)).is_err());
+        assert!(validate_rule(&rule_input("CA", None, "standard", 100.5, false)).is_err());
+        assert!(validate_rule(&rule_input("CA", None, "reduced", 5.0, true)).is_err());
+        assert!(validate_rule(&rule_input("CA", None, "food & drink", 5.0, false)).is_err());
+    }
+}
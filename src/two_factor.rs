@@ -0,0 +1,617 @@
+//! TOTP two-factor authentication (RFC 6238: HMAC-SHA1, 6 digits, 30 s).
+//!
+//! A user enrols with `/auth/2fa/setup`, which returns the secret and an
+//! `otpauth://` URI to show as a QR code, and confirms with a first code at
+//! `/auth/2fa/enable`, which returns one-time recovery codes. From then on
+//! `/auth/login` answers a correct password with a short-lived pre-auth
+//! token, and only `/auth/login/2fa` with that token and a code (or a
+//! recovery code) starts a session.
+//!
+//! - `REQUIRE_ADMIN_2FA`: refuse staff routes to admin-role accounts without
+//!   2FA (default `true`)
+//! - `TOTP_ISSUER`: name shown in authenticator apps (default `Secure Store`)
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder, get, post};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::{Row, SqlitePool};
+use crate::AppState;
+use crate::auth::{self, ROLE_ADMIN};
+use crate::passwords;
+use crate::session;
+
+pub const DIGITS: u32 = 6;
+pub const STEP_SECONDS: i64 = 30;
+/// Codes from this many steps either side of now are accepted, for clock drift
+pub const ALLOWED_DRIFT_STEPS: i64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// How long the pre-auth token from the password step is valid
+pub const PRE_AUTH_MINUTES: i64 = 5;
+const SECRET_BYTES: usize = 20;
+const PRE_AUTH_AUDIENCE: &str = "2fa";
+const DEFAULT_ISSUER: &str = "Secure Store";
+
+#[derive(Debug)]
+pub enum TwoFactorError {
+    /// 2FA isn't set up, or isn't enabled yet
+    NotEnrolled,
+    AlreadyEnabled,
+    InvalidCode,
+    /// The account's role requires 2FA, so it can't be turned off
+    Required,
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for TwoFactorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TwoFactorError::NotEnrolled => write!(f, "Two-factor authentication is not set up"),
+            TwoFactorError::AlreadyEnabled => write!(f, "Two-factor authentication is already enabled"),
+            TwoFactorError::InvalidCode => write!(f, "Invalid authentication code"),
+            TwoFactorError::Required => write!(f, "Two-factor authentication is required for this account"),
+            TwoFactorError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for TwoFactorError {
+    fn from(e: sqlx::Error) -> Self {
+        TwoFactorError::Database(e)
+    }
+}
+
+impl TwoFactorError {
+    pub fn to_response(&self) -> HttpResponse {
+        let mut response = match self {
+            TwoFactorError::NotEnrolled => HttpResponse::BadRequest(),
+            TwoFactorError::AlreadyEnabled | TwoFactorError::Required => HttpResponse::Conflict(),
+            TwoFactorError::InvalidCode => HttpResponse::Unauthorized(),
+            TwoFactorError::Database(e) => {
+                error!("Two-factor database error: {}", e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to update two-factor authentication"
+                }));
+            },
+        };
+        response.json(json!({
+            "success": false,
+            "error": self.to_string()
+        }))
+    }
+}
+
+/// Whether admin-role accounts must use 2FA (`REQUIRE_ADMIN_2FA`)
+pub fn admin_requirement() -> bool {
+    !matches!(
+        std::env::var("REQUIRE_ADMIN_2FA").map(|v| v.trim().to_ascii_lowercase()).as_deref(),
+        Ok("false" | "0" | "no" | "off")
+    )
+}
+
+/// Whether accounts with this role must use 2FA
+pub fn required_for_role(role: &str) -> bool {
+    role == ROLE_ADMIN && admin_requirement()
+}
+
+/// RFC 4648 base32 without padding, as authenticator apps expect secrets
+fn base32_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+/// The HOTP code for one counter value (RFC 4226)
+fn hotp(secret: &[u8], counter: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    value % 10u32.pow(DIGITS)
+}
+
+fn current_step() -> i64 {
+    Utc::now().timestamp() / STEP_SECONDS
+}
+
+/// The URI authenticator apps read from the enrolment QR code
+pub fn provisioning_uri(username: &str, secret: &str) -> String {
+    let issuer = std::env::var("TOTP_ISSUER").unwrap_or_else(|_| DEFAULT_ISSUER.to_string());
+    let encode = |s: &str| s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect::<String>();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        encode(&issuer), encode(username), secret, encode(&issuer), DIGITS, STEP_SECONDS
+    )
+}
+
+/// Recovery codes are compared without case, spaces or dashes
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_ascii_lowercase()
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    hex::encode(Sha256::digest(normalize_recovery_code(code).as_bytes()))
+}
+
+/// Ten random characters shown as `xxxxx-xxxxx`
+fn new_recovery_code() -> String {
+    const CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+    let code: String = (0..10).map(|_| CHARS[rng.gen_range(0..CHARS.len())] as char).collect();
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+/// Replace a user's recovery codes with new ones, returned once in plain text.
+async fn replace_recovery_codes(pool: &SqlitePool, user_id: &str) -> Result<Vec<String>, sqlx::Error> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| new_recovery_code()).collect();
+    let now = Utc::now().timestamp();
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    for code in &codes {
+        sqlx::query("INSERT INTO totp_recovery_codes (user_id, code_hash, created_at) VALUES (?, ?, ?)")
+            .bind(user_id)
+            .bind(hash_recovery_code(code))
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(codes)
+}
+
+/// Whether the user has confirmed 2FA enrolment
+pub async fn is_enabled(pool: &SqlitePool, user_id: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query("SELECT 1 FROM user_totp WHERE user_id = ? AND enabled_at IS NOT NULL")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.is_some())
+}
+
+/// Whether the user's role requires 2FA but it isn't enabled
+pub async fn missing_required(pool: &SqlitePool, user_id: &str) -> Result<bool, sqlx::Error> {
+    if !admin_requirement() {
+        return Ok(false);
+    }
+    let row = sqlx::query(
+        "SELECT u.role, t.enabled_at FROM users u
+         LEFT JOIN user_totp t ON t.user_id = u.id
+         WHERE u.id = ?"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(match row {
+        Some(row) => required_for_role(&row.get::<String, _>("role")) && row.get::<Option<i64>, _>("enabled_at").is_none(),
+        None => false,
+    })
+}
+
+/// Check a TOTP code against the user's secret. Each code works once: the
+/// step it matched is remembered and that step and earlier ones are refused.
+async fn verify_totp(pool: &SqlitePool, user_id: &str, code: &str, enabled: bool) -> Result<bool, TwoFactorError> {
+    let row = sqlx::query("SELECT secret, enabled_at, last_used_step FROM user_totp WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(TwoFactorError::NotEnrolled)?;
+    if row.get::<Option<i64>, _>("enabled_at").is_some() != enabled {
+        return Err(if enabled { TwoFactorError::NotEnrolled } else { TwoFactorError::AlreadyEnabled });
+    }
+
+    let code = code.trim();
+    let Ok(code) = code.parse::<u32>() else {
+        return Ok(false);
+    };
+    let secret = hex::decode(row.get::<String, _>("secret")).map_err(|_| TwoFactorError::NotEnrolled)?;
+    let last_used: i64 = row.get::<Option<i64>, _>("last_used_step").unwrap_or(0);
+
+    let now = current_step();
+    let Some(step) = (now - ALLOWED_DRIFT_STEPS..=now + ALLOWED_DRIFT_STEPS)
+        .find(|&step| step > last_used && hotp(&secret, step) == code)
+    else {
+        return Ok(false);
+    };
+
+    // Claim the step; a concurrent request with the same code loses
+    let claimed = sqlx::query("UPDATE user_totp SET last_used_step = ? WHERE user_id = ? AND COALESCE(last_used_step, 0) < ?")
+        .bind(step)
+        .bind(user_id)
+        .bind(step)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(claimed > 0)
+}
+
+/// Use up one recovery code
+async fn use_recovery_code(pool: &SqlitePool, user_id: &str, code: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE totp_recovery_codes SET used_at = ? WHERE user_id = ? AND code_hash = ? AND used_at IS NULL"
+    )
+    .bind(Utc::now().timestamp())
+    .bind(user_id)
+    .bind(hash_recovery_code(code))
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Check the second factor at login: a TOTP code, or else a recovery code.
+pub async fn verify_second_factor(pool: &SqlitePool, user_id: &str, code: &str) -> Result<bool, TwoFactorError> {
+    if verify_totp(pool, user_id, code, true).await? {
+        return Ok(true);
+    }
+    if use_recovery_code(pool, user_id, code).await? {
+        info!("User {} signed in with a recovery code", user_id);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Claims of the pre-auth token; it can't be used as an access token
+#[derive(Debug, Serialize, Deserialize)]
+struct PreAuthClaims {
+    sub: String,
+    aud: String,
+    exp: usize,
+    iat: usize,
+}
+
+/// Token proving the password step succeeded, only accepted by `/auth/login/2fa`
+pub fn create_pre_auth_token(user_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    let now = Utc::now();
+    session::sign(&PreAuthClaims {
+        sub: user_id.to_string(),
+        aud: PRE_AUTH_AUDIENCE.to_string(),
+        exp: (now + Duration::minutes(PRE_AUTH_MINUTES)).timestamp() as usize,
+        iat: now.timestamp() as usize,
+    })
+}
+
+/// The user a pre-auth token was issued to
+pub fn verify_pre_auth_token(token: &str) -> Result<String, String> {
+    session::verify::<PreAuthClaims>(token, |validation| {
+        validation.set_audience(&[PRE_AUTH_AUDIENCE]);
+        validation.leeway = 0;
+    })
+    .map(|claims| claims.sub)
+    .map_err(|e| format!("Invalid or expired pre-auth token: {}", e))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableRequest {
+    pub password: String,
+    pub code: String,
+}
+
+// Whether 2FA is on for the current user, and whether it has to be
+#[get("/2fa")]
+pub async fn status(req: HttpRequest, app_state: web::Data<AppState>) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    let row = sqlx::query(
+        "SELECT u.role, t.enabled_at,
+            (SELECT COUNT(*) FROM totp_recovery_codes c WHERE c.user_id = u.id AND c.used_at IS NULL) AS remaining
+         FROM users u LEFT JOIN user_totp t ON t.user_id = u.id
+         WHERE u.id = ?"
+    )
+    .bind(&claims.sub)
+    .fetch_optional(&app_state.db)
+    .await;
+
+    match row {
+        Ok(Some(row)) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "enabled": row.get::<Option<i64>, _>("enabled_at").is_some(),
+            "required": required_for_role(&row.get::<String, _>("role")),
+            "recovery_codes_remaining": row.get::<i64, _>("remaining")
+        })),
+        Ok(None) => HttpResponse::NotFound().json(json!({"success": false, "error": "User not found"})),
+        Err(e) => TwoFactorError::Database(e).to_response(),
+    }
+}
+
+// Start enrolment: a new secret, not used until confirmed with a code
+#[post("/2fa/setup")]
+pub async fn setup(req: HttpRequest, app_state: web::Data<AppState>) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    match is_enabled(&app_state.db, &claims.sub).await {
+        Ok(false) => {},
+        Ok(true) => return TwoFactorError::AlreadyEnabled.to_response(),
+        Err(e) => return TwoFactorError::Database(e).to_response(),
+    }
+
+    let secret = rand::random::<[u8; SECRET_BYTES]>();
+    let result = sqlx::query(
+        "INSERT INTO user_totp (user_id, secret, created_at) VALUES (?, ?, ?)
+         ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret, created_at = excluded.created_at,
+            enabled_at = NULL, last_used_step = NULL"
+    )
+    .bind(&claims.sub)
+    .bind(hex::encode(secret))
+    .bind(Utc::now().timestamp())
+    .execute(&app_state.db)
+    .await;
+    if let Err(e) = result {
+        return TwoFactorError::Database(e).to_response();
+    }
+
+    let secret = base32_encode(&secret);
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "secret": secret,
+        "otpauth_uri": provisioning_uri(&claims.username, &secret)
+    }))
+}
+
+// Finish enrolment with a code from the app; returns the recovery codes once
+#[post("/2fa/enable")]
+pub async fn enable(req: HttpRequest, body: web::Json<CodeRequest>, app_state: web::Data<AppState>) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    match verify_totp(&app_state.db, &claims.sub, &body.code, false).await {
+        Ok(true) => {},
+        Ok(false) => return TwoFactorError::InvalidCode.to_response(),
+        Err(e) => return e.to_response(),
+    }
+
+    let result = async {
+        sqlx::query("UPDATE user_totp SET enabled_at = ? WHERE user_id = ?")
+            .bind(Utc::now().timestamp())
+            .bind(&claims.sub)
+            .execute(&app_state.db)
+            .await?;
+        replace_recovery_codes(&app_state.db, &claims.sub).await
+    }.await;
+
+    match result {
+        Ok(recovery_codes) => {
+            info!("User {} enabled two-factor authentication", claims.sub);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "recovery_codes": recovery_codes
+            }))
+        },
+        Err(e) => TwoFactorError::Database(e).to_response(),
+    }
+}
+
+// Replace the recovery codes; the old ones stop working
+#[post("/2fa/recovery-codes")]
+pub async fn regenerate_recovery_codes(
+    req: HttpRequest,
+    body: web::Json<CodeRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    match verify_totp(&app_state.db, &claims.sub, &body.code, true).await {
+        Ok(true) => {},
+        Ok(false) => return TwoFactorError::InvalidCode.to_response(),
+        Err(e) => return e.to_response(),
+    }
+
+    match replace_recovery_codes(&app_state.db, &claims.sub).await {
+        Ok(recovery_codes) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "recovery_codes": recovery_codes
+        })),
+        Err(e) => TwoFactorError::Database(e).to_response(),
+    }
+}
+
+// Turn 2FA off; needs the password and a code
+#[post("/2fa/disable")]
+pub async fn disable(req: HttpRequest, body: web::Json<DisableRequest>, app_state: web::Data<AppState>) -> impl Responder {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
+        Ok(claims) => claims,
+        Err(e) => return e.to_response(),
+    };
+    let row = match sqlx::query("SELECT password_hash, role FROM users WHERE id = ?")
+        .bind(&claims.sub)
+        .fetch_optional(&app_state.db)
+        .await {
+        Ok(Some(row)) => row,
+        Ok(None) => return HttpResponse::NotFound().json(json!({"success": false, "error": "User not found"})),
+        Err(e) => return TwoFactorError::Database(e).to_response(),
+    };
+
+    if required_for_role(&row.get::<String, _>("role")) {
+        return TwoFactorError::Required.to_response();
+    }
+    if !passwords::verify_password(&body.password, &row.get::<String, _>("password_hash")) {
+        warn!("Wrong password turning off 2FA for {}", claims.sub);
+        return HttpResponse::Unauthorized().json(json!({"success": false, "error": "Invalid password"}));
+    }
+    match verify_second_factor(&app_state.db, &claims.sub, &body.code).await {
+        Ok(true) => {},
+        Ok(false) => return TwoFactorError::InvalidCode.to_response(),
+        Err(e) => return e.to_response(),
+    }
+
+    let result = async {
+        sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = ?")
+            .bind(&claims.sub)
+            .execute(&app_state.db)
+            .await?;
+        sqlx::query("DELETE FROM user_totp WHERE user_id = ?")
+            .bind(&claims.sub)
+            .execute(&app_state.db)
+            .await
+    }.await;
+
+    match result {
+        Ok(_) => {
+            info!("User {} turned off two-factor authentication", claims.sub);
+            HttpResponse::Ok().json(json!({"success": true}))
+        },
+        Err(e) => TwoFactorError::Database(e).to_response(),
+    }
+}
+
+/// Delete a user's 2FA secret and recovery codes, ahead of deleting the user.
+pub async fn delete_user_factors(conn: &mut sqlx::SqliteConnection, user_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM user_totp WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    // The shared secret of the RFC 4226 and RFC 6238 SHA-1 test vectors
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE user_totp (user_id TEXT PRIMARY KEY, secret TEXT NOT NULL, created_at INTEGER NOT NULL, enabled_at INTEGER, last_used_step INTEGER)",
+            "CREATE TABLE totp_recovery_codes (user_id TEXT NOT NULL, code_hash TEXT NOT NULL, created_at INTEGER NOT NULL, used_at INTEGER, PRIMARY KEY (user_id, code_hash))",
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        sqlx::query("INSERT INTO user_totp (user_id, secret, created_at, enabled_at) VALUES ('u1', ?, 0, 0)")
+            .bind(hex::encode(RFC_SECRET))
+            .execute(&db)
+            .await
+            .unwrap();
+        db
+    }
+
+    fn code(step: i64) -> String {
+        format!("{:06}", hotp(RFC_SECRET, step))
+    }
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let expected = [755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489];
+        for (counter, want) in expected.into_iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as i64), want, "counter {}", counter);
+        }
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238() {
+        // The RFC lists eight digits; we show the last six
+        let expected = [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ];
+        for (time, want) in expected {
+            assert_eq!(hotp(RFC_SECRET, time / STEP_SECONDS), want, "T = {}", time);
+        }
+    }
+
+    #[test]
+    fn secrets_are_base32_encoded() {
+        assert_eq!(base32_encode(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[actix_web::test]
+    async fn totp_codes_work_once() {
+        let db = database().await;
+        let now = current_step();
+
+        assert!(verify_totp(&db, "u1", &code(now), true).await.unwrap());
+        assert!(!verify_totp(&db, "u1", &code(now), true).await.unwrap());
+        // Codes from before the last one used are spent too
+        assert!(!verify_totp(&db, "u1", &code(now - 1), true).await.unwrap());
+        assert!(!verify_totp(&db, "u1", "not a code", true).await.unwrap());
+        assert!(!verify_totp(&db, "u1", &code(now + ALLOWED_DRIFT_STEPS + 1), true).await.unwrap());
+
+        let last_used: i64 = sqlx::query("SELECT last_used_step FROM user_totp WHERE user_id = 'u1'")
+            .fetch_one(&db)
+            .await
+            .unwrap()
+            .get("last_used_step");
+        assert!(last_used >= now);
+    }
+
+    #[actix_web::test]
+    async fn totp_checks_enrolment_state() {
+        let db = database().await;
+        let now = current_step();
+
+        assert!(matches!(verify_totp(&db, "u1", &code(now), false).await, Err(TwoFactorError::AlreadyEnabled)));
+        assert!(matches!(verify_totp(&db, "u2", &code(now), true).await, Err(TwoFactorError::NotEnrolled)));
+    }
+
+    #[actix_web::test]
+    async fn recovery_codes_work_once() {
+        let db = database().await;
+        let codes = replace_recovery_codes(&db, "u1").await.unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+
+        assert!(verify_second_factor(&db, "u1", &codes[0]).await.unwrap());
+        assert!(!verify_second_factor(&db, "u1", &codes[0]).await.unwrap());
+        // Case, spaces and dashes don't matter
+        let typed = codes[1].replace('-', " ").to_uppercase();
+        assert!(verify_second_factor(&db, "u1", &typed).await.unwrap());
+
+        // New codes replace the old ones
+        let fresh = replace_recovery_codes(&db, "u1").await.unwrap();
+        assert!(!verify_second_factor(&db, "u1", &codes[2]).await.unwrap());
+        assert!(verify_second_factor(&db, "u1", &fresh[2]).await.unwrap());
+    }
+}
@@ -24,39 +24,13 @@ pub struct Address {
 #[get("/addresses")]
 pub async fn get_user_addresses(req: HttpRequest, app_state: web::Data<AppState>) -> impl Responder {
     // Validate authentication
-    let claims = match auth::validate_token(req) {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
         Ok(claims) => claims,
-        Err(e) => {
-            return HttpResponse::Unauthorized().json(json!({
-                "success": false,
-                "error": e
-            }));
-        }
+        Err(e) => return e.to_response(),
     };
     
     let user_id = claims.sub;
     
-    // Special case for admin users or demo tokens
-    if claims.role == "admin" || user_id == "admin-user" {
-        // Return mock addresses for admin
-        return HttpResponse::Ok().json(json!({
-            "success": true,
-            "addresses": [
-                {
-                    "id": "addr-sample-1",
-                    "name": "Example Home",
-                    "address": "123 Main St",
-                    "city": "Springfield",
-                    "state": "IL",
-                    "zip": "62701",
-                    "country": "USA",
-                    "isDefault": true
-                }
-            ]
-        }));
-    }
-    
-    // Regular database lookup for non-admin users
     // Query addresses
     match sqlx::query(
         "SELECT id, name, address, city, state, zip, country, is_default FROM addresses WHERE user_id = ?"
@@ -101,14 +75,9 @@ pub async fn get_address(
     app_state: web::Data<AppState>
 ) -> impl Responder {
     // Validate authentication
-    let claims = match auth::validate_token(req) {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
         Ok(claims) => claims,
-        Err(e) => {
-            return HttpResponse::Unauthorized().json(json!({
-                "success": false,
-                "error": e
-            }));
-        }
+        Err(e) => return e.to_response(),
     };
     
     let user_id = claims.sub;
@@ -163,14 +132,9 @@ pub async fn create_address(
     address_data: web::Json<Address>
 ) -> impl Responder {
     // Validate authentication
-    let claims = match auth::validate_token(req) {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
         Ok(claims) => claims,
-        Err(e) => {
-            return HttpResponse::Unauthorized().json(json!({
-                "success": false,
-                "error": e
-            }));
-        }
+        Err(e) => return e.to_response(),
     };
     
     let user_id = claims.sub;
@@ -252,14 +216,9 @@ pub async fn update_address(
     app_state: web::Data<AppState>
 ) -> impl Responder {
     // Validate authentication
-    let claims = match auth::validate_token(req) {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
         Ok(claims) => claims,
-        Err(e) => {
-            return HttpResponse::Unauthorized().json(json!({
-                "success": false,
-                "error": e
-            }));
-        }
+        Err(e) => return e.to_response(),
     };
     
     let user_id = claims.sub;
@@ -345,14 +304,9 @@ pub async fn delete_address(
     app_state: web::Data<AppState>
 ) -> impl Responder {
     // Validate authentication
-    let claims = match auth::validate_token(req) {
+    let claims = match auth::validate_session(&req, &app_state.db).await {
         Ok(claims) => claims,
-        Err(e) => {
-            return HttpResponse::Unauthorized().json(json!({
-                "success": false,
-                "error": e
-            }));
-        }
+        Err(e) => return e.to_response(),
     };
     
     let user_id = claims.sub;
@@ -0,0 +1,442 @@
+// src/variants.rs
+use actix_web::{web, HttpResponse, Responder, post, put, delete};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::Utc;
+use uuid::Uuid;
+use sqlx::{Row, Sqlite, Executor};
+use std::collections::BTreeMap;
+use log::{info, error};
+use crate::AppState;
+use crate::rbac::{perm, Authorized};
+
+/// A purchasable variation of a product (size, edition, ...), identified by SKU.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProductVariant {
+    pub id: String,
+    pub product_id: String,
+    pub sku: String,
+    pub name: String,
+    /// Option attributes, e.g. {"size": "L", "edition": "Deluxe"}
+    pub options: BTreeMap<String, String>,
+    /// Per-variant price; `None` means the product price applies
+    pub price_override: Option<f64>,
+    /// Effective unit price for this variant
+    pub price: f64,
+    /// Units that can still be sold (on-hand stock minus active reservations)
+    pub stock: i64,
+    pub available: bool,
+}
+
+#[derive(Deserialize)]
+pub struct VariantInput {
+    pub sku: String,
+    pub name: String,
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+    pub price_override: Option<f64>,
+    #[serde(default)]
+    pub stock: i64,
+    #[serde(default = "default_available")]
+    pub available: bool,
+}
+
+fn default_available() -> bool {
+    true
+}
+
+const VARIANT_SELECT: &str = r#"
+    SELECT v.id, v.product_id, v.sku, v.name, v.options, v.price_override,
+           COALESCE(v.price_override, p.price) AS price,
+           v.stock - v.reserved AS stock, v.available
+    FROM product_variants v
+    JOIN products p ON p.id = v.product_id
+"#;
+
+fn variant_from_row(row: &sqlx::sqlite::SqliteRow) -> ProductVariant {
+    let options: String = row.get("options");
+    ProductVariant {
+        id: row.get("id"),
+        product_id: row.get("product_id"),
+        sku: row.get("sku"),
+        name: row.get("name"),
+        options: serde_json::from_str(&options).unwrap_or_default(),
+        price_override: row.get("price_override"),
+        price: row.get("price"),
+        stock: row.get("stock"),
+        available: row.get("available"),
+    }
+}
+
+/// Look up a single variant of a product. Works on a pool or inside a transaction.
+pub async fn fetch_variant<'e, E>(
+    executor: E,
+    product_id: &str,
+    variant_id: &str,
+) -> Result<Option<ProductVariant>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let query = format!("{} WHERE v.id = ? AND v.product_id = ?", VARIANT_SELECT);
+    let row = sqlx::query(&query)
+        .bind(variant_id)
+        .bind(product_id)
+        .fetch_optional(executor)
+        .await?;
+
+    Ok(row.as_ref().map(variant_from_row))
+}
+
+/// All variants for a product, ordered by SKU.
+pub async fn variants_for_product<'e, E>(
+    executor: E,
+    product_id: &str,
+) -> Result<Vec<ProductVariant>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let query = format!("{} WHERE v.product_id = ? ORDER BY v.sku", VARIANT_SELECT);
+    let rows = sqlx::query(&query)
+        .bind(product_id)
+        .fetch_all(executor)
+        .await?;
+
+    Ok(rows.iter().map(variant_from_row).collect())
+}
+
+fn validate_variant(input: &VariantInput) -> Result<(), String> {
+    if input.sku.trim().is_empty() {
+        return Err("SKU is required".to_string());
+    }
+    if input.name.trim().is_empty() {
+        return Err("Variant name is required".to_string());
+    }
+    if let Some(price) = input.price_override {
+        if price < 0.0 {
+            return Err("Price override cannot be negative".to_string());
+        }
+    }
+    if input.stock < 0 {
+        return Err("Stock cannot be negative".to_string());
+    }
+    Ok(())
+}
+
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(db) if db.message().contains("UNIQUE"))
+}
+
+// Public: list the variants a customer can choose from
+pub async fn list_product_variants(
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+
+    match variants_for_product(&app_state.db, &product_id).await {
+        Ok(variants) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "product_id": product_id,
+            "variants": variants
+        })),
+        Err(e) => {
+            error!("Failed to fetch variants for product {}: {}", product_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to fetch variants"
+            }))
+        }
+    }
+}
+
+// Admin: add a variant to a product
+#[post("/products/{id}/variants")]
+pub async fn create_variant(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    variant_data: web::Json<VariantInput>,
+) -> impl Responder {
+    let product_id = path.into_inner();
+    let input = variant_data.into_inner();
+
+    if let Err(msg) = validate_variant(&input) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": msg
+        }));
+    }
+
+    match sqlx::query("SELECT id FROM products WHERE id = ?")
+        .bind(&product_id)
+        .fetch_optional(&app_state.db)
+        .await {
+            Ok(Some(_)) => {},
+            Ok(None) => {
+                return HttpResponse::NotFound().json(json!({
+                    "success": false,
+                    "error": "Product not found"
+                }));
+            },
+            Err(e) => {
+                error!("Database error checking product {}: {}", product_id, e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Database error"
+                }));
+            }
+        }
+
+    let variant_id = format!("var-{}", Uuid::new_v4().simple());
+    let options = serde_json::to_string(&input.options).unwrap_or_else(|_| "{}".to_string());
+    let now = Utc::now().timestamp();
+
+    match sqlx::query(
+        "INSERT INTO product_variants (id, product_id, sku, name, options, price_override, stock, available, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&variant_id)
+    .bind(&product_id)
+    .bind(input.sku.trim())
+    .bind(input.name.trim())
+    .bind(&options)
+    .bind(input.price_override)
+    .bind(input.stock)
+    .bind(input.available)
+    .bind(now)
+    .execute(&app_state.db)
+    .await {
+        Ok(_) => {
+            info!("Created variant {} ({}) for product {}", variant_id, input.sku, product_id);
+            match fetch_variant(&app_state.db, &product_id, &variant_id).await {
+                Ok(Some(variant)) => HttpResponse::Created().json(json!({
+                    "success": true,
+                    "variant": variant
+                })),
+                _ => HttpResponse::Created().json(json!({
+                    "success": true,
+                    "variant": { "id": variant_id }
+                })),
+            }
+        },
+        Err(e) if is_unique_violation(&e) => {
+            HttpResponse::Conflict().json(json!({
+                "success": false,
+                "error": format!("SKU {} already exists", input.sku)
+            }))
+        },
+        Err(e) => {
+            error!("Failed to create variant: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to create variant"
+            }))
+        }
+    }
+}
+
+// Admin: update a variant's SKU, options or price. Stock changes go through
+// the stock adjustment endpoint so they are recorded in the history.
+#[put("/products/{product_id}/variants/{variant_id}")]
+pub async fn update_variant(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    variant_data: web::Json<VariantInput>,
+) -> impl Responder {
+    let (product_id, variant_id) = path.into_inner();
+    let input = variant_data.into_inner();
+
+    if let Err(msg) = validate_variant(&input) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": msg
+        }));
+    }
+
+    let options = serde_json::to_string(&input.options).unwrap_or_else(|_| "{}".to_string());
+
+    match sqlx::query(
+        "UPDATE product_variants SET sku = ?, name = ?, options = ?, price_override = ?, available = ?
+         WHERE id = ? AND product_id = ?"
+    )
+    .bind(input.sku.trim())
+    .bind(input.name.trim())
+    .bind(&options)
+    .bind(input.price_override)
+    .bind(input.available)
+    .bind(&variant_id)
+    .bind(&product_id)
+    .execute(&app_state.db)
+    .await {
+        Ok(result) if result.rows_affected() == 0 => {
+            HttpResponse::NotFound().json(json!({
+                "success": false,
+                "error": "Variant not found"
+            }))
+        },
+        Ok(_) => {
+            match fetch_variant(&app_state.db, &product_id, &variant_id).await {
+                Ok(Some(variant)) => HttpResponse::Ok().json(json!({
+                    "success": true,
+                    "variant": variant
+                })),
+                _ => HttpResponse::Ok().json(json!({
+                    "success": true,
+                    "message": "Variant updated"
+                })),
+            }
+        },
+        Err(e) if is_unique_violation(&e) => {
+            HttpResponse::Conflict().json(json!({
+                "success": false,
+                "error": format!("SKU {} already exists", input.sku)
+            }))
+        },
+        Err(e) => {
+            error!("Failed to update variant {}: {}", variant_id, e);
+            HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "error": "Failed to update variant"
+            }))
+        }
+    }
+}
+
+// Admin: remove a variant. Past orders keep their SKU/option snapshot, but a
+// variant still holding stock for unpaid orders cannot be removed.
+#[delete("/products/{product_id}/variants/{variant_id}")]
+pub async fn delete_variant(
+    _auth: Authorized<perm::ProductsWrite>,
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (product_id, variant_id) = path.into_inner();
+
+    match sqlx::query("SELECT reserved FROM product_variants WHERE id = ? AND product_id = ?")
+        .bind(&variant_id)
+        .bind(&product_id)
+        .fetch_optional(&app_state.db)
+        .await {
+            Ok(Some(row)) if row.get::<i64, _>("reserved") > 0 => {
+                return HttpResponse::Conflict().json(json!({
+                    "success": false,
+                    "error": "Variant has stock reserved by unpaid orders"
+                }));
+            },
+            Ok(Some(_)) => {},
+            Ok(None) => {
+                return HttpResponse::NotFound().json(json!({
+                    "success": false,
+                    "error": "Variant not found"
+                }));
+            },
+            Err(e) => {
+                error!("Database error checking variant {}: {}", variant_id, e);
+                return HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Database error"
+                }));
+            }
+        }
+
+    match sqlx::query("DELETE FROM product_variants WHERE id = ? AND product_id = ?")
+        .bind(&variant_id)
+        .bind(&product_id)
+        .execute(&app_state.db)
+        .await {
+            Ok(_) => {
+                info!("Deleted variant {} of product {}", variant_id, product_id);
+                HttpResponse::Ok().json(json!({
+                    "success": true,
+                    "message": "Variant deleted"
+                }))
+            },
+            Err(e) => {
+                error!("Failed to delete variant {}: {}", variant_id, e);
+                HttpResponse::InternalServerError().json(json!({
+                    "success": false,
+                    "error": "Failed to delete variant"
+                }))
+            }
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::SqlitePool;
+
+    async fn database() -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE products (id TEXT PRIMARY KEY, price REAL NOT NULL)",
+            "CREATE TABLE product_variants (id TEXT PRIMARY KEY, product_id TEXT NOT NULL, sku TEXT UNIQUE NOT NULL, name TEXT NOT NULL, options TEXT NOT NULL DEFAULT '{}', price_override REAL, stock INTEGER NOT NULL DEFAULT 0, reserved INTEGER NOT NULL DEFAULT 0, available BOOLEAN NOT NULL DEFAULT TRUE, created_at INTEGER NOT NULL)",
+            "INSERT INTO products VALUES ('shirt', 20.0), ('mug', 8.0)",
+            r#"INSERT INTO product_variants (id, product_id, sku, name, options, price_override, stock, reserved, created_at) VALUES
+                ('v-l', 'shirt', 'SHIRT-L', 'Large', '{"size":"L"}', 22.5, 4, 1, 0),
+                ('v-m', 'shirt', 'SHIRT-M', 'Medium', '{"size":"M"}', NULL, 2, 0, 0),
+                ('v-mug', 'mug', 'MUG-1', 'Mug', 'not json', NULL, 1, 0, 0)"#,
+        ] {
+            sqlx::query(sql).execute(&db).await.unwrap();
+        }
+        db
+    }
+
+    fn input(sku: &str, name: &str) -> VariantInput {
+        VariantInput {
+            sku: sku.to_string(),
+            name: name.to_string(),
+            options: BTreeMap::new(),
+            price_override: None,
+            stock: 0,
+            available: true,
+        }
+    }
+
+    #[test]
+    fn variants_need_a_sku_name_and_sane_numbers() {
+        assert!(validate_variant(&input("SKU-1", "One")).is_ok());
+        assert!(validate_variant(&input(" ", "One")).is_err());
+        assert!(validate_variant(&input("SKU-1", "")).is_err());
+        assert!(validate_variant(&VariantInput { price_override: Some(-1.0), ..input("SKU-1", "One") }).is_err());
+        assert!(validate_variant(&VariantInput { price_override: Some(0.0), ..input("SKU-1", "One") }).is_ok());
+        assert!(validate_variant(&VariantInput { stock: -1, ..input("SKU-1", "One") }).is_err());
+    }
+
+    #[actix_web::test]
+    async fn variants_show_their_effective_price_and_free_stock() {
+        let db = database().await;
+
+        let variants = variants_for_product(&db, "shirt").await.unwrap();
+        let skus: Vec<_> = variants.iter().map(|v| v.sku.as_str()).collect();
+        assert_eq!(skus, ["SHIRT-L", "SHIRT-M"]);
+        assert_eq!((variants[0].price, variants[0].stock), (22.5, 3));
+        assert_eq!(variants[0].options.get("size").map(String::as_str), Some("L"));
+        // Without an override the product price applies
+        assert_eq!((variants[1].price_override, variants[1].price), (None, 20.0));
+
+        let mug = fetch_variant(&db, "mug", "v-mug").await.unwrap().unwrap();
+        assert!(mug.options.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn variants_belong_to_one_product() {
+        let db = database().await;
+        assert!(fetch_variant(&db, "shirt", "v-mug").await.unwrap().is_none());
+        assert!(fetch_variant(&db, "shirt", "v-missing").await.unwrap().is_none());
+        assert!(variants_for_product(&db, "missing").await.unwrap().is_empty());
+
+        let duplicate = sqlx::query("INSERT INTO product_variants (id, product_id, sku, name, created_at) VALUES ('v-x', 'mug', 'SHIRT-L', 'Copy', 0)")
+            .execute(&db)
+            .await
+            .unwrap_err();
+        assert!(is_unique_violation(&duplicate));
+    }
+}
@@ -0,0 +1,322 @@
+use actix_web::http::{Method, StatusCode};
+use actix_web::{test, web, App};
+use secure_store::monero::MoneroPaymentStore;
+use secure_store::{admin, monero_admin, monero_api, orders, rbac, session, AppState};
+use sqlx::sqlite::SqlitePoolOptions;
+
+// Every route that only staff may call, with path parameters filled in
+const ADMIN_ROUTES: &[(&str, &str)] = &[
+    ("GET", "/admin/users"),
+    ("GET", "/admin/orders"),
+    ("GET", "/admin/api/users"),
+    ("GET", "/admin/api/orders"),
+    ("GET", "/admin/api/products"),
+    ("GET", "/admin/users/u1"),
+    ("POST", "/admin/users"),
+    ("PUT", "/admin/users/u1"),
+    ("DELETE", "/admin/users/u1"),
+    ("GET", "/admin/orders/o1"),
+    ("PUT", "/admin/orders/o1/status"),
+    ("GET", "/admin/products/p1/stock"),
+    ("POST", "/admin/products/p1/stock"),
+    ("POST", "/admin/products/p1/variants"),
+    ("PUT", "/admin/products/p1/variants/v1"),
+    ("DELETE", "/admin/products/p1/variants/v1"),
+    ("POST", "/admin/categories"),
+    ("PUT", "/admin/categories/c1"),
+    ("DELETE", "/admin/categories/c1"),
+    ("PUT", "/admin/products/p1/category"),
+    ("PUT", "/admin/products/p1/tags"),
+    ("POST", "/admin/search/rebuild"),
+    ("POST", "/admin/products/p1/images"),
+    ("PUT", "/admin/products/p1/images/order"),
+    ("DELETE", "/admin/products/p1/images/i1"),
+    ("PUT", "/admin/products/p1/digital"),
+    ("GET", "/admin/products/p1/files"),
+    ("POST", "/admin/products/p1/files"),
+    ("DELETE", "/admin/products/p1/files/f1"),
+    ("GET", "/admin/products/p1/license-keys"),
+    ("POST", "/admin/products/p1/license-keys"),
+    ("DELETE", "/admin/products/p1/license-keys/k1"),
+    ("POST", "/admin/orders/o1/fulfil"),
+    ("GET", "/admin/coupons"),
+    ("GET", "/admin/coupons/c1"),
+    ("POST", "/admin/coupons"),
+    ("PUT", "/admin/coupons/c1"),
+    ("DELETE", "/admin/coupons/c1"),
+    ("GET", "/admin/shipping/zones"),
+    ("POST", "/admin/shipping/zones"),
+    ("PUT", "/admin/shipping/zones/z1"),
+    ("DELETE", "/admin/shipping/zones/z1"),
+    ("POST", "/admin/shipping/zones/z1/methods"),
+    ("PUT", "/admin/shipping/methods/m1"),
+    ("DELETE", "/admin/shipping/methods/m1"),
+    ("GET", "/admin/tax/rules"),
+    ("POST", "/admin/tax/rules"),
+    ("PUT", "/admin/tax/rules/r1"),
+    ("DELETE", "/admin/tax/rules/r1"),
+    ("GET", "/admin/tax/report"),
+    ("GET", "/admin/carriers"),
+    ("POST", "/admin/carriers"),
+    ("PUT", "/admin/carriers/c1"),
+    ("DELETE", "/admin/carriers/c1"),
+    ("GET", "/admin/orders/o1/shipments"),
+    ("POST", "/admin/orders/o1/shipments"),
+    ("POST", "/admin/products/import"),
+    ("GET", "/admin/products/export"),
+    ("GET", "/admin/products"),
+    ("GET", "/admin/products/p1"),
+    ("PUT", "/admin/products/p1"),
+    ("POST", "/admin/products/p1/archive"),
+    ("POST", "/admin/products/p1/restore"),
+    ("POST", "/admin/products/p1/duplicate"),
+    ("GET", "/admin/permissions"),
+    ("GET", "/admin/roles"),
+    ("POST", "/admin/roles"),
+    ("PUT", "/admin/roles/support"),
+    ("DELETE", "/admin/roles/support"),
+    ("PUT", "/admin/users/u1/role"),
+    ("POST", "/admin/users/u1/unlock"),
+    ("GET", "/admin/login-events"),
+    // Monero admin
+    ("GET", "/api/monero/admin/transactions"),
+    ("POST", "/api/monero/admin/confirm/pay1"),
+    ("POST", "/api/monero/admin/refresh_wallet"),
+    // Payment maintenance
+    ("GET", "/monero/admin/orders"),
+    ("GET", "/monero/admin/validate/o1"),
+    ("POST", "/monero/admin/sync-all-payments"),
+    ("POST", "/monero/admin/force-create-payment-links"),
+    ("POST", "/monero/fix-orphaned-payments"),
+    ("POST", "/monero/force-update-order-status/o1"),
+    ("POST", "/monero/api/monero/mock_confirm/pay1"),
+    ("POST", "/monero/api/monero/check_now/pay1"),
+    ("GET", "/monero/debug/dump-all-payments"),
+    ("GET", "/orders/debug-orders"),
+    ("GET", "/orders/debug/dump-order/o1"),
+    ("POST", "/orders/admin/force-update-order/o1/Paid"),
+    ("POST", "/orders/fix-order-status-mismatch"),
+];
+
+// Accounts the tests sign tokens for; permissions come from their role
+const USERS: &[(&str, &str, &str)] = &[
+    ("usr-test", "tester", "user"),
+    ("adm-test", "root", "admin"),
+    ("ful-test", "packer", "fulfilment"),
+    ("adm-no2fa", "newadmin", "admin"),
+];
+
+// Accounts with two-factor authentication turned on
+const TWO_FACTOR_USERS: &[&str] = &["adm-test"];
+
+async fn app_state() -> web::Data<AppState> {
+    // Every test shares the process-wide keys; only the first install counts
+    let _ = session::install_keys(
+        session::JwtKeys::hmac("test", b"admin-routes-test-secret-0123456789").unwrap(),
+    );
+
+    // One connection, so every query sees the same in-memory database
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    for sql in [
+        "CREATE TABLE users (id TEXT PRIMARY KEY, username TEXT UNIQUE NOT NULL, password_hash TEXT NOT NULL, role TEXT NOT NULL, created_at INTEGER NOT NULL)",
+        "CREATE TABLE roles (name TEXT PRIMARY KEY, description TEXT NOT NULL DEFAULT '', built_in INTEGER NOT NULL DEFAULT 0, created_at INTEGER NOT NULL)",
+        "CREATE TABLE role_permissions (role TEXT NOT NULL, permission TEXT NOT NULL, PRIMARY KEY (role, permission))",
+        "CREATE TABLE sessions (id TEXT PRIMARY KEY, user_id TEXT NOT NULL, device TEXT NOT NULL, ip TEXT NOT NULL, created_at INTEGER NOT NULL, last_used_at INTEGER NOT NULL, expires_at INTEGER NOT NULL, revoked_at INTEGER, revoked_reason TEXT)",
+        "CREATE TABLE refresh_tokens (token_hash TEXT PRIMARY KEY, session_id TEXT NOT NULL, created_at INTEGER NOT NULL, used_at INTEGER)",
+        "CREATE TABLE user_totp (user_id TEXT PRIMARY KEY, secret TEXT NOT NULL, created_at INTEGER NOT NULL, enabled_at INTEGER, last_used_step INTEGER)",
+    ] {
+        sqlx::query(sql).execute(&db).await.unwrap();
+    }
+    rbac::seed_built_in_roles(&db).await.unwrap();
+    for (id, username, role) in USERS {
+        sqlx::query("INSERT INTO users (id, username, password_hash, role, created_at) VALUES (?, ?, '', ?, 0)")
+            .bind(id)
+            .bind(username)
+            .bind(role)
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+    for id in TWO_FACTOR_USERS {
+        sqlx::query("INSERT INTO user_totp (user_id, secret, created_at, enabled_at) VALUES (?, '00', 0, 0)")
+            .bind(id)
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+
+    web::Data::new(AppState {
+        db,
+        monero_payments: MoneroPaymentStore::new(),
+    })
+}
+
+macro_rules! init_app {
+    ($state:expr) => {
+        test::init_service(
+            App::new()
+                .app_data($state.clone())
+                .service(admin::init_routes())
+                .service(monero_api::init_routes())
+                .configure(orders::init_orders_routes)
+                .service(web::scope("/api").service(monero_admin::init_routes())),
+        )
+        .await
+    };
+}
+
+// Access token for a signed-in session of one of USERS; `role` is what the token claims
+async fn sign_in(state: &web::Data<AppState>, user_id: &str, role: &str) -> String {
+    let (_, username, _) = USERS.iter().find(|(id, _, _)| *id == user_id).unwrap();
+    session::start_session(&state.db, user_id, username, role, "test", "127.0.0.1")
+        .await
+        .unwrap()
+        .access_token
+}
+
+fn request(method: &str, path: &str, token: Option<&str>) -> test::TestRequest {
+    let mut req = test::TestRequest::default()
+        .method(Method::from_bytes(method.as_bytes()).unwrap())
+        .uri(path);
+    if let Some(token) = token {
+        req = req.insert_header(("Authorization", format!("Bearer {}", token)));
+    }
+    req
+}
+
+#[actix_web::test]
+async fn admin_routes_reject_user_tokens() {
+    let state = app_state().await;
+    let app = init_app!(state);
+    let token = sign_in(&state, "usr-test", "user").await;
+
+    for (method, path) in ADMIN_ROUTES {
+        let resp = test::call_service(&app, request(method, path, Some(&token)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{} {}", method, path);
+    }
+}
+
+#[actix_web::test]
+async fn admin_routes_reject_missing_or_invalid_tokens() {
+    let state = app_state().await;
+    let app = init_app!(state);
+    // Correctly signed, but not issued for any session
+    let sessionless = session::create_jwt("adm-test", "root", "admin", None).unwrap();
+
+    for (method, path) in ADMIN_ROUTES {
+        let resp = test::call_service(&app, request(method, path, None).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{} {}", method, path);
+
+        let resp = test::call_service(&app, request(method, path, Some("not-a-jwt")).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{} {}", method, path);
+
+        let resp = test::call_service(&app, request(method, path, Some(&sessionless)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{} {}", method, path);
+    }
+}
+
+#[actix_web::test]
+async fn rejections_use_the_standard_error_body() {
+    let state = app_state().await;
+    let app = init_app!(state);
+    let token = sign_in(&state, "usr-test", "user").await;
+
+    let resp = test::call_service(&app, request("GET", "/admin/users", Some(&token)).to_request()).await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["success"], false);
+    assert!(body["error"].is_string());
+
+    let resp = test::call_service(&app, request("GET", "/orders/debug-orders", None).to_request()).await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["success"], false);
+    assert!(body["error"].is_string());
+}
+
+#[actix_web::test]
+async fn admin_tokens_pass_the_guard() {
+    let state = app_state().await;
+    let app = init_app!(state);
+    let token = sign_in(&state, "adm-test", "admin").await;
+
+    // The test database only has the account tables, so only check that
+    // the guard lets the request through to the handler
+    for (method, path) in [
+        ("GET", "/admin/coupons"),
+        ("GET", "/api/monero/admin/transactions"),
+        ("GET", "/monero/admin/orders"),
+        ("GET", "/orders/debug-orders"),
+    ] {
+        let resp = test::call_service(&app, request(method, path, Some(&token)).to_request()).await;
+        assert_ne!(resp.status(), StatusCode::UNAUTHORIZED, "{} {}", method, path);
+        assert_ne!(resp.status(), StatusCode::FORBIDDEN, "{} {}", method, path);
+    }
+}
+
+#[actix_web::test]
+async fn staff_roles_only_reach_their_permissions() {
+    let state = app_state().await;
+    let app = init_app!(state);
+    // The token still says "user"; the role on the account is what counts
+    let token = sign_in(&state, "ful-test", "user").await;
+
+    for (method, path) in [
+        ("GET", "/admin/orders"),
+        ("GET", "/admin/carriers"),
+        ("GET", "/orders/debug-orders"),
+    ] {
+        let resp = test::call_service(&app, request(method, path, Some(&token)).to_request()).await;
+        assert_ne!(resp.status(), StatusCode::UNAUTHORIZED, "{} {}", method, path);
+        assert_ne!(resp.status(), StatusCode::FORBIDDEN, "{} {}", method, path);
+    }
+
+    for (method, path) in [
+        ("PUT", "/admin/products/p1"),
+        ("GET", "/admin/users"),
+        ("GET", "/admin/roles"),
+        ("POST", "/api/monero/admin/confirm/pay1"),
+        ("POST", "/orders/fix-order-status-mismatch"),
+    ] {
+        let resp = test::call_service(&app, request(method, path, Some(&token)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{} {}", method, path);
+    }
+}
+
+#[actix_web::test]
+async fn signed_out_sessions_lose_staff_access() {
+    let state = app_state().await;
+    let app = test::init_service(App::new().app_data(state.clone()).service(admin::init_routes())).await;
+    let tokens = session::start_session(&state.db, "adm-test", "root", "admin", "test", "127.0.0.1").await.unwrap();
+
+    let resp = test::call_service(&app, request("GET", "/admin/roles", Some(&tokens.access_token)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Presenting a refresh token twice signs the whole session out
+    let renewed = session::refresh(&state.db, &tokens.refresh_token, "127.0.0.1").await.unwrap();
+    assert!(matches!(
+        session::refresh(&state.db, &tokens.refresh_token, "127.0.0.1").await,
+        Err(session::SessionError::TokenReused)
+    ));
+    assert!(matches!(
+        session::refresh(&state.db, &renewed.refresh_token, "127.0.0.1").await,
+        Err(session::SessionError::InvalidToken)
+    ));
+
+    let resp = test::call_service(&app, request("GET", "/admin/roles", Some(&renewed.access_token)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn admins_without_two_factor_are_refused() {
+    let state = app_state().await;
+    let app = init_app!(state);
+    let token = sign_in(&state, "adm-no2fa", "admin").await;
+
+    for (method, path) in [("GET", "/admin/roles"), ("GET", "/monero/admin/orders")] {
+        let resp = test::call_service(&app, request(method, path, Some(&token)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{} {}", method, path);
+    }
+}
@@ -0,0 +1,162 @@
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use secure_store::monero::MoneroPaymentStore;
+use secure_store::{products, search, AppState};
+use serde_json::Value;
+use sqlx::sqlite::SqlitePoolOptions;
+
+async fn app_state() -> web::Data<AppState> {
+    // One connection, so every query sees the same in-memory database
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    for sql in [
+        "CREATE TABLE categories (id TEXT PRIMARY KEY, name TEXT NOT NULL, slug TEXT UNIQUE NOT NULL, parent_id TEXT, created_at INTEGER NOT NULL)",
+        "CREATE TABLE products (id TEXT PRIMARY KEY, sku TEXT UNIQUE, name TEXT NOT NULL, description TEXT NOT NULL, price REAL NOT NULL, available BOOLEAN NOT NULL DEFAULT TRUE, digital_delivery TEXT, weight_grams INTEGER NOT NULL DEFAULT 0, tax_class TEXT NOT NULL DEFAULT 'standard', stock INTEGER NOT NULL DEFAULT 0, reserved INTEGER NOT NULL DEFAULT 0, category_id TEXT, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL, archived_at INTEGER)",
+        "CREATE TABLE product_variants (id TEXT PRIMARY KEY, product_id TEXT NOT NULL, available BOOLEAN NOT NULL DEFAULT TRUE, stock INTEGER NOT NULL, reserved INTEGER NOT NULL DEFAULT 0)",
+        "CREATE TABLE product_tags (product_id TEXT NOT NULL, tag TEXT NOT NULL, PRIMARY KEY (product_id, tag))",
+        "CREATE VIRTUAL TABLE products_fts USING fts5(product_id UNINDEXED, name, description, tags, tokenize = 'unicode61 remove_diacritics 2')",
+        "CREATE TABLE product_images (id TEXT PRIMARY KEY, product_id TEXT NOT NULL, url TEXT NOT NULL, thumbnail_url TEXT NOT NULL, position INTEGER NOT NULL, content_type TEXT NOT NULL, width INTEGER NOT NULL, height INTEGER NOT NULL)",
+        "INSERT INTO categories VALUES ('c-clothes', 'Clothes', 'clothes', NULL, 0), ('c-shirts', 'Shirts', 'shirts', 'c-clothes', 0), ('c-books', 'Books', 'books', NULL, 0)",
+        "INSERT INTO products (id, name, description, price, available, stock, reserved, category_id, created_at, updated_at, archived_at) VALUES
+            ('p-tee', 'Tee', 'Plain cotton tee to wear under a coat', 15.0, TRUE, 3, 0, 'c-shirts', 3, 3, NULL),
+            ('p-coat', 'Coat', 'Warm wool coat for <winter>', 80.0, TRUE, 0, 0, 'c-clothes', 2, 2, NULL),
+            ('p-book', 'Book', '', 10.0, TRUE, 2, 2, 'c-books', 1, 1, NULL),
+            ('p-old', 'Old coat', '', 5.0, TRUE, 9, 0, 'c-clothes', 4, 4, 4),
+            ('p-off', 'Off', '', 30.0, FALSE, 1, 0, 'c-clothes', 5, 5, NULL)",
+        // The coat is only in stock through one of its variants
+        "INSERT INTO product_variants (id, product_id, available, stock) VALUES ('v-coat-m', 'p-coat', TRUE, 2), ('v-book-hc', 'p-book', FALSE, 5)",
+        "INSERT INTO product_tags VALUES ('p-tee', 'sale'), ('p-tee', 'cotton'), ('p-coat', 'sale'), ('p-old', 'sale')",
+    ] {
+        sqlx::query(sql).execute(&db).await.unwrap();
+    }
+    search::rebuild_index(&db).await.unwrap();
+
+    web::Data::new(AppState {
+        db,
+        monero_payments: MoneroPaymentStore::new(),
+    })
+}
+
+macro_rules! init_app {
+    ($state:expr) => {
+        test::init_service(
+            App::new()
+                .app_data($state.clone())
+                .route("/products/catalog", web::get().to(products::browse_products))
+                .route("/products/search", web::get().to(search::search_products)),
+        )
+        .await
+    };
+}
+
+// GET a catalog page and return the status and JSON reply
+macro_rules! browse {
+    ($app:expr, $query:expr) => {
+        browse!($app, "catalog", $query)
+    };
+    ($app:expr, $endpoint:expr, $query:expr) => {{
+        let req = test::TestRequest::get().uri(&format!("/products/{}?{}", $endpoint, $query)).to_request();
+        let resp = test::call_service(&$app, req).await;
+        let status = resp.status();
+        (status, test::read_body_json::<Value, _>(resp).await)
+    }};
+}
+
+fn ids(body: &Value) -> Vec<&str> {
+    body.get("products").or(body.get("results")).unwrap().as_array().unwrap().iter().map(|p| p["id"].as_str().unwrap()).collect()
+}
+
+#[actix_web::test]
+async fn filters_narrow_the_catalog() {
+    let state = app_state().await;
+    let app = init_app!(state);
+
+    let cases = [
+        // Newest first; archived products never show
+        ("", vec!["p-off", "p-tee", "p-coat", "p-book"]),
+        // Subcategories count, by id or slug
+        ("category=clothes", vec!["p-off", "p-tee", "p-coat"]),
+        ("category=c-shirts", vec!["p-tee"]),
+        ("category=none", vec![]),
+        // Every tag must match
+        ("tags=sale,cotton", vec!["p-tee"]),
+        ("tags=SALE", vec!["p-tee", "p-coat"]),
+        ("min_price=10&max_price=15", vec!["p-tee", "p-book"]),
+        ("available=false", vec!["p-off"]),
+        // Reserved units aren't sellable; an available variant with stock is
+        ("in_stock=true", vec!["p-off", "p-tee", "p-coat"]),
+        ("in_stock=false", vec!["p-book"]),
+        ("sort=name", vec!["p-book", "p-coat", "p-off", "p-tee"]),
+        ("sort=price_desc&category=clothes", vec!["p-coat", "p-off", "p-tee"]),
+    ];
+    for (query, expected) in cases {
+        let (status, body) = browse!(app, query);
+        assert_eq!(status, StatusCode::OK, "{}", query);
+        assert_eq!(ids(&body), expected, "{}", query);
+    }
+
+    let (_, body) = browse!(app, "category=shirts");
+    assert_eq!(body["products"][0]["tags"], serde_json::json!(["cotton", "sale"]));
+    assert_eq!(body["products"][0]["stock"], 3);
+}
+
+#[actix_web::test]
+async fn pages_follow_the_cursor() {
+    let state = app_state().await;
+    let app = init_app!(state);
+
+    let (_, first) = browse!(app, "sort=price_asc&limit=3");
+    assert_eq!(ids(&first), ["p-book", "p-tee", "p-off"]);
+    let cursor = first["next_cursor"].as_str().unwrap();
+
+    let (_, second) = browse!(app, format!("sort=price_asc&limit=3&cursor={}", cursor));
+    assert_eq!(ids(&second), ["p-coat"]);
+    assert!(second["next_cursor"].is_null());
+
+    // A cursor only continues the sort order it came from
+    let (status, _) = browse!(app, format!("sort=name&cursor={}", cursor));
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn bad_parameters_are_rejected() {
+    let state = app_state().await;
+    let app = init_app!(state);
+
+    for query in ["sort=popular", "limit=0", "limit=101", "cursor=zz", "cursor=abc"] {
+        let (status, body) = browse!(app, query);
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+        assert_eq!(body["success"], false);
+    }
+}
+
+#[actix_web::test]
+async fn search_ranks_and_highlights_matches() {
+    let state = app_state().await;
+    let app = init_app!(state);
+
+    // A name match outranks a description match; archived products never show
+    let (status, body) = browse!(app, "search", "q=coat");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(ids(&body), ["p-coat", "p-tee"]);
+    assert_eq!(body["results"][0]["name_highlighted"], "<mark>Coat</mark>");
+
+    // Tags are searched too
+    let (_, body) = browse!(app, "search", "q=sale");
+    let mut found = ids(&body);
+    found.sort();
+    assert_eq!(found, ["p-coat", "p-tee"]);
+
+    let (_, body) = browse!(app, "search", "q=wool");
+    let snippet = body["results"][0]["snippet"].as_str().unwrap();
+    assert!(snippet.contains("<mark>wool</mark>"), "{}", snippet);
+    assert!(snippet.contains("&lt;winter&gt;"), "{}", snippet);
+
+    for query in ["q=", "q=%22*", "q=tee&limit=0"] {
+        let (status, _) = browse!(app, "search", query);
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+    }
+}
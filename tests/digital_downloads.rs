@@ -0,0 +1,140 @@
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use secure_store::digital::{self, DigitalFiles};
+use secure_store::monero::MoneroPaymentStore;
+use secure_store::storage::{ImageStorage, LocalDiskStorage};
+use secure_store::{session, AppState};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::Row;
+use std::path::PathBuf;
+
+const MAX_DOWNLOADS: i64 = 3;
+
+struct Fixture {
+    state: web::Data<AppState>,
+    files: web::Data<DigitalFiles>,
+    dir: PathBuf,
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn fixture() -> Fixture {
+    // Every test shares the process-wide keys; only the first install counts
+    let _ = session::install_keys(
+        session::JwtKeys::hmac("test", b"digital-downloads-test-secret-0123456789").unwrap(),
+    );
+
+    // A database file, so parallel downloads really race on it
+    let dir = std::env::temp_dir().join(format!("digital-downloads-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let db = SqlitePoolOptions::new()
+        .max_connections(8)
+        .connect_with(SqliteConnectOptions::new().filename(dir.join("store.db")).create_if_missing(true))
+        .await
+        .unwrap();
+    let now = chrono::Utc::now().timestamp();
+    for sql in [
+        "CREATE TABLE digital_files (id TEXT PRIMARY KEY, product_id TEXT NOT NULL, file_name TEXT NOT NULL, content_type TEXT NOT NULL, storage_key TEXT NOT NULL, size_bytes INTEGER NOT NULL, created_at INTEGER NOT NULL)",
+        "CREATE TABLE download_grants (id TEXT PRIMARY KEY, order_item_id INTEGER NOT NULL, file_id TEXT NOT NULL, expires_at INTEGER NOT NULL, max_downloads INTEGER NOT NULL, download_count INTEGER NOT NULL DEFAULT 0, last_downloaded_at INTEGER, created_at INTEGER NOT NULL)",
+        "INSERT INTO digital_files VALUES ('f-1', 'p-1', 'book.pdf', 'application/pdf', 'p-1/f-1', 5, 0)",
+    ] {
+        sqlx::query(sql).execute(&db).await.unwrap();
+    }
+    for (id, expires_at) in [("dl-live", now + 3600), ("dl-stale", now - 1)] {
+        sqlx::query("INSERT INTO download_grants (id, order_item_id, file_id, expires_at, max_downloads, created_at) VALUES (?, 1, 'f-1', ?, ?, 0)")
+            .bind(id)
+            .bind(expires_at)
+            .bind(MAX_DOWNLOADS)
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+
+    let storage = LocalDiskStorage::new(dir.join("files"), "/downloads");
+    storage.put("p-1/f-1", b"%PDF-".to_vec()).await.unwrap();
+
+    Fixture {
+        state: web::Data::new(AppState { db, monero_payments: MoneroPaymentStore::new() }),
+        files: web::Data::new(DigitalFiles(storage)),
+        dir,
+    }
+}
+
+macro_rules! init_app {
+    ($fixture:expr) => {
+        test::init_service(
+            App::new()
+                .app_data($fixture.state.clone())
+                .app_data($fixture.files.clone())
+                .route("/downloads/{token}", web::get().to(digital::download_file)),
+        )
+        .await
+    };
+}
+
+// A signed link for a grant, valid for the next minute
+fn link(grant_id: &str) -> String {
+    digital::download_url(grant_id, chrono::Utc::now().timestamp() + 60).unwrap()
+}
+
+async fn download_count(fixture: &Fixture, grant_id: &str) -> i64 {
+    sqlx::query("SELECT download_count FROM download_grants WHERE id = ?")
+        .bind(grant_id)
+        .fetch_one(&fixture.state.db)
+        .await
+        .unwrap()
+        .get("download_count")
+}
+
+#[actix_web::test]
+async fn links_stop_working_after_the_limit() {
+    let fixture = fixture().await;
+    let app = init_app!(fixture);
+    let url = link("dl-live");
+
+    for _ in 0..MAX_DOWNLOADS {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&url).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let disposition = resp.headers().get("content-disposition").unwrap().to_str().unwrap().to_string();
+        assert!(disposition.starts_with("attachment") && disposition.contains("book.pdf"), "{}", disposition);
+        assert_eq!(test::read_body(resp).await, "%PDF-");
+    }
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&url).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::GONE);
+    assert_eq!(download_count(&fixture, "dl-live").await, MAX_DOWNLOADS);
+}
+
+#[actix_web::test]
+async fn parallel_downloads_respect_the_limit() {
+    let fixture = fixture().await;
+    let app = init_app!(fixture);
+    let url = link("dl-live");
+
+    let requests = (0..12).map(|_| test::call_service(&app, test::TestRequest::get().uri(&url).to_request()));
+    let served = futures::future::join_all(requests)
+        .await
+        .iter()
+        .filter(|resp| resp.status() == StatusCode::OK)
+        .count();
+
+    assert_eq!(served as i64, MAX_DOWNLOADS);
+    assert_eq!(download_count(&fixture, "dl-live").await, MAX_DOWNLOADS);
+}
+
+#[actix_web::test]
+async fn expired_and_forged_links_are_gone() {
+    let fixture = fixture().await;
+    let app = init_app!(fixture);
+
+    // The grant expired even though the link itself has not
+    for uri in [link("dl-stale"), link("dl-missing"), "/downloads/not-a-token".to_string()] {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::GONE, "{}", uri);
+    }
+    assert_eq!(download_count(&fixture, "dl-stale").await, 0);
+}
@@ -0,0 +1,203 @@
+use secure_store::orders::{self, OrderStatus, StatusChange, StatusError};
+use secure_store::{digital, inventory};
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{Row, SqlitePool};
+
+const ORDER: &str = "ORD-1";
+const PRODUCT: &str = "prod-1";
+
+async fn database() -> SqlitePool {
+    // One connection, so every query sees the same in-memory database
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    for sql in [
+        "CREATE TABLE orders (id TEXT PRIMARY KEY, status TEXT NOT NULL, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL)",
+        "CREATE TABLE order_items (id INTEGER PRIMARY KEY AUTOINCREMENT, order_number TEXT NOT NULL, product_id TEXT NOT NULL, quantity INTEGER NOT NULL, fulfilled_at INTEGER)",
+        "CREATE TABLE order_status_history (id INTEGER PRIMARY KEY AUTOINCREMENT, order_id TEXT NOT NULL, from_status TEXT, to_status TEXT NOT NULL, actor TEXT NOT NULL, reason TEXT, created_at INTEGER NOT NULL)",
+        "CREATE TABLE products (id TEXT PRIMARY KEY, digital_delivery TEXT, stock INTEGER NOT NULL CHECK (stock >= 0), reserved INTEGER NOT NULL DEFAULT 0 CHECK (reserved >= 0 AND reserved <= stock), archived_at INTEGER)",
+        "CREATE TABLE stock_reservations (id INTEGER PRIMARY KEY AUTOINCREMENT, order_id TEXT NOT NULL, product_id TEXT NOT NULL, variant_id TEXT, quantity INTEGER NOT NULL, status TEXT NOT NULL, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL)",
+        "CREATE TABLE stock_adjustments (id INTEGER PRIMARY KEY AUTOINCREMENT, product_id TEXT NOT NULL, variant_id TEXT, delta INTEGER NOT NULL, reason TEXT NOT NULL, actor TEXT NOT NULL, order_id TEXT, created_at INTEGER NOT NULL)",
+        "INSERT INTO products (id, stock) VALUES ('prod-1', 5)",
+    ] {
+        sqlx::query(sql).execute(&db).await.unwrap();
+    }
+    db
+}
+
+// An order holding two units of PRODUCT, as checkout leaves it
+async fn place_order(db: &SqlitePool, status: OrderStatus) {
+    sqlx::query("INSERT INTO orders (id, status, created_at, updated_at) VALUES (?, ?, 0, 0)")
+        .bind(ORDER)
+        .bind(status.as_str())
+        .execute(db)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO order_items (order_number, product_id, quantity) VALUES (?, ?, 2)")
+        .bind(ORDER)
+        .bind(PRODUCT)
+        .execute(db)
+        .await
+        .unwrap();
+    let mut tx = db.begin().await.unwrap();
+    inventory::reserve_stock(&mut tx, ORDER, PRODUCT, None, 2).await.unwrap();
+    tx.commit().await.unwrap();
+}
+
+async fn set_status(db: &SqlitePool, status: OrderStatus) {
+    sqlx::query("UPDATE orders SET status = ? WHERE id = ?")
+        .bind(status.as_str())
+        .bind(ORDER)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+async fn status(db: &SqlitePool) -> OrderStatus {
+    sqlx::query("SELECT status FROM orders WHERE id = ?")
+        .bind(ORDER)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .get::<String, _>("status")
+        .parse()
+        .unwrap()
+}
+
+async fn history_len(db: &SqlitePool) -> usize {
+    orders::status_history(db, ORDER).await.unwrap().len()
+}
+
+// (stock, reserved) of PRODUCT
+async fn stock(db: &SqlitePool) -> (i64, i64) {
+    let row = sqlx::query("SELECT stock, reserved FROM products WHERE id = ?")
+        .bind(PRODUCT)
+        .fetch_one(db)
+        .await
+        .unwrap();
+    (row.get("stock"), row.get("reserved"))
+}
+
+async fn reservation_status(db: &SqlitePool) -> String {
+    sqlx::query("SELECT status FROM stock_reservations WHERE order_id = ?")
+        .bind(ORDER)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .get("status")
+}
+
+#[actix_web::test]
+async fn only_listed_transitions_are_allowed() {
+    let db = database().await;
+    place_order(&db, OrderStatus::Pending).await;
+    let change = StatusChange::system("test");
+
+    for from in OrderStatus::ALL {
+        for to in OrderStatus::ALL {
+            set_status(&db, from).await;
+            let before = history_len(&db).await;
+            let mut conn = db.acquire().await.unwrap();
+            let result = orders::transition_status(&mut conn, ORDER, to, &change).await;
+            drop(conn);
+
+            if from == to {
+                assert!(matches!(result, Ok(previous) if previous == from), "{} -> {}", from, to);
+                assert_eq!(history_len(&db).await, before, "{} -> {} is not a change", from, to);
+            } else if from.can_transition_to(to) {
+                assert!(matches!(result, Ok(previous) if previous == from), "{} -> {}", from, to);
+                assert_eq!(status(&db).await, to);
+                assert_eq!(history_len(&db).await, before + 1, "{} -> {}", from, to);
+            } else {
+                assert!(
+                    matches!(result, Err(StatusError::NotAllowed { from: f, to: t }) if f == from && t == to),
+                    "{} -> {}", from, to
+                );
+                assert_eq!(status(&db).await, from);
+                assert_eq!(history_len(&db).await, before, "{} -> {} left no history", from, to);
+            }
+        }
+    }
+}
+
+#[actix_web::test]
+async fn unknown_orders_are_not_found() {
+    let db = database().await;
+    let mut conn = db.acquire().await.unwrap();
+    let result = orders::transition_status(&mut conn, "ORD-missing", OrderStatus::Paid, &StatusChange::system("test")).await;
+    assert!(matches!(result, Err(StatusError::NotFound)));
+}
+
+#[actix_web::test]
+async fn history_records_every_step_with_its_actor() {
+    let db = database().await;
+    place_order(&db, OrderStatus::AwaitingPayment).await;
+
+    orders::process_paid_orders(&db, &[ORDER.to_string()]).await;
+    let admin = StatusChange::admin("root", Some("Handed to courier".to_string()));
+    orders::change_status(&db, ORDER, OrderStatus::Shipped, &admin).await.unwrap();
+    orders::change_status(&db, ORDER, OrderStatus::Delivered, &StatusChange::system("Courier confirmed")).await.unwrap();
+
+    let history = orders::status_history(&db, ORDER).await.unwrap();
+    let steps: Vec<_> = history.iter()
+        .map(|e| (e.from_status.as_deref(), e.to_status.as_str(), e.actor.as_str()))
+        .collect();
+    assert_eq!(steps, [
+        (Some("AwaitingPayment"), "Paid", "payment"),
+        (Some("Paid"), "Shipped", "admin:root"),
+        (Some("Shipped"), "Delivered", "system"),
+    ]);
+    assert_eq!(history[1].reason.as_deref(), Some("Handed to courier"));
+
+    // Customers see who acted, not which staff member
+    let timeline = orders::customer_timeline(&db, ORDER).await.unwrap();
+    assert_eq!(timeline[1].actor, "admin");
+}
+
+#[actix_web::test]
+async fn payment_commits_reserved_stock_once() {
+    let db = database().await;
+    place_order(&db, OrderStatus::AwaitingPayment).await;
+    assert_eq!(stock(&db).await, (5, 2));
+
+    orders::process_paid_orders(&db, &[ORDER.to_string()]).await;
+    assert_eq!(status(&db).await, OrderStatus::Paid);
+    assert_eq!(stock(&db).await, (3, 0));
+    assert_eq!(reservation_status(&db).await, inventory::RESERVATION_COMMITTED);
+
+    // A repeated confirmation changes nothing
+    orders::process_paid_orders(&db, &[ORDER.to_string()]).await;
+    assert_eq!(stock(&db).await, (3, 0));
+    assert_eq!(history_len(&db).await, 1);
+}
+
+#[actix_web::test]
+async fn cancelled_orders_are_not_paid_or_fulfilled() {
+    let db = database().await;
+    place_order(&db, OrderStatus::AwaitingPayment).await;
+
+    let change = StatusChange::customer(Some("usr-1"), "Changed my mind");
+    orders::change_status(&db, ORDER, OrderStatus::Cancelled, &change).await.unwrap();
+    assert_eq!(stock(&db).await, (5, 0));
+    assert_eq!(reservation_status(&db).await, inventory::RESERVATION_RELEASED);
+
+    // A payment arriving late leaves the order cancelled and the stock alone
+    let mut conn = db.acquire().await.unwrap();
+    assert!(matches!(
+        orders::mark_paid(&mut conn, ORDER).await,
+        Err(StatusError::NotAllowed { from: OrderStatus::Cancelled, to: OrderStatus::Paid })
+    ));
+    drop(conn);
+    orders::process_paid_orders(&db, &[ORDER.to_string()]).await;
+    assert_eq!(status(&db).await, OrderStatus::Cancelled);
+    assert_eq!(stock(&db).await, (5, 0));
+    assert_eq!(reservation_status(&db).await, inventory::RESERVATION_RELEASED);
+    assert_eq!(history_len(&db).await, 1);
+
+    assert!(matches!(
+        digital::fulfil_order(&db, ORDER).await,
+        Err(StatusError::NotAllowed { from: OrderStatus::Cancelled, .. })
+    ));
+}
@@ -0,0 +1,146 @@
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use hmac::{Hmac, Mac};
+use secure_store::monero::MoneroPaymentStore;
+use secure_store::{auth, passwords, rbac, session, two_factor, AppState};
+use serde_json::{json, Value};
+use sha1::Sha1;
+use sqlx::sqlite::SqlitePoolOptions;
+
+const USER_ID: &str = "usr-2fa";
+const USERNAME: &str = "alice";
+const PASSWORD: &str = "correct horse battery staple";
+const SECRET: &[u8] = b"two-factor-test-key!";
+
+async fn app_state() -> web::Data<AppState> {
+    // Every test shares the process-wide keys; only the first install counts
+    let _ = session::install_keys(
+        session::JwtKeys::hmac("test", b"two-factor-test-secret-0123456789").unwrap(),
+    );
+
+    // One connection, so every query sees the same in-memory database
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    for sql in [
+        "CREATE TABLE users (id TEXT PRIMARY KEY, username TEXT UNIQUE NOT NULL, password_hash TEXT NOT NULL, role TEXT NOT NULL, created_at INTEGER NOT NULL)",
+        "CREATE TABLE roles (name TEXT PRIMARY KEY, description TEXT NOT NULL DEFAULT '', built_in INTEGER NOT NULL DEFAULT 0, created_at INTEGER NOT NULL)",
+        "CREATE TABLE role_permissions (role TEXT NOT NULL, permission TEXT NOT NULL, PRIMARY KEY (role, permission))",
+        "CREATE TABLE sessions (id TEXT PRIMARY KEY, user_id TEXT NOT NULL, device TEXT NOT NULL, ip TEXT NOT NULL, created_at INTEGER NOT NULL, last_used_at INTEGER NOT NULL, expires_at INTEGER NOT NULL, revoked_at INTEGER, revoked_reason TEXT)",
+        "CREATE TABLE refresh_tokens (token_hash TEXT PRIMARY KEY, session_id TEXT NOT NULL, created_at INTEGER NOT NULL, used_at INTEGER)",
+        "CREATE TABLE login_failures (scope TEXT NOT NULL, key TEXT NOT NULL, failures INTEGER NOT NULL, last_failure_at INTEGER NOT NULL, blocked_until INTEGER NOT NULL, locked BOOLEAN NOT NULL DEFAULT FALSE, PRIMARY KEY (scope, key))",
+        "CREATE TABLE login_events (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id TEXT, username TEXT NOT NULL, ip TEXT NOT NULL, device TEXT NOT NULL, event TEXT NOT NULL, detail TEXT, created_at INTEGER NOT NULL)",
+        "CREATE TABLE user_totp (user_id TEXT PRIMARY KEY, secret TEXT NOT NULL, created_at INTEGER NOT NULL, enabled_at INTEGER, last_used_step INTEGER)",
+        "CREATE TABLE totp_recovery_codes (user_id TEXT NOT NULL, code_hash TEXT NOT NULL, created_at INTEGER NOT NULL, used_at INTEGER, PRIMARY KEY (user_id, code_hash))",
+    ] {
+        sqlx::query(sql).execute(&db).await.unwrap();
+    }
+    rbac::seed_built_in_roles(&db).await.unwrap();
+    sqlx::query("INSERT INTO users (id, username, password_hash, role, created_at) VALUES (?, ?, ?, 'user', 0)")
+        .bind(USER_ID)
+        .bind(USERNAME)
+        .bind(passwords::hash_password(PASSWORD).unwrap())
+        .execute(&db)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO user_totp (user_id, secret, created_at, enabled_at) VALUES (?, ?, 0, 0)")
+        .bind(USER_ID)
+        .bind(hex::encode(SECRET))
+        .execute(&db)
+        .await
+        .unwrap();
+
+    web::Data::new(AppState {
+        db,
+        monero_payments: MoneroPaymentStore::new(),
+    })
+}
+
+macro_rules! init_app {
+    ($state:expr) => {
+        test::init_service(App::new().app_data($state.clone()).service(auth::init_routes())).await
+    };
+}
+
+// The code an authenticator app shows right now
+fn current_code() -> String {
+    let step = chrono::Utc::now().timestamp() / two_factor::STEP_SECONDS;
+    let mut mac = Hmac::<Sha1>::new_from_slice(SECRET).unwrap();
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    format!("{:06}", value % 10u32.pow(two_factor::DIGITS))
+}
+
+// POST a JSON body and return the status and JSON reply
+macro_rules! post {
+    ($app:expr, $path:expr, $body:expr) => {{
+        let req = test::TestRequest::post().uri($path).set_json($body).to_request();
+        let resp = test::call_service(&$app, req).await;
+        let status = resp.status();
+        (status, test::read_body_json::<Value, _>(resp).await)
+    }};
+}
+
+#[actix_web::test]
+async fn password_then_code_signs_in() {
+    let state = app_state().await;
+    let app = init_app!(state);
+
+    // The password only earns a pre-auth token
+    let (status, body) = post!(app, "/auth/login", json!({"username": USERNAME, "password": PASSWORD}));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["two_factor_required"], true);
+    assert!(body.get("token").is_none());
+    let pre_auth = body["pre_auth_token"].as_str().unwrap().to_string();
+
+    // ... which isn't an access token
+    let req = test::TestRequest::get()
+        .uri("/auth/profile")
+        .insert_header(("Authorization", format!("Bearer {}", pre_auth)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let (status, _) = post!(app, "/auth/login/2fa", json!({"pre_auth_token": pre_auth, "code": "000000x"}));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let code = current_code();
+    let (status, body) = post!(app, "/auth/login/2fa", json!({"pre_auth_token": pre_auth, "code": code}));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user_id"], USER_ID);
+    let token = body["token"].as_str().unwrap();
+    let req = test::TestRequest::get()
+        .uri("/auth/profile")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    // The same code can't sign in twice
+    let (status, _) = post!(app, "/auth/login/2fa", json!({"pre_auth_token": pre_auth, "code": code}));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn second_step_needs_a_pre_auth_token() {
+    let state = app_state().await;
+    let app = init_app!(state);
+
+    let (status, _) = post!(app, "/auth/login/2fa", json!({"pre_auth_token": "not-a-jwt", "code": current_code()}));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // An access token is signed with the same keys but for another audience
+    let access = session::start_session(&state.db, USER_ID, USERNAME, "user", "test", "127.0.0.1")
+        .await
+        .unwrap()
+        .access_token;
+    let (status, _) = post!(app, "/auth/login/2fa", json!({"pre_auth_token": access, "code": current_code()}));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // A wrong password gets no pre-auth token at all
+    let (status, body) = post!(app, "/auth/login", json!({"username": USERNAME, "password": "wrong"}));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body.get("pre_auth_token").is_none());
+}